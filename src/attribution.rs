@@ -0,0 +1,319 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    time::{Duration, Instant},
+};
+
+use cadence_macros::{statsd_count, statsd_time};
+use dashmap::DashMap;
+use serde::Serialize;
+use solana_sdk::slot_history::Slot;
+
+/// LeaderDelivery is one of our sends that a leader received
+#[derive(Clone, Debug)]
+struct LeaderDelivery {
+    leader: String,
+    leader_num: usize,
+    delivered_at: Instant,
+}
+
+/// TrackedTransaction is a transaction waiting to land, with the sends made for it so far
+#[derive(Clone, Debug)]
+struct TrackedTransaction {
+    route: &'static str,
+    identity: String,
+    received_at: Instant,
+    deliveries: Vec<LeaderDelivery>,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct LandingCounts {
+    transactions: u64,
+    landed: u64,
+    landing_time_total: Duration,
+}
+
+impl LandingCounts {
+    fn record(&mut self, landing_time: Option<Duration>) {
+        self.transactions += 1;
+        if let Some(landing_time) = landing_time {
+            self.landed += 1;
+            self.landing_time_total += landing_time;
+        }
+    }
+
+    fn to_stats(self) -> LandingStats {
+        LandingStats {
+            transactions: self.transactions,
+            landed: self.landed,
+            land_rate: match self.transactions {
+                0 => 0.0,
+                transactions => self.landed as f64 / transactions as f64,
+            },
+            avg_landing_ms: (self.landed > 0)
+                .then(|| (self.landing_time_total / self.landed as u32).as_millis() as u64),
+        }
+    }
+}
+
+/// LandingStats is how many transactions landed and how fast, from submission to confirmation
+#[derive(Serialize, Clone, Copy, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct LandingStats {
+    pub transactions: u64,
+    pub landed: u64,
+    pub land_rate: f64,
+    pub avg_landing_ms: Option<u64>,
+}
+
+/// LandingReport breaks the landing stats down by send port, slot leader, identity and position of
+/// the leader among the leaders each transaction was sent to
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct LandingReport {
+    pub by_route: HashMap<String, LandingStats>,
+    pub by_leader: HashMap<String, LandingStats>,
+    pub by_identity: HashMap<String, LandingStats>,
+    pub by_leader_num: HashMap<usize, LandingStats>,
+}
+
+/// LandingAttribution matches each landed transaction to the sends that reached its slot leader.
+/// A leader counts a transaction when it was sent one, and a landing when it led the slot the
+/// transaction landed in.
+pub struct LandingAttribution {
+    transactions: DashMap<String, TrackedTransaction>,
+    by_route: DashMap<String, LandingCounts>,
+    by_leader: DashMap<String, LandingCounts>,
+    by_identity: DashMap<String, LandingCounts>,
+    by_leader_num: DashMap<usize, LandingCounts>,
+}
+
+impl LandingAttribution {
+    pub fn new() -> Self {
+        Self {
+            transactions: DashMap::new(),
+            by_route: DashMap::new(),
+            by_leader: DashMap::new(),
+            by_identity: DashMap::new(),
+            by_leader_num: DashMap::new(),
+        }
+    }
+
+    /// track starts collecting the sends of a transaction
+    pub fn track(
+        &self,
+        signature: String,
+        route: &'static str,
+        identity: String,
+        received_at: Instant,
+    ) {
+        self.transactions.insert(
+            signature,
+            TrackedTransaction {
+                route,
+                identity,
+                received_at,
+                deliveries: vec![],
+            },
+        );
+    }
+
+    /// record_delivery notes that the leader received the transaction
+    pub fn record_delivery(&self, signature: &str, leader: &str, leader_num: usize) {
+        if let Some(mut transaction) = self.transactions.get_mut(signature) {
+            transaction.deliveries.push(LeaderDelivery {
+                leader: leader.to_string(),
+                leader_num,
+                delivered_at: Instant::now(),
+            });
+        }
+    }
+
+    /// record_landing attributes the transaction to the leader of the slot it landed in, or records
+    /// it as not landed when slot is None
+    pub fn record_landing(&self, signature: &str, slot: Option<Slot>, slot_leader: Option<String>) {
+        let Some((_, transaction)) = self.transactions.remove(signature) else {
+            return;
+        };
+        let landing_time = slot.map(|_| transaction.received_at.elapsed());
+        self.by_route
+            .entry(transaction.route.to_string())
+            .or_default()
+            .record(landing_time);
+        self.by_identity
+            .entry(transaction.identity.clone())
+            .or_default()
+            .record(landing_time);
+
+        // the first delivery to the slot leader is the one that landed
+        let landing_delivery = slot_leader.as_ref().and_then(|slot_leader| {
+            transaction
+                .deliveries
+                .iter()
+                .filter(|delivery| &delivery.leader == slot_leader)
+                .min_by_key(|delivery| delivery.delivered_at)
+        });
+        let leaders: BTreeSet<&str> = transaction
+            .deliveries
+            .iter()
+            .map(|delivery| delivery.leader.as_str())
+            .collect();
+        for leader in leaders {
+            let landed = landing_delivery.is_some_and(|delivery| delivery.leader == leader);
+            self.by_leader
+                .entry(leader.to_string())
+                .or_default()
+                .record(landing_time.filter(|_| landed));
+        }
+        let leader_nums: BTreeSet<usize> = transaction
+            .deliveries
+            .iter()
+            .map(|delivery| delivery.leader_num)
+            .collect();
+        for leader_num in leader_nums {
+            let landed = landing_delivery.is_some_and(|delivery| delivery.leader_num == leader_num);
+            self.by_leader_num
+                .entry(leader_num)
+                .or_default()
+                .record(landing_time.filter(|_| landed));
+        }
+
+        let Some(landing_time) = landing_time else {
+            return;
+        };
+        let sent_to_slot_leader = if landing_delivery.is_some() {
+            "true"
+        } else {
+            "false"
+        };
+        statsd_time!(
+            "transaction_landing_time",
+            landing_time,
+            "route" => transaction.route,
+            "identity" => &transaction.identity,
+            "sent_to_slot_leader" => sent_to_slot_leader
+        );
+        if let Some(delivery) = landing_delivery {
+            let leader_num = delivery.leader_num.to_string();
+            statsd_count!(
+                "transaction_landed_via_leader",
+                1,
+                "route" => transaction.route,
+                "leader_num" => &leader_num
+            );
+        }
+    }
+
+    pub fn report(&self) -> LandingReport {
+        LandingReport {
+            by_route: to_stats(&self.by_route),
+            by_leader: to_stats(&self.by_leader),
+            by_identity: to_stats(&self.by_identity),
+            by_leader_num: to_stats(&self.by_leader_num),
+        }
+    }
+}
+
+fn to_stats<K: Clone + Eq + std::hash::Hash>(
+    counts: &DashMap<K, LandingCounts>,
+) -> HashMap<K, LandingStats> {
+    counts
+        .iter()
+        .map(|entry| (entry.key().clone(), entry.value().to_stats()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::init_metrics;
+
+    fn attribution() -> LandingAttribution {
+        init_metrics();
+        LandingAttribution::new()
+    }
+
+    #[test]
+    fn attributes_landing_to_slot_leader() {
+        let attribution = attribution();
+        attribution.track(
+            "landed".to_string(),
+            "p3",
+            "identity".to_string(),
+            Instant::now(),
+        );
+        attribution.record_delivery("landed", "first", 0);
+        attribution.record_delivery("landed", "second", 1);
+        attribution.record_delivery("landed", "second", 1);
+        attribution.record_landing("landed", Some(10), Some("second".to_string()));
+
+        let report = attribution.report();
+        assert_eq!(report.by_route["p3"].transactions, 1);
+        assert_eq!(report.by_route["p3"].landed, 1);
+        assert_eq!(report.by_identity["identity"].landed, 1);
+        // a leader counts a transaction once, however often it was sent to it
+        assert_eq!(report.by_leader["second"].transactions, 1);
+        assert_eq!(report.by_leader["second"].landed, 1);
+        assert_eq!(report.by_leader["first"].transactions, 1);
+        assert_eq!(report.by_leader["first"].landed, 0);
+        assert_eq!(report.by_leader_num[&1].landed, 1);
+        assert_eq!(report.by_leader_num[&0].landed, 0);
+        assert!(report.by_leader["first"].avg_landing_ms.is_none());
+        assert!(report.by_leader["second"].avg_landing_ms.is_some());
+    }
+
+    #[test]
+    fn counts_transactions_that_did_not_land() {
+        let attribution = attribution();
+        for signature in ["landed", "dropped"] {
+            attribution.track(
+                signature.to_string(),
+                "mev",
+                "identity".to_string(),
+                Instant::now(),
+            );
+            attribution.record_delivery(signature, "leader", 0);
+        }
+        attribution.record_landing("landed", Some(10), Some("leader".to_string()));
+        attribution.record_landing("dropped", None, None);
+        // recorded once, later landings of an untracked signature are ignored
+        attribution.record_landing("dropped", Some(11), Some("leader".to_string()));
+
+        let stats = attribution.report().by_route["mev"];
+        assert_eq!(stats.transactions, 2);
+        assert_eq!(stats.landed, 1);
+        assert_eq!(stats.land_rate, 0.5);
+        assert_eq!(attribution.report().by_leader["leader"].transactions, 2);
+    }
+
+    #[test]
+    fn landing_outside_the_leaders_sent_to() {
+        let attribution = attribution();
+        attribution.track(
+            "landed".to_string(),
+            "p3s",
+            "identity".to_string(),
+            Instant::now(),
+        );
+        attribution.record_delivery("landed", "first", 0);
+        attribution.record_landing("landed", Some(10), Some("other".to_string()));
+
+        let report = attribution.report();
+        // the transaction landed, but not through a leader it was sent to
+        assert_eq!(report.by_route["p3s"].landed, 1);
+        assert_eq!(report.by_leader["first"].landed, 0);
+        assert!(!report.by_leader.contains_key("other"));
+    }
+
+    #[test]
+    fn averages_landing_time() {
+        let mut counts = LandingCounts::default();
+        counts.record(Some(Duration::from_millis(100)));
+        counts.record(Some(Duration::from_millis(300)));
+        counts.record(None);
+        let stats = counts.to_stats();
+        assert_eq!(stats.transactions, 3);
+        assert_eq!(stats.landed, 2);
+        assert_eq!(stats.avg_landing_ms, Some(200));
+        assert_eq!(LandingCounts::default().to_stats().land_rate, 0.0);
+    }
+}
@@ -10,6 +10,17 @@ pub fn invalid_request(reason: &str) -> ErrorObjectOwned {
     )
 }
 
+// same code as the solana RPC node health check
+const NODE_UNHEALTHY_CODE: i32 = -32005;
+
+pub fn node_unhealthy(reason: &str) -> ErrorObjectOwned {
+    ErrorObjectOwned::owned(
+        NODE_UNHEALTHY_CODE,
+        format!("Node is unhealthy: {reason}"),
+        None::<String>,
+    )
+}
+
 #[derive(Debug)]
 pub enum AtlasTxnSenderError {
     Custom(String),
@@ -0,0 +1,504 @@
+use std::{path::PathBuf, time::Duration};
+
+use cadence_macros::{statsd_count, statsd_time};
+use serde::Serialize;
+use solana_sdk::{slot_history::Slot, transaction::TransactionError};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::AsyncWriteExt,
+    net::{TcpStream, UdpSocket},
+    sync::mpsc::{self, error::TrySendError},
+    time::{timeout, Instant},
+};
+use tracing::{error, info};
+
+use crate::{errors::AtlasTxnSenderError, persistent_store::unix_millis_now};
+
+const SINK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// TransactionEvent is something that happened to one of our transactions
+#[derive(Serialize, Clone, Debug)]
+#[serde(
+    tag = "event",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum TransactionEvent {
+    /// the transaction was sent for the first time
+    Submitted {
+        signature: String,
+        api_key: String,
+        route: &'static str,
+        identity: String,
+        leader_policy: &'static str,
+        compute_unit_price: u64,
+        tip_lamports: u64,
+    },
+    /// a send to one leader finished, `outcome` is delivered, error or timeout
+    SendAttempt {
+        signature: String,
+        leader: String,
+        leader_num: usize,
+        destination: String,
+        identity: String,
+        retry: bool,
+        outcome: &'static str,
+        attempts: usize,
+        elapsed_ms: u64,
+    },
+    /// geyser saw the transaction processed in a block
+    Landed {
+        signature: String,
+        slot: Slot,
+        index: Option<u64>,
+        err: Option<TransactionError>,
+    },
+    /// the block the transaction was processed in died on a fork. Not an outcome, the transaction
+    /// may still land in another block.
+    Forked { signature: String, slot: Slot },
+    /// the transaction was confirmed
+    Confirmed {
+        signature: String,
+        slot: Option<Slot>,
+        landing_ms: u64,
+    },
+    /// we stopped following the transaction without it landing
+    Dropped {
+        signature: String,
+        reason: &'static str,
+    },
+}
+
+/// EventRecord is an event with the time it was published, as written to the sinks
+#[derive(Serialize, Clone, Debug)]
+struct EventRecord {
+    /// unix timestamp in milliseconds
+    at: u64,
+    #[serde(flatten)]
+    event: TransactionEvent,
+}
+
+/// EventSinkConfig holds the settings shared by the sinks
+#[derive(Clone, Debug)]
+pub struct EventSinkConfig {
+    /// events buffered per sink before new ones are dropped
+    pub buffer_size: usize,
+    /// most events written to a sink at once
+    pub batch_size: usize,
+    /// size at which a file sink rotates its file
+    pub file_max_bytes: u64,
+    /// rotated files kept next to the current one
+    pub file_max_files: usize,
+}
+
+/// EventSink is a destination for events
+enum EventSink {
+    /// JSON lines appended to a file that is rotated when it gets too big
+    File(FileSink),
+    /// a JSON line per datagram
+    Udp(UdpSocket),
+    /// JSON lines over a connection that is reopened after an error
+    Tcp {
+        addr: String,
+        stream: Option<TcpStream>,
+    },
+    /// batches POSTed as a JSON array
+    Http {
+        client: reqwest::Client,
+        url: String,
+    },
+}
+
+impl EventSink {
+    /// open parses `file:///path`, `udp://host:port`, `tcp://host:port` or an http(s) url
+    async fn open(url: &str, config: &EventSinkConfig) -> Result<Self, AtlasTxnSenderError> {
+        if let Some(path) = url.strip_prefix("file://") {
+            return Ok(EventSink::File(FileSink {
+                path: PathBuf::from(path),
+                file: None,
+                size: 0,
+                max_bytes: config.file_max_bytes,
+                max_files: config.file_max_files,
+            }));
+        }
+        if let Some(addr) = url.strip_prefix("udp://") {
+            let socket = UdpSocket::bind("0.0.0.0:0")
+                .await
+                .map_err(|e| format!("Error binding event socket: {e}"))?;
+            socket
+                .connect(addr)
+                .await
+                .map_err(|e| format!("Error resolving event sink {url}: {e}"))?;
+            return Ok(EventSink::Udp(socket));
+        }
+        if let Some(addr) = url.strip_prefix("tcp://") {
+            return Ok(EventSink::Tcp {
+                addr: addr.to_string(),
+                stream: None,
+            });
+        }
+        if url.starts_with("http://") || url.starts_with("https://") {
+            let client = reqwest::Client::builder()
+                .timeout(SINK_TIMEOUT)
+                .build()
+                .map_err(|e| format!("Error building http client: {e}"))?;
+            return Ok(EventSink::Http {
+                client,
+                url: url.to_string(),
+            });
+        }
+        Err(format!("unsupported event sink: {url}").into())
+    }
+
+    async fn write(&mut self, events: &[EventRecord]) -> Result<(), AtlasTxnSenderError> {
+        match self {
+            EventSink::File(file_sink) => file_sink.write(&to_lines(events)?).await,
+            EventSink::Udp(socket) => {
+                for event in events {
+                    let mut line = serde_json::to_vec(event)
+                        .map_err(|e| format!("Error serializing event: {e}"))?;
+                    line.push(b'\n');
+                    socket
+                        .send(&line)
+                        .await
+                        .map_err(|e| format!("Error sending event: {e}"))?;
+                }
+                Ok(())
+            }
+            EventSink::Tcp { addr, stream } => {
+                let lines = to_lines(events)?;
+                if stream.is_none() {
+                    let connection = timeout(SINK_TIMEOUT, TcpStream::connect(addr.as_str()))
+                        .await
+                        .map_err(|_| format!("Timed out connecting to {addr}"))?
+                        .map_err(|e| format!("Error connecting to {addr}: {e}"))?;
+                    *stream = Some(connection);
+                }
+                let connection = stream.as_mut().unwrap();
+                match timeout(SINK_TIMEOUT, connection.write_all(&lines)).await {
+                    Ok(Ok(())) => Ok(()),
+                    Ok(Err(e)) => {
+                        *stream = None;
+                        Err(format!("Error writing events to {addr}: {e}").into())
+                    }
+                    Err(_) => {
+                        *stream = None;
+                        Err(format!("Timed out writing events to {addr}").into())
+                    }
+                }
+            }
+            EventSink::Http { client, url } => {
+                let response = client
+                    .post(url.as_str())
+                    .json(events)
+                    .send()
+                    .await
+                    .map_err(|e| format!("Error posting events: {e}"))?;
+                if !response.status().is_success() {
+                    return Err(format!("Event sink returned {}", response.status()).into());
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+fn to_lines(events: &[EventRecord]) -> Result<Vec<u8>, AtlasTxnSenderError> {
+    let mut lines = vec![];
+    for event in events {
+        serde_json::to_writer(&mut lines, event)
+            .map_err(|e| format!("Error serializing event: {e}"))?;
+        lines.push(b'\n');
+    }
+    Ok(lines)
+}
+
+struct FileSink {
+    path: PathBuf,
+    file: Option<File>,
+    size: u64,
+    max_bytes: u64,
+    max_files: usize,
+}
+
+impl FileSink {
+    async fn write(&mut self, lines: &[u8]) -> Result<(), AtlasTxnSenderError> {
+        if self.file.is_some() && self.size + lines.len() as u64 > self.max_bytes {
+            self.rotate().await?;
+        }
+        if self.file.is_none() {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)
+                .await
+                .map_err(|e| format!("Error opening {}: {e}", self.path.display()))?;
+            self.size = file.metadata().await.map(|m| m.len()).unwrap_or(0);
+            self.file = Some(file);
+        }
+        let file = self.file.as_mut().unwrap();
+        if let Err(e) = file.write_all(lines).await {
+            self.file = None;
+            return Err(format!("Error writing {}: {e}", self.path.display()).into());
+        }
+        file.flush()
+            .await
+            .map_err(|e| format!("Error writing {}: {e}", self.path.display()))?;
+        self.size += lines.len() as u64;
+        Ok(())
+    }
+
+    /// rotate shifts events.jsonl to events.jsonl.1, events.jsonl.1 to events.jsonl.2 and so on,
+    /// dropping the oldest file
+    async fn rotate(&mut self) -> Result<(), AtlasTxnSenderError> {
+        self.file = None;
+        let rotated = |n: usize| PathBuf::from(format!("{}.{n}", self.path.display()));
+        for n in (1..self.max_files).rev() {
+            // missing files are fine, there were fewer rotations so far
+            let _ = fs::rename(rotated(n), rotated(n + 1)).await;
+        }
+        if self.max_files == 0 {
+            fs::remove_file(&self.path).await
+        } else {
+            fs::rename(&self.path, rotated(1)).await
+        }
+        .map_err(|e| format!("Error rotating {}: {e}", self.path.display()))?;
+        statsd_count!("event_file_rotated", 1);
+        Ok(())
+    }
+}
+
+/// EventBus publishes transaction events to the configured sinks. Each sink has its own bounded
+/// buffer and writer task, so a slow sink never blocks the send path or the other sinks: events
+/// that don't fit in a sink's buffer are dropped and counted.
+pub struct EventBus {
+    sinks: Vec<(String, mpsc::Sender<EventRecord>)>,
+}
+
+impl EventBus {
+    /// new opens the sinks, a comma separated list of urls. Without sinks, publishing does nothing.
+    pub async fn new(
+        sink_urls: &str,
+        config: EventSinkConfig,
+    ) -> Result<Self, AtlasTxnSenderError> {
+        let mut sinks = vec![];
+        for url in sink_urls
+            .split(',')
+            .map(|url| url.trim())
+            .filter(|url| !url.is_empty())
+        {
+            let sink = EventSink::open(url, &config).await?;
+            // the url may hold credentials, so sinks are named by scheme in metrics
+            let name = url.split("://").next().unwrap_or_default().to_string();
+            let (sender, receiver) = mpsc::channel(config.buffer_size);
+            write_events(name.clone(), sink, receiver, config.batch_size);
+            info!("Publishing transaction events to a {} sink", name);
+            sinks.push((name, sender));
+        }
+        Ok(Self { sinks })
+    }
+
+    /// publish buffers the event for every sink, returning the number of sinks it was dropped for
+    pub fn publish(&self, event: TransactionEvent) -> usize {
+        if self.sinks.is_empty() {
+            return 0;
+        }
+        let record = EventRecord {
+            at: unix_millis_now(),
+            event,
+        };
+        let mut dropped = 0;
+        for (name, sender) in self.sinks.iter() {
+            match sender.try_send(record.clone()) {
+                Ok(()) => continue,
+                Err(TrySendError::Full(_)) => {
+                    statsd_count!("event_dropped", 1, "sink" => name, "reason" => "buffer_full");
+                }
+                Err(TrySendError::Closed(_)) => {
+                    statsd_count!("event_dropped", 1, "sink" => name, "reason" => "sink_closed");
+                }
+            }
+            dropped += 1;
+        }
+        dropped
+    }
+}
+
+/// write_events writes the buffered events to the sink in batches. A batch that fails to write is
+/// dropped, the sink is expected to be retried by the next one.
+fn write_events(
+    name: String,
+    mut sink: EventSink,
+    mut receiver: mpsc::Receiver<EventRecord>,
+    batch_size: usize,
+) {
+    tokio::spawn(async move {
+        let mut batch = Vec::with_capacity(batch_size);
+        while receiver.recv_many(&mut batch, batch_size).await > 0 {
+            let start = Instant::now();
+            match sink.write(&batch).await {
+                Ok(()) => {
+                    statsd_count!("event_written", batch.len() as i64, "sink" => &name);
+                    statsd_time!("event_write_time", start.elapsed(), "sink" => &name);
+                }
+                Err(e) => {
+                    error!(sink = name, "Error writing {} events: {}", batch.len(), e);
+                    statsd_count!("event_dropped", batch.len() as i64, "sink" => &name, "reason" => "write_error");
+                }
+            }
+            batch.clear();
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncBufReadExt, BufReader},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::test_utils::init_metrics;
+
+    fn dropped(signature: &str) -> EventRecord {
+        EventRecord {
+            at: unix_millis_now(),
+            event: TransactionEvent::Dropped {
+                signature: signature.to_string(),
+                reason: "not_confirmed",
+            },
+        }
+    }
+
+    fn file_sink(max_bytes: u64, max_files: usize) -> FileSink {
+        FileSink {
+            path: std::env::temp_dir().join(format!("events-{}.jsonl", uuid::Uuid::new_v4())),
+            file: None,
+            size: 0,
+            max_bytes,
+            max_files,
+        }
+    }
+
+    fn rotated(file_sink: &FileSink, n: usize) -> PathBuf {
+        PathBuf::from(format!("{}.{n}", file_sink.path.display()))
+    }
+
+    async fn read(path: &PathBuf) -> Option<String> {
+        fs::read_to_string(path).await.ok()
+    }
+
+    #[test]
+    fn serializes_events() {
+        let record = EventRecord {
+            at: 1,
+            event: TransactionEvent::Forked {
+                signature: "sig".to_string(),
+                slot: 10,
+            },
+        };
+        assert_eq!(
+            serde_json::to_value(&record).unwrap(),
+            serde_json::json!({"at": 1, "event": "forked", "signature": "sig", "slot": 10})
+        );
+        assert_eq!(
+            serde_json::to_value(dropped("sig")).unwrap()["reason"],
+            "not_confirmed"
+        );
+    }
+
+    #[tokio::test]
+    async fn rotates_files() {
+        init_metrics();
+        let mut file_sink = file_sink(10, 2);
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            file_sink.write(line.as_bytes()).await.unwrap();
+        }
+        assert_eq!(read(&file_sink.path).await.unwrap(), "fourth\n");
+        assert_eq!(read(&rotated(&file_sink, 1)).await.unwrap(), "third\n");
+        assert_eq!(read(&rotated(&file_sink, 2)).await.unwrap(), "second\n");
+        // only max_files rotated files are kept
+        assert!(read(&rotated(&file_sink, 3)).await.is_none());
+
+        // lines are appended until the file is full
+        file_sink.write(b"a\n").await.unwrap();
+        assert_eq!(read(&file_sink.path).await.unwrap(), "fourth\na\n");
+
+        for n in 0..=2 {
+            let path = match n {
+                0 => file_sink.path.clone(),
+                n => rotated(&file_sink, n),
+            };
+            let _ = fs::remove_file(path).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn rotates_without_keeping_files() {
+        init_metrics();
+        let mut file_sink = file_sink(10, 0);
+        file_sink.write(b"first\n").await.unwrap();
+        file_sink.write(b"second\n").await.unwrap();
+        assert_eq!(read(&file_sink.path).await.unwrap(), "second\n");
+        assert!(read(&rotated(&file_sink, 1)).await.is_none());
+        let _ = fs::remove_file(&file_sink.path).await;
+    }
+
+    #[tokio::test]
+    async fn reconnects_tcp_sink() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = EventSinkConfig {
+            buffer_size: 10,
+            batch_size: 10,
+            file_max_bytes: 0,
+            file_max_files: 0,
+        };
+        let mut sink = EventSink::open(&format!("tcp://{addr}"), &config)
+            .await
+            .unwrap();
+
+        sink.write(&[dropped("first")]).await.unwrap();
+        let (connection, _) = listener.accept().await.unwrap();
+        let mut lines = BufReader::new(connection).lines();
+        assert!(lines.next_line().await.unwrap().unwrap().contains("first"));
+
+        // the closed connection fails a write, once the peer's reset arrives
+        drop(lines);
+        let mut failed = false;
+        for _ in 0..50 {
+            if sink.write(&[dropped("lost")]).await.is_err() {
+                failed = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(failed);
+        assert!(matches!(sink, EventSink::Tcp { stream: None, .. }));
+
+        sink.write(&[dropped("second")]).await.unwrap();
+        let (connection, _) = listener.accept().await.unwrap();
+        let mut lines = BufReader::new(connection).lines();
+        assert!(lines.next_line().await.unwrap().unwrap().contains("second"));
+    }
+
+    #[tokio::test]
+    async fn counts_dropped_events() {
+        init_metrics();
+        let (full, _full_receiver) = mpsc::channel(1);
+        let (closed, closed_receiver) = mpsc::channel(1);
+        drop(closed_receiver);
+        let event_bus = EventBus {
+            sinks: vec![("full".to_string(), full), ("closed".to_string(), closed)],
+        };
+        let event = || TransactionEvent::Dropped {
+            signature: "signature".to_string(),
+            reason: "not_confirmed",
+        };
+        // buffered for the first sink
+        assert_eq!(event_bus.publish(event()), 1);
+        assert_eq!(event_bus.publish(event()), 2);
+        assert_eq!(EventBus { sinks: vec![] }.publish(event()), 0);
+    }
+}
@@ -1,59 +1,146 @@
-use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::Instant;
-use std::{collections::HashMap, sync::Arc, time::Duration};
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::Arc,
+    time::Duration,
+};
 
-use cadence_macros::statsd_count;
-use dashmap::DashMap;
+use cadence_macros::{statsd_count, statsd_gauge, statsd_time};
+use dashmap::{mapref::entry::Entry, DashMap};
 use futures::sink::SinkExt;
 use futures::StreamExt;
-use rand::distributions::Alphanumeric;
-use rand::Rng;
+use serde::Deserialize;
 use solana_sdk::clock::UnixTimestamp;
+use solana_sdk::commitment_config;
 use solana_sdk::signature::Signature;
-use tokio::time::sleep;
+use solana_sdk::slot_history::Slot;
+use tokio::sync::watch;
+use tokio::time::{interval, sleep};
 use tonic::async_trait;
 use tracing::error;
 use yellowstone_grpc_client::{
     ClientTlsConfig, GeyserGrpcBuilderError, GeyserGrpcClient, Interceptor,
 };
+use yellowstone_grpc_proto::convert_from::create_tx_error;
 use yellowstone_grpc_proto::geyser::SubscribeRequestFilterBlocks;
 use yellowstone_grpc_proto::geyser::{
-    subscribe_update::UpdateOneof, CommitmentLevel, SubscribeRequest, SubscribeRequestFilterSlots,
-    SubscribeRequestPing,
+    subscribe_update::UpdateOneof, CommitmentLevel, SlotStatus, SubscribeRequest,
+    SubscribeRequestFilterSlots, SubscribeRequestFilterTransactions, SubscribeRequestPing,
+    SubscribeUpdateTransactionInfo,
 };
 
-use crate::solana_rpc::SolanaRpc;
+use crate::errors::AtlasTxnSenderError;
+use crate::events::{EventBus, TransactionEvent};
+use crate::latency::PipelineLatency;
+use crate::signature_status::{ConfirmationStatus, SignatureStatus, SignatureStatuses};
+use crate::solana_rpc::{publish_slot, SlotUpdate, SolanaRpc};
+use crate::transaction_store::TransactionStore;
+
+const PING_INTERVAL: Duration = Duration::from_secs(10);
+const FILTER_UPDATE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// GeyserEndpoint is a Yellowstone gRPC geyser url and its x-token
+#[derive(Clone, Debug)]
+pub struct GeyserEndpoint {
+    pub url: String,
+    pub x_token: Option<String>,
+}
 
+impl GeyserEndpoint {
+    /// parse_list pairs comma separated urls with the comma separated x-tokens in the same position.
+    /// An empty token or a missing trailing one means no token.
+    pub fn parse_list(
+        urls: &str,
+        x_tokens: Option<&str>,
+    ) -> Result<Vec<GeyserEndpoint>, AtlasTxnSenderError> {
+        let urls: Vec<&str> = urls.split(',').map(str::trim).collect();
+        let x_tokens: Vec<&str> = x_tokens
+            .map(|x_tokens| x_tokens.split(',').map(str::trim).collect())
+            .unwrap_or_default();
+        if x_tokens.len() > urls.len() {
+            return Err(
+                format!("{} x-tokens for {} geyser urls", x_tokens.len(), urls.len()).into(),
+            );
+        }
+        Ok(urls
+            .iter()
+            .enumerate()
+            .filter(|(_, url)| !url.is_empty())
+            .map(|(i, url)| GeyserEndpoint {
+                url: url.to_string(),
+                x_token: x_tokens
+                    .get(i)
+                    .filter(|x_token| !x_token.is_empty())
+                    .map(|x_token| x_token.to_string()),
+            })
+            .collect())
+    }
+}
+
+/// GeyserConfirmation chooses which updates tell us a transaction landed
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum GeyserConfirmation {
+    /// every processed block with all its transactions
+    #[default]
+    Blocks,
+    /// only the processed transactions of the fee payers in the transaction store
+    Transactions,
+}
+
+/// GrpcGeyserImpl subscribes to every endpoint at once. The highest slot and the first block seen for
+/// each slot win, so one endpoint stalling doesn't leave a gap.
 pub struct GrpcGeyserImpl {
-    endpoint: String,
-    auth_header: Option<String>,
-    cur_slot: Arc<AtomicU64>,
-    signature_cache: Arc<DashMap<String, (UnixTimestamp, Instant)>>,
+    endpoints: Vec<GeyserEndpoint>,
+    /// reconnect when no slot arrives for this long
+    stall_timeout: Duration,
+    confirmation: GeyserConfirmation,
+    transaction_store: Arc<dyn TransactionStore>,
+    slot_sender: Arc<watch::Sender<Option<SlotUpdate>>>,
+    signature_statuses: Arc<SignatureStatuses>,
+    event_bus: Arc<EventBus>,
+    pipeline_latency: Arc<PipelineLatency>,
+    /// when each slot status and block was first seen, to measure how far behind the other
+    /// endpoints are. A slot is updated once per status, so each status is timed on its own.
+    slots_seen: Arc<DashMap<(Slot, i32), Instant>>,
+    blocks_seen: Arc<DashMap<Slot, Instant>>,
 }
 
 impl GrpcGeyserImpl {
-    pub fn new(endpoint: String, auth_header: Option<String>) -> Self {
+    pub fn new(
+        endpoints: Vec<GeyserEndpoint>,
+        stall_timeout: Duration,
+        confirmation: GeyserConfirmation,
+        transaction_store: Arc<dyn TransactionStore>,
+        signature_statuses: Arc<SignatureStatuses>,
+        event_bus: Arc<EventBus>,
+        pipeline_latency: Arc<PipelineLatency>,
+    ) -> Self {
         let grpc_geyser = Self {
-            endpoint,
-            auth_header,
-            cur_slot: Arc::new(AtomicU64::new(0)),
-            signature_cache: Arc::new(DashMap::new()),
+            endpoints,
+            stall_timeout,
+            confirmation,
+            transaction_store,
+            slot_sender: Arc::new(watch::channel(None).0),
+            signature_statuses,
+            event_bus,
+            pipeline_latency,
+            slots_seen: Arc::new(DashMap::new()),
+            blocks_seen: Arc::new(DashMap::new()),
         };
-        // polling with processed commitment to get latest leaders
-        grpc_geyser.poll_slots();
-        // polling with confirmed commitment to get confirmed transactions
-        grpc_geyser.poll_blocks();
-        grpc_geyser.clean_signature_cache();
+        for endpoint in grpc_geyser.endpoints.iter() {
+            grpc_geyser.poll_updates(endpoint.clone());
+        }
+        grpc_geyser.clean_seen_slots();
         grpc_geyser
     }
 
     async fn connect(
-        endpoint: String,
-        auth_header: Option<String>,
+        endpoint: &GeyserEndpoint,
     ) -> Result<GeyserGrpcClient<impl Interceptor>, GeyserGrpcBuilderError> {
-        GeyserGrpcClient::build_from_shared(endpoint)
+        GeyserGrpcClient::build_from_shared(endpoint.url.clone())
             .unwrap()
-            .x_token(auth_header)
+            .x_token(endpoint.x_token.clone())
             .unwrap()
             .connect_timeout(Duration::from_secs(10))
             .timeout(Duration::from_secs(10))
@@ -64,65 +151,205 @@ impl GrpcGeyserImpl {
             .await
     }
 
-    fn clean_signature_cache(&self) {
-        let signature_cache = self.signature_cache.clone();
+    fn clean_seen_slots(&self) {
+        let slots_seen = self.slots_seen.clone();
+        let blocks_seen = self.blocks_seen.clone();
         tokio::spawn(async move {
             loop {
-                let signature_cache = signature_cache.clone();
-                signature_cache.retain(|_, (_, v)| v.elapsed().as_secs() < 90);
+                slots_seen.retain(|_, v| v.elapsed().as_secs() < 90);
+                blocks_seen.retain(|_, v| v.elapsed().as_secs() < 90);
                 sleep(Duration::from_secs(60)).await;
             }
         });
     }
 
-    fn poll_blocks(&self) {
-        let endpoint = self.endpoint.clone();
-        let auth_header = self.auth_header.clone();
-        let signature_cache = self.signature_cache.clone();
+    /// poll_updates streams slots and processed blocks from the endpoint over one subscription. Slot
+    /// status updates then move the transactions of each block to confirmed, finalized or dropped.
+    /// It reconnects when no slot arrives within the stall timeout, and resubscribes from the slot after
+    /// the last block it saw so the blocks missed while reconnecting are replayed.
+    fn poll_updates(&self, endpoint: GeyserEndpoint) {
+        let slot_sender = self.slot_sender.clone();
+        let signature_statuses = self.signature_statuses.clone();
+        let slots_seen = self.slots_seen.clone();
+        let blocks_seen = self.blocks_seen.clone();
+        let stall_timeout = self.stall_timeout;
+        let confirmation = self.confirmation;
+        let transaction_store = self.transaction_store.clone();
+        let event_bus = self.event_bus.clone();
+        let pipeline_latency = self.pipeline_latency.clone();
         tokio::spawn(async move {
+            let mut from_slot = None;
             loop {
+                let mut fee_payers;
                 let mut grpc_tx;
                 let mut grpc_rx;
                 {
-                    let grpc_client = Self::connect(endpoint.clone(), auth_header.clone()).await;
+                    let grpc_client = Self::connect(&endpoint).await;
 
                     if let Err(e) = grpc_client {
-                        error!("Error connecting to gRPC, waiting one second then retrying connect: {}", e);
-                        statsd_count!("grpc_connect_error", 1);
+                        error!(endpoint = endpoint.url, "Error connecting to gRPC, waiting one second then retrying connect: {}", e);
+                        statsd_count!("grpc_connect_error", 1, "endpoint" => &endpoint.url);
                         sleep(Duration::from_secs(1)).await;
                         continue;
                     }
+                    fee_payers = transaction_store.get_fee_payers();
                     let subscription = grpc_client
                         .unwrap()
-                        .subscribe_with_request(Some(get_block_subscribe_request()))
+                        .subscribe_with_request(Some(get_subscribe_request(
+                            from_slot,
+                            confirmation,
+                            &fee_payers,
+                        )))
                         .await;
                     if let Err(e) = subscription {
-                        error!("Error subscribing to gRPC stream, waiting one second then retrying connect: {}", e);
-                        statsd_count!("grpc_subscribe_error", 1);
+                        error!(endpoint = endpoint.url, "Error subscribing to gRPC stream, waiting one second then retrying connect: {}", e);
+                        statsd_count!("grpc_subscribe_error", 1, "endpoint" => &endpoint.url);
+                        // the endpoint may not keep blocks that far back
+                        from_slot = None;
                         sleep(Duration::from_secs(1)).await;
                         continue;
                     }
                     (grpc_tx, grpc_rx) = subscription.unwrap();
                 }
-                while let Some(message) = grpc_rx.next().await {
+                let mut ping_interval = interval(PING_INTERVAL);
+                let mut filter_interval = interval(FILTER_UPDATE_INTERVAL);
+                let mut last_slot_at = Instant::now();
+                loop {
+                    let message = tokio::select! {
+                        message = grpc_rx.next() => message,
+                        _ = ping_interval.tick() => {
+                            // This is necessary to keep load balancers that expect client pings alive
+                            if let Err(e) = grpc_tx.send(ping()).await {
+                                error!(endpoint = endpoint.url, "Error sending ping: {}", e);
+                                statsd_count!("grpc_ping_error", 1, "endpoint" => &endpoint.url);
+                                break;
+                            }
+                            continue;
+                        }
+                        _ = filter_interval.tick(), if confirmation == GeyserConfirmation::Transactions => {
+                            // replace the transaction filter when the tracked fee payers change
+                            let new_fee_payers = transaction_store.get_fee_payers();
+                            if new_fee_payers == fee_payers {
+                                continue;
+                            }
+                            fee_payers = new_fee_payers;
+                            statsd_gauge!("grpc_filtered_fee_payers", fee_payers.len() as u64, "endpoint" => &endpoint.url);
+                            let request = get_subscribe_request(None, confirmation, &fee_payers);
+                            if let Err(e) = grpc_tx.send(request).await {
+                                error!(endpoint = endpoint.url, "Error updating transaction filter: {}", e);
+                                statsd_count!("grpc_filter_update_error", 1, "endpoint" => &endpoint.url);
+                                break;
+                            }
+                            continue;
+                        }
+                        _ = sleep(stall_timeout.saturating_sub(last_slot_at.elapsed())) => {
+                            error!(endpoint = endpoint.url, "no slot update in {:?}, reconnecting", stall_timeout);
+                            statsd_count!("grpc_stall", 1, "endpoint" => &endpoint.url);
+                            break;
+                        }
+                    };
+                    let Some(message) = message else {
+                        error!(
+                            endpoint = endpoint.url,
+                            "gRPC stream ended, resubscribing in 1 second"
+                        );
+                        statsd_count!("grpc_resubscribe", 1, "endpoint" => &endpoint.url);
+                        break;
+                    };
                     match message {
                         Ok(message) => match message.update_oneof {
+                            Some(UpdateOneof::Slot(slot)) => {
+                                last_slot_at = Instant::now();
+                                match slots_seen.entry((slot.slot, slot.status)) {
+                                    Entry::Occupied(first_seen) => {
+                                        statsd_time!("grpc_slot_lag", first_seen.get().elapsed(), "endpoint" => &endpoint.url);
+                                    }
+                                    Entry::Vacant(first_seen) => {
+                                        first_seen.insert(Instant::now());
+                                        statsd_count!("grpc_slot_first", 1, "endpoint" => &endpoint.url);
+                                    }
+                                }
+                                let commitment = match SlotStatus::try_from(slot.status) {
+                                    Ok(SlotStatus::SlotConfirmed) => {
+                                        signature_statuses
+                                            .update_slot(slot.slot, ConfirmationStatus::Confirmed);
+                                        commitment_config::CommitmentLevel::Confirmed
+                                    }
+                                    Ok(SlotStatus::SlotFinalized) => {
+                                        for signature in
+                                            signature_statuses.finalize_up_to(slot.slot)
+                                        {
+                                            pipeline_latency.record_finalization(&signature);
+                                        }
+                                        commitment_config::CommitmentLevel::Finalized
+                                    }
+                                    Ok(SlotStatus::SlotDead) => {
+                                        for signature in signature_statuses
+                                            .slot_signatures(slot.slot)
+                                            .into_iter()
+                                            .filter(|s| transaction_store.has_signature(s))
+                                        {
+                                            event_bus.publish(TransactionEvent::Forked {
+                                                signature,
+                                                slot: slot.slot,
+                                            });
+                                        }
+                                        signature_statuses
+                                            .update_slot(slot.slot, ConfirmationStatus::Dropped);
+                                        commitment_config::CommitmentLevel::Processed
+                                    }
+                                    _ => commitment_config::CommitmentLevel::Processed,
+                                };
+                                publish_slot(&slot_sender, slot.slot, commitment);
+                            }
                             Some(UpdateOneof::Block(block)) => {
-                                let block_time = block.block_time.unwrap().timestamp;
+                                from_slot = from_slot.max(Some(block.slot + 1));
+                                match blocks_seen.entry(block.slot) {
+                                    Entry::Occupied(first_seen) => {
+                                        statsd_time!("grpc_block_lag", first_seen.get().elapsed(), "endpoint" => &endpoint.url);
+                                        continue;
+                                    }
+                                    Entry::Vacant(first_seen) => {
+                                        first_seen.insert(Instant::now());
+                                        statsd_count!("grpc_block_first", 1, "endpoint" => &endpoint.url);
+                                    }
+                                }
+                                let block_time = block.block_time.map(|t| t.timestamp);
                                 for transaction in block.transactions {
-                                    let signature = Signature::try_from(transaction.signature)
-                                        .unwrap()
-                                        .to_string();
-                                    signature_cache.insert(signature, (block_time, Instant::now()));
+                                    record_transaction(
+                                        &signature_statuses,
+                                        &transaction_store,
+                                        &event_bus,
+                                        &pipeline_latency,
+                                        block.slot,
+                                        transaction,
+                                        block_time,
+                                    );
                                 }
                             }
+                            Some(UpdateOneof::Transaction(transaction)) => {
+                                // transactions of a slot can still arrive after the first one
+                                from_slot = from_slot.max(Some(transaction.slot));
+                                let Some(transaction_info) = transaction.transaction else {
+                                    continue;
+                                };
+                                // transaction updates have no block time
+                                record_transaction(
+                                    &signature_statuses,
+                                    &transaction_store,
+                                    &event_bus,
+                                    &pipeline_latency,
+                                    transaction.slot,
+                                    transaction_info,
+                                    None,
+                                );
+                            }
                             Some(UpdateOneof::Ping(_)) => {
-                                // This is necessary to keep load balancers that expect client pings alive. If your load balancer doesn't
-                                // require periodic client pings then this is unnecessary
+                                // answer server pings too, for load balancers that expect a reply
                                 let ping = grpc_tx.send(ping()).await;
                                 if let Err(e) = ping {
-                                    error!("Error sending ping: {}", e);
-                                    statsd_count!("grpc_ping_error", 1);
+                                    error!(endpoint = endpoint.url, "Error sending ping: {}", e);
+                                    statsd_count!("grpc_ping_error", 1, "endpoint" => &endpoint.url);
                                     break;
                                 }
                             }
@@ -133,72 +360,10 @@ impl GrpcGeyserImpl {
                         },
                         Err(error) => {
                             error!(
-                                "error in block subscribe, resubscribing in 1 second: {error:?}"
+                                endpoint = endpoint.url,
+                                "error in subscribe, resubscribing in 1 second: {error:?}"
                             );
-                            statsd_count!("grpc_resubscribe", 1);
-                            break;
-                        }
-                    }
-                }
-                sleep(Duration::from_secs(1)).await;
-            }
-        });
-    }
-
-    fn poll_slots(&self) {
-        let endpoint = self.endpoint.clone();
-        let auth_header = self.auth_header.clone();
-        let cur_slot = self.cur_slot.clone();
-        // let grpc_tx = self.grpc_tx.clone();
-        tokio::spawn(async move {
-            loop {
-                let mut grpc_tx;
-                let mut grpc_rx;
-                {
-                    let grpc_client = Self::connect(endpoint.clone(), auth_header.clone()).await;
-
-                    if let Err(e) = grpc_client {
-                        error!("Error connecting to gRPC, waiting one second then retrying connect: {}", e);
-                        statsd_count!("grpc_connect_error", 1);
-                        sleep(Duration::from_secs(1)).await;
-                        continue;
-                    }
-                    let subscription = grpc_client.unwrap().subscribe().await;
-                    if let Err(e) = subscription {
-                        error!("Error subscribing to gRPC stream, waiting one second then retrying connect: {}", e);
-                        statsd_count!("grpc_subscribe_error", 1);
-                        sleep(Duration::from_secs(1)).await;
-                        continue;
-                    }
-                    (grpc_tx, grpc_rx) = subscription.unwrap();
-                }
-                grpc_tx.send(get_slot_subscribe_request()).await.unwrap();
-                while let Some(message) = grpc_rx.next().await {
-                    match message {
-                        Ok(msg) => {
-                            match msg.update_oneof {
-                                Some(UpdateOneof::Slot(slot)) => {
-                                    cur_slot.store(slot.slot, Ordering::Relaxed);
-                                }
-                                Some(UpdateOneof::Ping(_)) => {
-                                    // This is necessary to keep load balancers that expect client pings alive. If your load balancer doesn't
-                                    // require periodic client pings then this is unnecessary
-                                    let ping = grpc_tx.send(ping()).await;
-                                    if let Err(e) = ping {
-                                        error!("Error sending ping: {}", e);
-                                        statsd_count!("grpc_ping_error", 1);
-                                        break;
-                                    }
-                                }
-                                Some(UpdateOneof::Pong(_)) => {}
-                                _ => {
-                                    error!("Unknown message: {:?}", msg);
-                                }
-                            }
-                        }
-                        Err(error) => {
-                            error!("error in slot subscribe, resubscribing in 1 second: {error:?}");
-                            statsd_count!("grpc_resubscribe", 1);
+                            statsd_count!("grpc_resubscribe", 1, "endpoint" => &endpoint.url);
                             break;
                         }
                     }
@@ -212,60 +377,111 @@ impl GrpcGeyserImpl {
 #[async_trait]
 impl SolanaRpc for GrpcGeyserImpl {
     async fn confirm_transaction(&self, signature: String) -> Option<UnixTimestamp> {
-        let start = Instant::now();
-        // in practice if a tx doesn't land in less than 60 seconds it's probably not going to land
-        while start.elapsed() < Duration::from_secs(60) {
-            if let Some(block_time) = self.signature_cache.get(&signature) {
-                return Some(block_time.0);
-            }
-            sleep(Duration::from_millis(10)).await;
-        }
-        return None;
+        self.signature_statuses
+            .wait_for_confirmation(&signature)
+            .await
+    }
+    fn get_signature_status(&self, signature: &str) -> Option<SignatureStatus> {
+        self.signature_statuses.get(signature)
     }
     fn get_next_slot(&self) -> Option<u64> {
-        let cur_slot = self.cur_slot.load(Ordering::Relaxed);
-        if cur_slot == 0 {
-            return None;
-        }
-        Some(cur_slot)
+        self.slot_sender
+            .borrow()
+            .map(|slot_update| slot_update.slot)
     }
-}
-
-fn generate_random_string(len: usize) -> String {
-    rand::thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(len)
-        .map(char::from)
-        .collect()
-}
-
-fn get_block_subscribe_request() -> SubscribeRequest {
-    SubscribeRequest {
-        blocks: HashMap::from_iter(vec![(
-            generate_random_string(20),
-            SubscribeRequestFilterBlocks {
-                account_include: vec![],
-                include_transactions: Some(true),
-                include_accounts: Some(false),
-                include_entries: Some(false),
-            },
-        )]),
-        commitment: Some(CommitmentLevel::Confirmed.into()),
-        ..Default::default()
+    fn subscribe_slots(&self) -> watch::Receiver<Option<SlotUpdate>> {
+        self.slot_sender.subscribe()
     }
 }
 
-fn get_slot_subscribe_request() -> SubscribeRequest {
-    SubscribeRequest {
+/// get_subscribe_request subscribes to slots at every commitment and, depending on the confirmation
+/// mode, to processed blocks or to the processed transactions of the fee payers
+fn get_subscribe_request(
+    from_slot: Option<Slot>,
+    confirmation: GeyserConfirmation,
+    fee_payers: &BTreeSet<String>,
+) -> SubscribeRequest {
+    let mut request = SubscribeRequest {
         slots: HashMap::from_iter(vec![(
-            generate_random_string(20).to_string(),
+            "slots".to_string(),
             SubscribeRequestFilterSlots {
-                filter_by_commitment: Some(true),
+                // every status of a slot, to move its transactions to confirmed and finalized
+                filter_by_commitment: Some(false),
                 interslot_updates: Some(false),
             },
         )]),
+        // blocks and transactions as soon as they are processed, their slot updates confirm them
+        commitment: Some(CommitmentLevel::Processed.into()),
+        from_slot,
         ..Default::default()
+    };
+    match confirmation {
+        GeyserConfirmation::Blocks => {
+            request.blocks = HashMap::from_iter(vec![(
+                "blocks".to_string(),
+                SubscribeRequestFilterBlocks {
+                    account_include: vec![],
+                    include_transactions: Some(true),
+                    include_accounts: Some(false),
+                    include_entries: Some(false),
+                },
+            )]);
+        }
+        // an empty account_include would match every transaction
+        GeyserConfirmation::Transactions if !fee_payers.is_empty() => {
+            request.transactions = HashMap::from_iter(vec![(
+                "transactions".to_string(),
+                SubscribeRequestFilterTransactions {
+                    vote: Some(false),
+                    account_include: fee_payers.iter().cloned().collect(),
+                    ..Default::default()
+                },
+            )]);
+        }
+        GeyserConfirmation::Transactions => {}
+    }
+    request
+}
+
+/// record_transaction records a processed transaction with its index in the block and its error,
+/// and publishes and times the landing of our transactions
+fn record_transaction(
+    signature_statuses: &SignatureStatuses,
+    transaction_store: &Arc<dyn TransactionStore>,
+    event_bus: &EventBus,
+    pipeline_latency: &PipelineLatency,
+    slot: Slot,
+    transaction: SubscribeUpdateTransactionInfo,
+    block_time: Option<UnixTimestamp>,
+) {
+    let Ok(signature) = Signature::try_from(transaction.signature) else {
+        return;
+    };
+    let err = transaction
+        .meta
+        .and_then(|meta| create_tx_error(meta.err.as_ref()).ok().flatten());
+    let signature = signature.to_string();
+    // every endpoint sends the transaction, publish it once per slot
+    let first_seen = signature_statuses
+        .get(&signature)
+        .is_none_or(|status| status.slot != slot);
+    if first_seen && transaction_store.has_signature(&signature) {
+        pipeline_latency.record_landing(&signature);
+        event_bus.publish(TransactionEvent::Landed {
+            signature: signature.clone(),
+            slot,
+            index: Some(transaction.index),
+            err: err.clone(),
+        });
     }
+    signature_statuses.record(
+        signature,
+        slot,
+        Some(transaction.index),
+        err,
+        block_time,
+        ConfirmationStatus::Processed,
+    );
 }
 
 fn ping() -> SubscribeRequest {
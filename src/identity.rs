@@ -0,0 +1,543 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::Duration,
+};
+
+use cadence_macros::statsd_count;
+use rand::{distributions::WeightedIndex, prelude::Distribution};
+use serde::{Deserialize, Serialize};
+use solana_client::{connection_cache::ConnectionCache, rpc_client::RpcClient};
+use solana_sdk::{
+    pubkey::Pubkey,
+    signature::{read_keypair_file, Keypair},
+    signer::Signer,
+};
+use tokio::time::sleep;
+use tracing::{error, info, warn};
+
+use crate::{errors::AtlasTxnSenderError, rpc_server::RequestMetadata};
+
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+// how often the epoch is checked for stake weighted selection, whose stakes change every epoch
+const EPOCH_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// IdentitySelection picks the identity for transactions that have no route, api key or request identity
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum IdentitySelection {
+    #[default]
+    RoundRobin,
+    /// picks identities at random, weighted by their activated SOL stake
+    StakeWeighted,
+}
+
+/// Identity is a QUIC client identity and the connection cache using it as its certificate
+pub struct Identity {
+    pub pubkey: Pubkey,
+    pub connection_cache: Arc<ConnectionCache>,
+    pub stake: u64,
+}
+
+impl Identity {
+    pub fn new(keypair: &Keypair, tpu_connection_pool_size: usize) -> Self {
+        let connection_cache = Arc::new(ConnectionCache::new_with_client_options(
+            "p3-txn-sender",
+            tpu_connection_pool_size,
+            None, // created if none specified
+            Some((keypair, IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)))),
+            None, // not used as far as I can tell
+        ));
+        Self {
+            pubkey: keypair.pubkey(),
+            connection_cache,
+            stake: 0,
+        }
+    }
+}
+
+/// IdentityRotation is an identity whose keypair file changed
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct IdentityRotation {
+    pub old_identity: String,
+    pub new_identity: String,
+}
+
+pub struct IdentityPool {
+    /// keypair file of each identity, None for the unstaked identity used when there are no files
+    keypair_files: Vec<Option<String>>,
+    tpu_connection_pool_size: usize,
+    // identities are swapped on rotation. Sends in flight keep their own Arc of the old connection cache.
+    identities: RwLock<Vec<Arc<Identity>>>,
+    by_pubkey: RwLock<HashMap<Pubkey, usize>>,
+    /// identities rotated out, pointing at the identity that replaced them
+    replaced: RwLock<HashMap<Pubkey, usize>>,
+    by_route: HashMap<u16, usize>,
+    by_api_key: HashMap<String, usize>,
+    selection: IdentitySelection,
+    rpc_client: Option<Arc<RpcClient>>,
+    stake_weights: RwLock<Option<WeightedIndex<u64>>>,
+    next: AtomicUsize,
+    reload_lock: Mutex<()>,
+}
+
+impl IdentityPool {
+    /// new builds one connection cache per keypair file, or a single unstaked identity if there are none
+    pub fn new(
+        keypair_files: Vec<String>,
+        tpu_connection_pool_size: usize,
+        routes: HashMap<u16, Pubkey>,
+        api_keys: HashMap<String, Pubkey>,
+        selection: IdentitySelection,
+    ) -> Result<Self, AtlasTxnSenderError> {
+        let mut identities = vec![];
+        for keypair_file in keypair_files.iter() {
+            let keypair = read_keypair(keypair_file)?;
+            identities.push(Arc::new(Identity::new(&keypair, tpu_connection_pool_size)));
+        }
+        let mut keypair_files: Vec<Option<String>> = keypair_files.into_iter().map(Some).collect();
+        if identities.is_empty() {
+            identities.push(Arc::new(Identity::new(
+                &Keypair::new(),
+                tpu_connection_pool_size,
+            )));
+            keypair_files.push(None);
+        }
+        let by_pubkey = index_by_pubkey(&identities);
+        let lookup = |pubkey: &Pubkey| {
+            by_pubkey
+                .get(pubkey)
+                .copied()
+                .ok_or_else(|| AtlasTxnSenderError::from(format!("Unknown identity {pubkey}")))
+        };
+        let by_route = routes
+            .iter()
+            .map(|(port, pubkey)| Ok((*port, lookup(pubkey)?)))
+            .collect::<Result<_, AtlasTxnSenderError>>()?;
+        let by_api_key = api_keys
+            .iter()
+            .map(|(api_key, pubkey)| Ok((api_key.clone(), lookup(pubkey)?)))
+            .collect::<Result<_, AtlasTxnSenderError>>()?;
+        for identity in identities.iter() {
+            info!("loaded identity {}", identity.pubkey);
+        }
+        Ok(Self {
+            keypair_files,
+            tpu_connection_pool_size,
+            identities: RwLock::new(identities),
+            by_pubkey: RwLock::new(by_pubkey),
+            replaced: RwLock::new(HashMap::new()),
+            by_route,
+            by_api_key,
+            selection,
+            rpc_client: None,
+            stake_weights: RwLock::new(None),
+            next: AtomicUsize::new(0),
+            reload_lock: Mutex::new(()),
+        })
+    }
+
+    /// with_stakes looks up the activated SOL stake of each identity for stake weighted selection
+    pub fn with_stakes(mut self, rpc_client: Arc<RpcClient>) -> Result<Self, AtlasTxnSenderError> {
+        if self.selection != IdentitySelection::StakeWeighted {
+            return Ok(self);
+        }
+        self.rpc_client = Some(rpc_client);
+        self.refresh_stakes()?;
+        Ok(self)
+    }
+
+    fn refresh_stakes(&self) -> Result<(), AtlasTxnSenderError> {
+        let Some(rpc_client) = &self.rpc_client else {
+            return Ok(());
+        };
+        let (stakes, _) = get_node_stakes(rpc_client)?;
+        let mut identities = self.identities.write().unwrap();
+        for identity in identities.iter_mut() {
+            let stake = stakes
+                .get(&identity.pubkey.to_string())
+                .copied()
+                .unwrap_or(0);
+            info!("identity {} has {} stake", identity.pubkey, stake);
+            *identity = Arc::new(Identity {
+                pubkey: identity.pubkey,
+                connection_cache: identity.connection_cache.clone(),
+                stake,
+            });
+        }
+        let stake_weights = WeightedIndex::new(identities.iter().map(|i| i.stake)).ok();
+        if stake_weights.is_none() {
+            warn!("no identity has stake, falling back to round robin identity selection");
+        }
+        *self.stake_weights.write().unwrap() = stake_weights;
+        Ok(())
+    }
+
+    /// refresh_stakes_every_epoch re-reads the stakes of the identities when a new epoch starts, as
+    /// activated stake only changes at epoch boundaries
+    pub fn refresh_stakes_every_epoch(self: &Arc<Self>) {
+        let Some(rpc_client) = self.rpc_client.clone() else {
+            return;
+        };
+        let identity_pool = self.clone();
+        tokio::spawn(async move {
+            let mut last_epoch = None;
+            loop {
+                sleep(EPOCH_CHECK_INTERVAL).await;
+                let rpc_client = rpc_client.clone();
+                let epoch = match tokio::task::spawn_blocking(move || rpc_client.get_epoch_info())
+                    .await
+                    .map_err(|e| e.to_string())
+                    .and_then(|epoch_info| epoch_info.map_err(|e| e.to_string()))
+                {
+                    Ok(epoch_info) => epoch_info.epoch,
+                    Err(e) => {
+                        error!("Error getting epoch info: {}", e);
+                        continue;
+                    }
+                };
+                // the stakes were read at startup, within the first epoch seen
+                if last_epoch
+                    .replace(epoch)
+                    .is_none_or(|last_epoch| last_epoch == epoch)
+                {
+                    continue;
+                }
+                info!("refreshing identity stakes for epoch {}", epoch);
+                let identity_pool = identity_pool.clone();
+                if let Ok(Err(e)) =
+                    tokio::task::spawn_blocking(move || identity_pool.refresh_stakes()).await
+                {
+                    error!("Error refreshing identity stakes: {}", e);
+                    statsd_count!("identity_stake_refresh_error", 1);
+                }
+            }
+        });
+    }
+
+    /// get returns the identity, or the one that replaced it if it was rotated out
+    pub fn get(&self, pubkey: &Pubkey) -> Option<Arc<Identity>> {
+        let i = match self.by_pubkey.read().unwrap().get(pubkey) {
+            Some(i) => *i,
+            None => *self.replaced.read().unwrap().get(pubkey)?,
+        };
+        Some(self.identities.read().unwrap()[i].clone())
+    }
+
+    pub fn pubkeys(&self) -> Vec<Pubkey> {
+        self.identities
+            .read()
+            .unwrap()
+            .iter()
+            .map(|identity| identity.pubkey)
+            .collect()
+    }
+
+    pub fn contains(&self, pubkey: &str) -> bool {
+        Pubkey::from_str(pubkey)
+            .is_ok_and(|pubkey| self.by_pubkey.read().unwrap().contains_key(&pubkey))
+    }
+
+    /// select returns the identity requested, then the one for the api key, then the one for the
+    /// route, and otherwise picks one with the selection policy
+    pub fn select(&self, request_metadata: &RequestMetadata) -> Arc<Identity> {
+        if let Some(identity) = request_metadata
+            .identity
+            .as_deref()
+            .and_then(|pubkey| Pubkey::from_str(pubkey).ok())
+            .and_then(|pubkey| self.get(&pubkey))
+        {
+            return identity;
+        }
+        let identities = self.identities.read().unwrap();
+        if let Some(i) = self.by_api_key.get(&request_metadata.api_key) {
+            return identities[*i].clone();
+        }
+        if let Some(i) = self.by_route.get(&request_metadata.send_port) {
+            return identities[*i].clone();
+        }
+        let i = match &*self.stake_weights.read().unwrap() {
+            Some(stake_weights) => stake_weights.sample(&mut rand::thread_rng()),
+            None => self.next.fetch_add(1, Ordering::Relaxed) % identities.len(),
+        };
+        identities[i].clone()
+    }
+
+    /// reload re-reads the keypair files and swaps in a new connection cache for every identity that changed.
+    /// Transactions pinned to an old identity move to the identity that replaced it.
+    pub fn reload(&self) -> Result<Vec<IdentityRotation>, AtlasTxnSenderError> {
+        let _reload_guard = self.reload_lock.lock().unwrap();
+        let mut rotations = vec![];
+        let mut new_identities = self.identities.read().unwrap().clone();
+        for (i, keypair_file) in self.keypair_files.iter().enumerate() {
+            let Some(keypair_file) = keypair_file else {
+                continue;
+            };
+            let keypair = read_keypair(keypair_file)?;
+            if keypair.pubkey() == new_identities[i].pubkey {
+                continue;
+            }
+            rotations.push(IdentityRotation {
+                old_identity: new_identities[i].pubkey.to_string(),
+                new_identity: keypair.pubkey().to_string(),
+            });
+            new_identities[i] = Arc::new(Identity::new(&keypair, self.tpu_connection_pool_size));
+        }
+        if rotations.is_empty() {
+            return Ok(rotations);
+        }
+        {
+            let mut by_pubkey = self.by_pubkey.write().unwrap();
+            let mut replaced = self.replaced.write().unwrap();
+            let mut identities = self.identities.write().unwrap();
+            for (i, (old, new)) in identities.iter().zip(new_identities.iter()).enumerate() {
+                if old.pubkey != new.pubkey {
+                    replaced.insert(old.pubkey, i);
+                }
+            }
+            *by_pubkey = index_by_pubkey(&new_identities);
+            // an identity rotated back in is current again
+            replaced.retain(|pubkey, _| !by_pubkey.contains_key(pubkey));
+            *identities = new_identities;
+        }
+        for rotation in rotations.iter() {
+            info!(
+                old_identity = rotation.old_identity,
+                new_identity = rotation.new_identity,
+                "rotated identity"
+            );
+            statsd_count!("identity_rotated", 1, "old_identity" => &rotation.old_identity, "new_identity" => &rotation.new_identity);
+        }
+        if let Err(e) = self.refresh_stakes() {
+            error!("Error refreshing identity stakes after rotation: {}", e);
+        }
+        Ok(rotations)
+    }
+
+    /// watch_keypair_files reloads the identities whenever a keypair file changes
+    pub fn watch_keypair_files(self: &Arc<Self>) {
+        let identity_pool = self.clone();
+        tokio::spawn(async move {
+            loop {
+                sleep(RELOAD_INTERVAL).await;
+                let identity_pool = identity_pool.clone();
+                // reading the files and the stake lookup block
+                let reloaded = tokio::task::spawn_blocking(move || identity_pool.reload()).await;
+                if let Ok(Err(e)) = reloaded {
+                    error!("Error reloading identities: {}", e);
+                    statsd_count!("identity_reload_error", 1);
+                }
+            }
+        });
+    }
+}
+
+fn read_keypair(keypair_file: &str) -> Result<Keypair, AtlasTxnSenderError> {
+    read_keypair_file(keypair_file)
+        .map_err(|e| format!("Error reading keypair file {keypair_file}: {e}").into())
+}
+
+/// get_node_stakes returns the activated SOL stake of every node and the total activated stake
+pub fn get_node_stakes(
+    rpc_client: &RpcClient,
+) -> Result<(HashMap<String, u64>, u64), AtlasTxnSenderError> {
+    let vote_accounts = rpc_client
+        .get_vote_accounts()
+        .map_err(|e| format!("Error getting vote accounts: {e}"))?;
+    let mut stakes: HashMap<String, u64> = HashMap::new();
+    let mut total_stake = 0;
+    for vote_account in vote_accounts
+        .current
+        .iter()
+        .chain(vote_accounts.delinquent.iter())
+    {
+        *stakes.entry(vote_account.node_pubkey.clone()).or_default() +=
+            vote_account.activated_stake;
+        total_stake += vote_account.activated_stake;
+    }
+    Ok((stakes, total_stake))
+}
+
+fn index_by_pubkey(identities: &[Arc<Identity>]) -> HashMap<Pubkey, usize> {
+    identities
+        .iter()
+        .enumerate()
+        .map(|(i, identity)| (identity.pubkey, i))
+        .collect()
+}
+
+/// parse_identity_map parses a comma separated list of `key:identity_pubkey` pairs
+pub fn parse_identity_map(value: &str) -> Result<HashMap<String, Pubkey>, AtlasTxnSenderError> {
+    let mut identity_map = HashMap::new();
+    for entry in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let Some((key, pubkey)) = entry.rsplit_once(':') else {
+            return Err(format!("Invalid identity entry: {entry}").into());
+        };
+        let pubkey = Pubkey::from_str(pubkey.trim())
+            .map_err(|e| format!("Invalid identity for {key}: {e}"))?;
+        identity_map.insert(key.trim().to_string(), pubkey);
+    }
+    Ok(identity_map)
+}
+
+#[cfg(test)]
+mod tests {
+    use solana_sdk::signature::write_keypair_file;
+
+    use super::*;
+    use crate::test_utils::init_metrics;
+
+    fn keypair_file(keypair: &Keypair) -> String {
+        let path = std::env::temp_dir().join(format!("identity-{}.json", uuid::Uuid::new_v4()));
+        write_keypair_file(keypair, &path).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    fn request(api_key: &str, send_port: u16, identity: Option<Pubkey>) -> RequestMetadata {
+        RequestMetadata {
+            api_key: api_key.to_string(),
+            send_port,
+            identity: identity.map(|identity| identity.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn selects_by_request_api_key_and_route() {
+        let (first, second) = (Keypair::new(), Keypair::new());
+        let pool = IdentityPool::new(
+            vec![keypair_file(&first), keypair_file(&second)],
+            1,
+            HashMap::from([(4820, first.pubkey())]),
+            HashMap::from([("key".to_string(), second.pubkey())]),
+            IdentitySelection::RoundRobin,
+        )
+        .unwrap();
+        assert_eq!(
+            pool.select(&request("key", 4820, None)).pubkey,
+            second.pubkey()
+        );
+        assert_eq!(
+            pool.select(&request("other", 4820, None)).pubkey,
+            first.pubkey()
+        );
+        // the requested identity comes first
+        assert_eq!(
+            pool.select(&request("key", 4820, Some(first.pubkey())))
+                .pubkey,
+            first.pubkey()
+        );
+        assert!(pool.contains(&first.pubkey().to_string()));
+        assert!(!pool.contains(&Pubkey::new_unique().to_string()));
+    }
+
+    #[test]
+    fn falls_back_to_round_robin() {
+        let (first, second) = (Keypair::new(), Keypair::new());
+        let pool = IdentityPool::new(
+            vec![keypair_file(&first), keypair_file(&second)],
+            1,
+            HashMap::new(),
+            HashMap::from([("key".to_string(), second.pubkey())]),
+            IdentitySelection::RoundRobin,
+        )
+        .unwrap();
+        // an unknown requested identity falls back too
+        let selected: Vec<Pubkey> = (0..4)
+            .map(|_| {
+                pool.select(&request("other", 4819, Some(Pubkey::new_unique())))
+                    .pubkey
+            })
+            .collect();
+        assert_eq!(
+            selected,
+            vec![
+                first.pubkey(),
+                second.pubkey(),
+                first.pubkey(),
+                second.pubkey()
+            ]
+        );
+
+        // without keypair files there is one unstaked identity
+        let pool = IdentityPool::new(
+            vec![],
+            1,
+            HashMap::new(),
+            HashMap::new(),
+            IdentitySelection::RoundRobin,
+        )
+        .unwrap();
+        assert_eq!(pool.pubkeys().len(), 1);
+    }
+
+    #[test]
+    fn rotated_identity_is_replaced() {
+        init_metrics();
+        let (old, new, other) = (Keypair::new(), Keypair::new(), Keypair::new());
+        let rotated_file = keypair_file(&old);
+        let pool = IdentityPool::new(
+            vec![keypair_file(&other), rotated_file.clone()],
+            1,
+            HashMap::new(),
+            HashMap::from([("key".to_string(), old.pubkey())]),
+            IdentitySelection::RoundRobin,
+        )
+        .unwrap();
+        assert!(pool.reload().unwrap().is_empty());
+
+        write_keypair_file(&new, &rotated_file).unwrap();
+        let rotations = pool.reload().unwrap();
+        assert_eq!(rotations.len(), 1);
+        assert_eq!(rotations[0].old_identity, old.pubkey().to_string());
+        assert_eq!(rotations[0].new_identity, new.pubkey().to_string());
+        assert_eq!(pool.pubkeys(), vec![other.pubkey(), new.pubkey()]);
+        // transactions pinned to the old identity, or its api key, go out with the new one
+        assert_eq!(pool.get(&old.pubkey()).unwrap().pubkey, new.pubkey());
+        assert_eq!(
+            pool.select(&request("none", 4819, Some(old.pubkey())))
+                .pubkey,
+            new.pubkey()
+        );
+        assert_eq!(
+            pool.select(&request("key", 4819, None)).pubkey,
+            new.pubkey()
+        );
+        assert!(!pool.contains(&old.pubkey().to_string()));
+
+        // rotated back in, the old identity is current again
+        write_keypair_file(&old, &rotated_file).unwrap();
+        pool.reload().unwrap();
+        assert_eq!(pool.get(&old.pubkey()).unwrap().pubkey, old.pubkey());
+        assert_eq!(pool.get(&new.pubkey()).unwrap().pubkey, old.pubkey());
+    }
+
+    #[test]
+    fn parses_identity_maps() {
+        let (first, second) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let identity_map =
+            parse_identity_map(&format!(" 4819:{first}, key:with:colons:{second} ,")).unwrap();
+        assert_eq!(identity_map["4819"], first);
+        assert_eq!(identity_map["key:with:colons"], second);
+        assert!(parse_identity_map("").unwrap().is_empty());
+
+        assert!(parse_identity_map(&first.to_string()).is_err());
+        assert!(parse_identity_map("4819:not-a-pubkey").is_err());
+
+        // every mapped identity has to be loaded
+        let result = IdentityPool::new(
+            vec![keypair_file(&Keypair::new())],
+            1,
+            HashMap::from([(4819, first)]),
+            HashMap::new(),
+            IdentitySelection::RoundRobin,
+        );
+        assert!(result.is_err());
+    }
+}
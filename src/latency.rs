@@ -0,0 +1,226 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use cadence_macros::statsd_time;
+use dashmap::DashMap;
+use tokio::time::sleep;
+
+use crate::errors::AtlasTxnSenderError;
+
+// a transaction that isn't finalized by then never landed
+const MAX_TRACKING_AGE: Duration = Duration::from_secs(300);
+const DEFAULT_TIER: &str = "default";
+
+/// TransactionTiming is what is known about a transaction's way through the pipeline so far
+struct TransactionTiming {
+    received_at: Instant,
+    route: &'static str,
+    tier: String,
+    acked_leader_nums: HashSet<usize>,
+    landed: bool,
+}
+
+/// PipelineLatency reports how long each transaction takes to reach each stage of the send
+/// pipeline, counted from when the request was received, as histograms by route and api key tier:
+/// - `transaction_decode_time` - decoding the request, on its own
+/// - `transaction_enqueue_time` - until its first sends are handed to the send runtime
+/// - `transaction_first_leader_ack_time` - until each leader position first accepts it
+/// - `transaction_first_landing_time` - until geyser first sees it processed
+/// - `transaction_finalization_time` - until geyser sees it finalized
+pub struct PipelineLatency {
+    api_key_tiers: HashMap<String, String>,
+    transactions: Arc<DashMap<String, TransactionTiming>>,
+}
+
+impl PipelineLatency {
+    pub fn new(api_key_tiers: HashMap<String, String>) -> Self {
+        let pipeline_latency = Self {
+            api_key_tiers,
+            transactions: Arc::new(DashMap::new()),
+        };
+        pipeline_latency.clean_transactions();
+        pipeline_latency
+    }
+
+    fn clean_transactions(&self) {
+        let transactions = self.transactions.clone();
+        tokio::spawn(async move {
+            loop {
+                transactions.retain(|_, timing| timing.received_at.elapsed() < MAX_TRACKING_AGE);
+                sleep(Duration::from_secs(60)).await;
+            }
+        });
+    }
+
+    /// tier groups api keys so the histograms stay low cardinality
+    pub fn tier(&self, api_key: &str) -> &str {
+        self.api_key_tiers
+            .get(api_key)
+            .map(String::as_str)
+            .unwrap_or(DEFAULT_TIER)
+    }
+
+    pub fn record_decode(&self, elapsed: Duration, route: &str, api_key: &str) {
+        statsd_time!(
+            "transaction_decode_time",
+            elapsed,
+            "route" => route,
+            "tier" => self.tier(api_key)
+        );
+    }
+
+    /// track starts timing the stages of a transaction received at `received_at`
+    pub fn track(
+        &self,
+        signature: String,
+        route: &'static str,
+        api_key: &str,
+        received_at: Instant,
+    ) {
+        self.transactions.insert(
+            signature,
+            TransactionTiming {
+                received_at,
+                route,
+                tier: self.tier(api_key).to_string(),
+                acked_leader_nums: HashSet::new(),
+                landed: false,
+            },
+        );
+    }
+
+    /// The record_ functions return the time recorded, None when the transaction isn't tracked or
+    /// the stage was recorded before.
+    pub fn record_enqueue(&self, signature: &str) -> Option<Duration> {
+        let timing = self.transactions.get(signature)?;
+        let elapsed = timing.received_at.elapsed();
+        statsd_time!(
+            "transaction_enqueue_time",
+            elapsed,
+            "route" => timing.route,
+            "tier" => &timing.tier
+        );
+        Some(elapsed)
+    }
+
+    /// record_leader_ack records the first send to the leader position that succeeded
+    pub fn record_leader_ack(&self, signature: &str, leader_num: usize) -> Option<Duration> {
+        let mut timing = self.transactions.get_mut(signature)?;
+        if !timing.acked_leader_nums.insert(leader_num) {
+            return None;
+        }
+        let elapsed = timing.received_at.elapsed();
+        let leader_num = leader_num.to_string();
+        statsd_time!(
+            "transaction_first_leader_ack_time",
+            elapsed,
+            "route" => timing.route,
+            "tier" => &timing.tier,
+            "leader_num" => &leader_num
+        );
+        Some(elapsed)
+    }
+
+    /// record_landing records the first time the transaction was seen processed
+    pub fn record_landing(&self, signature: &str) -> Option<Duration> {
+        let mut timing = self.transactions.get_mut(signature)?;
+        if timing.landed {
+            return None;
+        }
+        timing.landed = true;
+        let elapsed = timing.received_at.elapsed();
+        statsd_time!(
+            "transaction_first_landing_time",
+            elapsed,
+            "route" => timing.route,
+            "tier" => &timing.tier
+        );
+        Some(elapsed)
+    }
+
+    /// record_finalization records the last stage and stops timing the transaction
+    pub fn record_finalization(&self, signature: &str) -> Option<Duration> {
+        let (_, timing) = self.transactions.remove(signature)?;
+        let elapsed = timing.received_at.elapsed();
+        statsd_time!(
+            "transaction_finalization_time",
+            elapsed,
+            "route" => timing.route,
+            "tier" => &timing.tier
+        );
+        Some(elapsed)
+    }
+}
+
+/// parse_api_key_tiers parses a comma separated list of `api_key:tier` pairs
+pub fn parse_api_key_tiers(value: &str) -> Result<HashMap<String, String>, AtlasTxnSenderError> {
+    let mut api_key_tiers = HashMap::new();
+    for entry in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let Some((api_key, tier)) = entry.rsplit_once(':') else {
+            return Err(format!("Invalid api key tier entry: {entry}").into());
+        };
+        api_key_tiers.insert(api_key.trim().to_string(), tier.trim().to_string());
+    }
+    Ok(api_key_tiers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::init_metrics;
+
+    fn pipeline_latency() -> PipelineLatency {
+        init_metrics();
+        PipelineLatency::new(parse_api_key_tiers("key-a:premium, key-b:free").unwrap())
+    }
+
+    #[test]
+    fn parses_api_key_tiers() {
+        let tiers = parse_api_key_tiers(" key-a:premium,,key:with:colons:free ").unwrap();
+        assert_eq!(tiers["key-a"], "premium");
+        assert_eq!(tiers["key:with:colons"], "free");
+        assert!(parse_api_key_tiers("no-tier").is_err());
+        assert!(parse_api_key_tiers("").unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn groups_api_keys_into_tiers() {
+        let pipeline_latency = pipeline_latency();
+        assert_eq!(pipeline_latency.tier("key-a"), "premium");
+        assert_eq!(pipeline_latency.tier("unknown"), DEFAULT_TIER);
+    }
+
+    #[tokio::test]
+    async fn times_stages_from_reception() {
+        let pipeline_latency = pipeline_latency();
+        let received_at = Instant::now() - Duration::from_millis(500);
+        pipeline_latency.track("signature".to_string(), "p3", "key-b", received_at);
+
+        let enqueue = pipeline_latency.record_enqueue("signature").unwrap();
+        assert!(enqueue >= Duration::from_millis(500));
+        let first_ack = pipeline_latency.record_leader_ack("signature", 0).unwrap();
+        assert!(first_ack >= enqueue);
+        // only the first ack of each leader position counts
+        assert!(pipeline_latency.record_leader_ack("signature", 0).is_none());
+        assert!(pipeline_latency.record_leader_ack("signature", 1).unwrap() >= first_ack);
+
+        let landing = pipeline_latency.record_landing("signature").unwrap();
+        assert!(pipeline_latency.record_landing("signature").is_none());
+        assert!(pipeline_latency.record_finalization("signature").unwrap() >= landing);
+        // finalization ends the tracking
+        assert!(pipeline_latency.record_finalization("signature").is_none());
+        assert!(pipeline_latency.record_enqueue("signature").is_none());
+    }
+
+    #[tokio::test]
+    async fn ignores_untracked_transactions() {
+        let pipeline_latency = pipeline_latency();
+        assert!(pipeline_latency.record_enqueue("untracked").is_none());
+        assert!(pipeline_latency.record_leader_ack("untracked", 0).is_none());
+        assert!(pipeline_latency.record_landing("untracked").is_none());
+        assert!(pipeline_latency.record_finalization("untracked").is_none());
+    }
+}
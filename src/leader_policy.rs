@@ -0,0 +1,135 @@
+use std::{
+    collections::HashSet,
+    fs,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use cadence_macros::statsd_count;
+use serde::{Deserialize, Serialize};
+use tokio::time::sleep;
+use tracing::{error, info};
+
+use crate::errors::AtlasTxnSenderError;
+
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// LeaderPolicyMode is chosen per request and decides which leaders the transaction is sent to
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub enum LeaderPolicyMode {
+    /// send to every upcoming leader
+    Ignore,
+    /// send to the upcoming leaders that are not on the deny list
+    #[default]
+    SkipDenied,
+    /// only send once an allowed leader is upcoming. Without an allow list every leader
+    /// that is not denied is allowed.
+    WaitForAllowed,
+}
+
+impl LeaderPolicyMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LeaderPolicyMode::Ignore => "ignore",
+            LeaderPolicyMode::SkipDenied => "skip_denied",
+            LeaderPolicyMode::WaitForAllowed => "wait_for_allowed",
+        }
+    }
+}
+
+/// LeaderLists is the content of the leader policy file, lists of validator identities
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct LeaderLists {
+    pub deny: HashSet<String>,
+    pub allow: HashSet<String>,
+}
+
+pub struct LeaderPolicy {
+    lists: Arc<RwLock<LeaderLists>>,
+}
+
+impl LeaderPolicy {
+    /// new loads the deny and allow lists from `path` and reloads them when the file changes
+    pub fn new(path: Option<String>) -> Result<Self, AtlasTxnSenderError> {
+        let leader_policy = Self {
+            lists: Arc::new(RwLock::new(LeaderLists::default())),
+        };
+        if let Some(path) = path {
+            let (lists, modified) = load_lists(&path)?;
+            info!(
+                "loaded leader policy from {}: {} denied, {} allowed",
+                path,
+                lists.deny.len(),
+                lists.allow.len()
+            );
+            *leader_policy.lists.write().unwrap() = lists;
+            leader_policy.reload_lists(path, modified);
+        }
+        Ok(leader_policy)
+    }
+
+    pub fn is_denied(&self, leader: &str) -> bool {
+        self.lists.read().unwrap().deny.contains(leader)
+    }
+
+    pub fn is_allowed(&self, leader: &str) -> bool {
+        let lists = self.lists.read().unwrap();
+        if lists.deny.contains(leader) {
+            return false;
+        }
+        lists.allow.is_empty() || lists.allow.contains(leader)
+    }
+
+    /// permits returns whether a transaction sent with `mode` may be sent to `leader`
+    pub fn permits(&self, leader: &str, mode: LeaderPolicyMode) -> bool {
+        match mode {
+            LeaderPolicyMode::Ignore => true,
+            LeaderPolicyMode::SkipDenied => !self.is_denied(leader),
+            LeaderPolicyMode::WaitForAllowed => self.is_allowed(leader),
+        }
+    }
+
+    /// reload_lists polls the file's modification time and swaps in the new lists when it changes.
+    /// A file that fails to parse keeps the previous lists.
+    fn reload_lists(&self, path: String, mut last_modified: Option<SystemTime>) {
+        let lists = self.lists.clone();
+        tokio::spawn(async move {
+            loop {
+                sleep(RELOAD_INTERVAL).await;
+                let modified = fs::metadata(&path).and_then(|m| m.modified()).ok();
+                if modified == last_modified {
+                    continue;
+                }
+                match load_lists(&path) {
+                    Ok((new_lists, modified)) => {
+                        info!(
+                            "reloaded leader policy from {}: {} denied, {} allowed",
+                            path,
+                            new_lists.deny.len(),
+                            new_lists.allow.len()
+                        );
+                        *lists.write().unwrap() = new_lists;
+                        last_modified = modified;
+                        statsd_count!("leader_policy_reload", 1, "status" => "ok");
+                    }
+                    Err(e) => {
+                        error!("Error reloading leader policy: {}", e);
+                        statsd_count!("leader_policy_reload", 1, "status" => "error");
+                        last_modified = modified;
+                    }
+                }
+            }
+        });
+    }
+}
+
+fn load_lists(path: &str) -> Result<(LeaderLists, Option<SystemTime>), AtlasTxnSenderError> {
+    let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("Error reading leader policy file {path}: {e}"))?;
+    let lists = serde_json::from_str(&content)
+        .map_err(|e| format!("Error parsing leader policy file {path}: {e}"))?;
+    Ok((lists, modified))
+}
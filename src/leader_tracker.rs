@@ -1,23 +1,27 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     sync::{
         atomic::{AtomicU64, Ordering},
-        Arc,
+        Arc, RwLock,
     },
     time::{Duration, Instant},
 };
 
-use cadence_macros::statsd_time;
-use dashmap::DashMap;
+use cadence_macros::{statsd_count, statsd_time};
 use enum_dispatch::enum_dispatch;
 use indexmap::IndexMap;
 use solana_client::rpc_client::RpcClient;
-use solana_rpc_client_api::response::RpcContactInfo;
-use solana_sdk::slot_history::Slot;
+use solana_rpc_client_api::response::{RpcContactInfo, RpcLeaderSchedule};
+use solana_sdk::{clock::Epoch, slot_history::Slot};
 use tokio::time::sleep;
 use tracing::{debug, error, info};
 
-use crate::{errors::AtlasTxnSenderError, solana_rpc::SolanaRpc, static_leader::StaticLeaderImpl};
+use crate::{
+    errors::AtlasTxnSenderError,
+    leader_policy::{LeaderPolicy, LeaderPolicyMode},
+    solana_rpc::SolanaRpc,
+    static_leader::StaticLeaderImpl,
+};
 
 #[allow(clippy::large_enum_variant)]
 #[enum_dispatch]
@@ -28,20 +32,71 @@ pub enum LeaderTracker {
 
 #[enum_dispatch(LeaderTracker)]
 pub trait LeaderTrackerTrait: Send + Sync {
-    /// get_leaders returns the next slot leaders in order
-    fn get_leaders(&self) -> Vec<RpcContactInfo>;
+    /// get_leaders returns the next slot leaders permitted by the leader policy, in order
+    fn get_leaders(&self, mode: LeaderPolicyMode) -> Vec<RpcContactInfo>;
+    /// get_slot_leader returns the leader scheduled for the slot, if known
+    fn get_slot_leader(&self, slot: Slot) -> Option<RpcContactInfo>;
+    /// get_next_leader_slot returns the first slot in `from_slot..=to_slot` the leader is scheduled for, if known
+    fn get_next_leader_slot(&self, leader: &str, from_slot: Slot, to_slot: Slot) -> Option<Slot>;
+}
+
+pub const NUM_LEADERS_PER_SLOT: usize = 4;
+
+const LEADER_SCHEDULE_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+const CLUSTER_NODES_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+const RPC_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// EpochLeaders is the leader schedule of one epoch
+struct EpochLeaders {
+    first_slot: Slot,
+    pubkeys: Vec<String>,
+    /// index into pubkeys of the leader of each slot in the epoch
+    slot_leaders: Vec<Option<u32>>,
 }
 
-const NUM_LEADERS_PER_SLOT: usize = 4;
+impl EpochLeaders {
+    fn new(first_slot: Slot, slots_in_epoch: u64, leader_schedule: RpcLeaderSchedule) -> Self {
+        let mut pubkeys = Vec::with_capacity(leader_schedule.len());
+        let mut slot_leaders = vec![None; slots_in_epoch as usize];
+        for (pubkey, slot_indexes) in leader_schedule {
+            let i = pubkeys.len() as u32;
+            pubkeys.push(pubkey);
+            for slot_index in slot_indexes {
+                if let Some(slot_leader) = slot_leaders.get_mut(slot_index) {
+                    *slot_leader = Some(i);
+                }
+            }
+        }
+        Self {
+            first_slot,
+            pubkeys,
+            slot_leaders,
+        }
+    }
+
+    fn last_slot(&self) -> Slot {
+        self.first_slot + self.slot_leaders.len() as u64 - 1
+    }
+
+    fn get(&self, slot: Slot) -> Option<&str> {
+        let slot_index = slot.checked_sub(self.first_slot)? as usize;
+        let i = (*self.slot_leaders.get(slot_index)?)?;
+        Some(&self.pubkeys[i as usize])
+    }
+}
 
 #[derive(Clone)]
 pub struct LeaderTrackerImpl {
     rpc_client: Arc<RpcClient>,
     solana_rpc: Arc<dyn SolanaRpc>,
     cur_slot: Arc<AtomicU64>,
-    cur_leaders: Arc<DashMap<Slot, RpcContactInfo>>,
+    /// leader schedule of the current and next epoch, by epoch
+    leader_schedule: Arc<RwLock<BTreeMap<Epoch, EpochLeaders>>>,
+    /// contact info of the cluster nodes, by identity
+    cluster_nodes: Arc<RwLock<HashMap<String, RpcContactInfo>>>,
     num_leaders: usize,
     leader_offset: i64,
+    leader_policy: Arc<LeaderPolicy>,
 }
 
 impl LeaderTrackerImpl {
@@ -50,100 +105,186 @@ impl LeaderTrackerImpl {
         solana_rpc: Arc<dyn SolanaRpc>,
         num_leaders: usize,
         leader_offset: i64,
+        leader_policy: Arc<LeaderPolicy>,
     ) -> Self {
         let leader_tracker = Self {
             rpc_client,
             solana_rpc,
             cur_slot: Arc::new(AtomicU64::new(0)),
-            cur_leaders: Arc::new(DashMap::new()),
+            leader_schedule: Arc::new(RwLock::new(BTreeMap::new())),
+            cluster_nodes: Arc::new(RwLock::new(HashMap::new())),
             num_leaders,
             leader_offset,
+            leader_policy,
         };
         leader_tracker.poll_slot();
-        leader_tracker.poll_slot_leaders();
+        leader_tracker.poll_leader_schedule();
+        leader_tracker.poll_cluster_nodes();
         leader_tracker
     }
 
-    /// poll_slot polls for every new slot returned by gRPC geyser
+    /// poll_slot updates the current slot on every new slot returned by gRPC geyser
     fn poll_slot(&self) {
-        let solana_rpc = self.solana_rpc.clone();
+        let mut slot_updates = self.solana_rpc.subscribe_slots();
         let cur_slot = self.cur_slot.clone();
         let leader_offset = self.leader_offset;
+        let self_clone = self.clone();
         tokio::spawn(async move {
-            loop {
-                let next_slot = solana_rpc.get_next_slot();
-                let start_slot = next_slot.map(|s| _get_start_slot(s, leader_offset));
-                if let Some(start_slot) = start_slot {
-                    if start_slot > cur_slot.load(Ordering::Relaxed) {
-                        cur_slot.store(start_slot, Ordering::Relaxed);
+            while slot_updates.changed().await.is_ok() {
+                let Some(slot_update) = *slot_updates.borrow_and_update() else {
+                    continue;
+                };
+                let start_slot = _get_start_slot(slot_update.slot, leader_offset);
+                let prev_slot = cur_slot.load(Ordering::Relaxed);
+                if start_slot > prev_slot {
+                    cur_slot.store(start_slot, Ordering::Relaxed);
+                    if prev_slot > 0 {
+                        self_clone.record_policy_skipped_slots(prev_slot, start_slot);
                     }
                 }
             }
         });
     }
 
-    /// poll_slot_leaders polls every minute for the next 1000 slot leaders and populates the cur_leaders map with the slot and ContactInfo of each leader
-    fn poll_slot_leaders(&self) {
+    /// record_policy_skipped_slots counts the slots in `from_slot..to_slot` whose leader the policy skips
+    fn record_policy_skipped_slots(&self, from_slot: Slot, to_slot: Slot) {
+        let leader_schedule = self.leader_schedule.read().unwrap();
+        for slot in from_slot..to_slot {
+            let Some(leader) = get_scheduled_leader(&leader_schedule, slot) else {
+                continue;
+            };
+            if self.leader_policy.is_denied(leader) {
+                statsd_count!("leader_policy_skipped_slots", 1, "reason" => "denied");
+            } else if !self.leader_policy.is_allowed(leader) {
+                statsd_count!("leader_policy_skipped_slots", 1, "reason" => "not_allowed");
+            }
+        }
+    }
+
+    /// poll_leader_schedule loads the leader schedule of the current epoch and prefetches the next
+    /// one as soon as it is known, so the epoch boundary is crossed with the schedule in place
+    fn poll_leader_schedule(&self) {
         let self_clone = self.clone();
         tokio::spawn(async move {
             loop {
                 let start = Instant::now();
-                if let Err(e) = self_clone.poll_slot_leaders_once() {
-                    error!("Error polling slot leaders: {}", e);
-                    sleep(Duration::from_secs(1)).await;
+                let leader_tracker = self_clone.clone();
+                let loaded =
+                    tokio::task::spawn_blocking(move || leader_tracker.load_leader_schedule())
+                        .await
+                        .map_err(|e| e.to_string().into())
+                        .and_then(|loaded| loaded);
+                if let Err(e) = loaded {
+                    error!("Error polling leader schedule: {}", e);
+                    statsd_count!("poll_leader_schedule_error", 1);
+                    sleep(RPC_RETRY_INTERVAL).await;
                     continue;
                 }
-                statsd_time!("poll_slot_leaders", start.elapsed());
-                sleep(Duration::from_secs(60)).await;
+                statsd_time!("poll_leader_schedule", start.elapsed());
+                sleep(LEADER_SCHEDULE_REFRESH_INTERVAL).await;
             }
         });
     }
 
-    fn poll_slot_leaders_once(&self) -> Result<(), AtlasTxnSenderError> {
-        let next_slot = self.cur_slot.load(Ordering::Relaxed);
-        debug!("Polling slot leaders for slot {}", next_slot);
-        // polling 1000 slots ahead is more than enough
-        let slot_leaders = self.rpc_client.get_slot_leaders(next_slot, 1000);
-        if let Err(e) = slot_leaders {
-            return Err(format!("Error getting slot leaders: {e}").into());
-        }
-        let slot_leaders = slot_leaders.unwrap();
-        let new_cluster_nodes = self.rpc_client.get_cluster_nodes();
-        if let Err(e) = new_cluster_nodes {
-            return Err(format!("Error getting cluster nodes: {e}").into());
-        }
-        let new_cluster_nodes = new_cluster_nodes.unwrap();
-        let mut cluster_node_map = HashMap::new();
-        for node in new_cluster_nodes {
-            cluster_node_map.insert(node.pubkey.clone(), node);
-        }
-        for (i, leader) in slot_leaders.iter().enumerate() {
-            let contact_info = cluster_node_map.get(&leader.to_string());
-            if let Some(contact_info) = contact_info {
-                self.cur_leaders
-                    .insert(next_slot + i as u64, contact_info.clone());
-            } else {
-                error!("Leader {} not found in cluster nodes", leader);
+    fn load_leader_schedule(&self) -> Result<(), AtlasTxnSenderError> {
+        let epoch_schedule = self
+            .rpc_client
+            .get_epoch_schedule()
+            .map_err(|e| format!("Error getting epoch schedule: {e}"))?;
+        let epoch_info = self
+            .rpc_client
+            .get_epoch_info()
+            .map_err(|e| format!("Error getting epoch info: {e}"))?;
+        for epoch in [epoch_info.epoch, epoch_info.epoch + 1] {
+            if self.leader_schedule.read().unwrap().contains_key(&epoch) {
+                continue;
             }
+            let first_slot = epoch_schedule.get_first_slot_in_epoch(epoch);
+            let leader_schedule = self
+                .rpc_client
+                .get_leader_schedule(Some(first_slot))
+                .map_err(|e| format!("Error getting leader schedule for epoch {epoch}: {e}"))?;
+            let Some(leader_schedule) = leader_schedule else {
+                debug!("Leader schedule for epoch {} is not known yet", epoch);
+                continue;
+            };
+            let epoch_leaders = EpochLeaders::new(
+                first_slot,
+                epoch_schedule.get_slots_in_epoch(epoch),
+                leader_schedule,
+            );
+            info!(
+                "loaded leader schedule for epoch {}: slots {} to {}, {} leaders",
+                epoch,
+                epoch_leaders.first_slot,
+                epoch_leaders.last_slot(),
+                epoch_leaders.pubkeys.len()
+            );
+            self.leader_schedule
+                .write()
+                .unwrap()
+                .insert(epoch, epoch_leaders);
         }
-        self.clean_up_slot_leaders();
+        // drop the epochs that ended
+        self.leader_schedule
+            .write()
+            .unwrap()
+            .retain(|epoch, _| *epoch >= epoch_info.epoch);
         Ok(())
     }
 
-    fn clean_up_slot_leaders(&self) {
-        let cur_slot = self.cur_slot.load(Ordering::Relaxed);
-        let mut slots_to_remove = vec![];
-        for leaders in self.cur_leaders.iter() {
-            if *leaders.key() < cur_slot {
-                slots_to_remove.push(*leaders.key());
+    /// poll_cluster_nodes refreshes the contact info of the cluster nodes
+    fn poll_cluster_nodes(&self) {
+        let rpc_client = self.rpc_client.clone();
+        let cluster_nodes = self.cluster_nodes.clone();
+        tokio::spawn(async move {
+            loop {
+                let rpc_client = rpc_client.clone();
+                let new_cluster_nodes =
+                    tokio::task::spawn_blocking(move || rpc_client.get_cluster_nodes()).await;
+                match new_cluster_nodes {
+                    Ok(Ok(new_cluster_nodes)) => {
+                        *cluster_nodes.write().unwrap() = new_cluster_nodes
+                            .into_iter()
+                            .map(|node| (node.pubkey.clone(), node))
+                            .collect();
+                        sleep(CLUSTER_NODES_REFRESH_INTERVAL).await;
+                    }
+                    Ok(Err(e)) => {
+                        error!("Error getting cluster nodes: {}", e);
+                        statsd_count!("poll_cluster_nodes_error", 1);
+                        sleep(RPC_RETRY_INTERVAL).await;
+                    }
+                    Err(e) => {
+                        error!("Error getting cluster nodes: {}", e);
+                        statsd_count!("poll_cluster_nodes_error", 1);
+                        sleep(RPC_RETRY_INTERVAL).await;
+                    }
+                }
             }
+        });
+    }
+
+    /// get_contact_info returns the contact info of the leader, if it is in the cluster nodes
+    fn get_contact_info(&self, leader: &str) -> Option<RpcContactInfo> {
+        let contact_info = self.cluster_nodes.read().unwrap().get(leader).cloned();
+        if contact_info.is_none() {
+            debug!("Leader {} not found in cluster nodes", leader);
         }
-        for slot in slots_to_remove {
-            self.cur_leaders.remove(&slot);
-        }
+        contact_info
     }
 }
 
+fn get_scheduled_leader(
+    leader_schedule: &BTreeMap<Epoch, EpochLeaders>,
+    slot: Slot,
+) -> Option<&str> {
+    leader_schedule
+        .values()
+        .find(|epoch_leaders| slot <= epoch_leaders.last_slot())
+        .and_then(|epoch_leaders| epoch_leaders.get(slot))
+}
+
 fn _get_start_slot(next_slot: u64, leader_offset: i64) -> u64 {
     let slot_buffer = leader_offset * (NUM_LEADERS_PER_SLOT as i64);
 
@@ -155,17 +296,25 @@ fn _get_start_slot(next_slot: u64, leader_offset: i64) -> u64 {
 }
 
 impl LeaderTrackerTrait for LeaderTrackerImpl {
-    fn get_leaders(&self) -> Vec<RpcContactInfo> {
+    fn get_leaders(&self, mode: LeaderPolicyMode) -> Vec<RpcContactInfo> {
         let start_slot = self.cur_slot.load(Ordering::Relaxed);
         let end_slot = start_slot + (self.num_leaders * NUM_LEADERS_PER_SLOT) as u64;
         let mut leaders = IndexMap::new();
-        for slot in start_slot..end_slot {
-            let leader = self.cur_leaders.get(&slot);
-            if let Some(leader) = leader {
-                _ = leaders.insert(leader.pubkey.to_owned(), leader.value().to_owned());
-            }
-            if leaders.len() >= self.num_leaders {
-                break;
+        {
+            let leader_schedule = self.leader_schedule.read().unwrap();
+            let cluster_nodes = self.cluster_nodes.read().unwrap();
+            for slot in start_slot..end_slot {
+                let leader = get_scheduled_leader(&leader_schedule, slot)
+                    .and_then(|leader| cluster_nodes.get(leader));
+                if let Some(leader) = leader {
+                    if !self.leader_policy.permits(&leader.pubkey, mode) {
+                        continue;
+                    }
+                    _ = leaders.insert(leader.pubkey.to_owned(), leader.to_owned());
+                }
+                if leaders.len() >= self.num_leaders {
+                    break;
+                }
             }
         }
         info!(
@@ -175,4 +324,15 @@ impl LeaderTrackerTrait for LeaderTrackerImpl {
         );
         leaders.values().clone().map(|v| v.to_owned()).collect()
     }
+
+    fn get_slot_leader(&self, slot: Slot) -> Option<RpcContactInfo> {
+        let leader = get_scheduled_leader(&self.leader_schedule.read().unwrap(), slot)?.to_owned();
+        self.get_contact_info(&leader)
+    }
+
+    fn get_next_leader_slot(&self, leader: &str, from_slot: Slot, to_slot: Slot) -> Option<Slot> {
+        let leader_schedule = self.leader_schedule.read().unwrap();
+        (from_slot..=to_slot)
+            .find(|slot| get_scheduled_leader(&leader_schedule, *slot) == Some(leader))
+    }
 }
@@ -0,0 +1,296 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use cadence_macros::statsd_count;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use solana_sdk::{slot_history::Slot, transaction::TransactionError};
+use tokio::time::sleep;
+use tracing::debug;
+
+use crate::{persistent_store::unix_millis_now, transaction_store::TransactionStore};
+
+// transactions that never finish, e.g. ones taken over by another instance, are dropped after this
+const MAX_UNFINISHED_AGE: Duration = Duration::from_secs(3600);
+
+/// TransactionState is a step in the life of a transaction, from the request to its outcome
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "state", rename_all = "camelCase")]
+pub enum TransactionState {
+    /// accepted by sendTransaction or replaceTransaction
+    Received,
+    /// first sent to the leaders
+    Sent,
+    /// resent by the retry loop, `retry` counts from 1
+    Retried {
+        retry: usize,
+    },
+    Landed {
+        slot: Slot,
+    },
+    /// landed, but the transaction failed
+    FailedOnChain {
+        slot: Slot,
+        err: TransactionError,
+    },
+    /// didn't land in time, or its slot window passed
+    Expired,
+    /// dropped by load shedding of the retry queue
+    Shed,
+    Cancelled,
+    /// stopped retrying without seeing it land
+    MaxRetries,
+}
+
+impl TransactionState {
+    /// is_landed is true for the states nothing can follow
+    pub fn is_landed(&self) -> bool {
+        matches!(
+            self,
+            TransactionState::Landed { .. } | TransactionState::FailedOnChain { .. }
+        )
+    }
+
+    /// is_finished is true once we stopped sending the transaction
+    pub fn is_finished(&self) -> bool {
+        !matches!(
+            self,
+            TransactionState::Received | TransactionState::Sent | TransactionState::Retried { .. }
+        )
+    }
+
+    /// can_follow returns whether the transaction can move from `previous` to this state.
+    /// A transaction we stopped sending may still land from an earlier send.
+    pub fn can_follow(&self, previous: &TransactionState) -> bool {
+        if previous.is_landed() {
+            return false;
+        }
+        match self {
+            TransactionState::Received => false,
+            TransactionState::Sent => previous == &TransactionState::Received,
+            TransactionState::Retried { retry } => match previous {
+                TransactionState::Sent => true,
+                TransactionState::Retried { retry: previous } => retry > previous,
+                _ => false,
+            },
+            TransactionState::Landed { .. } | TransactionState::FailedOnChain { .. } => true,
+            _ => !previous.is_finished(),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransactionState::Received => "received",
+            TransactionState::Sent => "sent",
+            TransactionState::Retried { .. } => "retried",
+            TransactionState::Landed { .. } => "landed",
+            TransactionState::FailedOnChain { .. } => "failed_on_chain",
+            TransactionState::Expired => "expired",
+            TransactionState::Shed => "shed",
+            TransactionState::Cancelled => "cancelled",
+            TransactionState::MaxRetries => "max_retries",
+        }
+    }
+}
+
+/// LifecycleEvent is a state change of a transaction
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct LifecycleEvent {
+    #[serde(flatten)]
+    pub state: TransactionState,
+    /// unix timestamp in milliseconds
+    pub at: u64,
+    pub details: Option<String>,
+}
+
+/// TransactionLifecycle is the history of a transaction
+#[derive(Clone, Debug)]
+struct TransactionLifecycle {
+    events: Vec<LifecycleEvent>,
+    updated_at: Instant,
+}
+
+impl TransactionLifecycle {
+    fn is_finished(&self) -> bool {
+        self.events
+            .last()
+            .is_some_and(|event| event.state.is_finished())
+    }
+}
+
+/// TransactionLifecycles records the state changes of each transaction, and keeps the history of
+/// finished transactions for the retention period. The history of a queued transaction is also
+/// kept with it in the transaction store, so it is recovered and taken over with the transaction.
+pub struct TransactionLifecycles {
+    retention: Duration,
+    lifecycles: Arc<DashMap<String, TransactionLifecycle>>,
+    transaction_store: Arc<dyn TransactionStore>,
+}
+
+impl TransactionLifecycles {
+    pub fn new(retention: Duration, transaction_store: Arc<dyn TransactionStore>) -> Self {
+        let transaction_lifecycles = Self {
+            retention,
+            lifecycles: Arc::new(DashMap::new()),
+            transaction_store,
+        };
+        transaction_lifecycles.clean_lifecycles();
+        transaction_lifecycles
+    }
+
+    fn clean_lifecycles(&self) {
+        let retention = self.retention;
+        let lifecycles = self.lifecycles.clone();
+        tokio::spawn(async move {
+            loop {
+                lifecycles.retain(|_, lifecycle| {
+                    let max_age = if lifecycle.is_finished() {
+                        retention
+                    } else {
+                        MAX_UNFINISHED_AGE
+                    };
+                    lifecycle.updated_at.elapsed() < max_age
+                });
+                sleep(Duration::from_secs(60)).await;
+            }
+        });
+    }
+
+    /// record moves the transaction to `state`. Transitions the state machine doesn't allow are
+    /// ignored, e.g. a retry after the transaction landed. Transactions recovered from a store or
+    /// taken over from another instance continue from the history kept with them.
+    pub fn record(&self, signature: &str, state: TransactionState, details: Option<String>) {
+        let mut lifecycle = self
+            .lifecycles
+            .entry(signature.to_string())
+            .or_insert_with(|| TransactionLifecycle {
+                events: self.stored_history(signature).unwrap_or_default(),
+                updated_at: Instant::now(),
+            });
+        if let Some(previous) = lifecycle.events.last() {
+            if !state.can_follow(&previous.state) {
+                debug!(
+                    "Ignoring transition of {} from {} to {}",
+                    signature,
+                    previous.state.as_str(),
+                    state.as_str()
+                );
+                return;
+            }
+        }
+        statsd_count!("transaction_state", 1, "state" => state.as_str());
+        let event = LifecycleEvent {
+            state,
+            at: unix_millis_now(),
+            details,
+        };
+        lifecycle.events.push(event.clone());
+        lifecycle.updated_at = Instant::now();
+        drop(lifecycle);
+        self.transaction_store.record_event(signature, event);
+    }
+
+    /// get_state returns the current state of the transaction
+    pub fn get_state(&self, signature: &str) -> Option<TransactionState> {
+        self.get_history(signature)
+            .and_then(|events| events.last().map(|event| event.state.clone()))
+    }
+
+    /// get_history returns the state changes of the transaction, oldest first
+    pub fn get_history(&self, signature: &str) -> Option<Vec<LifecycleEvent>> {
+        match self.lifecycles.get(signature) {
+            Some(lifecycle) => Some(lifecycle.events.clone()),
+            None => self.stored_history(signature),
+        }
+    }
+
+    /// stored_history returns the history kept with a queued transaction
+    fn stored_history(&self, signature: &str) -> Option<Vec<LifecycleEvent>> {
+        self.transaction_store
+            .get_transactions()
+            .get(signature)
+            .map(|transaction| transaction.history.clone())
+            .filter(|history| !history.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use solana_client::rpc_client::RpcClient;
+    use solana_sdk::{
+        hash::Hash, pubkey::Pubkey, signature::Keypair, system_transaction,
+        transaction::VersionedTransaction,
+    };
+
+    use super::*;
+    use crate::{
+        priority::TransactionPriority,
+        rpc_server::RequestMetadata,
+        test_utils::init_metrics,
+        transaction_store::{get_signature, TransactionData, TransactionStoreImpl},
+    };
+
+    fn states(history: Option<Vec<LifecycleEvent>>) -> Vec<&'static str> {
+        history
+            .unwrap_or_default()
+            .iter()
+            .map(|event| event.state.as_str())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn history_is_kept_with_the_queued_transaction() {
+        init_metrics();
+        let transaction_store: Arc<dyn TransactionStore> = Arc::new(TransactionStoreImpl::new(
+            Arc::new(RpcClient::new("http://127.0.0.1:1".to_string())),
+        ));
+        let transaction = TransactionData {
+            wire_transaction: vec![],
+            versioned_transaction: VersionedTransaction::from(system_transaction::transfer(
+                &Keypair::new(),
+                &Pubkey::new_unique(),
+                1,
+                Hash::new_unique(),
+            )),
+            sent_at: Instant::now(),
+            retry_count: 0,
+            max_retries: 5,
+            priority: TransactionPriority::default(),
+            request_metadata: RequestMetadata::default(),
+            history: vec![],
+        };
+        let signature = get_signature(&transaction).unwrap();
+        let lifecycles =
+            TransactionLifecycles::new(Duration::from_secs(60), transaction_store.clone());
+        lifecycles.record(&signature, TransactionState::Received, None);
+        let mut queued = transaction.clone();
+        queued.history = lifecycles.get_history(&signature).unwrap();
+        transaction_store.add_transaction(queued);
+        lifecycles.record(&signature, TransactionState::Sent, None);
+        lifecycles.record(&signature, TransactionState::Retried { retry: 1 }, None);
+        // out of order, ignored
+        lifecycles.record(&signature, TransactionState::Received, None);
+
+        let stored = transaction_store
+            .get_transactions()
+            .get(&signature)
+            .map(|transaction| transaction.history.clone());
+        assert_eq!(states(stored), vec!["received", "sent", "retried"]);
+
+        // e.g. after a restart, the history continues from the store
+        let restarted = TransactionLifecycles::new(Duration::from_secs(60), transaction_store);
+        assert_eq!(
+            restarted.get_state(&signature),
+            Some(TransactionState::Retried { retry: 1 })
+        );
+        restarted.record(&signature, TransactionState::Sent, None);
+        restarted.record(&signature, TransactionState::Landed { slot: 1 }, None);
+        assert_eq!(
+            states(restarted.get_history(&signature)),
+            vec!["received", "sent", "retried", "landed"]
+        );
+    }
+}
@@ -1,38 +1,105 @@
+mod attribution;
 mod errors;
+mod events;
 mod grpc_geyser;
+mod identity;
+mod latency;
+mod leader_policy;
 mod leader_tracker;
+mod lifecycle;
+mod persistent_store;
+mod priority;
+mod prometheus;
+mod rpc_poller;
+mod rpc_pubsub;
 mod rpc_server;
+mod scheduler;
+mod shared_store;
+mod signature_status;
 mod solana_rpc;
 mod static_leader;
+mod telemetry;
+#[cfg(test)]
+mod test_utils;
+mod throttle;
 mod transaction_store;
 mod txn_sender;
 mod vendor;
+mod webhook;
 
-use std::{
-    env,
-    net::{IpAddr, Ipv4Addr, UdpSocket},
-    sync::Arc,
-};
+use std::{env, net::UdpSocket, panic::RefUnwindSafe, path::PathBuf, sync::Arc, time::Duration};
 
-use cadence::{BufferedUdpMetricSink, QueuingMetricSink, StatsdClient};
+use attribution::LandingAttribution;
+use cadence::{BufferedUdpMetricSink, MetricSink, QueuingMetricSink, StatsdClient};
 use cadence_macros::set_global_default;
+use errors::AtlasTxnSenderError;
+use events::{EventBus, EventSinkConfig};
 use figment::{providers::Env, Figment};
-use grpc_geyser::GrpcGeyserImpl;
+use grpc_geyser::{GeyserConfirmation, GeyserEndpoint, GrpcGeyserImpl};
+use identity::{parse_identity_map, IdentityPool, IdentitySelection};
 use jsonrpsee::server::{middleware::ProxyGetRequestLayer, ServerBuilder};
-use leader_tracker::LeaderTrackerImpl;
+use latency::{parse_api_key_tiers, PipelineLatency};
+use leader_policy::LeaderPolicy;
+use leader_tracker::{LeaderTracker, LeaderTrackerImpl};
+use lifecycle::TransactionLifecycles;
+use persistent_store::PersistentTransactionStore;
+use priority::RetryQueuePolicy;
+use prometheus::{MetricsLayer, MultiMetricSink, PrometheusMetricSink, PrometheusRegistry};
+use rpc_poller::RpcPollerImpl;
+use rpc_pubsub::{PubsubConfirmation, RpcPubsubImpl};
 use rpc_server::{AtlasTxnSenderImpl, AtlasTxnSenderServer};
+use scheduler::Scheduler;
 use serde::Deserialize;
-use solana_client::{connection_cache::ConnectionCache, rpc_client::RpcClient};
-use solana_sdk::signature::{read_keypair_file, Keypair};
+use shared_store::SharedTransactionStore;
+use signature_status::SignatureStatuses;
+use solana_client::rpc_client::RpcClient;
+use solana_rpc::{SolanaRpc, SolanaRpcBackend};
 use static_leader::StaticLeaderImpl;
+use telemetry::TraceContextLayer;
+use throttle::{parse_pal_stakes, PalStakeProgram, PalStakes, SendThrottle};
 use tracing::{error, info};
-use transaction_store::TransactionStoreImpl;
+use transaction_store::{TransactionStore, TransactionStoreBackend, TransactionStoreImpl};
 use txn_sender::TxnSenderImpl;
+use webhook::{parse_allowed_hosts, parse_callback_urls, WebhookNotifier};
 
 #[derive(Debug, Deserialize)]
 struct AtlasTxnSenderEnv {
     identity_keypair_file: Option<String>,
+    identity_keypair_files: Option<String>,
+    identity_routes: Option<String>,
+    identity_api_keys: Option<String>,
+    identity_selection: Option<IdentitySelection>,
+    solana_rpc_backend: Option<SolanaRpcBackend>,
     grpc_url: Option<String>,
+    grpc_urls: Option<String>,
+    x_tokens: Option<String>,
+    grpc_stall_timeout_ms: Option<u64>,
+    grpc_confirmation: Option<GeyserConfirmation>,
+    ws_url: Option<String>,
+    ws_confirmation: Option<PubsubConfirmation>,
+    rpc_poll_slot_interval_ms: Option<u64>,
+    rpc_poll_status_interval_ms: Option<u64>,
+    confirmation_timeout_seconds: Option<u64>,
+    signature_retention_seconds: Option<u64>,
+    transaction_history_retention_seconds: Option<u64>,
+    event_sinks: Option<String>,
+    event_buffer_size: Option<usize>,
+    event_batch_size: Option<usize>,
+    event_file_max_mb: Option<u64>,
+    event_file_max_files: Option<usize>,
+    callback_secret: Option<String>,
+    callback_urls: Option<String>,
+    callback_allowed_hosts: Option<String>,
+    callback_max_attempts: Option<usize>,
+    callback_dead_letter_path: Option<String>,
+    api_key_tiers: Option<String>,
+    transaction_store: Option<TransactionStoreBackend>,
+    transaction_log_path: Option<String>,
+    transaction_log_compact_interval_seconds: Option<u64>,
+    shared_store_url: Option<String>,
+    shared_store_prefix: Option<String>,
+    shared_store_lease_ms: Option<u64>,
+    instance_id: Option<String>,
     rpc_url: Option<String>,
     port: Option<u16>,
     tpu_connection_pool_size: Option<usize>,
@@ -43,6 +110,16 @@ struct AtlasTxnSenderEnv {
     max_txn_send_retries: Option<usize>,
     txn_send_retry_interval: Option<usize>,
     max_retry_queue_size: Option<usize>,
+    retry_queue_reserved_capacity: Option<String>,
+    leader_policy_file: Option<String>,
+    admin_api_key: Option<String>,
+    send_throttle: Option<bool>,
+    pal_stakes: Option<String>,
+    pal_total_stake: Option<u64>,
+    pal_stake_program_id: Option<String>,
+    pal_stake_config: Option<String>,
+    otel_exporter_otlp_endpoint: Option<String>,
+    otel_service_name: Option<String>,
 }
 
 // Defualt on RPC is 4
@@ -53,15 +130,23 @@ async fn main() -> anyhow::Result<()> {
     // Init metrics/logging
     let env: AtlasTxnSenderEnv = Figment::from(Env::raw()).extract().unwrap();
     let env_filter = env::var("RUST_LOG").unwrap_or("info".to_string());
-    tracing_subscriber::fmt()
-        .with_env_filter(env_filter)
-        .json()
-        .init();
-    new_metrics_client();
+    telemetry::init_tracing(
+        env_filter,
+        env.otel_exporter_otlp_endpoint.clone(),
+        env.otel_service_name
+            .clone()
+            .unwrap_or("p3_txn_sender".to_string()),
+    )?;
+    let prometheus_registry = new_metrics_client()?;
 
     let service_builder = tower::ServiceBuilder::new()
         // Proxy `GET /health` requests to internal `health` method.
-        .layer(ProxyGetRequestLayer::new("/health", "health")?);
+        .layer(ProxyGetRequestLayer::new("/health", "health")?)
+        .layer(MetricsLayer::new(
+            prometheus_registry,
+            env::var("PROMETHEUS_BEARER_TOKEN").ok(),
+        ))
+        .layer(TraceContextLayer);
     let port = env.port.unwrap_or(4040);
 
     let server = ServerBuilder::default()
@@ -74,78 +159,297 @@ async fn main() -> anyhow::Result<()> {
     let tpu_connection_pool_size = env
         .tpu_connection_pool_size
         .unwrap_or(DEFAULT_TPU_CONNECTION_POOL_SIZE);
-    let connection_cache;
-    if let Some(identity_keypair_file) = env.identity_keypair_file.clone() {
-        let identity_keypair =
-            read_keypair_file(identity_keypair_file).expect("keypair file must exist");
-        connection_cache = Arc::new(ConnectionCache::new_with_client_options(
-            "p3-txn-sender",
-            tpu_connection_pool_size,
-            None, // created if none specified
-            Some((&identity_keypair, IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)))),
-            None, // not used as far as I can tell
-        ));
-    } else {
-        let identity_keypair = Keypair::new();
-        connection_cache = Arc::new(ConnectionCache::new_with_client_options(
-            "p3-txn-sender",
-            tpu_connection_pool_size,
-            None, // created if none specified
-            Some((&identity_keypair, IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)))),
-            None, // not used as far as I can tell
-        ));
-    }
-
-    let transaction_store = Arc::new(TransactionStoreImpl::new());
-    let solana_rpc = Arc::new(GrpcGeyserImpl::new(
-        env.grpc_url.clone().expect("GRPC_URL"),
-        env.x_token.clone(),
+    let rpc_client = Arc::new(RpcClient::new(env.rpc_url.clone().unwrap()));
+    let transaction_store: Arc<dyn TransactionStore> = match env
+        .transaction_store
+        .unwrap_or_default()
+    {
+        TransactionStoreBackend::Memory => Arc::new(TransactionStoreImpl::new(rpc_client.clone())),
+        TransactionStoreBackend::File => {
+            let path = PathBuf::from(
+                env.transaction_log_path
+                    .clone()
+                    .unwrap_or("transactions.log".to_string()),
+            );
+            let compact_interval =
+                Duration::from_secs(env.transaction_log_compact_interval_seconds.unwrap_or(60));
+            let rpc_client = rpc_client.clone();
+            // recovery checks the blockhashes with the blocking client
+            Arc::new(
+                tokio::task::spawn_blocking(move || {
+                    PersistentTransactionStore::open(path, compact_interval, rpc_client)
+                })
+                .await??,
+            )
+        }
+        TransactionStoreBackend::Shared => Arc::new(SharedTransactionStore::new(
+            &env.shared_store_url.clone().expect("SHARED_STORE_URL"),
+            env.shared_store_prefix
+                .clone()
+                .unwrap_or("atlas_txn_sender".to_string()),
+            env.instance_id
+                .clone()
+                .unwrap_or_else(|| format!("{:016x}", rand::random::<u64>())),
+            Duration::from_millis(env.shared_store_lease_ms.unwrap_or(10_000)),
+            rpc_client.clone(),
+        )?),
+    };
+    // in practice if a tx doesn't land in less than 60 seconds it's probably not going to land
+    let signature_statuses = Arc::new(SignatureStatuses::new(
+        Duration::from_secs(env.confirmation_timeout_seconds.unwrap_or(60)),
+        Duration::from_secs(env.signature_retention_seconds.unwrap_or(90)),
+    ));
+    let pipeline_latency = Arc::new(PipelineLatency::new(
+        env.api_key_tiers
+            .as_deref()
+            .map(parse_api_key_tiers)
+            .transpose()?
+            .unwrap_or_default(),
     ));
-    let rpc_client = Arc::new(RpcClient::new(env.rpc_url.unwrap()));
+    let event_bus = Arc::new(
+        EventBus::new(
+            &env.event_sinks.clone().unwrap_or_default(),
+            EventSinkConfig {
+                buffer_size: env.event_buffer_size.unwrap_or(10_000),
+                batch_size: env.event_batch_size.unwrap_or(500),
+                file_max_bytes: env.event_file_max_mb.unwrap_or(100) * 1024 * 1024,
+                file_max_files: env.event_file_max_files.unwrap_or(10),
+            },
+        )
+        .await?,
+    );
+    let solana_rpc: Arc<dyn SolanaRpc> = match env.solana_rpc_backend.unwrap_or_default() {
+        SolanaRpcBackend::Geyser => {
+            let mut geyser_endpoints = vec![];
+            if let Some(grpc_url) = env.grpc_url.clone() {
+                geyser_endpoints.push(GeyserEndpoint {
+                    url: grpc_url,
+                    x_token: env.x_token.clone(),
+                });
+            }
+            if let Some(grpc_urls) = env.grpc_urls.as_deref() {
+                geyser_endpoints.extend(GeyserEndpoint::parse_list(
+                    grpc_urls,
+                    env.x_tokens.as_deref(),
+                )?);
+            }
+            assert!(!geyser_endpoints.is_empty(), "GRPC_URL or GRPC_URLS");
+            Arc::new(GrpcGeyserImpl::new(
+                geyser_endpoints,
+                Duration::from_millis(env.grpc_stall_timeout_ms.unwrap_or(10_000)),
+                env.grpc_confirmation.unwrap_or_default(),
+                transaction_store.clone(),
+                signature_statuses,
+                event_bus.clone(),
+                pipeline_latency.clone(),
+            ))
+        }
+        SolanaRpcBackend::Websocket => Arc::new(RpcPubsubImpl::new(
+            env.ws_url.clone().expect("WS_URL"),
+            env.ws_confirmation.unwrap_or_default(),
+            signature_statuses,
+        )),
+        SolanaRpcBackend::Polling => Arc::new(RpcPollerImpl::new(
+            env.rpc_url.clone().expect("RPC_URL"),
+            Duration::from_millis(env.rpc_poll_slot_interval_ms.unwrap_or(400)),
+            Duration::from_millis(env.rpc_poll_status_interval_ms.unwrap_or(1000)),
+            signature_statuses,
+        )),
+    };
+    let identity_keypair_files = env
+        .identity_keypair_file
+        .iter()
+        .cloned()
+        .chain(
+            env.identity_keypair_files
+                .iter()
+                .flat_map(|files| files.split(','))
+                .map(|file| file.trim().to_string())
+                .filter(|file| !file.is_empty()),
+        )
+        .collect();
+    let identity_routes = env
+        .identity_routes
+        .as_deref()
+        .map(parse_identity_map)
+        .transpose()?
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(port, pubkey)| Ok((port.parse::<u16>()?, pubkey)))
+        .collect::<anyhow::Result<_>>()?;
+    let identity_api_keys = env
+        .identity_api_keys
+        .as_deref()
+        .map(parse_identity_map)
+        .transpose()?
+        .unwrap_or_default();
+    let identities = Arc::new(
+        IdentityPool::new(
+            identity_keypair_files,
+            tpu_connection_pool_size,
+            identity_routes,
+            identity_api_keys,
+            env.identity_selection.unwrap_or_default(),
+        )?
+        .with_stakes(rpc_client.clone())?,
+    );
+    identities.watch_keypair_files();
+    identities.refresh_stakes_every_epoch();
     let num_leaders = env.num_leaders.unwrap_or(2);
     let leader_offset = env.leader_offset.unwrap_or(0);
-    let leader_tracker = match env::var("STATIC_IP") {
+    let leader_policy = Arc::new(LeaderPolicy::new(env.leader_policy_file.clone())?);
+    let leader_tracker: Arc<LeaderTracker> = match env::var("STATIC_IP") {
         Ok(leader_addr) => Arc::new(StaticLeaderImpl::new(leader_addr).into()),
         Err(_) => Arc::new(
-            LeaderTrackerImpl::new(rpc_client, solana_rpc.clone(), num_leaders, leader_offset)
-                .into(),
+            LeaderTrackerImpl::new(
+                rpc_client.clone(),
+                solana_rpc.clone(),
+                num_leaders,
+                leader_offset,
+                leader_policy,
+            )
+            .into(),
         ),
     };
 
     let txn_send_retry_interval_seconds = env.txn_send_retry_interval.unwrap_or(2);
+    let reserved_capacity = env
+        .retry_queue_reserved_capacity
+        .as_deref()
+        .map(RetryQueuePolicy::parse_reserved_capacity)
+        .transpose()?
+        .unwrap_or_default();
+    let retry_queue_policy = RetryQueuePolicy::new(env.max_retry_queue_size, reserved_capacity);
+    let pal_stakes = PalStakes {
+        program: PalStakeProgram::parse(
+            env.pal_stake_program_id.as_deref(),
+            env.pal_stake_config.as_deref(),
+        )?,
+        stakes: env
+            .pal_stakes
+            .as_deref()
+            .map(parse_pal_stakes)
+            .transpose()?
+            .unwrap_or_default(),
+        total_stake: env.pal_total_stake,
+    };
+    let send_throttle = Arc::new(SendThrottle::new(
+        env.send_throttle.unwrap_or(false),
+        rpc_client,
+        identities.clone(),
+        pal_stakes,
+    ));
+    let landing_attribution = Arc::new(LandingAttribution::new());
+    let webhooks = Arc::new(WebhookNotifier::new(
+        env.callback_secret,
+        env.callback_urls
+            .as_deref()
+            .map(parse_callback_urls)
+            .transpose()?
+            .unwrap_or_default(),
+        env.callback_allowed_hosts
+            .as_deref()
+            .map(parse_allowed_hosts)
+            .unwrap_or_default(),
+        env.callback_max_attempts.unwrap_or(6),
+        PathBuf::from(
+            env.callback_dead_letter_path
+                .unwrap_or("callback_dead_letters.jsonl".to_string()),
+        ),
+    )?);
+    let transaction_lifecycles = Arc::new(TransactionLifecycles::new(
+        Duration::from_secs(env.transaction_history_retention_seconds.unwrap_or(300)),
+        transaction_store.clone(),
+    ));
     let txn_sender = Arc::new(TxnSenderImpl::new(
-        leader_tracker,
+        leader_tracker.clone(),
         transaction_store.clone(),
-        connection_cache,
-        solana_rpc,
+        identities.clone(),
+        solana_rpc.clone(),
         env.txn_sender_threads.unwrap_or(4),
         txn_send_retry_interval_seconds,
-        env.max_retry_queue_size,
+        retry_queue_policy,
+        send_throttle,
+        landing_attribution.clone(),
+        transaction_lifecycles.clone(),
+        event_bus,
+        webhooks.clone(),
+        pipeline_latency.clone(),
+    ));
+    txn_sender.track_adopted_transactions();
+    let scheduler = Arc::new(Scheduler::new(
+        leader_tracker,
+        solana_rpc.clone(),
+        transaction_store.clone(),
+        txn_sender.clone(),
+        transaction_lifecycles.clone(),
+        webhooks.clone(),
     ));
     let max_txn_send_retries = env.max_txn_send_retries.unwrap_or(5);
-    let atlas_txn_sender =
-        AtlasTxnSenderImpl::new(txn_sender, transaction_store, max_txn_send_retries);
+    let atlas_txn_sender = AtlasTxnSenderImpl::new(
+        txn_sender,
+        transaction_store,
+        scheduler,
+        identities,
+        max_txn_send_retries,
+        env.admin_api_key,
+        solana_rpc,
+        landing_attribution,
+        transaction_lifecycles,
+        webhooks,
+        pipeline_latency,
+    );
     let handle = server.start(atlas_txn_sender.into_rpc());
     handle.stopped().await;
+    telemetry::shutdown_tracing();
     Ok(())
 }
 
-fn new_metrics_client() {
-    let uri = env::var("METRICS_URI").unwrap_or("127.0.0.1".to_string());
-    let port = env::var("METRICS_PORT")
-        .unwrap_or("7998".to_string())
-        .parse::<u16>()
-        .unwrap();
-    info!("collecting metrics on: {}:{}", uri, port);
-    let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
-    socket.set_nonblocking(true).unwrap();
+/// new_metrics_client sends the metrics to statsd and/or an in-process Prometheus registry, which is
+/// returned to be served at `/metrics`
+fn new_metrics_client() -> Result<Option<Arc<PrometheusRegistry>>, AtlasTxnSenderError> {
+    let statsd_enabled = bool_env("STATSD_ENABLED", true)?;
+    let prometheus_enabled = bool_env("PROMETHEUS_ENABLED", false)?;
+    let mut sinks: Vec<Box<dyn MetricSink + Send + Sync + RefUnwindSafe>> = vec![];
+    if statsd_enabled {
+        let uri = env::var("METRICS_URI").unwrap_or("127.0.0.1".to_string());
+        let port = env::var("METRICS_PORT").unwrap_or("7998".to_string());
+        let port = port
+            .parse::<u16>()
+            .map_err(|e| format!("Invalid METRICS_PORT {port}: {e}"))?;
+        info!("collecting metrics on: {}:{}", uri, port);
+        let socket = UdpSocket::bind("0.0.0.0:0")
+            .map_err(|e| format!("Error binding metrics socket: {e}"))?;
+        socket
+            .set_nonblocking(true)
+            .map_err(|e| format!("Error binding metrics socket: {e}"))?;
 
-    let host = (uri, port);
-    let udp_sink = BufferedUdpMetricSink::from(host, socket).unwrap();
-    let queuing_sink = QueuingMetricSink::from(udp_sink);
+        let host = (uri, port);
+        let udp_sink = BufferedUdpMetricSink::from(host, socket)
+            .map_err(|e| format!("Error creating statsd sink: {e}"))?;
+        sinks.push(Box::new(udp_sink));
+    }
+    let prometheus_registry = prometheus_enabled.then(|| Arc::new(PrometheusRegistry::new()));
+    if let Some(registry) = &prometheus_registry {
+        info!("serving prometheus metrics on /metrics");
+        sinks.push(Box::new(PrometheusMetricSink::new(registry.clone())));
+    }
+    // both sinks are fed from the queue's thread, off the send path
+    let queuing_sink = QueuingMetricSink::from(MultiMetricSink::new(sinks));
     let builder = StatsdClient::builder("p3_txn_sender", queuing_sink);
     let client = builder
         .with_error_handler(|e| error!("statsd metrics error: {}", e))
         .build();
     set_global_default(client);
+    Ok(prometheus_registry)
+}
+
+/// bool_env reads a true/false env var, refusing anything else so a typo isn't taken for the default
+fn bool_env(name: &str, default: bool) -> Result<bool, AtlasTxnSenderError> {
+    match env::var(name) {
+        Ok(value) => value
+            .parse::<bool>()
+            .map_err(|_| format!("Invalid {name} {value}, expected true or false").into()),
+        Err(_) => Ok(default),
+    }
 }
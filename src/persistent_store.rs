@@ -0,0 +1,829 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use cadence_macros::{statsd_count, statsd_gauge, statsd_time};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use solana_client::rpc_client::RpcClient;
+use solana_sdk::{
+    commitment_config::CommitmentConfig, hash::Hash, transaction::VersionedTransaction,
+};
+use tracing::{error, info};
+
+use crate::{
+    errors::AtlasTxnSenderError,
+    lifecycle::{LifecycleEvent, TransactionState},
+    priority::TransactionPriority,
+    rpc_server::RequestMetadata,
+    transaction_store::{
+        get_signature, DurableNonce, Tombstone, TransactionData, TransactionStore,
+        TransactionStoreImpl,
+    },
+};
+
+/// StoredTransaction is a queued transaction as written to the log or the shared store
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct StoredTransaction {
+    /// base64 wire transaction
+    wire_transaction: String,
+    /// unix millis the transaction was received at
+    received_at: u64,
+    retry_count: usize,
+    max_retries: usize,
+    request_metadata: RequestMetadata,
+    #[serde(default)]
+    history: Vec<LifecycleEvent>,
+}
+
+impl StoredTransaction {
+    pub fn new(transaction: &TransactionData) -> Self {
+        Self {
+            wire_transaction: BASE64_STANDARD.encode(&transaction.wire_transaction),
+            received_at: unix_millis_now()
+                .saturating_sub(transaction.sent_at.elapsed().as_millis() as u64),
+            retry_count: transaction.retry_count,
+            max_retries: transaction.max_retries,
+            request_metadata: transaction.request_metadata.clone(),
+            history: transaction.history.clone(),
+        }
+    }
+
+    pub fn into_transaction_data(self) -> Result<TransactionData, AtlasTxnSenderError> {
+        let wire_transaction = BASE64_STANDARD
+            .decode(&self.wire_transaction)
+            .map_err(|e| format!("invalid wire transaction: {e}"))?;
+        let versioned_transaction: VersionedTransaction =
+            bincode::deserialize(&wire_transaction)
+                .map_err(|e| format!("invalid wire transaction: {e}"))?;
+        Ok(TransactionData {
+            priority: TransactionPriority::from_transaction(&versioned_transaction),
+            wire_transaction,
+            versioned_transaction,
+            sent_at: instant_from_unix_millis(self.received_at),
+            retry_count: self.retry_count,
+            max_retries: self.max_retries,
+            request_metadata: self.request_metadata,
+            history: self.history,
+        })
+    }
+}
+
+/// LogEntry is one line of the transaction log. Replaying the lines in order rebuilds the queue.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum LogEntry {
+    Add {
+        signature: String,
+        transaction: StoredTransaction,
+    },
+    Remove {
+        signature: String,
+    },
+    /// a state change of a queued transaction
+    Event {
+        signature: String,
+        event: LifecycleEvent,
+    },
+    Cancel {
+        signature: String,
+        /// unix millis
+        cancelled_at: u64,
+        /// the nonce of a durable nonce transaction, whose tombstone is kept until it advances
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        nonce: Option<DurableNonce>,
+    },
+    /// a transaction held by the scheduler, its SendSchedule is in the request metadata
+    Schedule {
+        signature: String,
+        transaction: StoredTransaction,
+    },
+    /// the scheduled transaction was released, expired or cancelled
+    Unschedule {
+        signature: String,
+    },
+}
+
+/// PersistentTransactionStore keeps the queue in memory like TransactionStoreImpl and appends every
+/// change to a log file, so queued retries and cancellations survive a restart.
+/// The log is compacted into a snapshot of the queue periodically.
+pub struct PersistentTransactionStore {
+    transactions: Arc<TransactionStoreImpl>,
+    /// the transactions held by the scheduler, kept for compaction
+    scheduled: Arc<DashMap<String, StoredTransaction>>,
+    log_sender: mpsc::Sender<LogEntry>,
+    /// the recovered transactions, until they are tracked
+    recovered: Mutex<Vec<TransactionData>>,
+    /// the recovered scheduled transactions, until the scheduler takes them
+    recovered_scheduled: Mutex<Vec<TransactionData>>,
+}
+
+impl PersistentTransactionStore {
+    /// open recovers the queue and the scheduled transactions from the log at `path`, dropping the
+    /// transactions whose blockhash expired while the sender was down, and starts the log writer
+    pub fn open(
+        path: PathBuf,
+        compact_interval: Duration,
+        rpc_client: Arc<RpcClient>,
+    ) -> Result<Self, AtlasTxnSenderError> {
+        let transactions = Arc::new(TransactionStoreImpl::new(rpc_client.clone()));
+        let RecoveredLog {
+            queued,
+            scheduled,
+            cancelled,
+        } = read_log(&path)?;
+        let is_blockhash_valid = |blockhash: &Hash| {
+            rpc_client
+                .is_blockhash_valid(blockhash, CommitmentConfig::processed())
+                .unwrap_or_else(|e| {
+                    error!("Error checking blockhash {}: {}", blockhash, e);
+                    true
+                })
+        };
+        let queued = drop_expired_transactions(queued, is_blockhash_valid);
+        let scheduled = drop_expired_transactions(scheduled, is_blockhash_valid);
+        for (signature, (cancelled_at, nonce)) in cancelled {
+            transactions.restore_tombstone(
+                signature,
+                Tombstone {
+                    cancelled_at: instant_from_unix_millis(cancelled_at),
+                    nonce,
+                },
+            );
+        }
+        for (signature, transaction) in queued {
+            match transaction.into_transaction_data() {
+                Ok(transaction) => transactions.add_transaction(transaction),
+                Err(e) => error!("Error recovering transaction {}: {}", signature, e),
+            }
+        }
+        let recovered: Vec<TransactionData> = transactions
+            .get_transactions()
+            .iter()
+            .map(|transaction| transaction.value().clone())
+            .collect();
+        info!("recovered {} transactions from {:?}", recovered.len(), path);
+        statsd_gauge!("transactions_recovered", recovered.len() as u64);
+        let mut recovered_scheduled = vec![];
+        let scheduled: DashMap<String, StoredTransaction> = scheduled
+            .into_iter()
+            .filter(
+                |(signature, transaction)| match transaction.clone().into_transaction_data() {
+                    Ok(transaction) => {
+                        recovered_scheduled.push(transaction);
+                        true
+                    }
+                    Err(e) => {
+                        error!(
+                            "Error recovering scheduled transaction {}: {}",
+                            signature, e
+                        );
+                        false
+                    }
+                },
+            )
+            .collect();
+        let scheduled = Arc::new(scheduled);
+        info!(
+            "recovered {} scheduled transactions from {:?}",
+            recovered_scheduled.len(),
+            path
+        );
+        statsd_gauge!(
+            "scheduled_transactions_recovered",
+            recovered_scheduled.len() as u64
+        );
+
+        compact_log(&path, &transactions, &scheduled)?;
+        let (log_sender, log_receiver) = mpsc::channel();
+        let log_transactions = transactions.clone();
+        let log_scheduled = scheduled.clone();
+        std::thread::spawn(move || {
+            write_log(
+                path,
+                compact_interval,
+                log_receiver,
+                log_transactions,
+                log_scheduled,
+            )
+        });
+        Ok(Self {
+            transactions,
+            scheduled,
+            log_sender,
+            recovered: Mutex::new(recovered),
+            recovered_scheduled: Mutex::new(recovered_scheduled),
+        })
+    }
+
+    fn log(&self, entry: LogEntry) {
+        if self.log_sender.send(entry).is_err() {
+            error!("transaction log writer stopped");
+            statsd_count!("transaction_log_write_error", 1);
+        }
+    }
+
+    fn log_add(&self, signature: String, transaction: &TransactionData) {
+        self.log(LogEntry::Add {
+            signature,
+            transaction: StoredTransaction::new(transaction),
+        });
+    }
+
+    fn log_cancel(&self, signature: &str) {
+        self.log(LogEntry::Cancel {
+            signature: signature.to_string(),
+            cancelled_at: unix_millis_now(),
+            nonce: self
+                .transactions
+                .get_tombstone(signature)
+                .and_then(|tombstone| tombstone.nonce),
+        });
+    }
+}
+
+impl TransactionStore for PersistentTransactionStore {
+    fn add_transaction(&self, transaction: TransactionData) {
+        let Some(signature) = get_signature(&transaction) else {
+            return self.transactions.add_transaction(transaction);
+        };
+        let queued = self.transactions.has_signature(&signature);
+        self.transactions.add_transaction(transaction.clone());
+        if !queued && self.transactions.has_signature(&signature) {
+            self.log_add(signature, &transaction);
+        }
+    }
+    fn remove_transaction(&self, signature: String) -> Option<TransactionData> {
+        let transaction = self.transactions.remove_transaction(signature.clone());
+        if transaction.is_some() {
+            self.log(LogEntry::Remove { signature });
+        }
+        transaction
+    }
+    fn get_transactions(&self) -> Arc<DashMap<String, TransactionData>> {
+        self.transactions.get_transactions()
+    }
+    fn has_signature(&self, signature: &str) -> bool {
+        self.transactions.has_signature(signature)
+    }
+    fn get_fee_payers(&self) -> BTreeSet<String> {
+        self.transactions.get_fee_payers()
+    }
+    fn record_event(&self, signature: &str, event: LifecycleEvent) {
+        if !self.transactions.has_signature(signature) {
+            return;
+        }
+        self.transactions.record_event(signature, event.clone());
+        self.log(LogEntry::Event {
+            signature: signature.to_string(),
+            event,
+        });
+    }
+    fn take_adopted(&self) -> Vec<TransactionData> {
+        std::mem::take(&mut *self.recovered.lock().unwrap())
+    }
+    fn schedule_transaction(&self, signature: &str, transaction: &TransactionData) {
+        let transaction = StoredTransaction::new(transaction);
+        self.scheduled
+            .insert(signature.to_string(), transaction.clone());
+        self.log(LogEntry::Schedule {
+            signature: signature.to_string(),
+            transaction,
+        });
+    }
+    fn unschedule_transaction(&self, signature: &str) {
+        if self.scheduled.remove(signature).is_some() {
+            self.log(LogEntry::Unschedule {
+                signature: signature.to_string(),
+            });
+        }
+    }
+    fn take_scheduled(&self) -> Vec<TransactionData> {
+        std::mem::take(&mut *self.recovered_scheduled.lock().unwrap())
+    }
+    fn cancel_transaction(
+        &self,
+        signature: &str,
+        held: Option<&TransactionData>,
+    ) -> Option<TransactionData> {
+        let transaction = self.transactions.cancel_transaction(signature, held);
+        self.log_cancel(signature);
+        transaction
+    }
+    fn is_cancelled(&self, signature: &str) -> bool {
+        self.transactions.is_cancelled(signature)
+    }
+    fn replace_transaction(
+        &self,
+        signature: &str,
+        transaction: TransactionData,
+    ) -> Result<TransactionData, AtlasTxnSenderError> {
+        let transaction = self
+            .transactions
+            .replace_transaction(signature, transaction)?;
+        self.log_cancel(signature);
+        if let Some(new_signature) = get_signature(&transaction) {
+            self.log_add(new_signature, &transaction);
+        }
+        Ok(transaction)
+    }
+}
+
+#[derive(Default)]
+struct RecoveredLog {
+    queued: HashMap<String, StoredTransaction>,
+    scheduled: HashMap<String, StoredTransaction>,
+    /// the unix millis each signature was cancelled at, and its nonce
+    cancelled: HashMap<String, (u64, Option<DurableNonce>)>,
+}
+
+/// read_log replays the log into the queued and scheduled transactions and the cancelled signatures.
+/// A torn last line from a crash is skipped.
+fn read_log(path: &Path) -> Result<RecoveredLog, AtlasTxnSenderError> {
+    let mut recovered = RecoveredLog::default();
+    let RecoveredLog {
+        queued,
+        scheduled,
+        cancelled,
+    } = &mut recovered;
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(recovered),
+        Err(e) => return Err(format!("Error opening transaction log {path:?}: {e}").into()),
+    };
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(|e| format!("Error reading transaction log {path:?}: {e}"))?;
+        let entry = match serde_json::from_str::<LogEntry>(&line) {
+            Ok(entry) => entry,
+            Err(e) => {
+                error!("Skipping invalid transaction log line: {}", e);
+                statsd_count!("transaction_log_invalid_line", 1);
+                continue;
+            }
+        };
+        match entry {
+            LogEntry::Add {
+                signature,
+                transaction,
+            } => {
+                queued.insert(signature, transaction);
+            }
+            LogEntry::Remove { signature } => {
+                queued.remove(&signature);
+            }
+            LogEntry::Event { signature, event } => {
+                if let Some(transaction) = queued.get_mut(&signature) {
+                    // retries are only logged as events, the last one has the retry count
+                    if let TransactionState::Retried { retry } = event.state {
+                        transaction.retry_count = retry.min(transaction.max_retries);
+                    }
+                    transaction.history.push(event);
+                }
+            }
+            LogEntry::Cancel {
+                signature,
+                cancelled_at,
+                nonce,
+            } => {
+                queued.remove(&signature);
+                scheduled.remove(&signature);
+                cancelled.insert(signature, (cancelled_at, nonce));
+            }
+            LogEntry::Schedule {
+                signature,
+                transaction,
+            } => {
+                scheduled.insert(signature, transaction);
+            }
+            LogEntry::Unschedule { signature } => {
+                scheduled.remove(&signature);
+            }
+        }
+    }
+    Ok(recovered)
+}
+
+/// drop_expired_transactions removes the transactions whose blockhash is no longer valid.
+/// Durable nonce transactions don't expire.
+fn drop_expired_transactions(
+    queued: HashMap<String, StoredTransaction>,
+    is_blockhash_valid: impl Fn(&Hash) -> bool,
+) -> HashMap<String, StoredTransaction> {
+    let mut blockhashes: HashMap<Hash, bool> = HashMap::new();
+    let mut expired = HashSet::new();
+    for (signature, transaction) in queued.iter() {
+        let Ok(transaction_data) = transaction.clone().into_transaction_data() else {
+            continue;
+        };
+        let versioned_transaction = &transaction_data.versioned_transaction;
+        if DurableNonce::from_transaction(versioned_transaction).is_some() {
+            continue;
+        }
+        let blockhash = *versioned_transaction.message.recent_blockhash();
+        let valid = *blockhashes
+            .entry(blockhash)
+            .or_insert_with(|| is_blockhash_valid(&blockhash));
+        if !valid {
+            expired.insert(signature.clone());
+        }
+    }
+    statsd_count!("recovered_transaction_expired", expired.len() as i64);
+    queued
+        .into_iter()
+        .filter(|(signature, _)| !expired.contains(signature))
+        .collect()
+}
+
+/// compact_log replaces the log with a snapshot of the queue, the scheduled transactions and the
+/// cancellations
+fn compact_log(
+    path: &Path,
+    transactions: &TransactionStoreImpl,
+    scheduled: &DashMap<String, StoredTransaction>,
+) -> Result<(), AtlasTxnSenderError> {
+    let start = Instant::now();
+    let tmp_path = path.with_extension("tmp");
+    let write_snapshot = || -> std::io::Result<()> {
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        for (signature, tombstone) in transactions.get_tombstones() {
+            let entry = LogEntry::Cancel {
+                signature,
+                cancelled_at: unix_millis_now()
+                    .saturating_sub(tombstone.cancelled_at.elapsed().as_millis() as u64),
+                nonce: tombstone.nonce,
+            };
+            serde_json::to_writer(&mut writer, &entry)?;
+            writer.write_all(b"\n")?;
+        }
+        for transaction in transactions.get_transactions().iter() {
+            let entry = LogEntry::Add {
+                signature: transaction.key().to_owned(),
+                transaction: StoredTransaction::new(transaction.value()),
+            };
+            serde_json::to_writer(&mut writer, &entry)?;
+            writer.write_all(b"\n")?;
+        }
+        for transaction in scheduled.iter() {
+            let entry = LogEntry::Schedule {
+                signature: transaction.key().to_owned(),
+                transaction: transaction.value().clone(),
+            };
+            serde_json::to_writer(&mut writer, &entry)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.into_inner()?.sync_all()?;
+        fs::rename(&tmp_path, path)
+    };
+    write_snapshot().map_err(|e| format!("Error compacting transaction log {path:?}: {e}"))?;
+    statsd_time!("transaction_log_compaction_time", start.elapsed());
+    Ok(())
+}
+
+fn open_log(path: &Path) -> Result<BufWriter<File>, AtlasTxnSenderError> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map(BufWriter::new)
+        .map_err(|e| format!("Error opening transaction log {path:?}: {e}").into())
+}
+
+/// write_log appends the entries as they arrive and compacts the log every compact_interval.
+/// It runs on its own thread so disk writes never block the request handlers.
+fn write_log(
+    path: PathBuf,
+    compact_interval: Duration,
+    log_receiver: mpsc::Receiver<LogEntry>,
+    transactions: Arc<TransactionStoreImpl>,
+    scheduled: Arc<DashMap<String, StoredTransaction>>,
+) {
+    let mut writer = None;
+    let mut last_compaction = Instant::now();
+    loop {
+        let entry = match log_receiver
+            .recv_timeout(compact_interval.saturating_sub(last_compaction.elapsed()))
+        {
+            Ok(entry) => Some(entry),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+        if last_compaction.elapsed() >= compact_interval {
+            // entries written after the snapshot are replayed on top of it, which is harmless as
+            // they are already reflected in it
+            writer = None;
+            if let Err(e) = compact_log(&path, &transactions, &scheduled) {
+                error!("{}", e);
+                statsd_count!("transaction_log_write_error", 1);
+            }
+            last_compaction = Instant::now();
+        }
+        let Some(entry) = entry else {
+            continue;
+        };
+        if writer.is_none() {
+            match open_log(&path) {
+                Ok(opened) => writer = Some(opened),
+                Err(e) => {
+                    error!("{}", e);
+                    statsd_count!("transaction_log_write_error", 1);
+                    continue;
+                }
+            }
+        }
+        let log_writer = writer.as_mut().unwrap();
+        let mut write_entries = || -> std::io::Result<()> {
+            serde_json::to_writer(&mut *log_writer, &entry)?;
+            log_writer.write_all(b"\n")?;
+            // write whatever else is already waiting before flushing
+            for entry in log_receiver.try_iter() {
+                serde_json::to_writer(&mut *log_writer, &entry)?;
+                log_writer.write_all(b"\n")?;
+            }
+            log_writer.flush()
+        };
+        if let Err(e) = write_entries() {
+            error!("Error writing transaction log {:?}: {}", path, e);
+            statsd_count!("transaction_log_write_error", 1);
+            writer = None;
+        }
+    }
+}
+
+pub fn unix_millis_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn instant_from_unix_millis(unix_millis: u64) -> Instant {
+    let age = Duration::from_millis(unix_millis_now().saturating_sub(unix_millis));
+    Instant::now().checked_sub(age).unwrap_or_else(Instant::now)
+}
+
+#[cfg(test)]
+mod tests {
+    use solana_sdk::{
+        pubkey::Pubkey, signature::Keypair, signer::Signer, system_transaction,
+        transaction::Transaction,
+    };
+    use solana_system_interface::instruction as system_instruction;
+
+    use super::*;
+    use crate::{scheduler::SendSchedule, test_utils::init_metrics};
+
+    fn log_path() -> PathBuf {
+        std::env::temp_dir().join(format!("transactions-{}.log", uuid::Uuid::new_v4()))
+    }
+
+    fn transaction_data(transaction: Transaction) -> TransactionData {
+        let versioned_transaction = VersionedTransaction::from(transaction);
+        TransactionData {
+            wire_transaction: bincode::serialize(&versioned_transaction).unwrap(),
+            priority: TransactionPriority::from_transaction(&versioned_transaction),
+            versioned_transaction,
+            sent_at: Instant::now(),
+            retry_count: 0,
+            max_retries: 5,
+            request_metadata: RequestMetadata::default(),
+            history: vec![],
+        }
+    }
+
+    fn transfer(blockhash: Hash) -> TransactionData {
+        transaction_data(system_transaction::transfer(
+            &Keypair::new(),
+            &Pubkey::new_unique(),
+            1,
+            blockhash,
+        ))
+    }
+
+    fn nonce_transfer(nonce: Hash) -> TransactionData {
+        let payer = Keypair::new();
+        transaction_data(Transaction::new_signed_with_payer(
+            &[
+                system_instruction::advance_nonce_account(&Pubkey::new_unique(), &payer.pubkey()),
+                system_instruction::transfer(&payer.pubkey(), &Pubkey::new_unique(), 1),
+            ],
+            Some(&payer.pubkey()),
+            &[&payer],
+            nonce,
+        ))
+    }
+
+    fn retried(retry: usize) -> LifecycleEvent {
+        LifecycleEvent {
+            state: TransactionState::Retried { retry },
+            at: unix_millis_now(),
+            details: None,
+        }
+    }
+
+    fn write_lines(path: &Path, entries: &[LogEntry], tail: &str) {
+        let mut file = File::create(path).unwrap();
+        for entry in entries {
+            serde_json::to_writer(&mut file, entry).unwrap();
+            file.write_all(b"\n").unwrap();
+        }
+        file.write_all(tail.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn replays_log_and_skips_torn_lines() {
+        let path = log_path();
+        let kept = transfer(Hash::new_unique());
+        let removed = transfer(Hash::new_unique());
+        let cancelled = transfer(Hash::new_unique());
+        let [kept_sig, removed_sig, cancelled_sig] =
+            [&kept, &removed, &cancelled].map(|t| get_signature(t).unwrap());
+        let add = |signature: &String, transaction: &TransactionData| LogEntry::Add {
+            signature: signature.clone(),
+            transaction: StoredTransaction::new(transaction),
+        };
+        write_lines(
+            &path,
+            &[
+                add(&kept_sig, &kept),
+                add(&removed_sig, &removed),
+                add(&cancelled_sig, &cancelled),
+                LogEntry::Event {
+                    signature: kept_sig.clone(),
+                    event: retried(1),
+                },
+                LogEntry::Event {
+                    signature: kept_sig.clone(),
+                    event: retried(2),
+                },
+                LogEntry::Remove {
+                    signature: removed_sig.clone(),
+                },
+                LogEntry::Cancel {
+                    signature: cancelled_sig.clone(),
+                    cancelled_at: 1,
+                    nonce: None,
+                },
+            ],
+            // a crash in the middle of a write
+            r#"{"op":"event","signature":"#,
+        );
+
+        let RecoveredLog {
+            queued, cancelled, ..
+        } = read_log(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(queued.len(), 1);
+        let recovered = queued[&kept_sig].clone().into_transaction_data().unwrap();
+        assert_eq!(recovered.retry_count, 2);
+        assert_eq!(recovered.history.len(), 2);
+        assert_eq!(recovered.wire_transaction, kept.wire_transaction);
+        assert_eq!(cancelled.get(&cancelled_sig), Some(&(1, None)));
+    }
+
+    #[test]
+    fn missing_log_is_empty() {
+        let recovered = read_log(&log_path()).unwrap();
+        assert!(recovered.queued.is_empty());
+        assert!(recovered.scheduled.is_empty() && recovered.cancelled.is_empty());
+    }
+
+    #[tokio::test]
+    async fn compacts_queue_and_tombstones() {
+        init_metrics();
+        let path = log_path();
+        let transactions =
+            TransactionStoreImpl::new(Arc::new(RpcClient::new("http://127.0.0.1:1".to_string())));
+        let mut queued = transfer(Hash::new_unique());
+        queued.retry_count = 3;
+        queued.history.push(retried(3));
+        let queued_sig = get_signature(&queued).unwrap();
+        transactions.add_transaction(queued);
+        let nonce = Hash::new_unique();
+        let cancelled = nonce_transfer(nonce);
+        let cancelled_sig = get_signature(&cancelled).unwrap();
+        transactions.cancel_transaction(&cancelled_sig, Some(&cancelled));
+
+        let scheduled = transfer(Hash::new_unique());
+        let scheduled_sig = get_signature(&scheduled).unwrap();
+        let scheduled =
+            DashMap::from_iter([(scheduled_sig.clone(), StoredTransaction::new(&scheduled))]);
+
+        compact_log(&path, &transactions, &scheduled).unwrap();
+        let RecoveredLog {
+            queued,
+            scheduled,
+            cancelled: tombstones,
+        } = read_log(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let recovered = queued[&queued_sig].clone().into_transaction_data().unwrap();
+        assert_eq!(recovered.retry_count, 3);
+        assert_eq!(recovered.history.len(), 1);
+        assert!(scheduled.contains_key(&scheduled_sig));
+        let (_, cancelled_nonce) = tombstones[&cancelled_sig];
+        assert_eq!(cancelled_nonce.map(|n| n.nonce), Some(nonce));
+    }
+
+    #[test]
+    fn drops_expired_transactions_but_not_durable_nonces() {
+        init_metrics();
+        let expired = transfer(Hash::new_unique());
+        let valid_blockhash = Hash::new_unique();
+        let valid = transfer(valid_blockhash);
+        let nonce = nonce_transfer(Hash::new_unique());
+        let queued: HashMap<String, StoredTransaction> = [&expired, &valid, &nonce]
+            .into_iter()
+            .map(|t| (get_signature(t).unwrap(), StoredTransaction::new(t)))
+            .collect();
+
+        let kept = drop_expired_transactions(queued, |blockhash| blockhash == &valid_blockhash);
+        assert_eq!(kept.len(), 2);
+        assert!(!kept.contains_key(&get_signature(&expired).unwrap()));
+        assert!(kept.contains_key(&get_signature(&nonce).unwrap()));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn recovers_queue_after_restart() {
+        init_metrics();
+        let path = log_path();
+        // the blockhash can't be checked, so the transaction is kept
+        let rpc_client = Arc::new(RpcClient::new("http://127.0.0.1:1".to_string()));
+        let open = |path: PathBuf| {
+            let rpc_client = rpc_client.clone();
+            tokio::task::spawn_blocking(move || {
+                PersistentTransactionStore::open(path, Duration::from_secs(3600), rpc_client)
+            })
+        };
+        let store = open(path.clone()).await.unwrap().unwrap();
+        assert!(store.take_adopted().is_empty());
+        let transaction = transfer(Hash::new_unique());
+        let signature = get_signature(&transaction).unwrap();
+        store.add_transaction(transaction);
+        store.record_event(&signature, retried(1));
+        store.cancel_transaction("cancelled", None);
+        drop(store);
+        // the entries are written by the log writer thread
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let store = open(path.clone()).await.unwrap().unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(store.is_cancelled("cancelled"));
+        let adopted = store.take_adopted();
+        assert_eq!(adopted.len(), 1);
+        assert_eq!(adopted[0].retry_count, 1);
+        assert!(store.take_adopted().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn recovers_scheduled_transactions_after_restart() {
+        init_metrics();
+        let path = log_path();
+        let rpc_client = Arc::new(RpcClient::new("http://127.0.0.1:1".to_string()));
+        let open = |path: PathBuf| {
+            let rpc_client = rpc_client.clone();
+            tokio::task::spawn_blocking(move || {
+                PersistentTransactionStore::open(path, Duration::from_secs(3600), rpc_client)
+            })
+        };
+        let store = open(path.clone()).await.unwrap().unwrap();
+        assert!(store.take_scheduled().is_empty());
+        let schedule = SendSchedule {
+            target_slot: Some(100),
+            ..Default::default()
+        };
+        let mut scheduled = transfer(Hash::new_unique());
+        scheduled.request_metadata.schedule = Some(schedule.clone());
+        let mut released = transfer(Hash::new_unique());
+        released.request_metadata.schedule = Some(schedule.clone());
+        let mut cancelled = transfer(Hash::new_unique());
+        cancelled.request_metadata.schedule = Some(schedule.clone());
+        let [scheduled_sig, released_sig, cancelled_sig] =
+            [&scheduled, &released, &cancelled].map(|t| get_signature(t).unwrap());
+        store.schedule_transaction(&scheduled_sig, &scheduled);
+        store.schedule_transaction(&released_sig, &released);
+        store.schedule_transaction(&cancelled_sig, &cancelled);
+        store.add_transaction(released);
+        store.unschedule_transaction(&released_sig);
+        store.cancel_transaction(&cancelled_sig, Some(&cancelled));
+        drop(store);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let store = open(path.clone()).await.unwrap().unwrap();
+        fs::remove_file(&path).unwrap();
+        let recovered = store.take_scheduled();
+        assert_eq!(recovered.len(), 1);
+        assert_eq!(get_signature(&recovered[0]), Some(scheduled_sig));
+        assert_eq!(recovered[0].request_metadata.schedule, Some(schedule));
+        assert_eq!(store.take_adopted().len(), 1);
+        assert!(store.take_scheduled().is_empty());
+    }
+}
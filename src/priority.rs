@@ -0,0 +1,411 @@
+use std::{cmp::Ordering, collections::HashMap};
+
+use solana_sdk::{compute_budget, pubkey, pubkey::Pubkey, transaction::VersionedTransaction};
+use solana_system_interface::{instruction::SystemInstruction, program as system_program};
+
+use crate::{errors::AtlasTxnSenderError, transaction_store::TransactionData};
+
+/// Jito tip payment accounts. Paladin leaders run the same tip payment program,
+/// so a transfer to any of these is a tip on both the P3 and MEV ports.
+pub const TIP_ACCOUNTS: [Pubkey; 8] = [
+    pubkey!("96gYZGLnJYVFmbjzopPSU6QiEV5fGqZNyN9nmNhvrZU5"),
+    pubkey!("HFqU5x63VTqvQss8hp11i4wVV8bD44PvwucfZ2bU7gRe"),
+    pubkey!("Cw8CFyM9FkoMi7K7Crf6HNQqf4uEMzpKw6QNghXLvLkY"),
+    pubkey!("ADaUMid9yfUytqMBgopwjb2DTLSokTSzL1zt6iGPaS49"),
+    pubkey!("DfXygSm4jCyNCybVYYK6DwvWqjKee8pbDmJGcLWNDXjh"),
+    pubkey!("ADuUkR4vqLUMWXxW9gh6D6L8pMSawimctcNZ5pGwDcEt"),
+    pubkey!("DttWaMuVvTiduZRnguLF7jNxTgiMBZ1hyAumKUiL2KRL"),
+    pubkey!("3AVi9Tg9Uo68tJfuvoKvqKNWKkC5wPdSSdeBnizKZ6jT"),
+];
+
+const MICRO_LAMPORTS_PER_LAMPORT: u128 = 1_000_000;
+const DEFAULT_INSTRUCTION_COMPUTE_UNIT_LIMIT: u32 = 200_000;
+const MAX_COMPUTE_UNIT_LIMIT: u32 = 1_400_000;
+
+// ComputeBudgetInstruction is borsh encoded, the first byte is the variant
+const SET_COMPUTE_UNIT_LIMIT_TAG: u8 = 2;
+const SET_COMPUTE_UNIT_PRICE_TAG: u8 = 3;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TransactionPriority {
+    /// micro-lamports per compute unit
+    pub compute_unit_price: u64,
+    pub compute_unit_limit: u32,
+    pub tip_lamports: u64,
+}
+
+impl TransactionPriority {
+    /// Reads the compute budget and tip instructions out of the transaction.
+    /// Accounts loaded through address lookup tables are not resolved, so tips paid to a
+    /// tip account that is only referenced through a lookup table are not counted.
+    pub fn from_transaction(transaction: &VersionedTransaction) -> Self {
+        let account_keys = transaction.message.static_account_keys();
+        let mut compute_unit_price = 0;
+        let mut compute_unit_limit = None;
+        let mut tip_lamports: u64 = 0;
+        let mut non_compute_budget_instructions: u32 = 0;
+        for instruction in transaction.message.instructions() {
+            let Some(program_id) = account_keys.get(instruction.program_id_index as usize) else {
+                continue;
+            };
+            if compute_budget::check_id(program_id) {
+                match instruction.data.split_first() {
+                    Some((&SET_COMPUTE_UNIT_LIMIT_TAG, rest)) => {
+                        if let Ok(bytes) = rest.try_into() {
+                            compute_unit_limit = Some(u32::from_le_bytes(bytes));
+                        }
+                    }
+                    Some((&SET_COMPUTE_UNIT_PRICE_TAG, rest)) => {
+                        if let Ok(bytes) = rest.try_into() {
+                            compute_unit_price = u64::from_le_bytes(bytes);
+                        }
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+            non_compute_budget_instructions += 1;
+            if !system_program::check_id(program_id) {
+                continue;
+            }
+            let Ok(SystemInstruction::Transfer { lamports }) =
+                bincode::deserialize::<SystemInstruction>(&instruction.data)
+            else {
+                continue;
+            };
+            let is_tip = instruction
+                .accounts
+                .get(1)
+                .and_then(|i| account_keys.get(*i as usize))
+                .is_some_and(|to| TIP_ACCOUNTS.contains(to));
+            if is_tip {
+                tip_lamports = tip_lamports.saturating_add(lamports);
+            }
+        }
+        let compute_unit_limit = compute_unit_limit
+            .unwrap_or(
+                non_compute_budget_instructions
+                    .saturating_mul(DEFAULT_INSTRUCTION_COMPUTE_UNIT_LIMIT),
+            )
+            .min(MAX_COMPUTE_UNIT_LIMIT);
+        Self {
+            compute_unit_price,
+            compute_unit_limit,
+            tip_lamports,
+        }
+    }
+
+    /// priority_fee returns the prioritization fee in lamports, rounded up like the runtime does
+    pub fn priority_fee(&self) -> u64 {
+        let micro_lamports = self.compute_unit_price as u128 * self.compute_unit_limit as u128;
+        micro_lamports
+            .div_ceil(MICRO_LAMPORTS_PER_LAMPORT)
+            .try_into()
+            .unwrap_or(u64::MAX)
+    }
+
+    /// effective_priority returns the micro-lamports paid to the leader per requested compute unit,
+    /// discounted by how many times the transaction has already been retried without landing.
+    pub fn effective_priority(&self, retry_count: usize) -> u64 {
+        let reward = self.priority_fee() as u128 + self.tip_lamports as u128;
+        let per_compute_unit =
+            reward * MICRO_LAMPORTS_PER_LAMPORT / self.compute_unit_limit.max(1) as u128;
+        (per_compute_unit / (retry_count as u128 + 1))
+            .try_into()
+            .unwrap_or(u64::MAX)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShedReason {
+    /// The transaction's api key has no reserved capacity and it did not fit in the shared capacity
+    LowPriority,
+    /// The transaction's api key used up its reserved capacity and it did not fit in the shared capacity
+    ReservedCapacityExceeded,
+}
+
+impl ShedReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ShedReason::LowPriority => "low_priority",
+            ShedReason::ReservedCapacityExceeded => "reserved_capacity_exceeded",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct RetryQueuePolicy {
+    pub max_size: Option<usize>,
+    /// Number of entries in the retry queue kept for each api key before the rest of the queue is
+    /// filled by effective priority.
+    pub reserved_capacity: HashMap<String, usize>,
+}
+
+impl RetryQueuePolicy {
+    pub fn new(max_size: Option<usize>, reserved_capacity: HashMap<String, usize>) -> Self {
+        Self {
+            max_size,
+            reserved_capacity,
+        }
+    }
+
+    /// parse_reserved_capacity parses a comma separated list of `api_key:capacity` pairs
+    pub fn parse_reserved_capacity(
+        value: &str,
+    ) -> Result<HashMap<String, usize>, AtlasTxnSenderError> {
+        let mut reserved_capacity = HashMap::new();
+        for entry in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let Some((api_key, capacity)) = entry.rsplit_once(':') else {
+                return Err(format!("Invalid reserved capacity entry: {entry}").into());
+            };
+            let capacity = capacity
+                .trim()
+                .parse::<usize>()
+                .map_err(|e| format!("Invalid reserved capacity for {api_key}: {e}"))?;
+            reserved_capacity.insert(api_key.trim().to_string(), capacity);
+        }
+        Ok(reserved_capacity)
+    }
+
+    /// shed sorts `transactions` from highest to lowest effective priority, and removes and returns
+    /// the ones that do not fit in the queue along with the reason they were shed.
+    pub fn shed(
+        &self,
+        transactions: &mut Vec<(String, TransactionData)>,
+    ) -> Vec<(String, ShedReason)> {
+        transactions.sort_by(|(_, a), (_, b)| compare_priority(a, b));
+        let Some(max_size) = self.max_size else {
+            return vec![];
+        };
+        if transactions.len() <= max_size {
+            return vec![];
+        }
+
+        let mut keep = vec![false; transactions.len()];
+        let mut kept = 0;
+        let mut reserved_used: HashMap<&str, usize> = HashMap::new();
+        for (i, (_, transaction)) in transactions.iter().enumerate() {
+            if kept >= max_size {
+                break;
+            }
+            let api_key = transaction.request_metadata.api_key.as_str();
+            let Some(reserved) = self.reserved_capacity.get(api_key) else {
+                continue;
+            };
+            let used = reserved_used.entry(api_key).or_default();
+            if *used < *reserved {
+                *used += 1;
+                keep[i] = true;
+                kept += 1;
+            }
+        }
+        for k in keep.iter_mut() {
+            if kept >= max_size {
+                break;
+            }
+            if !*k {
+                *k = true;
+                kept += 1;
+            }
+        }
+
+        let mut shed = vec![];
+        let mut keep = keep.into_iter();
+        transactions.retain(|(signature, transaction)| {
+            if keep.next().unwrap_or(false) {
+                return true;
+            }
+            let reason = if self
+                .reserved_capacity
+                .contains_key(&transaction.request_metadata.api_key)
+            {
+                ShedReason::ReservedCapacityExceeded
+            } else {
+                ShedReason::LowPriority
+            };
+            shed.push((signature.clone(), reason));
+            false
+        });
+        shed
+    }
+}
+
+/// compare_priority orders transactions from highest to lowest effective priority,
+/// falling back to the oldest transaction first.
+pub fn compare_priority(a: &TransactionData, b: &TransactionData) -> Ordering {
+    b.priority
+        .effective_priority(b.retry_count)
+        .cmp(&a.priority.effective_priority(a.retry_count))
+        .then_with(|| a.sent_at.cmp(&b.sent_at))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use solana_sdk::{
+        compute_budget::ComputeBudgetInstruction, instruction::Instruction, message::Message,
+        transaction::Transaction,
+    };
+    use solana_system_interface::instruction as system_instruction;
+
+    use super::*;
+    use crate::rpc_server::RequestMetadata;
+
+    // unsigned, the priority doesn't depend on the signatures
+    fn versioned(payer: &Pubkey, instructions: &[Instruction]) -> VersionedTransaction {
+        Transaction::new_unsigned(Message::new(instructions, Some(payer))).into()
+    }
+
+    fn queued(
+        signature: &str,
+        api_key: &str,
+        compute_unit_price: u64,
+        sent_at: Instant,
+    ) -> (String, TransactionData) {
+        let transaction = TransactionData {
+            wire_transaction: vec![],
+            versioned_transaction: VersionedTransaction::default(),
+            sent_at,
+            retry_count: 0,
+            max_retries: 0,
+            priority: TransactionPriority {
+                compute_unit_price,
+                compute_unit_limit: 1_000_000,
+                tip_lamports: 0,
+            },
+            request_metadata: RequestMetadata {
+                api_key: api_key.to_string(),
+                ..Default::default()
+            },
+            history: vec![],
+        };
+        (signature.to_string(), transaction)
+    }
+
+    fn signatures(transactions: &[(String, TransactionData)]) -> Vec<&str> {
+        transactions.iter().map(|(s, _)| s.as_str()).collect()
+    }
+
+    #[test]
+    fn reads_compute_budget_and_tips() {
+        let payer = Pubkey::new_unique();
+        let priority = TransactionPriority::from_transaction(&versioned(
+            &payer,
+            &[
+                ComputeBudgetInstruction::set_compute_unit_limit(50_000),
+                ComputeBudgetInstruction::set_compute_unit_price(2_000_000),
+                system_instruction::transfer(&payer, &TIP_ACCOUNTS[3], 10_000),
+                system_instruction::transfer(&payer, &TIP_ACCOUNTS[5], 5_000),
+            ],
+        ));
+        assert_eq!(
+            priority,
+            TransactionPriority {
+                compute_unit_price: 2_000_000,
+                compute_unit_limit: 50_000,
+                tip_lamports: 15_000,
+            }
+        );
+        // 2 lamports per compute unit for 50k units, plus the tips
+        assert_eq!(priority.priority_fee(), 100_000);
+        assert_eq!(priority.effective_priority(0), 115_000 * 1_000_000 / 50_000);
+        assert_eq!(
+            priority.effective_priority(1),
+            115_000 * 1_000_000 / 50_000 / 2
+        );
+    }
+
+    #[test]
+    fn ignores_transfers_to_other_accounts() {
+        let payer = Pubkey::new_unique();
+        let priority = TransactionPriority::from_transaction(&versioned(
+            &payer,
+            &[
+                system_instruction::transfer(&payer, &Pubkey::new_unique(), 10_000),
+                system_instruction::transfer(&payer, &Pubkey::new_unique(), 10_000),
+            ],
+        ));
+        assert_eq!(priority.tip_lamports, 0);
+        assert_eq!(priority.compute_unit_price, 0);
+        // no limit was requested, the runtime default applies per instruction
+        assert_eq!(
+            priority.compute_unit_limit,
+            2 * DEFAULT_INSTRUCTION_COMPUTE_UNIT_LIMIT
+        );
+        assert_eq!(priority.effective_priority(0), 0);
+    }
+
+    #[test]
+    fn sheds_lowest_priority_first() {
+        let now = Instant::now();
+        let policy = RetryQueuePolicy::new(Some(2), HashMap::new());
+        let mut transactions = vec![
+            queued("low", "a", 1, now),
+            queued("high", "a", 100, now),
+            queued("older", "a", 10, now),
+            queued("newer", "a", 10, now + Duration::from_millis(1)),
+        ];
+        let shed = policy.shed(&mut transactions);
+        assert_eq!(signatures(&transactions), vec!["high", "older"]);
+        assert_eq!(
+            shed,
+            vec![
+                ("newer".to_string(), ShedReason::LowPriority),
+                ("low".to_string(), ShedReason::LowPriority),
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_reserved_capacity() {
+        let now = Instant::now();
+        let policy = RetryQueuePolicy::new(
+            Some(3),
+            RetryQueuePolicy::parse_reserved_capacity("reserved:2").unwrap(),
+        );
+        let mut transactions = vec![
+            queued("shared-1", "shared", 100, now),
+            queued("shared-2", "shared", 90, now),
+            queued("shared-3", "shared", 80, now),
+            queued("reserved-1", "reserved", 2, now),
+            queued("reserved-2", "reserved", 1, now),
+            queued("reserved-3", "reserved", 0, now),
+        ];
+        let shed = policy.shed(&mut transactions);
+        // the reserved entries survive lower priorities, the rest is filled by priority
+        assert_eq!(
+            signatures(&transactions),
+            vec!["shared-1", "reserved-1", "reserved-2"]
+        );
+        assert_eq!(
+            shed,
+            vec![
+                ("shared-2".to_string(), ShedReason::LowPriority),
+                ("shared-3".to_string(), ShedReason::LowPriority),
+                (
+                    "reserved-3".to_string(),
+                    ShedReason::ReservedCapacityExceeded
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn does_not_shed_without_max_size() {
+        let now = Instant::now();
+        let mut transactions = vec![queued("a", "a", 1, now), queued("b", "a", 2, now)];
+        let shed = RetryQueuePolicy::default().shed(&mut transactions);
+        assert!(shed.is_empty());
+        assert_eq!(signatures(&transactions), vec!["b", "a"]);
+    }
+
+    #[test]
+    fn parses_reserved_capacity() {
+        let reserved = RetryQueuePolicy::parse_reserved_capacity(" a:1 , b:20,").unwrap();
+        assert_eq!(reserved.get("a"), Some(&1));
+        assert_eq!(reserved.get("b"), Some(&20));
+        assert!(RetryQueuePolicy::parse_reserved_capacity("a").is_err());
+        assert!(RetryQueuePolicy::parse_reserved_capacity("a:x").is_err());
+    }
+}
@@ -0,0 +1,578 @@
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt::Write,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use cadence::MetricSink;
+use hyper::{
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    Body, Method, Request, Response, StatusCode,
+};
+use subtle::ConstantTimeEq;
+use tower::{Layer, Service};
+
+// seconds, for timers
+const LATENCY_BUCKETS: [f64; 16] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0,
+];
+// for histograms of plain values, e.g. sizes
+const VALUE_BUCKETS: [f64; 15] = [
+    1.0, 2.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0, 5000.0, 10000.0,
+    100000.0, 1000000.0,
+];
+// tags whose values are chosen by callers, which would grow the registry without bound. They are
+// still sent to statsd.
+const DROPPED_LABELS: [&str; 1] = ["api_key"];
+// series kept, new ones past it are dropped
+const MAX_SERIES: usize = 10_000;
+
+enum Series {
+    Counter(f64),
+    Gauge(f64),
+    Histogram {
+        bounds: &'static [f64],
+        /// observations per bucket, not cumulative
+        counts: Vec<u64>,
+        sum: f64,
+        count: u64,
+    },
+}
+
+impl Series {
+    fn kind(&self) -> &'static str {
+        match self {
+            Series::Counter(_) => "counter",
+            Series::Gauge(_) => "gauge",
+            Series::Histogram { .. } => "histogram",
+        }
+    }
+}
+
+/// PrometheusRegistry keeps the metrics emitted through the statsd macros in memory, to be scraped
+/// in the Prometheus text format. Counters get a `_total` suffix and timers become histograms in
+/// seconds with a `_seconds` suffix. Tags become labels, except the high cardinality ones like
+/// `api_key`, and at most MAX_SERIES series are kept; the ones dropped past it are counted in
+/// `prometheus_series_dropped_total`.
+pub struct PrometheusRegistry {
+    /// series by metric name and rendered labels
+    series: Mutex<BTreeMap<(String, String), Series>>,
+    max_series: usize,
+    series_dropped: Mutex<u64>,
+}
+
+impl PrometheusRegistry {
+    pub fn new() -> Self {
+        Self {
+            series: Mutex::new(BTreeMap::new()),
+            max_series: MAX_SERIES,
+            series_dropped: Mutex::new(0),
+        }
+    }
+
+    /// record parses a statsd line, e.g. `prefix.name:12|ms|#api_key:abc,retry:false`
+    fn record(&self, line: &str) {
+        let mut parts = line.split('|');
+        let Some((name, value)) = parts.next().and_then(|metric| metric.rsplit_once(':')) else {
+            return;
+        };
+        let Ok(mut value) = value.parse::<f64>() else {
+            return;
+        };
+        let Some(kind) = parts.next() else {
+            return;
+        };
+        let mut labels = String::new();
+        for part in parts {
+            if let Some(rate) = part.strip_prefix('@') {
+                // sampled counters stand for more events than were sent
+                if let Ok(rate) = rate.parse::<f64>() {
+                    if rate > 0.0 && kind == "c" {
+                        value /= rate;
+                    }
+                }
+            } else if let Some(tags) = part.strip_prefix('#') {
+                labels = render_labels(tags);
+            }
+        }
+        // the statsd prefix is kept, it namespaces the metrics in Prometheus too
+        let name = sanitize(name);
+        let name = match kind {
+            "c" | "m" => format!("{name}_total"),
+            "ms" => format!("{name}_seconds"),
+            _ => name,
+        };
+        let mut series = self.series.lock().unwrap();
+        let key = (name, labels);
+        if series.len() >= self.max_series && !series.contains_key(&key) {
+            *self.series_dropped.lock().unwrap() += 1;
+            return;
+        }
+        let (name, labels) = key;
+        match kind {
+            "c" | "m" => {
+                let entry = series.entry((name, labels)).or_insert(Series::Counter(0.0));
+                if let Series::Counter(total) = entry {
+                    *total += value;
+                }
+            }
+            "g" => {
+                series.insert((name, labels), Series::Gauge(value));
+            }
+            "ms" => observe(&mut series, name, labels, &LATENCY_BUCKETS, value / 1000.0),
+            "h" | "d" => observe(&mut series, name, labels, &VALUE_BUCKETS, value),
+            // sets can't be represented without keeping every value
+            _ => {}
+        }
+    }
+
+    /// render returns the metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        let series = self.series.lock().unwrap();
+        let mut output = String::new();
+        let mut last_name = "";
+        for ((name, labels), series) in series.iter() {
+            if name != last_name {
+                let _ = writeln!(output, "# TYPE {} {}", name, series.kind());
+                last_name = name;
+            }
+            match series {
+                Series::Counter(value) | Series::Gauge(value) => {
+                    let _ = writeln!(output, "{}{} {}", name, braces(labels), value);
+                }
+                Series::Histogram {
+                    bounds,
+                    counts,
+                    sum,
+                    count,
+                } => {
+                    let mut cumulative = 0;
+                    for (bound, bucket_count) in bounds.iter().zip(counts) {
+                        cumulative += bucket_count;
+                        let _ = writeln!(
+                            output,
+                            "{}_bucket{} {}",
+                            name,
+                            braces(&join_labels(labels, &format!("le=\"{bound}\""))),
+                            cumulative
+                        );
+                    }
+                    let _ = writeln!(
+                        output,
+                        "{}_bucket{} {}",
+                        name,
+                        braces(&join_labels(labels, "le=\"+Inf\"")),
+                        count
+                    );
+                    let _ = writeln!(output, "{}_sum{} {}", name, braces(labels), sum);
+                    let _ = writeln!(output, "{}_count{} {}", name, braces(labels), count);
+                }
+            }
+        }
+        let series_dropped = *self.series_dropped.lock().unwrap();
+        if series_dropped > 0 {
+            let _ = writeln!(output, "# TYPE prometheus_series_dropped_total counter");
+            let _ = writeln!(output, "prometheus_series_dropped_total {series_dropped}");
+        }
+        output
+    }
+}
+
+fn observe(
+    series: &mut BTreeMap<(String, String), Series>,
+    name: String,
+    labels: String,
+    bounds: &'static [f64],
+    value: f64,
+) {
+    let entry = series
+        .entry((name, labels))
+        .or_insert_with(|| Series::Histogram {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        });
+    if let Series::Histogram {
+        bounds,
+        counts,
+        sum,
+        count,
+    } = entry
+    {
+        if let Some(bucket) = bounds.iter().position(|bound| value <= *bound) {
+            counts[bucket] += 1;
+        }
+        *sum += value;
+        *count += 1;
+    }
+}
+
+/// sanitize replaces the characters Prometheus doesn't allow in names, e.g. the dots of the prefix
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+/// render_labels turns statsd tags `key:value,key2:value2` into `key="value",key2="value2"`
+fn render_labels(tags: &str) -> String {
+    tags.split(',')
+        .filter_map(|tag| tag.split_once(':'))
+        .filter(|(key, _)| !DROPPED_LABELS.contains(key))
+        .map(|(key, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", sanitize(key), value)
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn join_labels(labels: &str, label: &str) -> String {
+    if labels.is_empty() {
+        label.to_string()
+    } else {
+        format!("{labels},{label}")
+    }
+}
+
+fn braces(labels: &str) -> String {
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{labels}}}")
+    }
+}
+
+/// PrometheusMetricSink feeds the metrics sent through the statsd client to the registry
+pub struct PrometheusMetricSink {
+    registry: Arc<PrometheusRegistry>,
+}
+
+impl PrometheusMetricSink {
+    pub fn new(registry: Arc<PrometheusRegistry>) -> Self {
+        Self { registry }
+    }
+}
+
+impl MetricSink for PrometheusMetricSink {
+    fn emit(&self, metric: &str) -> io::Result<usize> {
+        self.registry.record(metric);
+        Ok(metric.len())
+    }
+}
+
+/// MultiMetricSink sends every metric to each of its sinks. A sink that fails doesn't keep the
+/// metric from the others, the first error is returned.
+pub struct MultiMetricSink {
+    sinks: Vec<Box<dyn MetricSink + Send + Sync + std::panic::RefUnwindSafe>>,
+}
+
+impl MultiMetricSink {
+    pub fn new(sinks: Vec<Box<dyn MetricSink + Send + Sync + std::panic::RefUnwindSafe>>) -> Self {
+        Self { sinks }
+    }
+}
+
+impl MetricSink for MultiMetricSink {
+    fn emit(&self, metric: &str) -> io::Result<usize> {
+        let mut written = 0;
+        let mut first_error = None;
+        for sink in self.sinks.iter() {
+            match sink.emit(metric) {
+                Ok(sink_written) => written = written.max(sink_written),
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(written),
+        }
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        let mut first_error = None;
+        for sink in self.sinks.iter() {
+            if let Err(e) = sink.flush() {
+                first_error.get_or_insert(e);
+            }
+        }
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+/// MetricsLayer answers `GET /metrics` on the RPC server with the registry, when Prometheus is
+/// enabled. With a bearer token set, scrapes have to send it in their `Authorization` header.
+#[derive(Clone)]
+pub struct MetricsLayer {
+    registry: Option<Arc<PrometheusRegistry>>,
+    bearer_token: Option<Arc<String>>,
+}
+
+impl MetricsLayer {
+    pub fn new(registry: Option<Arc<PrometheusRegistry>>, bearer_token: Option<String>) -> Self {
+        Self {
+            registry,
+            bearer_token: bearer_token.map(Arc::new),
+        }
+    }
+
+    /// is_authorized compares the bearer token in constant time, so it can't be guessed from
+    /// response times
+    fn is_authorized(&self, req: &Request<Body>) -> bool {
+        let Some(bearer_token) = &self.bearer_token else {
+            return true;
+        };
+        req.headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .is_some_and(|token| bool::from(token.as_bytes().ct_eq(bearer_token.as_bytes())))
+    }
+
+    fn metrics_response(&self, req: &Request<Body>) -> Option<Response<Body>> {
+        let registry = self.registry.as_ref()?;
+        if req.uri().path() != "/metrics" || req.method() != Method::GET {
+            return None;
+        }
+        if !self.is_authorized(req) {
+            let mut response = Response::new(Body::empty());
+            *response.status_mut() = StatusCode::UNAUTHORIZED;
+            return Some(response);
+        }
+        let mut response = Response::new(Body::from(registry.render()));
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, "text/plain; version=0.0.4".parse().unwrap());
+        Some(response)
+    }
+}
+
+impl<S> Layer<S> for MetricsLayer {
+    type Service = MetricsService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        MetricsService {
+            inner,
+            metrics: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MetricsService<S> {
+    inner: S,
+    metrics: MetricsLayer,
+}
+
+impl<S> Service<Request<Body>> for MetricsService<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>>,
+    S::Error: Into<Box<dyn Error + Send + Sync>> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = Box<dyn Error + Send + Sync + 'static>;
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        match self.metrics.metrics_response(&req) {
+            Some(response) => Box::pin(async move { Ok(response) }),
+            None => {
+                let response = self.inner.call(req);
+                Box::pin(async move { response.await.map_err(Into::into) })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn lines(registry: &PrometheusRegistry) -> Vec<String> {
+        registry.render().lines().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn renders_counters_and_gauges() {
+        let registry = PrometheusRegistry::new();
+        registry.record("p3_txn_sender.sent:1|c|#route:p3");
+        registry.record("p3_txn_sender.sent:1|c|#route:p3");
+        // sampled at half the rate, so it stands for two
+        registry.record("p3_txn_sender.sent:1|c|@0.5|#route:mev");
+        registry.record("p3_txn_sender.queue:5|g");
+        registry.record("p3_txn_sender.queue:3|g");
+        // sets and malformed lines are ignored
+        registry.record("p3_txn_sender.unique:abc|s");
+        registry.record("p3_txn_sender.broken|c");
+
+        assert_eq!(
+            lines(&registry),
+            vec![
+                "# TYPE p3_txn_sender_queue gauge",
+                "p3_txn_sender_queue 3",
+                "# TYPE p3_txn_sender_sent_total counter",
+                "p3_txn_sender_sent_total{route=\"mev\"} 2",
+                "p3_txn_sender_sent_total{route=\"p3\"} 2",
+            ]
+        );
+    }
+
+    #[test]
+    fn renders_cumulative_buckets() {
+        let registry = PrometheusRegistry::new();
+        registry.record("latency:3|ms|#route:p3");
+        registry.record("latency:20|ms|#route:p3");
+        registry.record("latency:200000|ms|#route:p3");
+        registry.record("size:7|h");
+
+        let lines = lines(&registry);
+        assert!(lines.contains(&"# TYPE latency_seconds histogram".to_string()));
+        assert!(lines.contains(&"latency_seconds_bucket{route=\"p3\",le=\"0.0025\"} 0".to_string()));
+        assert!(lines.contains(&"latency_seconds_bucket{route=\"p3\",le=\"0.005\"} 1".to_string()));
+        assert!(lines.contains(&"latency_seconds_bucket{route=\"p3\",le=\"0.025\"} 2".to_string()));
+        // above the last bound, only counted in +Inf
+        assert!(lines.contains(&"latency_seconds_bucket{route=\"p3\",le=\"120\"} 2".to_string()));
+        assert!(lines.contains(&"latency_seconds_bucket{route=\"p3\",le=\"+Inf\"} 3".to_string()));
+        assert!(lines.contains(&"latency_seconds_count{route=\"p3\"} 3".to_string()));
+        assert!(lines.contains(&"size_bucket{le=\"5\"} 0".to_string()));
+        assert!(lines.contains(&"size_bucket{le=\"10\"} 1".to_string()));
+        assert!(lines.contains(&"size_sum 7".to_string()));
+    }
+
+    #[test]
+    fn drops_high_cardinality_labels() {
+        let registry = PrometheusRegistry::new();
+        registry.record("sent:1|c|#api_key:first,route:p3");
+        registry.record("sent:1|c|#api_key:second,route:p3");
+        registry.record("error:1|c|#reason:say \"no\"\\");
+
+        let lines = lines(&registry);
+        assert!(lines.contains(&"sent_total{route=\"p3\"} 2".to_string()));
+        assert!(lines.contains(&"error_total{reason=\"say \\\"no\\\"\\\\\"} 1".to_string()));
+        assert!(!registry.render().contains("api_key"));
+    }
+
+    #[test]
+    fn caps_series() {
+        let registry = PrometheusRegistry {
+            max_series: 2,
+            ..PrometheusRegistry::new()
+        };
+        registry.record("sent:1|c|#route:p3");
+        registry.record("sent:1|c|#route:mev");
+        registry.record("sent:1|c|#route:p3s");
+        registry.record("sent:1|c|#route:p3s");
+        // series already kept are still updated
+        registry.record("sent:1|c|#route:p3");
+
+        let lines = lines(&registry);
+        assert!(lines.contains(&"sent_total{route=\"p3\"} 2".to_string()));
+        assert!(!registry.render().contains("p3s"));
+        assert!(lines.contains(&"prometheus_series_dropped_total 2".to_string()));
+    }
+
+    struct FailingSink;
+
+    impl MetricSink for FailingSink {
+        fn emit(&self, _metric: &str) -> io::Result<usize> {
+            Err(io::Error::other("unreachable"))
+        }
+    }
+
+    #[test]
+    fn emits_to_every_sink() {
+        let registry = Arc::new(PrometheusRegistry::new());
+        let sink = MultiMetricSink::new(vec![
+            Box::new(FailingSink),
+            Box::new(PrometheusMetricSink::new(registry.clone())),
+        ]);
+        assert!(sink.emit("sent:1|c").is_err());
+        assert!(registry.render().contains("sent_total 1"));
+    }
+
+    /// get sends a request through the layer, in front of an RPC server answering 404
+    async fn get(layer: &MetricsLayer, uri: &str, token: Option<&str>) -> Response<Body> {
+        let rpc = tower::service_fn(|_req: Request<Body>| async {
+            let mut response = Response::new(Body::empty());
+            *response.status_mut() = StatusCode::NOT_FOUND;
+            Ok::<_, Infallible>(response)
+        });
+        let mut req = Request::get(uri);
+        if let Some(token) = token {
+            req = req.header(AUTHORIZATION, format!("Bearer {token}"));
+        }
+        layer
+            .layer(rpc)
+            .oneshot(req.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn serves_metrics_on_the_rpc_server() {
+        let registry = Arc::new(PrometheusRegistry::new());
+        registry.record("sent:1|c");
+        let layer = MetricsLayer::new(Some(registry.clone()), None);
+        let response = get(&layer, "/metrics", None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[CONTENT_TYPE],
+            "text/plain; version=0.0.4"
+        );
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("sent_total 1"));
+        assert_eq!(
+            get(&layer, "/health", None).await.status(),
+            StatusCode::NOT_FOUND
+        );
+
+        // with Prometheus disabled the request goes to the RPC server
+        let layer = MetricsLayer::new(None, None);
+        assert_eq!(
+            get(&layer, "/metrics", None).await.status(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn requires_the_bearer_token() {
+        let registry = Arc::new(PrometheusRegistry::new());
+        let layer = MetricsLayer::new(Some(registry), Some("secret".to_string()));
+        assert_eq!(
+            get(&layer, "/metrics", None).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            get(&layer, "/metrics", Some("wrong")).await.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            get(&layer, "/metrics", Some("secret")).await.status(),
+            StatusCode::OK
+        );
+    }
+}
@@ -0,0 +1,157 @@
+use std::{
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use cadence_macros::{statsd_count, statsd_gauge};
+use dashmap::DashMap;
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_sdk::{
+    clock::UnixTimestamp,
+    commitment_config::{CommitmentConfig, CommitmentLevel},
+    signature::Signature,
+};
+use solana_transaction_status::TransactionConfirmationStatus;
+use tokio::{sync::watch, time::sleep};
+use tonic::async_trait;
+use tracing::error;
+
+use crate::{
+    signature_status::{ConfirmationStatus, SignatureStatus, SignatureStatuses},
+    solana_rpc::{publish_slot, SlotUpdate, SolanaRpc},
+};
+
+// the most signatures getSignatureStatuses accepts in one call
+const MAX_SIGNATURES_PER_CALL: usize = 256;
+
+/// RpcPollerImpl implements SolanaRpc with nothing but HTTP RPC, polling `getSlot` and
+/// `getSignatureStatuses`
+pub struct RpcPollerImpl {
+    rpc_client: Arc<RpcClient>,
+    slot_sender: Arc<watch::Sender<Option<SlotUpdate>>>,
+    /// signatures polled until they are finalized, with when they were added
+    pending_signatures: Arc<DashMap<Signature, Instant>>,
+    signature_statuses: Arc<SignatureStatuses>,
+}
+
+impl RpcPollerImpl {
+    pub fn new(
+        rpc_url: String,
+        slot_interval: Duration,
+        status_interval: Duration,
+        signature_statuses: Arc<SignatureStatuses>,
+    ) -> Self {
+        let rpc_poller = Self {
+            rpc_client: Arc::new(RpcClient::new(rpc_url)),
+            slot_sender: Arc::new(watch::channel(None).0),
+            pending_signatures: Arc::new(DashMap::new()),
+            signature_statuses,
+        };
+        rpc_poller.poll_slots(slot_interval);
+        rpc_poller.poll_signature_statuses(status_interval);
+        rpc_poller
+    }
+
+    fn poll_slots(&self, slot_interval: Duration) {
+        let rpc_client = self.rpc_client.clone();
+        let slot_sender = self.slot_sender.clone();
+        tokio::spawn(async move {
+            loop {
+                match rpc_client
+                    .get_slot_with_commitment(CommitmentConfig::processed())
+                    .await
+                {
+                    Ok(slot) => publish_slot(&slot_sender, slot, CommitmentLevel::Processed),
+                    Err(e) => {
+                        error!("Error polling slot: {}", e);
+                        statsd_count!("rpc_poll_slot_error", 1);
+                    }
+                }
+                sleep(slot_interval).await;
+            }
+        });
+    }
+
+    /// poll_signature_statuses looks up every pending signature in batches and records their status
+    /// until they are finalized. A signature whose status disappears was dropped by a fork.
+    fn poll_signature_statuses(&self, status_interval: Duration) {
+        let rpc_client = self.rpc_client.clone();
+        let pending_signatures = self.pending_signatures.clone();
+        let signature_statuses = self.signature_statuses.clone();
+        tokio::spawn(async move {
+            loop {
+                sleep(status_interval).await;
+                // a transaction that is confirmed but never finalized would otherwise be polled forever
+                pending_signatures
+                    .retain(|_, added_at| added_at.elapsed() < signature_statuses.retention());
+                let signatures: Vec<Signature> =
+                    pending_signatures.iter().map(|s| *s.key()).collect();
+                statsd_gauge!("rpc_poll_pending_signatures", signatures.len() as u64);
+                for batch in signatures.chunks(MAX_SIGNATURES_PER_CALL) {
+                    let statuses = match rpc_client.get_signature_statuses(batch).await {
+                        Ok(statuses) => statuses.value,
+                        Err(e) => {
+                            error!("Error polling signature statuses: {}", e);
+                            statsd_count!("rpc_poll_signature_statuses_error", 1);
+                            continue;
+                        }
+                    };
+                    for (signature, status) in batch.iter().zip(statuses) {
+                        let Some(status) = status else {
+                            signature_statuses.drop_signature(&signature.to_string());
+                            continue;
+                        };
+                        let confirmation_status = match status.confirmation_status {
+                            Some(TransactionConfirmationStatus::Finalized) => {
+                                pending_signatures.remove(signature);
+                                ConfirmationStatus::Finalized
+                            }
+                            Some(TransactionConfirmationStatus::Confirmed) => {
+                                ConfirmationStatus::Confirmed
+                            }
+                            _ => ConfirmationStatus::Processed,
+                        };
+                        // the status has no block time or index in the block
+                        signature_statuses.record(
+                            signature.to_string(),
+                            status.slot,
+                            None,
+                            status.err,
+                            None,
+                            confirmation_status,
+                        );
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[async_trait]
+impl SolanaRpc for RpcPollerImpl {
+    async fn confirm_transaction(&self, signature: String) -> Option<UnixTimestamp> {
+        let parsed_signature = Signature::from_str(&signature).ok()?;
+        self.pending_signatures
+            .insert(parsed_signature, Instant::now());
+        let block_time = self
+            .signature_statuses
+            .wait_for_confirmation(&signature)
+            .await;
+        if block_time.is_none() {
+            self.pending_signatures.remove(&parsed_signature);
+        }
+        block_time
+    }
+    fn get_signature_status(&self, signature: &str) -> Option<SignatureStatus> {
+        self.signature_statuses.get(signature)
+    }
+    fn get_next_slot(&self) -> Option<u64> {
+        self.slot_sender
+            .borrow()
+            .map(|slot_update| slot_update.slot)
+    }
+    fn subscribe_slots(&self) -> watch::Receiver<Option<SlotUpdate>> {
+        self.slot_sender.subscribe()
+    }
+}
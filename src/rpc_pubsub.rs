@@ -0,0 +1,265 @@
+use std::{
+    str::FromStr,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use cadence_macros::statsd_count;
+use futures::StreamExt;
+use serde::Deserialize;
+use solana_client::nonblocking::pubsub_client::PubsubClient;
+use solana_rpc_client_api::config::{
+    RpcBlockSubscribeConfig, RpcBlockSubscribeFilter, RpcSignatureSubscribeConfig,
+};
+use solana_rpc_client_api::response::RpcSignatureResult;
+use solana_sdk::{
+    clock::UnixTimestamp,
+    commitment_config::{CommitmentConfig, CommitmentLevel},
+    signature::Signature,
+};
+use solana_transaction_status::{EncodedTransaction, TransactionDetails};
+use tokio::{
+    sync::watch,
+    time::{sleep, timeout},
+};
+use tonic::async_trait;
+use tracing::error;
+
+use crate::{
+    signature_status::{ConfirmationStatus, SignatureStatus, SignatureStatuses},
+    solana_rpc::{publish_slot, unix_timestamp_now, SlotUpdate, SolanaRpc},
+};
+
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// PubsubConfirmation chooses how the websocket backend learns that transactions landed
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PubsubConfirmation {
+    /// one signatureSubscribe per transaction, supported by every RPC node
+    #[default]
+    Signature,
+    /// a single blockSubscribe, needs `--rpc-pubsub-enable-block-subscription` on the RPC node
+    Block,
+}
+
+/// RpcPubsubImpl implements SolanaRpc on the standard RPC websocket, for environments without gRPC geyser
+pub struct RpcPubsubImpl {
+    ws_url: String,
+    confirmation: PubsubConfirmation,
+    slot_sender: Arc<watch::Sender<Option<SlotUpdate>>>,
+    // signature subscriptions share the slot subscription's connection, replaced when it reconnects
+    pubsub_client: Arc<RwLock<Option<Arc<PubsubClient>>>>,
+    signature_statuses: Arc<SignatureStatuses>,
+}
+
+impl RpcPubsubImpl {
+    pub fn new(
+        ws_url: String,
+        confirmation: PubsubConfirmation,
+        signature_statuses: Arc<SignatureStatuses>,
+    ) -> Self {
+        let rpc_pubsub = Self {
+            ws_url,
+            confirmation,
+            slot_sender: Arc::new(watch::channel(None).0),
+            pubsub_client: Arc::new(RwLock::new(None)),
+            signature_statuses,
+        };
+        rpc_pubsub.poll_slots();
+        if confirmation == PubsubConfirmation::Block {
+            rpc_pubsub.poll_blocks();
+        }
+        rpc_pubsub
+    }
+
+    async fn connect(ws_url: &str) -> Option<Arc<PubsubClient>> {
+        match PubsubClient::new(ws_url).await {
+            Ok(pubsub_client) => Some(Arc::new(pubsub_client)),
+            Err(e) => {
+                error!(
+                    "Error connecting to RPC websocket, waiting one second then retrying connect: {}",
+                    e
+                );
+                statsd_count!("ws_connect_error", 1);
+                None
+            }
+        }
+    }
+
+    /// poll_slots publishes the slots and finalizes the signature statuses as the root advances
+    fn poll_slots(&self) {
+        let ws_url = self.ws_url.clone();
+        let slot_sender = self.slot_sender.clone();
+        let signature_statuses = self.signature_statuses.clone();
+        let shared_pubsub_client = self.pubsub_client.clone();
+        tokio::spawn(async move {
+            loop {
+                let Some(pubsub_client) = Self::connect(&ws_url).await else {
+                    sleep(RECONNECT_INTERVAL).await;
+                    continue;
+                };
+                *shared_pubsub_client.write().unwrap() = Some(pubsub_client.clone());
+                match pubsub_client.slot_subscribe().await {
+                    Ok((mut slots, _unsubscribe)) => {
+                        while let Some(slot_info) = slots.next().await {
+                            publish_slot(&slot_sender, slot_info.slot, CommitmentLevel::Processed);
+                            signature_statuses.finalize_up_to(slot_info.root);
+                        }
+                        error!("slot subscription ended, resubscribing in 1 second");
+                        statsd_count!("ws_resubscribe", 1);
+                    }
+                    Err(e) => {
+                        error!("Error subscribing to slots, waiting one second then retrying connect: {}", e);
+                        statsd_count!("ws_subscribe_error", 1);
+                    }
+                }
+                sleep(RECONNECT_INTERVAL).await;
+            }
+        });
+    }
+
+    fn poll_blocks(&self) {
+        let ws_url = self.ws_url.clone();
+        let signature_statuses = self.signature_statuses.clone();
+        tokio::spawn(async move {
+            loop {
+                let Some(pubsub_client) = Self::connect(&ws_url).await else {
+                    sleep(RECONNECT_INTERVAL).await;
+                    continue;
+                };
+                let config = RpcBlockSubscribeConfig {
+                    commitment: Some(CommitmentConfig::confirmed()),
+                    // unlike Signatures, Accounts details include the transaction meta with its error
+                    transaction_details: Some(TransactionDetails::Accounts),
+                    show_rewards: Some(false),
+                    max_supported_transaction_version: Some(0),
+                    ..Default::default()
+                };
+                match pubsub_client
+                    .block_subscribe(RpcBlockSubscribeFilter::All, Some(config))
+                    .await
+                {
+                    Ok((mut blocks, _unsubscribe)) => {
+                        while let Some(block_update) = blocks.next().await {
+                            let Some(block) = block_update.value.block else {
+                                continue;
+                            };
+                            let slot = block_update.value.slot;
+                            for (index, transaction) in block
+                                .transactions
+                                .unwrap_or_default()
+                                .into_iter()
+                                .enumerate()
+                            {
+                                let EncodedTransaction::Accounts(accounts) =
+                                    transaction.transaction
+                                else {
+                                    continue;
+                                };
+                                let Some(signature) = accounts.signatures.into_iter().next() else {
+                                    continue;
+                                };
+                                signature_statuses.record(
+                                    signature,
+                                    slot,
+                                    Some(index as u64),
+                                    transaction.meta.and_then(|meta| meta.err),
+                                    block.block_time,
+                                    ConfirmationStatus::Confirmed,
+                                );
+                            }
+                        }
+                        error!("block subscription ended, resubscribing in 1 second");
+                        statsd_count!("ws_resubscribe", 1);
+                    }
+                    Err(e) => {
+                        error!("Error subscribing to blocks, waiting one second then retrying connect: {}", e);
+                        statsd_count!("ws_subscribe_error", 1);
+                    }
+                }
+                sleep(RECONNECT_INTERVAL).await;
+            }
+        });
+    }
+
+    /// confirm_signature waits for the signature notification, resubscribing if the connection drops.
+    /// The notification has no block time, so the time it arrived is returned.
+    async fn confirm_signature(&self, signature: Signature) -> Option<UnixTimestamp> {
+        let start = Instant::now();
+        let confirmation_timeout = self.signature_statuses.confirmation_timeout();
+        while start.elapsed() < confirmation_timeout {
+            let pubsub_client = self.pubsub_client.read().unwrap().clone();
+            let Some(pubsub_client) = pubsub_client else {
+                sleep(RECONNECT_INTERVAL).await;
+                continue;
+            };
+            let config = RpcSignatureSubscribeConfig {
+                commitment: Some(CommitmentConfig::confirmed()),
+                enable_received_notification: Some(false),
+            };
+            match pubsub_client
+                .signature_subscribe(&signature, Some(config))
+                .await
+            {
+                Ok((mut notifications, unsubscribe)) => {
+                    let remaining = confirmation_timeout.saturating_sub(start.elapsed());
+                    match timeout(remaining, notifications.next()).await {
+                        Ok(Some(notification)) => {
+                            let err = match notification.value {
+                                RpcSignatureResult::ProcessedSignature(result) => result.err,
+                                RpcSignatureResult::ReceivedSignature(_) => None,
+                            };
+                            self.signature_statuses.record(
+                                signature.to_string(),
+                                notification.context.slot,
+                                None,
+                                err,
+                                None,
+                                ConfirmationStatus::Confirmed,
+                            );
+                            return Some(unix_timestamp_now());
+                        }
+                        // the connection dropped, resubscribe on the next one
+                        Ok(None) => {}
+                        Err(_) => {
+                            unsubscribe().await;
+                            return None;
+                        }
+                    }
+                }
+                Err(e) => {
+                    error!("Error subscribing to signature {}: {}", signature, e);
+                    statsd_count!("ws_subscribe_error", 1);
+                }
+            }
+            sleep(RECONNECT_INTERVAL).await;
+        }
+        None
+    }
+}
+
+#[async_trait]
+impl SolanaRpc for RpcPubsubImpl {
+    async fn confirm_transaction(&self, signature: String) -> Option<UnixTimestamp> {
+        if self.confirmation == PubsubConfirmation::Signature {
+            return self
+                .confirm_signature(Signature::from_str(&signature).ok()?)
+                .await;
+        }
+        self.signature_statuses
+            .wait_for_confirmation(&signature)
+            .await
+    }
+    fn get_signature_status(&self, signature: &str) -> Option<SignatureStatus> {
+        self.signature_statuses.get(signature)
+    }
+    fn get_next_slot(&self) -> Option<u64> {
+        self.slot_sender
+            .borrow()
+            .map(|slot_update| slot_update.slot)
+    }
+    fn subscribe_slots(&self) -> watch::Receiver<Option<SlotUpdate>> {
+        self.slot_sender.subscribe()
+    }
+}
@@ -1,4 +1,9 @@
-use std::{fmt::Debug, sync::Arc, time::Instant};
+use std::{
+    fmt::Debug,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use cadence_macros::{statsd_count, statsd_time};
 use jsonrpsee::{
@@ -8,16 +13,32 @@ use jsonrpsee::{
 };
 use serde::{Deserialize, Serialize};
 use solana_rpc_client_api::config::RpcSendTransactionConfig;
-use solana_sdk::transaction::VersionedTransaction;
+use solana_sdk::{signature::Signature, transaction::VersionedTransaction};
 use solana_transaction_status::UiTransactionEncoding;
+use subtle::ConstantTimeEq;
+use tracing::{instrument, Span};
 
 use crate::{
-    errors::invalid_request,
+    attribution::{LandingAttribution, LandingReport},
+    errors::{invalid_request, node_unhealthy},
+    identity::{IdentityPool, IdentityRotation},
+    latency::PipelineLatency,
+    leader_policy::LeaderPolicyMode,
+    lifecycle::{LifecycleEvent, TransactionLifecycles, TransactionState},
+    priority::TransactionPriority,
+    scheduler::{Scheduler, SendSchedule},
+    signature_status::SignatureStatus,
+    solana_rpc::SolanaRpc,
+    telemetry::continue_trace,
     transaction_store::{TransactionData, TransactionStore},
     txn_sender::TxnSender,
     vendor::solana_rpc::decode_and_deserialize,
+    webhook::WebhookNotifier,
 };
 
+// slots arrive every ~400ms, a health check this far behind means the slot feed is down
+const MAX_SLOT_AGE: Duration = Duration::from_secs(10);
+
 #[repr(u16)]
 #[derive(Deserialize, Serialize, Clone, Debug)]
 #[serde(into = "u16", from = "u16")]
@@ -38,6 +59,22 @@ pub enum SendPorts {
     MevS3 = 4826,
 }
 
+impl SendPorts {
+    /// as_str names the port's route in metrics and landing stats
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SendPorts::P3 => "p3",
+            SendPorts::Mev => "mev",
+            SendPorts::P3S => "p3s",
+            SendPorts::MevS => "mevs",
+            SendPorts::P3S2 => "p3s2",
+            SendPorts::MevS2 => "mevs2",
+            SendPorts::P3S3 => "p3s3",
+            SendPorts::MevS3 => "mevs3",
+        }
+    }
+}
+
 impl From<SendPorts> for u16 {
     fn from(port: SendPorts) -> u16 {
         port as u16
@@ -55,11 +92,21 @@ impl From<u16> for SendPorts {
             4824 => SendPorts::MevS2,
             4825 => SendPorts::P3S3,
             4826 => SendPorts::MevS3,
-            _ => panic!("Invalid port value: {}", value),
+            _ => panic!("Invalid port value: {value}"),
         }
     }
 }
 
+/// TransactionStatus is where a transaction landed, if it did, and what happened to it so far
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TransactionStatus {
+    #[serde(flatten)]
+    pub status: Option<SignatureStatus>,
+    /// state changes of the transaction, oldest first
+    pub history: Vec<LifecycleEvent>,
+}
+
 // jsonrpsee does not make it easy to access http data,
 // so creating this optional param to pass in metadata
 #[derive(Deserialize, Clone, Debug)]
@@ -67,24 +114,44 @@ impl From<u16> for SendPorts {
 pub struct OptionalRequestMetadata {
     pub api_key: Option<String>,
     pub send_port: Option<SendPorts>,
+    pub leader_policy: Option<LeaderPolicyMode>,
+    /// pubkey of the identity to send with
+    pub identity: Option<String>,
+    #[serde(flatten)]
+    pub schedule: SendSchedule,
+    /// url to POST the outcome of the transaction to
+    pub callback_url: Option<String>,
 }
 
 impl OptionalRequestMetadata {
     pub fn unwrap_or_default(self) -> RequestMetadata {
         let api_key = self.api_key.unwrap_or("none".to_string());
         let send_port = self.send_port.unwrap_or(SendPorts::P3) as u16;
+        let leader_policy = self.leader_policy.unwrap_or_default();
+        let schedule = (!self.schedule.is_empty()).then_some(self.schedule);
 
-        RequestMetadata { api_key, send_port }
+        RequestMetadata {
+            api_key,
+            send_port,
+            leader_policy,
+            identity: self.identity,
+            schedule,
+            callback_url: self.callback_url,
+        }
     }
 }
 
 // jsonrpsee does not make it easy to access http data,
 // so creating this optional param to pass in metadata
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all(serialize = "camelCase", deserialize = "camelCase"))]
 pub struct RequestMetadata {
     pub api_key: String,
     pub send_port: u16,
+    pub leader_policy: LeaderPolicyMode,
+    pub identity: Option<String>,
+    pub schedule: Option<SendSchedule>,
+    pub callback_url: Option<String>,
 }
 
 impl Default for RequestMetadata {
@@ -92,6 +159,10 @@ impl Default for RequestMetadata {
         Self {
             api_key: "none".to_string(),
             send_port: SendPorts::P3 as u16,
+            leader_policy: LeaderPolicyMode::default(),
+            identity: None,
+            schedule: None,
+            callback_url: None,
         }
     }
 }
@@ -99,7 +170,8 @@ impl Default for RequestMetadata {
 #[rpc(server)]
 pub trait AtlasTxnSender {
     #[method(name = "health")]
-    async fn health(&self) -> String;
+    /// Fails when no new slot was seen recently
+    async fn health(&self) -> RpcResult<String>;
     #[method(name = "sendTransaction")]
     async fn send_transaction(
         &self,
@@ -107,33 +179,119 @@ pub trait AtlasTxnSender {
         params: RpcSendTransactionConfig,
         request_metadata: Option<OptionalRequestMetadata>,
     ) -> RpcResult<String>;
+    /// Stops retrying the transaction and refuses later resubmissions of it.
+    /// Returns whether the transaction was still queued.
+    /// Only the api key the transaction was sent with can cancel it.
+    #[method(name = "cancelTransaction")]
+    async fn cancel_transaction(
+        &self,
+        signature: String,
+        request_metadata: Option<OptionalRequestMetadata>,
+    ) -> RpcResult<bool>;
+    /// Swaps a queued transaction for `txn`, e.g. a higher fee version spending the same nonce.
+    /// The replacement has to be paid by the same fee payer and spend the same durable nonce, or
+    /// use the same blockhash, and be sent with the same api key as the original.
+    #[method(name = "replaceTransaction")]
+    async fn replace_transaction(
+        &self,
+        signature: String,
+        txn: String,
+        params: RpcSendTransactionConfig,
+        request_metadata: Option<OptionalRequestMetadata>,
+    ) -> RpcResult<String>;
+    /// Re-reads the identity keypair files and rotates the identities that changed.
+    /// Only available when ADMIN_API_KEY is set.
+    #[method(name = "reloadIdentities")]
+    async fn reload_identities(&self, admin_api_key: String) -> RpcResult<Vec<IdentityRotation>>;
+    /// Returns the slot, index in block, commitment and error of a transaction that landed in the
+    /// retention period, and the history of its states since it was received.
+    /// Returns null for transactions we know nothing about.
+    #[method(name = "getSignatureStatus")]
+    async fn get_signature_status(&self, signature: String)
+        -> RpcResult<Option<TransactionStatus>>;
+    /// Returns the land rate and landing time of the transactions sent since startup, by send port,
+    /// slot leader, identity and leader position.
+    #[method(name = "getLandingStats")]
+    async fn get_landing_stats(&self) -> RpcResult<LandingReport>;
 }
 
 pub struct AtlasTxnSenderImpl {
     txn_sender: Arc<dyn TxnSender>,
     transaction_store: Arc<dyn TransactionStore>,
+    scheduler: Arc<Scheduler>,
+    identities: Arc<IdentityPool>,
     max_txn_send_retries: usize,
+    admin_api_key: Option<String>,
+    solana_rpc: Arc<dyn SolanaRpc>,
+    landing_attribution: Arc<LandingAttribution>,
+    transaction_lifecycles: Arc<TransactionLifecycles>,
+    webhooks: Arc<WebhookNotifier>,
+    pipeline_latency: Arc<PipelineLatency>,
 }
 
 impl AtlasTxnSenderImpl {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         txn_sender: Arc<dyn TxnSender>,
         transaction_store: Arc<dyn TransactionStore>,
+        scheduler: Arc<Scheduler>,
+        identities: Arc<IdentityPool>,
         max_txn_send_retries: usize,
+        admin_api_key: Option<String>,
+        solana_rpc: Arc<dyn SolanaRpc>,
+        landing_attribution: Arc<LandingAttribution>,
+        transaction_lifecycles: Arc<TransactionLifecycles>,
+        webhooks: Arc<WebhookNotifier>,
+        pipeline_latency: Arc<PipelineLatency>,
     ) -> Self {
         Self {
             txn_sender,
             max_txn_send_retries,
             transaction_store,
+            scheduler,
+            identities,
+            admin_api_key,
+            solana_rpc,
+            landing_attribution,
+            transaction_lifecycles,
+            webhooks,
+            pipeline_latency,
         }
     }
+
+    /// check_owner refuses to change a transaction that was sent with another api key
+    async fn check_owner(&self, signature: &str, api_key: &str) -> RpcResult<()> {
+        let owner = match self.scheduler.get_api_key(signature) {
+            Some(owner) => Some(owner),
+            None => self.transaction_store.get_api_key(signature).await,
+        };
+        if owner.is_some_and(|owner| owner != api_key) {
+            statsd_count!("transaction_owner_mismatch", 1, "api_key" => api_key);
+            return Err(invalid_request("transaction was sent with another api key"));
+        }
+        Ok(())
+    }
 }
 
 #[async_trait]
 impl AtlasTxnSenderServer for AtlasTxnSenderImpl {
-    async fn health(&self) -> String {
-        "ok".to_string()
+    async fn health(&self) -> RpcResult<String> {
+        let Some(slot_update) = *self.solana_rpc.subscribe_slots().borrow() else {
+            return Err(node_unhealthy("no slot received yet"));
+        };
+        let slot_age = slot_update.received_at.elapsed();
+        if slot_age > MAX_SLOT_AGE {
+            return Err(node_unhealthy(&format!(
+                "last slot {} ({:?}) received {}s ago",
+                slot_update.slot,
+                slot_update.commitment,
+                slot_age.as_secs()
+            )));
+        }
+        Ok("ok".to_string())
     }
+    // a root span, unless the request continues a trace from its `traceparent` header
+    #[instrument(name = "sendTransaction", skip_all, fields(signature, route))]
     async fn send_transaction(
         &self,
         txn: String,
@@ -141,33 +299,53 @@ impl AtlasTxnSenderServer for AtlasTxnSenderImpl {
         request_metadata: Option<OptionalRequestMetadata>,
     ) -> RpcResult<String> {
         let sent_at = Instant::now();
-        let request_metadata = request_metadata
+        let span = Span::current();
+        continue_trace(&span);
+        let mut request_metadata = request_metadata
             .map(|m| m.unwrap_or_default())
             .unwrap_or_default();
+        let route = SendPorts::from(request_metadata.send_port).as_str();
+        span.record("route", route);
         let RequestMetadata { api_key, .. } = request_metadata.clone();
         statsd_count!("send_transaction", 1, "api_key" => &api_key);
         validate_send_transaction_params(&params)?;
+        if let Some(identity) = &request_metadata.identity {
+            if !self.identities.contains(identity) {
+                return Err(invalid_request(&format!("unknown identity: {identity}")));
+            }
+        }
+        if let Some(callback_url) = &request_metadata.callback_url {
+            if !self.webhooks.is_enabled() {
+                return Err(invalid_request("callbacks are not enabled"));
+            }
+            self.webhooks
+                .validate_callback_url(callback_url)
+                .map_err(|e| invalid_request(&e.to_string()))?;
+        }
+        if let Some(schedule) = request_metadata.schedule.take() {
+            let schedule = self.scheduler.validate(schedule).map_err(|e| {
+                statsd_count!("scheduled_transaction_rejected", 1, "api_key" => &api_key);
+                invalid_request(&e.to_string())
+            })?;
+            request_metadata.schedule = Some(schedule);
+        }
         let start = Instant::now();
-        let encoding = params.encoding.unwrap_or(UiTransactionEncoding::Base58);
-        let binary_encoding = encoding.into_binary_encoding().ok_or_else(|| {
-            invalid_request(&format!(
-                "unsupported encoding: {encoding}. Supported encodings: base58, base64"
-            ))
-        })?;
-        let (wire_transaction, versioned_transaction) =
-            match decode_and_deserialize::<VersionedTransaction>(txn, binary_encoding) {
-                Ok((wire_transaction, versioned_transaction)) => {
-                    (wire_transaction, versioned_transaction)
-                }
-                Err(e) => {
-                    return Err(invalid_request(&e.to_string()));
-                }
-            };
+        let (wire_transaction, versioned_transaction) = decode_transaction(txn, &params)?;
+        self.pipeline_latency
+            .record_decode(start.elapsed(), route, &api_key);
         let signature = versioned_transaction.signatures[0].to_string();
-        if self.transaction_store.has_signature(&signature) {
+        span.record("signature", signature.as_str());
+        if self.transaction_store.has_signature(&signature)
+            || self.scheduler.has_signature(&signature)
+        {
             statsd_count!("duplicate_transaction", 1, "api_key" => &api_key);
             return Ok(signature);
         }
+        if self.transaction_store.is_cancelled(&signature) {
+            statsd_count!("cancelled_transaction_resubmitted", 1, "api_key" => &api_key);
+            return Err(invalid_request("transaction was cancelled"));
+        }
+        let priority = TransactionPriority::from_transaction(&versioned_transaction);
         let transaction = TransactionData {
             wire_transaction,
             versioned_transaction,
@@ -177,9 +355,21 @@ impl AtlasTxnSenderServer for AtlasTxnSenderImpl {
                 self.max_txn_send_retries,
                 params.max_retries.unwrap_or(self.max_txn_send_retries),
             ),
+            priority,
             request_metadata,
+            history: vec![],
         };
-        self.txn_sender.send_transaction(transaction);
+        let scheduled = transaction.request_metadata.schedule.is_some();
+        self.transaction_lifecycles.record(
+            &signature,
+            TransactionState::Received,
+            scheduled.then(|| "scheduled".to_string()),
+        );
+        if scheduled {
+            self.scheduler.schedule_transaction(transaction);
+        } else {
+            self.txn_sender.send_transaction(transaction);
+        }
         statsd_time!(
             "send_transaction_time",
             start.elapsed(),
@@ -187,6 +377,157 @@ impl AtlasTxnSenderServer for AtlasTxnSenderImpl {
         );
         Ok(signature)
     }
+    async fn cancel_transaction(
+        &self,
+        signature: String,
+        request_metadata: Option<OptionalRequestMetadata>,
+    ) -> RpcResult<bool> {
+        validate_signature(&signature)?;
+        let api_key = request_metadata
+            .map(|m| m.unwrap_or_default())
+            .unwrap_or_default()
+            .api_key;
+        self.check_owner(&signature, &api_key).await?;
+        let scheduled = self.scheduler.cancel_transaction(&signature);
+        let queued = self
+            .transaction_store
+            .cancel_transaction(&signature, scheduled.as_ref())
+            .is_some();
+        let scheduled = scheduled.is_some();
+        if scheduled || queued {
+            self.transaction_lifecycles
+                .record(&signature, TransactionState::Cancelled, None);
+        }
+        Ok(scheduled || queued)
+    }
+    async fn replace_transaction(
+        &self,
+        signature: String,
+        txn: String,
+        params: RpcSendTransactionConfig,
+        request_metadata: Option<OptionalRequestMetadata>,
+    ) -> RpcResult<String> {
+        let sent_at = Instant::now();
+        validate_signature(&signature)?;
+        validate_send_transaction_params(&params)?;
+        let api_key = request_metadata
+            .map(|m| m.unwrap_or_default())
+            .unwrap_or_default()
+            .api_key;
+        self.check_owner(&signature, &api_key).await?;
+        let (wire_transaction, versioned_transaction) = decode_transaction(txn, &params)?;
+        let new_signature = versioned_transaction.signatures[0].to_string();
+        let priority = TransactionPriority::from_transaction(&versioned_transaction);
+        let transaction = TransactionData {
+            wire_transaction,
+            versioned_transaction,
+            sent_at,
+            retry_count: 0,
+            // both are replaced with the values of the original transaction, the api key is
+            // checked against it
+            max_retries: self.max_txn_send_retries,
+            priority,
+            request_metadata: RequestMetadata {
+                api_key,
+                ..Default::default()
+            },
+            history: vec![],
+        };
+        let scheduled = self.scheduler.has_signature(&signature);
+        let transaction = if scheduled {
+            if self.transaction_store.has_signature(&new_signature)
+                || self.transaction_store.is_cancelled(&new_signature)
+            {
+                return Err(invalid_request(&format!(
+                    "Transaction {new_signature} was already submitted"
+                )));
+            }
+            // the replacement keeps the schedule and waits in the scheduler
+            let original = self
+                .scheduler
+                .replace_transaction(&signature, transaction)
+                .map_err(|e| invalid_request(&e.to_string()))?;
+            self.transaction_store
+                .cancel_transaction(&signature, Some(&original));
+            None
+        } else {
+            Some(
+                self.transaction_store
+                    .replace_transaction(&signature, transaction)
+                    .map_err(|e| invalid_request(&e.to_string()))?,
+            )
+        };
+        self.transaction_lifecycles.record(
+            &signature,
+            TransactionState::Cancelled,
+            Some(format!("replaced by {new_signature}")),
+        );
+        self.transaction_lifecycles.record(
+            &new_signature,
+            TransactionState::Received,
+            Some(format!("replaces {signature}")),
+        );
+        if let Some(transaction) = transaction {
+            self.txn_sender.send_transaction(transaction);
+        }
+        Ok(new_signature)
+    }
+    async fn reload_identities(&self, admin_api_key: String) -> RpcResult<Vec<IdentityRotation>> {
+        // compared in constant time, so the key can't be guessed from response times
+        let authorized = self.admin_api_key.as_ref().is_some_and(|expected| {
+            bool::from(expected.as_bytes().ct_eq(admin_api_key.as_bytes()))
+        });
+        if !authorized {
+            return Err(invalid_request("invalid admin api key"));
+        }
+        let identities = self.identities.clone();
+        tokio::task::spawn_blocking(move || identities.reload())
+            .await
+            .map_err(|e| invalid_request(&e.to_string()))?
+            .map_err(|e| invalid_request(&e.to_string()))
+    }
+    async fn get_signature_status(
+        &self,
+        signature: String,
+    ) -> RpcResult<Option<TransactionStatus>> {
+        validate_signature(&signature)?;
+        let status = self.solana_rpc.get_signature_status(&signature);
+        let history = match self.transaction_lifecycles.get_history(&signature) {
+            Some(history) => Some(history),
+            // tracked by another instance
+            None => self.transaction_store.fetch_history(&signature).await,
+        };
+        if status.is_none() && history.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(TransactionStatus {
+            status,
+            history: history.unwrap_or_default(),
+        }))
+    }
+    async fn get_landing_stats(&self) -> RpcResult<LandingReport> {
+        Ok(self.landing_attribution.report())
+    }
+}
+
+fn decode_transaction(
+    txn: String,
+    params: &RpcSendTransactionConfig,
+) -> RpcResult<(Vec<u8>, VersionedTransaction)> {
+    let encoding = params.encoding.unwrap_or(UiTransactionEncoding::Base58);
+    let binary_encoding = encoding.into_binary_encoding().ok_or_else(|| {
+        invalid_request(&format!(
+            "unsupported encoding: {encoding}. Supported encodings: base58, base64"
+        ))
+    })?;
+    decode_and_deserialize::<VersionedTransaction>(txn, binary_encoding)
+        .map_err(|e| invalid_request(&e.to_string()))
+}
+
+fn validate_signature(signature: &str) -> Result<(), ErrorObjectOwned> {
+    Signature::from_str(signature)
+        .map(|_| ())
+        .map_err(|e| invalid_request(&format!("invalid signature: {e}")))
 }
 
 fn validate_send_transaction_params(
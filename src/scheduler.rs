@@ -0,0 +1,427 @@
+use std::{collections::HashMap, sync::Arc};
+
+use cadence_macros::{statsd_count, statsd_gauge};
+use dashmap::{mapref::entry::Entry, DashMap};
+use serde::{Deserialize, Serialize};
+use solana_rpc_client_api::response::RpcContactInfo;
+use solana_sdk::{pubkey::Pubkey, slot_history::Slot};
+use tracing::warn;
+
+use crate::{
+    errors::AtlasTxnSenderError,
+    leader_tracker::{LeaderTracker, LeaderTrackerTrait},
+    lifecycle::{TransactionLifecycles, TransactionState},
+    solana_rpc::SolanaRpc,
+    transaction_store::{check_replacement, get_signature, TransactionData, TransactionStore},
+    txn_sender::TxnSender,
+    webhook::{CallbackPayload, CallbackStatus, WebhookNotifier},
+};
+
+// how far ahead transactions can be scheduled
+const MAX_SCHEDULE_AHEAD_SLOTS: u64 = 1000;
+
+/// SendSchedule restricts when and to which leader a transaction is sent
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all(serialize = "camelCase", deserialize = "camelCase"))]
+pub struct SendSchedule {
+    pub target_slot: Option<Slot>,
+    pub not_before_slot: Option<Slot>,
+    pub not_after_slot: Option<Slot>,
+    pub target_leader: Option<String>,
+}
+
+impl SendSchedule {
+    pub fn is_empty(&self) -> bool {
+        self == &SendSchedule::default()
+    }
+
+    /// first_slot returns the first slot the transaction may be sent in.
+    /// A target slot is only sent in that slot, never earlier in its leader's window.
+    pub fn first_slot(&self) -> Option<Slot> {
+        self.not_before_slot.max(self.target_slot)
+    }
+
+    /// last_slot returns the last slot the transaction may be sent in
+    pub fn last_slot(&self) -> Option<Slot> {
+        match (self.not_after_slot, self.target_slot) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    pub fn is_expired(&self, cur_slot: Slot) -> bool {
+        self.last_slot()
+            .is_some_and(|last_slot| cur_slot > last_slot)
+    }
+
+    /// is_due returns whether the transaction can be sent to the current leaders
+    pub fn is_due(&self, cur_slot: Slot, leaders: impl FnOnce() -> Vec<RpcContactInfo>) -> bool {
+        if self
+            .first_slot()
+            .is_some_and(|first_slot| cur_slot < first_slot)
+        {
+            return false;
+        }
+        self.target_leader.is_none() || !self.filter_leaders(leaders()).is_empty()
+    }
+
+    /// filter_leaders keeps only the target leader, if there is one
+    pub fn filter_leaders(&self, leaders: Vec<RpcContactInfo>) -> Vec<RpcContactInfo> {
+        match &self.target_leader {
+            Some(target_leader) => leaders
+                .into_iter()
+                .filter(|leader| &leader.pubkey == target_leader)
+                .collect(),
+            None => leaders,
+        }
+    }
+}
+
+/// Scheduler holds transactions with a SendSchedule until their slot window or leader comes up.
+/// Every scheduled transaction is also kept in the TransactionStore, so it survives a restart.
+pub struct Scheduler {
+    leader_tracker: Arc<LeaderTracker>,
+    solana_rpc: Arc<dyn SolanaRpc>,
+    transaction_store: Arc<dyn TransactionStore>,
+    scheduled_transactions: Arc<DashMap<String, TransactionData>>,
+    transaction_lifecycles: Arc<TransactionLifecycles>,
+    webhooks: Arc<WebhookNotifier>,
+}
+
+impl Scheduler {
+    pub fn new(
+        leader_tracker: Arc<LeaderTracker>,
+        solana_rpc: Arc<dyn SolanaRpc>,
+        transaction_store: Arc<dyn TransactionStore>,
+        txn_sender: Arc<dyn TxnSender>,
+        transaction_lifecycles: Arc<TransactionLifecycles>,
+        webhooks: Arc<WebhookNotifier>,
+    ) -> Self {
+        let scheduled_transactions: DashMap<String, TransactionData> = transaction_store
+            .take_scheduled()
+            .into_iter()
+            .filter_map(|transaction| Some((get_signature(&transaction)?, transaction)))
+            .collect();
+        statsd_gauge!(
+            "scheduled_transactions_queue_length",
+            scheduled_transactions.len() as u64
+        );
+        let scheduler = Self {
+            leader_tracker,
+            solana_rpc,
+            transaction_store,
+            scheduled_transactions: Arc::new(scheduled_transactions),
+            transaction_lifecycles,
+            webhooks,
+        };
+        scheduler.release_transactions(txn_sender);
+        scheduler
+    }
+
+    /// validate rejects schedules that can't be served and resolves the leader of a target slot
+    pub fn validate(
+        &self,
+        mut schedule: SendSchedule,
+    ) -> Result<SendSchedule, AtlasTxnSenderError> {
+        let Some(cur_slot) = self.solana_rpc.get_next_slot() else {
+            return Err("current slot is unknown, scheduled sends are unavailable"
+                .to_string()
+                .into());
+        };
+        if let Some(target_leader) = &schedule.target_leader {
+            if target_leader.parse::<Pubkey>().is_err() {
+                return Err(format!("invalid targetLeader: {target_leader}").into());
+            }
+        }
+        if let (Some(first_slot), Some(last_slot)) = (schedule.first_slot(), schedule.last_slot()) {
+            if first_slot > last_slot {
+                return Err(format!("empty slot window: {first_slot} to {last_slot}").into());
+            }
+        }
+        if schedule.is_expired(cur_slot) {
+            return Err(format!("slot window already passed, current slot is {cur_slot}").into());
+        }
+        let horizon = cur_slot + MAX_SCHEDULE_AHEAD_SLOTS;
+        if schedule
+            .first_slot()
+            .is_some_and(|first_slot| first_slot > horizon)
+        {
+            return Err(format!("slot window starts after slot {horizon}").into());
+        }
+        if let Some(target_slot) = schedule.target_slot {
+            let Some(slot_leader) = self.leader_tracker.get_slot_leader(target_slot) else {
+                return Err(format!("leader for slot {target_slot} is unknown").into());
+            };
+            match &schedule.target_leader {
+                Some(target_leader) if target_leader != &slot_leader.pubkey => {
+                    return Err(format!(
+                        "targetLeader {target_leader} is not the leader of slot {target_slot}"
+                    )
+                    .into());
+                }
+                _ => schedule.target_leader = Some(slot_leader.pubkey),
+            }
+        }
+        if let Some(target_leader) = &schedule.target_leader {
+            let from_slot = schedule.first_slot().unwrap_or(cur_slot).max(cur_slot);
+            let to_slot = schedule.last_slot().unwrap_or(horizon).min(horizon);
+            if self
+                .leader_tracker
+                .get_next_leader_slot(target_leader, from_slot, to_slot)
+                .is_none()
+            {
+                return Err(format!(
+                    "{target_leader} is not scheduled between slots {from_slot} and {to_slot}"
+                )
+                .into());
+            }
+        }
+        Ok(schedule)
+    }
+
+    pub fn schedule_transaction(&self, transaction: TransactionData) {
+        if let Some(signature) = get_signature(&transaction) {
+            self.transaction_store
+                .schedule_transaction(&signature, &transaction);
+            self.scheduled_transactions.insert(signature, transaction);
+            statsd_count!("transaction_scheduled", 1);
+        }
+    }
+
+    pub fn has_signature(&self, signature: &str) -> bool {
+        self.scheduled_transactions.contains_key(signature)
+    }
+
+    /// get_api_key returns the api key the scheduled transaction was sent with
+    pub fn get_api_key(&self, signature: &str) -> Option<String> {
+        self.scheduled_transactions
+            .get(signature)
+            .map(|transaction| transaction.request_metadata.api_key.clone())
+    }
+
+    pub fn cancel_transaction(&self, signature: &str) -> Option<TransactionData> {
+        let (_, transaction) = self.scheduled_transactions.remove(signature)?;
+        self.transaction_store.unschedule_transaction(signature);
+        Some(transaction)
+    }
+
+    /// replace_transaction swaps a scheduled transaction for a new one with the same schedule and
+    /// returns the original. The replacement is refused unless it passes check_replacement.
+    pub fn replace_transaction(
+        &self,
+        signature: &str,
+        mut transaction: TransactionData,
+    ) -> Result<TransactionData, AtlasTxnSenderError> {
+        let new_signature = get_signature(&transaction).ok_or_else(|| {
+            AtlasTxnSenderError::from("Transaction has no signatures".to_string())
+        })?;
+        if let Some(original) = self.scheduled_transactions.get(signature) {
+            check_replacement(&original, &transaction)?;
+        }
+        let Some((_, original)) = self.scheduled_transactions.remove(signature) else {
+            return Err(format!("Transaction {signature} is not queued").into());
+        };
+        transaction.request_metadata = original.request_metadata.clone();
+        transaction.max_retries = original.max_retries;
+        match self.scheduled_transactions.entry(new_signature.clone()) {
+            Entry::Vacant(entry) => {
+                self.transaction_store
+                    .schedule_transaction(&new_signature, &transaction);
+                entry.insert(transaction);
+            }
+            Entry::Occupied(_) => {
+                self.scheduled_transactions
+                    .insert(signature.to_string(), original);
+                return Err(format!("Transaction {new_signature} was already submitted").into());
+            }
+        }
+        self.transaction_store.unschedule_transaction(signature);
+        statsd_count!("scheduled_transaction_replaced", 1);
+        Ok(original)
+    }
+
+    /// release_transactions hands due transactions to the sender and drops the ones whose window passed
+    fn release_transactions(&self, txn_sender: Arc<dyn TxnSender>) {
+        let leader_tracker = self.leader_tracker.clone();
+        let transaction_store = self.transaction_store.clone();
+        let scheduled_transactions = self.scheduled_transactions.clone();
+        let transaction_lifecycles = self.transaction_lifecycles.clone();
+        let webhooks = self.webhooks.clone();
+        let mut slot_updates = self.solana_rpc.subscribe_slots();
+        tokio::spawn(async move {
+            // transactions become due when a new slot brings new leaders
+            while slot_updates.changed().await.is_ok() {
+                let Some(cur_slot) = slot_updates.borrow_and_update().map(|s| s.slot) else {
+                    continue;
+                };
+                // taken over from another instance that stopped
+                for transaction in transaction_store.take_scheduled() {
+                    if let Some(signature) = get_signature(&transaction) {
+                        scheduled_transactions
+                            .entry(signature)
+                            .or_insert(transaction);
+                    }
+                }
+                statsd_gauge!(
+                    "scheduled_transactions_queue_length",
+                    scheduled_transactions.len() as u64
+                );
+                let mut released = vec![];
+                let mut dropped = vec![];
+                let mut leaders = HashMap::new();
+                scheduled_transactions.retain(|signature, transaction| {
+                    let Some(schedule) = &transaction.request_metadata.schedule else {
+                        released.push((signature.clone(), transaction.clone()));
+                        return false;
+                    };
+                    // cancelled, or scheduled by another instance that sends it instead
+                    if transaction_store.is_cancelled(signature)
+                        || !transaction_store.holds_scheduled(signature)
+                    {
+                        dropped.push(signature.clone());
+                        return false;
+                    }
+                    if schedule.is_expired(cur_slot) {
+                        warn!(
+                            "Scheduled transaction {} expired at slot {}",
+                            signature, cur_slot
+                        );
+                        statsd_count!("scheduled_transaction_expired", 1);
+                        transaction_lifecycles.record(
+                            signature,
+                            TransactionState::Expired,
+                            Some("slot window passed".to_string()),
+                        );
+                        webhooks.notify(
+                            &transaction.request_metadata,
+                            CallbackPayload {
+                                signature: signature.clone(),
+                                status: CallbackStatus::Expired,
+                                slot: None,
+                                err: None,
+                                reason: Some("slot_window_passed"),
+                                history: transaction_lifecycles
+                                    .get_history(signature)
+                                    .unwrap_or_default(),
+                            },
+                        );
+                        dropped.push(signature.clone());
+                        return false;
+                    }
+                    let mode = transaction.request_metadata.leader_policy;
+                    let leaders = || {
+                        leaders
+                            .entry(mode)
+                            .or_insert_with(|| leader_tracker.get_leaders(mode))
+                            .clone()
+                    };
+                    if schedule.is_due(cur_slot, leaders) {
+                        released.push((signature.clone(), transaction.clone()));
+                        return false;
+                    }
+                    true
+                });
+                for (signature, transaction) in released {
+                    statsd_count!("scheduled_transaction_released", 1);
+                    // queued by the sender before it is forgotten, so a crash in between can't lose it
+                    txn_sender.send_transaction(transaction);
+                    transaction_store.unschedule_transaction(&signature);
+                }
+                for signature in dropped {
+                    transaction_store.unschedule_transaction(&signature);
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leader(pubkey: &str) -> RpcContactInfo {
+        RpcContactInfo {
+            pubkey: pubkey.to_string(),
+            gossip: None,
+            tvu: None,
+            tpu: None,
+            tpu_quic: None,
+            tpu_forwards: None,
+            tpu_forwards_quic: None,
+            tpu_vote: None,
+            serve_repair: None,
+            rpc: None,
+            pubsub: None,
+            version: None,
+            feature_set: None,
+            shred_version: None,
+        }
+    }
+
+    #[test]
+    fn target_slot_is_the_whole_window() {
+        // the middle of a leader window, sent neither at its start nor after it
+        let schedule = SendSchedule {
+            target_slot: Some(102),
+            ..Default::default()
+        };
+        assert_eq!(schedule.first_slot(), Some(102));
+        assert_eq!(schedule.last_slot(), Some(102));
+        assert!(!schedule.is_due(100, Vec::new));
+        assert!(!schedule.is_due(101, Vec::new));
+        assert!(schedule.is_due(102, Vec::new));
+        assert!(!schedule.is_expired(102));
+        assert!(schedule.is_expired(103));
+    }
+
+    #[test]
+    fn slot_bounds_narrow_the_window() {
+        let schedule = SendSchedule {
+            not_before_slot: Some(90),
+            not_after_slot: Some(110),
+            ..Default::default()
+        };
+        assert_eq!(schedule.first_slot(), Some(90));
+        assert_eq!(schedule.last_slot(), Some(110));
+        let schedule = SendSchedule {
+            target_slot: Some(100),
+            not_before_slot: Some(90),
+            not_after_slot: Some(95),
+            ..Default::default()
+        };
+        // an empty window, rejected when validated
+        assert_eq!(schedule.first_slot(), Some(100));
+        assert_eq!(schedule.last_slot(), Some(95));
+        assert_eq!(SendSchedule::default().first_slot(), None);
+        assert!(SendSchedule::default().is_empty());
+    }
+
+    #[test]
+    fn target_leader_waits_for_the_leader() {
+        let target = Pubkey::new_unique().to_string();
+        let other = Pubkey::new_unique().to_string();
+        let schedule = SendSchedule {
+            target_leader: Some(target.clone()),
+            not_before_slot: Some(100),
+            ..Default::default()
+        };
+        assert!(!schedule.is_due(99, || vec![leader(&target)]));
+        assert!(!schedule.is_due(100, || vec![leader(&other)]));
+        assert!(schedule.is_due(100, || vec![leader(&other), leader(&target)]));
+        let leaders = schedule.filter_leaders(vec![leader(&other), leader(&target)]);
+        assert_eq!(leaders.len(), 1);
+        assert_eq!(leaders[0].pubkey, target);
+
+        // without a target leader every leader is sent to
+        let schedule = SendSchedule {
+            target_slot: Some(100),
+            ..Default::default()
+        };
+        assert_eq!(
+            schedule
+                .filter_leaders(vec![leader(&other), leader(&target)])
+                .len(),
+            2
+        );
+    }
+}
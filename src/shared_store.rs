@@ -0,0 +1,1784 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use cadence_macros::{statsd_count, statsd_gauge};
+use dashmap::{mapref::entry::Entry, DashMap};
+use redis::{
+    aio::{ConnectionManager, ConnectionManagerConfig},
+    AsyncCommands, Client, RedisError, Script,
+};
+use solana_client::rpc_client::RpcClient;
+use tokio::{
+    sync::{mpsc, OnceCell},
+    time::sleep,
+};
+use tonic::async_trait;
+use tracing::{error, info};
+
+use crate::{
+    errors::AtlasTxnSenderError,
+    lifecycle::LifecycleEvent,
+    persistent_store::{unix_millis_now, StoredTransaction},
+    transaction_store::{
+        advanced_nonces, check_replacement, get_fee_payer, get_signature, DurableNonce, Tombstone,
+        TransactionData, TransactionStore, TransactionStoreImpl, NONCE_CHECK_INTERVAL,
+        TOMBSTONE_RETENTION,
+    },
+};
+
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+// a transaction left behind by an instance that died can be taken over for this long
+const TRANSACTION_TTL: Duration = Duration::from_secs(600);
+// signatures per script call, and per page of queued or cancelled signatures read
+const BATCH_SIZE: usize = 500;
+
+// the scripts score the leases, queued and cancelled signatures with the server's clock, in ms, so
+// the instances' clocks don't have to agree
+const NOW: &str = r#"
+local time = redis.call('time')
+local now = time[1] * 1000 + math.floor(time[2] / 1000)
+"#;
+
+// claims the leases of newly queued or scheduled transactions for ARGV[1], unless another instance
+// holds them as either or they were cancelled, and writes the transactions.
+// KEYS: leases, owners, queued, cancelled, the leases and owners of the other kind, then a
+//   transaction key per signature
+// ARGV: owner, lease ms, transaction ttl ms, then a signature and transaction per transaction key
+// Returns 1 for each claimed signature and 0 for each lost one.
+const CLAIM_SCRIPT: &str = r#"
+local claimed = {}
+for i = 7, #KEYS do
+  local signature = ARGV[2 * i - 10]
+  local expiry = redis.call('zscore', KEYS[1], signature)
+  local owner = redis.call('hget', KEYS[2], signature)
+  local other_expiry = redis.call('zscore', KEYS[5], signature)
+  local other_owner = redis.call('hget', KEYS[6], signature)
+  if redis.call('zscore', KEYS[4], signature) then
+    claimed[#claimed + 1] = 0
+  elseif other_expiry and tonumber(other_expiry) > now and other_owner ~= ARGV[1] then
+    claimed[#claimed + 1] = 0
+  elseif not expiry or tonumber(expiry) <= now or owner == ARGV[1] then
+    redis.call('zadd', KEYS[1], now + ARGV[2], signature)
+    redis.call('hset', KEYS[2], signature, ARGV[1])
+    redis.call('zadd', KEYS[3], 'NX', now, signature)
+    redis.call('set', KEYS[i], ARGV[2 * i - 9], 'PX', ARGV[3])
+    claimed[#claimed + 1] = 1
+  else
+    claimed[#claimed + 1] = 0
+  end
+end
+return claimed
+"#;
+
+// renews the leases ARGV[1] holds, or that expired without being taken over, e.g. while the store
+// was unreachable, and writes back the transactions, whose retry counts and history changed.
+// KEYS: leases, owners, then a transaction key per signature
+// ARGV: owner, lease ms, transaction ttl ms, then a signature and transaction per transaction key
+// Returns the signatures that were released, cancelled or taken over by another instance.
+const RENEW_SCRIPT: &str = r#"
+local lost = {}
+for i = 3, #KEYS do
+  local signature = ARGV[2 * i - 2]
+  local expiry = redis.call('zscore', KEYS[1], signature)
+  if expiry and (redis.call('hget', KEYS[2], signature) == ARGV[1] or tonumber(expiry) <= now) then
+    redis.call('zadd', KEYS[1], now + ARGV[2], signature)
+    redis.call('hset', KEYS[2], signature, ARGV[1])
+    redis.call('set', KEYS[i], ARGV[2 * i - 1], 'PX', ARGV[3])
+  else
+    lost[#lost + 1] = signature
+  end
+end
+return lost
+"#;
+
+// takes the leases of the signatures that expired for ARGV[1]. A lease whose transaction expired
+// too is dropped from the queue.
+// KEYS: leases, owners, queued, then a transaction key per signature
+// ARGV: owner, lease ms, then a signature per transaction key
+// Returns each signature taken followed by its transaction.
+const TAKEOVER_SCRIPT: &str = r#"
+local taken = {}
+for i = 4, #KEYS do
+  local signature = ARGV[i - 1]
+  local expiry = redis.call('zscore', KEYS[1], signature)
+  if expiry and tonumber(expiry) <= now then
+    local transaction = redis.call('get', KEYS[i])
+    if transaction then
+      redis.call('zadd', KEYS[1], now + ARGV[2], signature)
+      redis.call('hset', KEYS[2], signature, ARGV[1])
+      taken[#taken + 1] = signature
+      taken[#taken + 1] = transaction
+    else
+      redis.call('zrem', KEYS[1], signature)
+      redis.call('hdel', KEYS[2], signature)
+      redis.call('zrem', KEYS[3], signature)
+    end
+  end
+end
+return taken
+"#;
+
+// removes a transaction from the queue or the schedule if ARGV[1] holds its lease. The queued
+// signature is kept, as a released scheduled transaction is queued next.
+// KEYS: leases, owners, transaction
+// ARGV: owner, signature
+const RELEASE_SCRIPT: &str = r#"
+if redis.call('hget', KEYS[2], ARGV[2]) == ARGV[1] then
+  redis.call('zrem', KEYS[1], ARGV[2])
+  redis.call('hdel', KEYS[2], ARGV[2])
+  redis.call('del', KEYS[3])
+  return 1
+end
+return 0
+"#;
+
+// removes a transaction from the queue and the schedule, whoever holds its lease, and records the
+// cancellation
+// KEYS: cancelled, cancelled nonces, queued, then the leases, owners and transaction keys of the
+//   queue and of the schedule
+// ARGV: signature, the nonce of a durable nonce transaction or an empty string
+const CANCEL_SCRIPT: &str = r#"
+if ARGV[2] ~= '' then
+  redis.call('hset', KEYS[2], ARGV[1], ARGV[2])
+end
+redis.call('zadd', KEYS[1], now, ARGV[1])
+redis.call('zrem', KEYS[3], ARGV[1])
+for i = 4, 7, 3 do
+  redis.call('zrem', KEYS[i], ARGV[1])
+  redis.call('hdel', KEYS[i + 1], ARGV[1])
+  redis.call('del', KEYS[i + 2])
+end
+return 1
+"#;
+
+/// LeaseKind tells the transactions retried by an instance from the ones waiting in its scheduler.
+/// Both are leased the same way, under their own keys.
+#[derive(Clone, Copy)]
+enum LeaseKind {
+    Queued,
+    Scheduled,
+}
+
+impl LeaseKind {
+    fn other(self) -> Self {
+        match self {
+            LeaseKind::Queued => LeaseKind::Scheduled,
+            LeaseKind::Scheduled => LeaseKind::Queued,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            LeaseKind::Queued => "queued",
+            LeaseKind::Scheduled => "scheduled",
+        }
+    }
+}
+
+/// SharedStoreOp is a change to the queue, applied to the shared store in order
+enum SharedStoreOp {
+    /// claims the lease of a pending transaction and queues it
+    Add {
+        signature: String,
+    },
+    Remove {
+        signature: String,
+    },
+    Cancel {
+        signature: String,
+        nonce: Option<DurableNonce>,
+    },
+    /// a state change of a transaction, shared so its status can be queried on any instance
+    Event {
+        signature: String,
+        event: LifecycleEvent,
+    },
+    /// claims the lease of a transaction handed to this instance's scheduler
+    Schedule {
+        signature: String,
+    },
+    Unschedule {
+        signature: String,
+    },
+}
+
+/// LocalQueue is the part of the shared queue kept by this instance
+struct LocalQueue {
+    /// the transactions this instance holds the lease of and retries
+    transactions: Arc<TransactionStoreImpl>,
+    /// the transactions sent through this instance whose lease isn't claimed yet, not retried
+    pending: DashMap<String, TransactionData>,
+    /// the transactions taken over from other instances, until they are tracked
+    adopted: Mutex<Vec<TransactionData>>,
+    /// the transactions held by this instance's scheduler, until their lease is lost
+    scheduled: DashMap<String, TransactionData>,
+    /// the scheduled transactions taken over from other instances, until the scheduler takes them
+    adopted_scheduled: Mutex<Vec<TransactionData>>,
+    /// signatures queued on any instance, with when this instance learned of them. Releases on other
+    /// instances aren't mirrored, so the signatures are forgotten after TRANSACTION_TTL.
+    shared_signatures: RwLock<HashMap<String, Instant>>,
+}
+
+impl LocalQueue {
+    fn new(rpc_client: Arc<RpcClient>) -> Self {
+        Self {
+            transactions: Arc::new(TransactionStoreImpl::new(rpc_client)),
+            pending: DashMap::new(),
+            adopted: Mutex::new(vec![]),
+            scheduled: DashMap::new(),
+            adopted_scheduled: Mutex::new(vec![]),
+            shared_signatures: RwLock::new(HashMap::new()),
+        }
+    }
+}
+
+struct Scripts {
+    claim: Script,
+    renew: Script,
+    takeover: Script,
+    release: Script,
+    cancel: Script,
+}
+
+impl Scripts {
+    fn new() -> Self {
+        let script = |body: &str| Script::new(&format!("{NOW}{body}"));
+        Self {
+            claim: script(CLAIM_SCRIPT),
+            renew: script(RENEW_SCRIPT),
+            takeover: script(TAKEOVER_SCRIPT),
+            release: script(RELEASE_SCRIPT),
+            cancel: script(CANCEL_SCRIPT),
+        }
+    }
+}
+
+/// SharedStore holds the keys of one queue in a Redis protocol key-value service:
+/// - `{prefix}:leases` the queued signatures, scored by when their lease expires
+/// - `{prefix}:owners` the instance holding the lease of each queued signature
+/// - `{prefix}:queued` the queued and scheduled signatures, scored by when they were first queued
+/// - `{prefix}:txn:{signature}` the transaction, written back with its retry count and history
+///   whenever its lease is renewed
+/// - `{prefix}:scheduled_leases`, `{prefix}:scheduled_owners` and `{prefix}:scheduled_txn:{signature}`
+///   the same for the transactions waiting in a scheduler, with their SendSchedule
+/// - `{prefix}:history:{signature}` the state changes of the transaction
+/// - `{prefix}:cancelled` the cancelled signatures, scored by when they were cancelled
+/// - `{prefix}:cancelled_nonces` the nonces of the cancelled durable nonce transactions, whose
+///   tombstones are kept until the nonce advances
+///
+/// Every change spanning several keys is one script or MULTI/EXEC transaction, so instances never
+/// see it half applied.
+struct SharedStore {
+    client: Client,
+    /// opened on first use, then reconnects by itself
+    connection: OnceCell<ConnectionManager>,
+    scripts: Scripts,
+    prefix: String,
+    /// this instance's id, the owner of the leases it holds
+    owner: String,
+    lease: Duration,
+    rpc_client: Arc<RpcClient>,
+    last_nonce_check: RwLock<Instant>,
+    /// the score `{prefix}:queued` was read up to
+    queued_cursor: AtomicU64,
+    /// the score `{prefix}:cancelled` was read up to
+    cancelled_cursor: AtomicU64,
+}
+
+fn store_error(e: RedisError) -> AtlasTxnSenderError {
+    format!("shared store error: {e}").into()
+}
+
+impl SharedStore {
+    fn new(
+        url: &str,
+        prefix: String,
+        owner: String,
+        lease: Duration,
+        rpc_client: Arc<RpcClient>,
+    ) -> Result<Self, AtlasTxnSenderError> {
+        let client =
+            Client::open(url).map_err(|e| format!("unsupported shared store url {url}: {e}"))?;
+        Ok(Self {
+            client,
+            connection: OnceCell::new(),
+            scripts: Scripts::new(),
+            prefix,
+            owner,
+            lease,
+            rpc_client,
+            last_nonce_check: RwLock::new(Instant::now() - NONCE_CHECK_INTERVAL),
+            queued_cursor: AtomicU64::new(0),
+            cancelled_cursor: AtomicU64::new(0),
+        })
+    }
+
+    async fn connection(&self) -> Result<ConnectionManager, AtlasTxnSenderError> {
+        let connection = self
+            .connection
+            .get_or_try_init(|| {
+                let config = ConnectionManagerConfig::new()
+                    .set_connection_timeout(COMMAND_TIMEOUT)
+                    .set_response_timeout(COMMAND_TIMEOUT);
+                ConnectionManager::new_with_config(self.client.clone(), config)
+            })
+            .await
+            .map_err(|e| format!("Error connecting to shared store: {e}"))?;
+        Ok(connection.clone())
+    }
+
+    fn leases_key(&self, kind: LeaseKind) -> String {
+        match kind {
+            LeaseKind::Queued => format!("{}:leases", self.prefix),
+            LeaseKind::Scheduled => format!("{}:scheduled_leases", self.prefix),
+        }
+    }
+
+    fn owners_key(&self, kind: LeaseKind) -> String {
+        match kind {
+            LeaseKind::Queued => format!("{}:owners", self.prefix),
+            LeaseKind::Scheduled => format!("{}:scheduled_owners", self.prefix),
+        }
+    }
+
+    fn queued_key(&self) -> String {
+        format!("{}:queued", self.prefix)
+    }
+
+    fn transaction_key(&self, kind: LeaseKind, signature: &str) -> String {
+        match kind {
+            LeaseKind::Queued => format!("{}:txn:{}", self.prefix, signature),
+            LeaseKind::Scheduled => format!("{}:scheduled_txn:{}", self.prefix, signature),
+        }
+    }
+
+    fn history_key(&self, signature: &str) -> String {
+        format!("{}:history:{}", self.prefix, signature)
+    }
+
+    fn cancelled_key(&self) -> String {
+        format!("{}:cancelled", self.prefix)
+    }
+
+    fn cancelled_nonces_key(&self) -> String {
+        format!("{}:cancelled_nonces", self.prefix)
+    }
+
+    /// claim takes the leases of transactions new to the shared store and writes them, returning
+    /// whether this instance holds each lease
+    async fn claim(
+        &self,
+        kind: LeaseKind,
+        transactions: &[(String, String)],
+    ) -> Result<Vec<bool>, AtlasTxnSenderError> {
+        let mut connection = self.connection().await?;
+        let mut claimed = Vec::with_capacity(transactions.len());
+        for batch in transactions.chunks(BATCH_SIZE) {
+            let mut invocation = self.scripts.claim.prepare_invoke();
+            invocation
+                .key(self.leases_key(kind))
+                .key(self.owners_key(kind))
+                .key(self.queued_key())
+                .key(self.cancelled_key())
+                .key(self.leases_key(kind.other()))
+                .key(self.owners_key(kind.other()))
+                .arg(&self.owner)
+                .arg(self.lease.as_millis() as u64)
+                .arg(TRANSACTION_TTL.as_millis() as u64);
+            for (signature, stored) in batch {
+                invocation
+                    .key(self.transaction_key(kind, signature))
+                    .arg(signature)
+                    .arg(stored);
+            }
+            let batch_claimed: Vec<bool> = invocation
+                .invoke_async(&mut connection)
+                .await
+                .map_err(store_error)?;
+            claimed.extend(batch_claimed);
+        }
+        Ok(claimed)
+    }
+
+    /// queue_pending claims the leases of pending transactions and writes them to the shared store.
+    /// Only then they are moved to the local queue and retried, so no other instance retries them too.
+    async fn queue_pending(
+        &self,
+        signatures: &[String],
+        local: &LocalQueue,
+    ) -> Result<(), AtlasTxnSenderError> {
+        let pending = stored_transactions(signatures, &local.pending);
+        let claimed = self.claim(LeaseKind::Queued, &pending).await?;
+        for ((signature, _), claimed) in pending.iter().zip(claimed) {
+            if !claimed {
+                // another instance was sent the same transaction first and retries it
+                local.pending.remove(signature);
+                statsd_count!("shared_store_claim_lost", 1);
+                continue;
+            }
+            // the pending entry stays locked until the transaction is queued, so a concurrent
+            // removal finds it in one of them
+            if let Entry::Occupied(entry) = local.pending.entry(signature.clone()) {
+                local.transactions.add_transaction(entry.get().clone());
+                entry.remove();
+            }
+            if !local.transactions.has_signature(signature) {
+                // cancelled while the lease was claimed
+                self.release(LeaseKind::Queued, signature).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// schedule claims the leases of transactions handed to this instance's scheduler. The ones
+    /// another instance scheduled first are dropped, so its scheduler is the only one to send them.
+    async fn schedule(
+        &self,
+        signatures: &[String],
+        local: &LocalQueue,
+    ) -> Result<(), AtlasTxnSenderError> {
+        let scheduled = stored_transactions(signatures, &local.scheduled);
+        let claimed = self.claim(LeaseKind::Scheduled, &scheduled).await?;
+        for ((signature, _), claimed) in scheduled.iter().zip(claimed) {
+            if !claimed {
+                local.scheduled.remove(signature);
+                statsd_count!("shared_store_claim_lost", 1, "kind" => "scheduled");
+            } else if !local.scheduled.contains_key(signature) {
+                // released or cancelled while the lease was claimed
+                self.release(LeaseKind::Scheduled, signature).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// release removes the transaction from the queue or the schedule if this instance holds its
+    /// lease
+    async fn release(&self, kind: LeaseKind, signature: &str) -> Result<(), AtlasTxnSenderError> {
+        let mut connection = self.connection().await?;
+        self.scripts
+            .release
+            .key(self.leases_key(kind))
+            .key(self.owners_key(kind))
+            .key(self.transaction_key(kind, signature))
+            .arg(&self.owner)
+            .arg(signature)
+            .invoke_async::<()>(&mut connection)
+            .await
+            .map_err(store_error)
+    }
+
+    async fn apply(
+        &self,
+        op: SharedStoreOp,
+        local: &LocalQueue,
+    ) -> Result<(), AtlasTxnSenderError> {
+        match op {
+            SharedStoreOp::Add { signature } => {
+                self.queue_pending(&[signature], local).await?;
+            }
+            SharedStoreOp::Remove { signature } => {
+                self.release(LeaseKind::Queued, &signature).await?;
+            }
+            SharedStoreOp::Schedule { signature } => {
+                self.schedule(&[signature], local).await?;
+            }
+            SharedStoreOp::Unschedule { signature } => {
+                self.release(LeaseKind::Scheduled, &signature).await?;
+            }
+            SharedStoreOp::Cancel { signature, nonce } => {
+                let mut connection = self.connection().await?;
+                let mut invocation = self.scripts.cancel.prepare_invoke();
+                invocation
+                    .key(self.cancelled_key())
+                    .key(self.cancelled_nonces_key())
+                    .key(self.queued_key());
+                for kind in [LeaseKind::Queued, LeaseKind::Scheduled] {
+                    invocation
+                        .key(self.leases_key(kind))
+                        .key(self.owners_key(kind))
+                        .key(self.transaction_key(kind, &signature));
+                }
+                invocation
+                    .arg(&signature)
+                    .arg(nonce.map(String::from).unwrap_or_default())
+                    .invoke_async::<()>(&mut connection)
+                    .await
+                    .map_err(store_error)?;
+            }
+            SharedStoreOp::Event { signature, event } => {
+                let event = serde_json::to_string(&event)
+                    .map_err(|e| format!("Error serializing event: {e}"))?;
+                let history_key = self.history_key(&signature);
+                let mut connection = self.connection().await?;
+                redis::pipe()
+                    .atomic()
+                    .rpush(&history_key, event)
+                    .pexpire(&history_key, TRANSACTION_TTL.as_millis() as i64)
+                    .query_async::<()>(&mut connection)
+                    .await
+                    .map_err(store_error)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// fetch_transaction returns the transaction as last written by the instance retrying or
+    /// scheduling it
+    async fn fetch_transaction(
+        &self,
+        signature: &str,
+    ) -> Result<Option<TransactionData>, AtlasTxnSenderError> {
+        let mut connection = self.connection().await?;
+        let (queued, scheduled): (Option<String>, Option<String>) = redis::cmd("MGET")
+            .arg(self.transaction_key(LeaseKind::Queued, signature))
+            .arg(self.transaction_key(LeaseKind::Scheduled, signature))
+            .query_async(&mut connection)
+            .await
+            .map_err(store_error)?;
+        Ok(queued
+            .or(scheduled)
+            .and_then(|stored| serde_json::from_str::<StoredTransaction>(&stored).ok())
+            .and_then(|stored| stored.into_transaction_data().ok()))
+    }
+
+    /// fetch_history returns the state changes of the transaction recorded by any instance
+    async fn fetch_history(
+        &self,
+        signature: &str,
+    ) -> Result<Vec<LifecycleEvent>, AtlasTxnSenderError> {
+        let mut connection = self.connection().await?;
+        let history: Vec<String> = connection
+            .lrange(self.history_key(signature), 0, -1)
+            .await
+            .map_err(store_error)?;
+        Ok(history
+            .into_iter()
+            .filter_map(|event| serde_json::from_str(&event).ok())
+            .collect())
+    }
+
+    /// read_since returns the members of a sorted set scored from `cursor` on, a page at a time, and
+    /// moves the cursor to the highest score read. The members with that score are read again by
+    /// the next call, so one added in the same millisecond isn't missed.
+    async fn read_since(
+        &self,
+        key: &str,
+        cursor: &AtomicU64,
+    ) -> Result<Vec<String>, AtlasTxnSenderError> {
+        let mut connection = self.connection().await?;
+        let from = cursor.load(Ordering::Relaxed);
+        let mut members: Vec<(String, u64)> = vec![];
+        loop {
+            let page: Vec<(String, u64)> = connection
+                .zrangebyscore_limit_withscores(
+                    key,
+                    from,
+                    "+inf",
+                    members.len() as isize,
+                    BATCH_SIZE as isize,
+                )
+                .await
+                .map_err(store_error)?;
+            let last_page = page.len() < BATCH_SIZE;
+            members.extend(page);
+            if last_page {
+                break;
+            }
+        }
+        if let Some(to) = members.iter().map(|(_, score)| *score).max() {
+            cursor.store(to, Ordering::Relaxed);
+        }
+        Ok(members.into_iter().map(|(member, _)| member).collect())
+    }
+
+    /// release_tombstones drops the cancellations past the retention, except the ones of durable
+    /// nonce transactions whose nonce hasn't advanced
+    async fn release_tombstones(&self) -> Result<(), AtlasTxnSenderError> {
+        if self.last_nonce_check.read().unwrap().elapsed() < NONCE_CHECK_INTERVAL {
+            return Ok(());
+        }
+        *self.last_nonce_check.write().unwrap() = Instant::now();
+        let retention_start =
+            unix_millis_now().saturating_sub(TOMBSTONE_RETENTION.as_millis() as u64);
+        let mut connection = self.connection().await?;
+        let expired: Vec<String> = connection
+            .zrangebyscore_limit(
+                self.cancelled_key(),
+                "-inf",
+                retention_start,
+                0,
+                BATCH_SIZE as isize,
+            )
+            .await
+            .map_err(store_error)?;
+        if expired.is_empty() {
+            return Ok(());
+        }
+        let nonces: Vec<Option<String>> = redis::cmd("HMGET")
+            .arg(self.cancelled_nonces_key())
+            .arg(&expired)
+            .query_async(&mut connection)
+            .await
+            .map_err(store_error)?;
+        let nonces: Vec<Option<DurableNonce>> = nonces
+            .into_iter()
+            .map(|nonce| nonce.and_then(|nonce| DurableNonce::try_from(nonce).ok()))
+            .collect();
+        let held: Vec<DurableNonce> = nonces.iter().flatten().copied().collect();
+        let advanced = if held.is_empty() {
+            Default::default()
+        } else {
+            let rpc_client = self.rpc_client.clone();
+            tokio::task::spawn_blocking(move || advanced_nonces(&rpc_client, &held))
+                .await
+                .map_err(|e| format!("Error checking nonces of tombstones: {e}"))?
+        };
+        let released: Vec<&String> = expired
+            .iter()
+            .zip(nonces)
+            .filter(|(_, nonce)| nonce.is_none_or(|nonce| advanced.contains(&nonce)))
+            .map(|(signature, _)| signature)
+            .collect();
+        if released.is_empty() {
+            return Ok(());
+        }
+        redis::pipe()
+            .atomic()
+            .zrem(self.cancelled_key(), &released)
+            .hdel(self.cancelled_nonces_key(), &released)
+            .query_async::<()>(&mut connection)
+            .await
+            .map_err(store_error)
+    }
+
+    /// sync_cancellations applies the cancellations made on any instance since the last sync
+    async fn sync_cancellations(&self, local: &LocalQueue) -> Result<(), AtlasTxnSenderError> {
+        let cancelled = self
+            .read_since(&self.cancelled_key(), &self.cancelled_cursor)
+            .await?;
+        let cancelled: Vec<String> = cancelled
+            .into_iter()
+            .filter(|signature| !local.transactions.is_cancelled(signature))
+            .collect();
+        let mut connection = self.connection().await?;
+        for batch in cancelled.chunks(BATCH_SIZE) {
+            let nonces: Vec<Option<String>> = redis::cmd("HMGET")
+                .arg(self.cancelled_nonces_key())
+                .arg(batch)
+                .query_async(&mut connection)
+                .await
+                .map_err(store_error)?;
+            let mut shared_signatures = local.shared_signatures.write().unwrap();
+            for (signature, nonce) in batch.iter().zip(nonces) {
+                local.pending.remove(signature);
+                local.scheduled.remove(signature);
+                local.transactions.remove_transaction(signature.clone());
+                shared_signatures.remove(signature);
+                // a durable nonce transaction stays cancelled here until its nonce advances, like
+                // on the instance it was cancelled on
+                local.transactions.restore_tombstone(
+                    signature.clone(),
+                    Tombstone {
+                        cancelled_at: Instant::now(),
+                        nonce: nonce.and_then(|nonce| DurableNonce::try_from(nonce).ok()),
+                    },
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// renew renews the leases of the transactions this instance retries or schedules and writes
+    /// them back, returning the signatures whose lease was lost
+    async fn renew(
+        &self,
+        kind: LeaseKind,
+        owned: &[(String, String)],
+    ) -> Result<Vec<String>, AtlasTxnSenderError> {
+        let mut connection = self.connection().await?;
+        let mut lost = vec![];
+        for batch in owned.chunks(BATCH_SIZE) {
+            let mut invocation = self.scripts.renew.prepare_invoke();
+            invocation
+                .key(self.leases_key(kind))
+                .key(self.owners_key(kind))
+                .arg(&self.owner)
+                .arg(self.lease.as_millis() as u64)
+                .arg(TRANSACTION_TTL.as_millis() as u64);
+            for (signature, stored) in batch {
+                invocation
+                    .key(self.transaction_key(kind, signature))
+                    .arg(signature)
+                    .arg(stored);
+            }
+            let batch_lost: Vec<String> = invocation
+                .invoke_async(&mut connection)
+                .await
+                .map_err(store_error)?;
+            lost.extend(batch_lost);
+        }
+        Ok(lost)
+    }
+
+    /// take_over claims a batch of expired leases of transactions left behind by other instances,
+    /// skipping the signatures in `held`
+    async fn take_over(
+        &self,
+        kind: LeaseKind,
+        held: impl Fn(&str) -> bool,
+    ) -> Result<Vec<TransactionData>, AtlasTxnSenderError> {
+        let mut connection = self.connection().await?;
+        let expired: Vec<String> = connection
+            .zrangebyscore_limit(
+                self.leases_key(kind),
+                "-inf",
+                unix_millis_now(),
+                0,
+                BATCH_SIZE as isize,
+            )
+            .await
+            .map_err(store_error)?;
+        let expired: Vec<String> = expired
+            .into_iter()
+            .filter(|signature| !held(signature))
+            .collect();
+        if expired.is_empty() {
+            return Ok(vec![]);
+        }
+        let mut invocation = self.scripts.takeover.prepare_invoke();
+        invocation
+            .key(self.leases_key(kind))
+            .key(self.owners_key(kind))
+            .key(self.queued_key())
+            .arg(&self.owner)
+            .arg(self.lease.as_millis() as u64);
+        for signature in &expired {
+            invocation
+                .key(self.transaction_key(kind, signature))
+                .arg(signature);
+        }
+        let taken: Vec<(String, String)> = invocation
+            .invoke_async(&mut connection)
+            .await
+            .map_err(store_error)?;
+        let mut transactions = vec![];
+        for (signature, stored) in taken {
+            let transaction = serde_json::from_str::<StoredTransaction>(&stored)
+                .map_err(|e| AtlasTxnSenderError::from(e.to_string()))
+                .and_then(StoredTransaction::into_transaction_data);
+            match transaction {
+                Ok(transaction) => {
+                    info!("took over {} transaction {}", kind.as_str(), signature);
+                    statsd_count!("shared_store_takeover", 1, "kind" => kind.as_str());
+                    transactions.push(transaction);
+                }
+                Err(e) => {
+                    error!("Error taking over transaction {}: {}", signature, e);
+                    self.release(kind, &signature).await?;
+                }
+            }
+        }
+        Ok(transactions)
+    }
+
+    /// sync mirrors the signatures queued and cancelled on every instance since the last sync,
+    /// renews the leases of the transactions this instance retries and takes over the transactions
+    /// whose lease expired. Only changes and this instance's own transactions are read, so a sync
+    /// costs the same however long the shared queue is.
+    async fn sync(&self, local: &LocalQueue) -> Result<(), AtlasTxnSenderError> {
+        let mut connection = self.connection().await?;
+        let queue_length: u64 = connection
+            .zcard(self.leases_key(LeaseKind::Queued))
+            .await
+            .map_err(store_error)?;
+        statsd_gauge!("shared_store_queue_length", queue_length);
+
+        // the signatures queued longer ago are forgotten by every instance
+        let _: () = connection
+            .zrembyscore(
+                self.queued_key(),
+                "-inf",
+                unix_millis_now().saturating_sub(TRANSACTION_TTL.as_millis() as u64),
+            )
+            .await
+            .map_err(store_error)?;
+        let queued = self
+            .read_since(&self.queued_key(), &self.queued_cursor)
+            .await?;
+        {
+            let mut shared_signatures = local.shared_signatures.write().unwrap();
+            shared_signatures.retain(|_, seen_at| seen_at.elapsed() < TRANSACTION_TTL);
+            for signature in queued {
+                shared_signatures
+                    .entry(signature)
+                    .or_insert_with(Instant::now);
+            }
+        }
+
+        self.release_tombstones().await?;
+        self.sync_cancellations(local).await?;
+
+        // claims that failed, e.g. while the shared store was unreachable
+        let pending: Vec<String> = local.pending.iter().map(|t| t.key().to_owned()).collect();
+        self.queue_pending(&pending, local).await?;
+
+        let owned = stored_transactions_of(&local.transactions.get_transactions());
+        for signature in self.renew(LeaseKind::Queued, &owned).await? {
+            local.transactions.remove_transaction(signature);
+            statsd_count!("shared_store_lease_lost", 1);
+        }
+        let scheduled = stored_transactions_of(&local.scheduled);
+        for signature in self.renew(LeaseKind::Scheduled, &scheduled).await? {
+            local.scheduled.remove(&signature);
+            statsd_count!("shared_store_lease_lost", 1, "kind" => "scheduled");
+        }
+
+        let held = |signature: &str| {
+            local.transactions.has_signature(signature)
+                || local.pending.contains_key(signature)
+                || local.scheduled.contains_key(signature)
+                || local.transactions.is_cancelled(signature)
+        };
+        for transaction in self.take_over(LeaseKind::Queued, held).await? {
+            local.transactions.add_transaction(transaction.clone());
+            local.adopted.lock().unwrap().push(transaction);
+        }
+        for transaction in self.take_over(LeaseKind::Scheduled, held).await? {
+            if let Some(signature) = get_signature(&transaction) {
+                local.scheduled.insert(signature, transaction.clone());
+                local.adopted_scheduled.lock().unwrap().push(transaction);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// stored_transactions serializes the transactions of `signatures` still in `transactions`
+fn stored_transactions(
+    signatures: &[String],
+    transactions: &DashMap<String, TransactionData>,
+) -> Vec<(String, String)> {
+    signatures
+        .iter()
+        // cancelled, replaced, released or confirmed in the meantime otherwise
+        .filter_map(|signature| {
+            let transaction = transactions.get(signature)?;
+            serde_json::to_string(&StoredTransaction::new(&transaction))
+                .ok()
+                .map(|stored| (signature.clone(), stored))
+        })
+        .collect()
+}
+
+fn stored_transactions_of(
+    transactions: &DashMap<String, TransactionData>,
+) -> Vec<(String, String)> {
+    transactions
+        .iter()
+        .filter_map(|transaction| {
+            serde_json::to_string(&StoredTransaction::new(transaction.value()))
+                .ok()
+                .map(|stored| (transaction.key().to_owned(), stored))
+        })
+        .collect()
+}
+
+/// SharedTransactionStore lets several instances share one queue. Each instance keeps the
+/// transactions it holds the lease of in memory and retries only those, so every signature is
+/// retried by exactly one instance. A transaction is retried only once its lease is claimed, and
+/// leases are renewed every second together with the transaction's retry count and history; when
+/// an instance stops, its transactions are taken over by the others once their leases expire.
+/// Changes are written to the shared store in the background, so an instance learns of the
+/// signatures queued on the others within a second.
+pub struct SharedTransactionStore {
+    local: Arc<LocalQueue>,
+    shared_store: Arc<SharedStore>,
+    op_sender: mpsc::UnboundedSender<SharedStoreOp>,
+}
+
+impl SharedTransactionStore {
+    pub fn new(
+        url: &str,
+        prefix: String,
+        owner: String,
+        lease: Duration,
+        rpc_client: Arc<RpcClient>,
+    ) -> Result<Self, AtlasTxnSenderError> {
+        let shared_store = Arc::new(SharedStore::new(
+            url,
+            prefix,
+            owner,
+            lease,
+            rpc_client.clone(),
+        )?);
+        let (op_sender, op_receiver) = mpsc::unbounded_channel();
+        let shared_transaction_store = Self {
+            local: Arc::new(LocalQueue::new(rpc_client)),
+            shared_store,
+            op_sender,
+        };
+        shared_transaction_store.apply_ops(op_receiver);
+        shared_transaction_store.sync();
+        Ok(shared_transaction_store)
+    }
+
+    fn apply_ops(&self, mut op_receiver: mpsc::UnboundedReceiver<SharedStoreOp>) {
+        let shared_store = self.shared_store.clone();
+        let local = self.local.clone();
+        tokio::spawn(async move {
+            while let Some(op) = op_receiver.recv().await {
+                if let Err(e) = shared_store.apply(op, &local).await {
+                    error!("Error updating shared store: {}", e);
+                    statsd_count!("shared_store_error", 1, "op" => "update");
+                }
+            }
+        });
+    }
+
+    fn sync(&self) {
+        let shared_store = self.shared_store.clone();
+        let local = self.local.clone();
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = shared_store.sync(&local).await {
+                    error!("Error syncing shared store: {}", e);
+                    statsd_count!("shared_store_error", 1, "op" => "sync");
+                }
+                sleep(SYNC_INTERVAL).await;
+            }
+        });
+    }
+
+    fn send_op(&self, op: SharedStoreOp) {
+        if self.op_sender.send(op).is_err() {
+            error!("shared store writer stopped");
+            statsd_count!("shared_store_error", 1, "op" => "update");
+        }
+    }
+
+    fn cancelled_nonce(&self, signature: &str) -> Option<DurableNonce> {
+        self.local
+            .transactions
+            .get_tombstone(signature)
+            .and_then(|tombstone| tombstone.nonce)
+    }
+}
+
+#[async_trait]
+impl TransactionStore for SharedTransactionStore {
+    /// add_transaction keeps the transaction pending until its lease is claimed. A transaction
+    /// released by this instance's scheduler is already known to the others, and is queued anyway.
+    fn add_transaction(&self, transaction: TransactionData) {
+        let Some(signature) = get_signature(&transaction) else {
+            return self.local.transactions.add_transaction(transaction);
+        };
+        let queued = if self.local.scheduled.contains_key(&signature) {
+            self.local.transactions.has_signature(&signature)
+                || self.local.pending.contains_key(&signature)
+        } else {
+            self.has_signature(&signature)
+        };
+        if queued {
+            return;
+        }
+        if self.is_cancelled(&signature) {
+            statsd_count!("cancelled_transaction_resubmitted", 1);
+            return;
+        }
+        if let Entry::Vacant(entry) = self.local.pending.entry(signature.clone()) {
+            entry.insert(transaction);
+            self.send_op(SharedStoreOp::Add { signature });
+        }
+    }
+    fn remove_transaction(&self, signature: String) -> Option<TransactionData> {
+        let transaction = self
+            .local
+            .pending
+            .remove(&signature)
+            .map(|(_, transaction)| transaction)
+            .or_else(|| {
+                self.local
+                    .transactions
+                    .remove_transaction(signature.clone())
+            });
+        if transaction.is_some() {
+            self.send_op(SharedStoreOp::Remove { signature });
+        }
+        transaction
+    }
+    /// get_transactions only returns the transactions this instance retries
+    fn get_transactions(&self) -> Arc<DashMap<String, TransactionData>> {
+        self.local.transactions.get_transactions()
+    }
+    fn has_signature(&self, signature: &str) -> bool {
+        self.local.transactions.has_signature(signature)
+            || self.local.pending.contains_key(signature)
+            || self
+                .local
+                .shared_signatures
+                .read()
+                .unwrap()
+                .contains_key(signature)
+    }
+    /// get_fee_payers only returns the fee payers of the transactions queued on this instance
+    fn get_fee_payers(&self) -> BTreeSet<String> {
+        let mut fee_payers = self.local.transactions.get_fee_payers();
+        fee_payers.extend(
+            self.local
+                .pending
+                .iter()
+                .filter_map(|transaction| get_fee_payer(transaction.value()))
+                .map(|fee_payer| fee_payer.to_string()),
+        );
+        fee_payers
+    }
+    fn record_event(&self, signature: &str, event: LifecycleEvent) {
+        if let Some(mut transaction) = self.local.pending.get_mut(signature) {
+            transaction.history.push(event.clone());
+        }
+        self.local
+            .transactions
+            .record_event(signature, event.clone());
+        self.send_op(SharedStoreOp::Event {
+            signature: signature.to_string(),
+            event,
+        });
+    }
+    fn take_adopted(&self) -> Vec<TransactionData> {
+        std::mem::take(&mut *self.local.adopted.lock().unwrap())
+    }
+    /// schedule_transaction leases the scheduled transaction, so it is sent by one instance and
+    /// taken over by another if this one stops
+    fn schedule_transaction(&self, signature: &str, transaction: &TransactionData) {
+        self.local
+            .scheduled
+            .insert(signature.to_string(), transaction.clone());
+        self.send_op(SharedStoreOp::Schedule {
+            signature: signature.to_string(),
+        });
+    }
+    fn unschedule_transaction(&self, signature: &str) {
+        if self.local.scheduled.remove(signature).is_some() {
+            self.send_op(SharedStoreOp::Unschedule {
+                signature: signature.to_string(),
+            });
+        }
+    }
+    /// take_scheduled returns the scheduled transactions taken over from other instances
+    fn take_scheduled(&self) -> Vec<TransactionData> {
+        std::mem::take(&mut *self.local.adopted_scheduled.lock().unwrap())
+    }
+    fn holds_scheduled(&self, signature: &str) -> bool {
+        self.local.scheduled.contains_key(signature)
+    }
+    /// get_api_key also finds the transactions queued on other instances
+    async fn get_api_key(&self, signature: &str) -> Option<String> {
+        if let Some(transaction) = self.local.pending.get(signature) {
+            return Some(transaction.request_metadata.api_key.clone());
+        }
+        if let Some(transaction) = self.get_transactions().get(signature) {
+            return Some(transaction.request_metadata.api_key.clone());
+        }
+        match self.shared_store.fetch_transaction(signature).await {
+            Ok(transaction) => transaction.map(|transaction| transaction.request_metadata.api_key),
+            Err(e) => {
+                error!("Error fetching transaction from shared store: {}", e);
+                statsd_count!("shared_store_error", 1, "op" => "fetch");
+                None
+            }
+        }
+    }
+    async fn fetch_history(&self, signature: &str) -> Option<Vec<LifecycleEvent>> {
+        match self.shared_store.fetch_history(signature).await {
+            Ok(history) => Some(history).filter(|history| !history.is_empty()),
+            Err(e) => {
+                error!("Error fetching history from shared store: {}", e);
+                statsd_count!("shared_store_error", 1, "op" => "history");
+                None
+            }
+        }
+    }
+    /// cancel_transaction also cancels transactions queued on other instances, but only returns the
+    /// ones queued on this one
+    fn cancel_transaction(
+        &self,
+        signature: &str,
+        held: Option<&TransactionData>,
+    ) -> Option<TransactionData> {
+        let pending = self
+            .local
+            .pending
+            .remove(signature)
+            .map(|(_, transaction)| transaction);
+        let transaction = self
+            .local
+            .transactions
+            .cancel_transaction(signature, pending.as_ref().or(held))
+            .or(pending);
+        self.send_op(SharedStoreOp::Cancel {
+            signature: signature.to_string(),
+            nonce: self.cancelled_nonce(signature),
+        });
+        transaction
+    }
+    fn is_cancelled(&self, signature: &str) -> bool {
+        self.local.transactions.is_cancelled(signature)
+    }
+    /// replace_transaction queues the replacement like a new transaction, retried once its lease is
+    /// claimed
+    fn replace_transaction(
+        &self,
+        signature: &str,
+        mut transaction: TransactionData,
+    ) -> Result<TransactionData, AtlasTxnSenderError> {
+        let new_signature = get_signature(&transaction).ok_or_else(|| {
+            AtlasTxnSenderError::from("Transaction has no signatures".to_string())
+        })?;
+        if self.has_signature(&new_signature) || self.is_cancelled(&new_signature) {
+            return Err(format!("Transaction {new_signature} was already submitted").into());
+        }
+        let queued = self
+            .local
+            .pending
+            .get(signature)
+            .map(|original| original.clone())
+            .or_else(|| {
+                self.get_transactions()
+                    .get(signature)
+                    .map(|original| original.clone())
+            });
+        if let Some(original) = &queued {
+            check_replacement(original, &transaction)?;
+        }
+        let original = match self.local.pending.remove(signature) {
+            Some((_, original)) => {
+                self.local
+                    .transactions
+                    .restore_tombstone(signature.to_string(), Tombstone::new(Some(&original)));
+                original
+            }
+            None => match self.local.transactions.tombstone_transaction(signature) {
+                Some(original) => original,
+                None if self.has_signature(signature) => {
+                    return Err(
+                        format!("Transaction {signature} is queued on another instance").into(),
+                    );
+                }
+                None => return Err(format!("Transaction {signature} is not queued").into()),
+            },
+        };
+        transaction.request_metadata = original.request_metadata.clone();
+        transaction.max_retries = original.max_retries;
+        self.local
+            .pending
+            .entry(new_signature.clone())
+            .or_insert_with(|| transaction.clone());
+        statsd_count!("transaction_replaced", 1);
+        self.send_op(SharedStoreOp::Cancel {
+            signature: signature.to_string(),
+            nonce: self.cancelled_nonce(signature),
+        });
+        self.send_op(SharedStoreOp::Add {
+            signature: new_signature,
+        });
+        Ok(transaction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use solana_sdk::{
+        hash::Hash, pubkey::Pubkey, signature::Keypair, system_transaction,
+        transaction::VersionedTransaction,
+    };
+
+    use super::*;
+    use crate::{
+        lifecycle::TransactionState, priority::TransactionPriority, rpc_server::RequestMetadata,
+        test_utils::init_metrics,
+    };
+
+    // these tests run the scripts on a real server, see tests/README.md:
+    // REDIS_URL=redis://127.0.0.1:6379 cargo test --bin atlas_txn_sender shared_store -- --ignored
+    fn redis_url() -> String {
+        std::env::var("REDIS_URL").unwrap_or("redis://127.0.0.1:6379".to_string())
+    }
+
+    /// prefix keeps the keys of every test apart, so the tests can share one server
+    fn prefix() -> String {
+        format!("test-{}", uuid::Uuid::new_v4())
+    }
+
+    fn rpc_client() -> Arc<RpcClient> {
+        Arc::new(RpcClient::new("http://127.0.0.1:1".to_string()))
+    }
+
+    fn shared_store(prefix: &str, owner: &str, lease: Duration) -> SharedStore {
+        init_metrics();
+        SharedStore::new(
+            &redis_url(),
+            prefix.to_string(),
+            owner.to_string(),
+            lease,
+            rpc_client(),
+        )
+        .unwrap()
+    }
+
+    async fn get(store: &SharedStore, key: String) -> Option<String> {
+        store.connection().await.unwrap().get(key).await.unwrap()
+    }
+
+    async fn owner(store: &SharedStore, signature: &str) -> Option<String> {
+        let mut connection = store.connection().await.unwrap();
+        connection
+            .hget(store.owners_key(LeaseKind::Queued), signature)
+            .await
+            .unwrap()
+    }
+
+    async fn is_queued(store: &SharedStore, signature: &str) -> bool {
+        let mut connection = store.connection().await.unwrap();
+        let expiry: Option<u64> = connection
+            .zscore(store.leases_key(LeaseKind::Queued), signature)
+            .await
+            .unwrap();
+        expiry.is_some()
+    }
+
+    fn transfer() -> TransactionData {
+        let versioned_transaction = VersionedTransaction::from(system_transaction::transfer(
+            &Keypair::new(),
+            &Pubkey::new_unique(),
+            1,
+            Hash::new_unique(),
+        ));
+        TransactionData {
+            wire_transaction: bincode::serialize(&versioned_transaction).unwrap(),
+            versioned_transaction,
+            sent_at: Instant::now(),
+            retry_count: 0,
+            max_retries: 5,
+            priority: TransactionPriority::default(),
+            request_metadata: RequestMetadata::default(),
+            history: vec![],
+        }
+    }
+
+    fn retried(retry: usize) -> LifecycleEvent {
+        LifecycleEvent {
+            state: TransactionState::Retried { retry },
+            at: unix_millis_now(),
+            details: None,
+        }
+    }
+
+    async fn queue(store: &SharedStore, local: &LocalQueue, transaction: &TransactionData) {
+        let signature = get_signature(transaction).unwrap();
+        local.pending.insert(signature.clone(), transaction.clone());
+        store.queue_pending(&[signature], local).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a redis-server at REDIS_URL"]
+    async fn claims_lease_before_retrying() {
+        let prefix = prefix();
+        let (first, second) = (
+            shared_store(&prefix, "first", Duration::from_secs(10)),
+            shared_store(&prefix, "second", Duration::from_secs(10)),
+        );
+        let (first_queue, second_queue) =
+            (LocalQueue::new(rpc_client()), LocalQueue::new(rpc_client()));
+        let transaction = transfer();
+        let signature = get_signature(&transaction).unwrap();
+
+        first_queue
+            .pending
+            .insert(signature.clone(), transaction.clone());
+        assert!(!first_queue.transactions.has_signature(&signature));
+        first
+            .queue_pending(&[signature.clone()], &first_queue)
+            .await
+            .unwrap();
+        assert!(first_queue.transactions.has_signature(&signature));
+        assert!(first_queue.pending.is_empty());
+        assert_eq!(owner(&first, &signature).await.unwrap(), "first");
+        assert!(is_queued(&first, &signature).await);
+        assert!(
+            get(&first, first.transaction_key(LeaseKind::Queued, &signature))
+                .await
+                .is_some()
+        );
+
+        // sent to the second instance before it learned of the first
+        queue(&second, &second_queue, &transaction).await;
+        assert!(!second_queue.transactions.has_signature(&signature));
+        assert!(second_queue.pending.is_empty());
+        assert_eq!(owner(&first, &signature).await.unwrap(), "first");
+
+        // the second instance learns of the signature on its next sync
+        second.sync(&second_queue).await.unwrap();
+        assert!(second_queue
+            .shared_signatures
+            .read()
+            .unwrap()
+            .contains_key(&signature));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a redis-server at REDIS_URL"]
+    async fn takes_over_with_retry_count() {
+        let prefix = prefix();
+        let lease = Duration::from_millis(200);
+        let (first, second) = (
+            shared_store(&prefix, "first", lease),
+            shared_store(&prefix, "second", lease),
+        );
+        let (first_queue, second_queue) =
+            (LocalQueue::new(rpc_client()), LocalQueue::new(rpc_client()));
+        let transaction = transfer();
+        let signature = get_signature(&transaction).unwrap();
+        queue(&first, &first_queue, &transaction).await;
+
+        if let Some(mut queued) = first_queue
+            .transactions
+            .get_transactions()
+            .get_mut(&signature)
+        {
+            queued.retry_count = 3;
+            queued.history.push(retried(3));
+        }
+        first.sync(&first_queue).await.unwrap();
+        let stored: StoredTransaction = serde_json::from_str(
+            &get(&first, first.transaction_key(LeaseKind::Queued, &signature))
+                .await
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(stored.into_transaction_data().unwrap().retry_count, 3);
+
+        // the lease is held, so the second instance doesn't take it
+        second.sync(&second_queue).await.unwrap();
+        assert!(!second_queue.transactions.has_signature(&signature));
+
+        // the first instance stops renewing
+        sleep(lease * 2).await;
+        second.sync(&second_queue).await.unwrap();
+        let taken_over = second_queue
+            .transactions
+            .get_transactions()
+            .get(&signature)
+            .map(|t| t.clone())
+            .unwrap();
+        assert_eq!(taken_over.retry_count, 3);
+        assert_eq!(taken_over.history.len(), 1);
+        assert_eq!(second_queue.adopted.lock().unwrap().len(), 1);
+        assert_eq!(owner(&second, &signature).await.unwrap(), "second");
+
+        // the first instance learns it lost the lease when renewing it
+        first.sync(&first_queue).await.unwrap();
+        assert!(!first_queue.transactions.has_signature(&signature));
+        assert_eq!(owner(&second, &signature).await.unwrap(), "second");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a redis-server at REDIS_URL"]
+    async fn expired_lease_is_renewed_until_taken_over() {
+        let prefix = prefix();
+        let lease = Duration::from_millis(200);
+        let store = shared_store(&prefix, "first", lease);
+        let local = LocalQueue::new(rpc_client());
+        let transaction = transfer();
+        let signature = get_signature(&transaction).unwrap();
+        queue(&store, &local, &transaction).await;
+
+        // e.g. the shared store was unreachable for longer than the lease
+        sleep(lease * 2).await;
+        let owned = stored_transactions_of(&local.transactions.get_transactions());
+        let lost = store.renew(LeaseKind::Queued, &owned).await.unwrap();
+        assert!(lost.is_empty());
+        assert_eq!(owner(&store, &signature).await.unwrap(), "first");
+
+        // a released transaction isn't queued again by a renewal
+        store.release(LeaseKind::Queued, &signature).await.unwrap();
+        let lost = store.renew(LeaseKind::Queued, &owned).await.unwrap();
+        assert_eq!(lost, vec![signature.clone()]);
+        assert!(!is_queued(&store, &signature).await);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a redis-server at REDIS_URL"]
+    async fn drops_leases_of_expired_transactions() {
+        let prefix = prefix();
+        let lease = Duration::from_millis(200);
+        let (first, second) = (
+            shared_store(&prefix, "first", lease),
+            shared_store(&prefix, "second", lease),
+        );
+        let (first_queue, second_queue) =
+            (LocalQueue::new(rpc_client()), LocalQueue::new(rpc_client()));
+        let transaction = transfer();
+        let signature = get_signature(&transaction).unwrap();
+        queue(&first, &first_queue, &transaction).await;
+        let mut connection = first.connection().await.unwrap();
+        let _: () = connection
+            .del(first.transaction_key(LeaseKind::Queued, &signature))
+            .await
+            .unwrap();
+
+        sleep(lease * 2).await;
+        second.sync(&second_queue).await.unwrap();
+        assert!(!second_queue.transactions.has_signature(&signature));
+        assert!(!is_queued(&second, &signature).await);
+        assert!(owner(&second, &signature).await.is_none());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a redis-server at REDIS_URL"]
+    async fn releases_and_cancels() {
+        let prefix = prefix();
+        let (first, second) = (
+            shared_store(&prefix, "first", Duration::from_secs(10)),
+            shared_store(&prefix, "second", Duration::from_secs(10)),
+        );
+        let (first_queue, second_queue) =
+            (LocalQueue::new(rpc_client()), LocalQueue::new(rpc_client()));
+        let (released, cancelled) = (transfer(), transfer());
+        queue(&first, &first_queue, &released).await;
+        queue(&first, &first_queue, &cancelled).await;
+        let (released, cancelled) = (
+            get_signature(&released).unwrap(),
+            get_signature(&cancelled).unwrap(),
+        );
+
+        // only the lease holder releases a transaction
+        second
+            .apply(
+                SharedStoreOp::Remove {
+                    signature: released.clone(),
+                },
+                &second_queue,
+            )
+            .await
+            .unwrap();
+        assert!(is_queued(&first, &released).await);
+        first
+            .apply(
+                SharedStoreOp::Remove {
+                    signature: released.clone(),
+                },
+                &first_queue,
+            )
+            .await
+            .unwrap();
+        assert!(!is_queued(&first, &released).await);
+        assert!(owner(&first, &released).await.is_none());
+        assert!(
+            get(&first, first.transaction_key(LeaseKind::Queued, &released))
+                .await
+                .is_none()
+        );
+
+        // cancellations apply on every instance
+        let nonce = DurableNonce {
+            account: Pubkey::new_unique(),
+            nonce: Hash::new_unique(),
+        };
+        second
+            .apply(
+                SharedStoreOp::Cancel {
+                    signature: cancelled.clone(),
+                    nonce: Some(nonce),
+                },
+                &second_queue,
+            )
+            .await
+            .unwrap();
+        assert!(!is_queued(&first, &cancelled).await);
+        assert!(
+            get(&first, first.transaction_key(LeaseKind::Queued, &cancelled))
+                .await
+                .is_none()
+        );
+        first.sync(&first_queue).await.unwrap();
+        assert!(!first_queue.transactions.has_signature(&cancelled));
+        assert_eq!(
+            first_queue
+                .transactions
+                .get_tombstone(&cancelled)
+                .and_then(|tombstone| tombstone.nonce),
+            Some(nonce)
+        );
+
+        // a cancelled transaction can't be claimed again
+        let resubmitted = transfer();
+        let signature = get_signature(&resubmitted).unwrap();
+        second
+            .apply(
+                SharedStoreOp::Cancel {
+                    signature: signature.clone(),
+                    nonce: None,
+                },
+                &second_queue,
+            )
+            .await
+            .unwrap();
+        queue(&second, &second_queue, &resubmitted).await;
+        assert!(!second_queue.transactions.has_signature(&signature));
+        assert!(!is_queued(&second, &signature).await);
+    }
+
+    async fn schedule(store: &SharedStore, local: &LocalQueue, transaction: &TransactionData) {
+        let signature = get_signature(transaction).unwrap();
+        local
+            .scheduled
+            .insert(signature.clone(), transaction.clone());
+        store.schedule(&[signature], local).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a redis-server at REDIS_URL"]
+    async fn schedules_on_one_instance() {
+        let prefix = prefix();
+        let (first, second) = (
+            shared_store(&prefix, "first", Duration::from_secs(10)),
+            shared_store(&prefix, "second", Duration::from_secs(10)),
+        );
+        let (first_queue, second_queue) =
+            (LocalQueue::new(rpc_client()), LocalQueue::new(rpc_client()));
+        let transaction = transfer();
+        let signature = get_signature(&transaction).unwrap();
+        schedule(&first, &first_queue, &transaction).await;
+        assert!(first_queue.scheduled.contains_key(&signature));
+        assert!(get(
+            &first,
+            first.transaction_key(LeaseKind::Scheduled, &signature)
+        )
+        .await
+        .is_some());
+        assert!(first.fetch_transaction(&signature).await.unwrap().is_some());
+
+        // neither scheduled nor queued by another instance
+        schedule(&second, &second_queue, &transaction).await;
+        assert!(second_queue.scheduled.is_empty());
+        queue(&second, &second_queue, &transaction).await;
+        assert!(!second_queue.transactions.has_signature(&signature));
+        second.sync(&second_queue).await.unwrap();
+        assert!(second_queue
+            .shared_signatures
+            .read()
+            .unwrap()
+            .contains_key(&signature));
+
+        // released into the queue of the instance that scheduled it
+        queue(&first, &first_queue, &transaction).await;
+        assert!(first_queue.transactions.has_signature(&signature));
+        first_queue.scheduled.remove(&signature);
+        first
+            .apply(
+                SharedStoreOp::Unschedule {
+                    signature: signature.clone(),
+                },
+                &first_queue,
+            )
+            .await
+            .unwrap();
+        assert!(get(
+            &first,
+            first.transaction_key(LeaseKind::Scheduled, &signature)
+        )
+        .await
+        .is_none());
+        assert_eq!(owner(&first, &signature).await.unwrap(), "first");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a redis-server at REDIS_URL"]
+    async fn takes_over_scheduled_transactions() {
+        let prefix = prefix();
+        let lease = Duration::from_millis(200);
+        let (first, second) = (
+            shared_store(&prefix, "first", lease),
+            shared_store(&prefix, "second", lease),
+        );
+        let (first_queue, second_queue) =
+            (LocalQueue::new(rpc_client()), LocalQueue::new(rpc_client()));
+        let (taken, cancelled) = (transfer(), transfer());
+        schedule(&first, &first_queue, &taken).await;
+        schedule(&first, &first_queue, &cancelled).await;
+        let (taken, cancelled) = (
+            get_signature(&taken).unwrap(),
+            get_signature(&cancelled).unwrap(),
+        );
+
+        // the first instance stops renewing its leases
+        sleep(lease * 2).await;
+        second.sync(&second_queue).await.unwrap();
+        assert!(second_queue.scheduled.contains_key(&taken));
+        assert_eq!(second_queue.adopted_scheduled.lock().unwrap().len(), 2);
+        assert!(!second_queue.transactions.has_signature(&taken));
+
+        // the first instance learns it lost the leases when renewing them
+        first.sync(&first_queue).await.unwrap();
+        assert!(first_queue.scheduled.is_empty());
+
+        second
+            .apply(
+                SharedStoreOp::Cancel {
+                    signature: cancelled.clone(),
+                    nonce: None,
+                },
+                &second_queue,
+            )
+            .await
+            .unwrap();
+        assert!(get(
+            &second,
+            second.transaction_key(LeaseKind::Scheduled, &cancelled)
+        )
+        .await
+        .is_none());
+        second.sync(&second_queue).await.unwrap();
+        assert!(!second_queue.scheduled.contains_key(&cancelled));
+        assert!(second_queue.scheduled.contains_key(&taken));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a redis-server at REDIS_URL"]
+    async fn reads_cancellations_incrementally() {
+        let prefix = prefix();
+        let store = shared_store(&prefix, "first", Duration::from_secs(10));
+        let local = LocalQueue::new(rpc_client());
+        let cancel = |signature: String| {
+            store.apply(
+                SharedStoreOp::Cancel {
+                    signature,
+                    nonce: None,
+                },
+                &local,
+            )
+        };
+        // more than a page
+        let signatures: Vec<String> = (0..BATCH_SIZE + 10)
+            .map(|_| get_signature(&transfer()).unwrap())
+            .collect();
+        for signature in &signatures {
+            cancel(signature.clone()).await.unwrap();
+        }
+        let cancelled = store
+            .read_since(&store.cancelled_key(), &store.cancelled_cursor)
+            .await
+            .unwrap();
+        assert_eq!(cancelled.len(), signatures.len());
+        assert!(store.cancelled_cursor.load(Ordering::Relaxed) > 0);
+
+        // the next read starts where this one ended
+        let later = get_signature(&transfer()).unwrap();
+        cancel(later.clone()).await.unwrap();
+        let cancelled = store
+            .read_since(&store.cancelled_key(), &store.cancelled_cursor)
+            .await
+            .unwrap();
+        assert!(cancelled.contains(&later));
+        assert!(cancelled.len() < BATCH_SIZE);
+        store.sync_cancellations(&local).await.unwrap();
+        assert!(local.transactions.is_cancelled(&later));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a redis-server at REDIS_URL"]
+    async fn shares_history() {
+        let prefix = prefix();
+        let (first, second) = (
+            shared_store(&prefix, "first", Duration::from_secs(10)),
+            shared_store(&prefix, "second", Duration::from_secs(10)),
+        );
+        let first_queue = LocalQueue::new(rpc_client());
+        let signature = get_signature(&transfer()).unwrap();
+        for retry in 1..=2 {
+            first
+                .apply(
+                    SharedStoreOp::Event {
+                        signature: signature.clone(),
+                        event: retried(retry),
+                    },
+                    &first_queue,
+                )
+                .await
+                .unwrap();
+        }
+        let states: Vec<TransactionState> = second
+            .fetch_history(&signature)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|event| event.state)
+            .collect();
+        assert_eq!(
+            states,
+            vec![
+                TransactionState::Retried { retry: 1 },
+                TransactionState::Retried { retry: 2 }
+            ]
+        );
+        let ttl: i64 = first
+            .connection()
+            .await
+            .unwrap()
+            .pttl(first.history_key(&signature))
+            .await
+            .unwrap();
+        assert!(ttl > 0);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a redis-server at REDIS_URL"]
+    async fn retries_only_claimed_transactions() {
+        init_metrics();
+        let prefix = prefix();
+        let store = SharedTransactionStore::new(
+            &redis_url(),
+            prefix.clone(),
+            "first".to_string(),
+            Duration::from_secs(10),
+            rpc_client(),
+        )
+        .unwrap();
+        let transaction = transfer();
+        let signature = get_signature(&transaction).unwrap();
+        store.add_transaction(transaction);
+        assert!(store.has_signature(&signature));
+        assert!(!store.get_transactions().contains_key(&signature));
+
+        for _ in 0..50 {
+            if store.get_transactions().contains_key(&signature) {
+                break;
+            }
+            sleep(Duration::from_millis(20)).await;
+        }
+        assert!(store.get_transactions().contains_key(&signature));
+        assert_eq!(
+            owner(&store.shared_store, &signature).await.unwrap(),
+            "first"
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_unsupported_urls() {
+        assert!(SharedStore::new(
+            "http://127.0.0.1:6379",
+            prefix(),
+            "first".to_string(),
+            Duration::from_secs(10),
+            rpc_client(),
+        )
+        .is_err());
+    }
+}
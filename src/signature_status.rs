@@ -0,0 +1,274 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use serde::Serialize;
+use solana_sdk::{clock::UnixTimestamp, slot_history::Slot, transaction::TransactionError};
+use tokio::{
+    sync::oneshot,
+    time::{sleep, timeout},
+};
+
+use crate::solana_rpc::unix_timestamp_now;
+
+/// ConfirmationStatus is how far a landed transaction has progressed
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "camelCase")]
+pub enum ConfirmationStatus {
+    Processed,
+    Confirmed,
+    Finalized,
+    /// the transaction landed in a slot that a fork abandoned
+    Dropped,
+}
+
+impl ConfirmationStatus {
+    pub fn is_confirmed(&self) -> bool {
+        matches!(
+            self,
+            ConfirmationStatus::Confirmed | ConfirmationStatus::Finalized
+        )
+    }
+}
+
+/// SignatureStatus is where and how a transaction landed
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SignatureStatus {
+    pub slot: Slot,
+    /// position of the transaction in its block, when the backend knows it
+    pub index: Option<u64>,
+    pub confirmation_status: ConfirmationStatus,
+    /// the error the transaction failed with, None if it succeeded
+    pub err: Option<TransactionError>,
+    pub block_time: Option<UnixTimestamp>,
+    #[serde(skip)]
+    updated_at: Instant,
+}
+
+/// SignatureStatuses tracks the recently landed transactions across forks, and wakes the
+/// callers waiting for them as soon as they are confirmed
+pub struct SignatureStatuses {
+    /// how long confirm_transaction waits for a transaction to land
+    confirmation_timeout: Duration,
+    /// how long a status is kept after its last update
+    retention: Duration,
+    statuses: Arc<DashMap<String, SignatureStatus>>,
+    /// signatures by the slot they landed in, until the slot is finalized
+    slot_signatures: Arc<DashMap<Slot, Vec<String>>>,
+    /// callers waiting for each signature to be confirmed, sent its block time
+    waiters: Arc<DashMap<String, Vec<oneshot::Sender<UnixTimestamp>>>>,
+}
+
+impl SignatureStatuses {
+    pub fn new(confirmation_timeout: Duration, retention: Duration) -> Self {
+        let signature_statuses = Self {
+            confirmation_timeout,
+            retention,
+            statuses: Arc::new(DashMap::new()),
+            slot_signatures: Arc::new(DashMap::new()),
+            waiters: Arc::new(DashMap::new()),
+        };
+        signature_statuses.clean_statuses();
+        signature_statuses
+    }
+
+    fn clean_statuses(&self) {
+        let retention = self.retention;
+        let statuses = self.statuses.clone();
+        let slot_signatures = self.slot_signatures.clone();
+        let waiters = self.waiters.clone();
+        tokio::spawn(async move {
+            loop {
+                statuses.retain(|_, status| status.updated_at.elapsed() < retention);
+                slot_signatures.retain(|_, signatures| {
+                    signatures
+                        .iter()
+                        .any(|signature| statuses.contains_key(signature))
+                });
+                // waiters that timed out
+                waiters.retain(|_, senders| {
+                    senders.retain(|sender| !sender.is_closed());
+                    !senders.is_empty()
+                });
+                sleep(Duration::from_secs(60)).await;
+            }
+        });
+    }
+
+    pub fn confirmation_timeout(&self) -> Duration {
+        self.confirmation_timeout
+    }
+
+    pub fn retention(&self) -> Duration {
+        self.retention
+    }
+
+    pub fn get(&self, signature: &str) -> Option<SignatureStatus> {
+        self.statuses.get(signature).map(|status| status.clone())
+    }
+
+    /// wait_for_confirmation returns the block time once the transaction is confirmed, or None if
+    /// it isn't within the confirmation timeout. Backends that don't see the block time return the
+    /// current time.
+    pub async fn wait_for_confirmation(&self, signature: &str) -> Option<UnixTimestamp> {
+        let (sender, receiver) = oneshot::channel();
+        self.waiters
+            .entry(signature.to_string())
+            .or_default()
+            .push(sender);
+        // the transaction may have been confirmed before the waiter was registered
+        if let Some(block_time) = self.get(signature).and_then(confirmed_block_time) {
+            return Some(block_time);
+        }
+        timeout(self.confirmation_timeout, receiver)
+            .await
+            .ok()?
+            .ok()
+    }
+
+    /// wake_waiters sends the block time to the callers waiting for the signature, if it is confirmed
+    fn wake_waiters(&self, signature: &str) {
+        let Some(block_time) = self.get(signature).and_then(confirmed_block_time) else {
+            return;
+        };
+        if let Some((_, senders)) = self.waiters.remove(signature) {
+            for sender in senders {
+                let _ = sender.send(block_time);
+            }
+        }
+    }
+
+    /// record saves the transaction as landed in the slot. A transaction seen again in another slot
+    /// replaces the old one unless that one is already confirmed, and the status never goes backwards.
+    pub fn record(
+        &self,
+        signature: String,
+        slot: Slot,
+        index: Option<u64>,
+        err: Option<TransactionError>,
+        block_time: Option<UnixTimestamp>,
+        confirmation_status: ConfirmationStatus,
+    ) {
+        let mut status =
+            self.statuses
+                .entry(signature.clone())
+                .or_insert_with(|| SignatureStatus {
+                    slot,
+                    index,
+                    confirmation_status,
+                    err: err.clone(),
+                    block_time,
+                    updated_at: Instant::now(),
+                });
+        if status.slot != slot {
+            if status.confirmation_status.is_confirmed() {
+                return;
+            }
+            *status = SignatureStatus {
+                slot,
+                index,
+                confirmation_status,
+                err,
+                block_time,
+                updated_at: Instant::now(),
+            };
+        } else {
+            status.index = status.index.or(index);
+            status.block_time = status.block_time.or(block_time);
+            if confirmation_status > status.confirmation_status {
+                status.confirmation_status = confirmation_status;
+            }
+            status.updated_at = Instant::now();
+        }
+        drop(status);
+        self.wake_waiters(&signature);
+        let mut signatures = self.slot_signatures.entry(slot).or_default();
+        if !signatures.contains(&signature) {
+            signatures.push(signature);
+        }
+    }
+
+    /// slot_signatures returns the signatures that landed in the slot and weren't finalized yet
+    pub fn slot_signatures(&self, slot: Slot) -> Vec<String> {
+        self.slot_signatures
+            .get(&slot)
+            .map(|signatures| signatures.clone())
+            .unwrap_or_default()
+    }
+
+    /// update_slot moves the transactions that landed in the slot to the status, e.g. Confirmed when
+    /// the slot is confirmed or Dropped when it is dead
+    pub fn update_slot(&self, slot: Slot, confirmation_status: ConfirmationStatus) {
+        let Some(signatures) = self.slot_signatures.get(&slot).map(|s| s.clone()) else {
+            return;
+        };
+        for signature in signatures {
+            if let Some(mut status) = self.statuses.get_mut(&signature) {
+                if status.slot == slot && confirmation_status > status.confirmation_status {
+                    status.confirmation_status = confirmation_status;
+                    status.updated_at = Instant::now();
+                }
+            }
+            self.wake_waiters(&signature);
+        }
+    }
+
+    /// finalize_up_to is called with each new root. Confirmed transactions at or below it are final,
+    /// processed ones at or below it were on a fork that was abandoned.
+    /// Returns the signatures that were finalized.
+    pub fn finalize_up_to(&self, root: Slot) -> Vec<String> {
+        let mut finalized = vec![];
+        let slots: Vec<Slot> = self
+            .slot_signatures
+            .iter()
+            .map(|entry| *entry.key())
+            .filter(|slot| *slot <= root)
+            .collect();
+        for slot in slots {
+            let Some((_, signatures)) = self.slot_signatures.remove(&slot) else {
+                continue;
+            };
+            for signature in signatures {
+                let Some(mut status) = self.statuses.get_mut(&signature) else {
+                    continue;
+                };
+                if status.slot != slot {
+                    continue;
+                }
+                status.confirmation_status = match status.confirmation_status {
+                    ConfirmationStatus::Processed if slot != root => ConfirmationStatus::Dropped,
+                    ConfirmationStatus::Dropped => ConfirmationStatus::Dropped,
+                    _ => ConfirmationStatus::Finalized,
+                };
+                status.updated_at = Instant::now();
+                let is_finalized = status.confirmation_status == ConfirmationStatus::Finalized;
+                drop(status);
+                self.wake_waiters(&signature);
+                if is_finalized {
+                    finalized.push(signature);
+                }
+            }
+        }
+        finalized
+    }
+
+    /// drop_signature marks a transaction that is no longer found on chain as dropped
+    pub fn drop_signature(&self, signature: &str) {
+        if let Some(mut status) = self.statuses.get_mut(signature) {
+            if status.confirmation_status != ConfirmationStatus::Finalized {
+                status.confirmation_status = ConfirmationStatus::Dropped;
+                status.updated_at = Instant::now();
+            }
+        }
+    }
+}
+
+fn confirmed_block_time(status: SignatureStatus) -> Option<UnixTimestamp> {
+    status
+        .confirmation_status
+        .is_confirmed()
+        .then(|| status.block_time.unwrap_or_else(unix_timestamp_now))
+}
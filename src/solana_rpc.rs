@@ -1,9 +1,67 @@
-use solana_sdk::clock::UnixTimestamp;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use serde::Deserialize;
+use solana_sdk::{clock::UnixTimestamp, commitment_config::CommitmentLevel, slot_history::Slot};
+use tokio::sync::watch;
 use tonic::async_trait;
 
+use crate::signature_status::SignatureStatus;
+
+/// SolanaRpcBackend chooses where slots and transaction confirmations come from
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SolanaRpcBackend {
+    /// Yellowstone gRPC geyser at GRPC_URL
+    #[default]
+    Geyser,
+    /// standard RPC websocket at WS_URL
+    Websocket,
+    /// `getSlot` and `getSignatureStatuses` polling of RPC_URL
+    Polling,
+}
+
+/// SlotUpdate is the latest slot seen at a commitment level
+#[derive(Clone, Copy, Debug)]
+pub struct SlotUpdate {
+    pub slot: Slot,
+    pub commitment: CommitmentLevel,
+    pub received_at: Instant,
+}
+
 #[async_trait]
 pub trait SolanaRpc: Send + Sync {
     fn get_next_slot(&self) -> Option<u64>;
+    /// subscribe_slots returns a receiver that is notified of every new slot
+    fn subscribe_slots(&self) -> watch::Receiver<Option<SlotUpdate>>;
     // return block_time if confirmed, None otherwise
     async fn confirm_transaction(&self, signatures: String) -> Option<UnixTimestamp>;
+    /// get_signature_status returns the latest status of a transaction that landed within the retention period
+    fn get_signature_status(&self, signature: &str) -> Option<SignatureStatus>;
+}
+
+/// publish_slot sends the slot to the subscribers if it is newer than the last one
+pub fn publish_slot(
+    slot_sender: &watch::Sender<Option<SlotUpdate>>,
+    slot: Slot,
+    commitment: CommitmentLevel,
+) {
+    slot_sender.send_if_modified(|slot_update| {
+        if slot_update.is_some_and(|slot_update| slot_update.slot >= slot) {
+            return false;
+        }
+        *slot_update = Some(SlotUpdate {
+            slot,
+            commitment,
+            received_at: Instant::now(),
+        });
+        true
+    });
+}
+
+/// unix_timestamp_now stands in for the block time when a backend only learns the slot
+pub fn unix_timestamp_now() -> UnixTimestamp {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as UnixTimestamp
 }
@@ -1,8 +1,9 @@
 use std::net::SocketAddr;
 
 use solana_rpc_client_api::response::RpcContactInfo;
+use solana_sdk::slot_history::Slot;
 
-use crate::leader_tracker::LeaderTrackerTrait;
+use crate::{leader_policy::LeaderPolicyMode, leader_tracker::LeaderTrackerTrait};
 
 #[derive(Clone)]
 pub struct StaticLeaderImpl {
@@ -34,7 +35,15 @@ impl StaticLeaderImpl {
 }
 
 impl LeaderTrackerTrait for StaticLeaderImpl {
-    fn get_leaders(&self) -> Vec<RpcContactInfo> {
+    fn get_leaders(&self, _mode: LeaderPolicyMode) -> Vec<RpcContactInfo> {
         vec![self.static_leader.clone()]
     }
+
+    fn get_slot_leader(&self, _slot: Slot) -> Option<RpcContactInfo> {
+        Some(self.static_leader.clone())
+    }
+
+    fn get_next_leader_slot(&self, leader: &str, from_slot: Slot, _to_slot: Slot) -> Option<Slot> {
+        (leader == self.static_leader.pubkey).then_some(from_slot)
+    }
 }
@@ -0,0 +1,214 @@
+use std::{
+    error::Error,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use hyper::{
+    header::{CONNECTION, UPGRADE},
+    HeaderMap, Request,
+};
+use opentelemetry::{
+    global,
+    propagation::{Extractor, TextMapPropagator},
+    KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{propagation::TraceContextPropagator, runtime, trace, Resource};
+use tower::{Layer, Service};
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{
+    filter::{LevelFilter, Targets},
+    layer::SubscriberExt,
+    util::SubscriberInitExt,
+    EnvFilter, Layer as _,
+};
+
+use crate::errors::AtlasTxnSenderError;
+
+tokio::task_local! {
+    /// the trace context of the HTTP request being handled, from its `traceparent` header
+    static TRACE_PARENT: opentelemetry::Context;
+}
+
+/// init_tracing logs JSON to stdout, filtered by env_filter, and, when an OTLP endpoint is set,
+/// exports the spans of this crate to it whatever the log level
+pub fn init_tracing(
+    env_filter: String,
+    otlp_endpoint: Option<String>,
+    service_name: String,
+) -> Result<(), AtlasTxnSenderError> {
+    let otel_layer = match otlp_endpoint {
+        Some(otlp_endpoint) => {
+            let tracer = opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(
+                    opentelemetry_otlp::new_exporter()
+                        .tonic()
+                        .with_endpoint(otlp_endpoint),
+                )
+                .with_trace_config(trace::config().with_resource(Resource::new(vec![
+                    KeyValue::new("service.name", service_name),
+                ])))
+                .install_batch(runtime::Tokio)
+                .map_err(|e| format!("Error installing OTLP exporter: {e}"))?;
+            global::set_text_map_propagator(TraceContextPropagator::new());
+            // only our own spans, the exporter's gRPC client would otherwise trace its own exports
+            Some(
+                tracing_opentelemetry::layer()
+                    .with_tracer(tracer)
+                    .with_filter(Targets::new().with_target("atlas_txn_sender", LevelFilter::INFO)),
+            )
+        }
+        None => None,
+    };
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::fmt::layer()
+                .json()
+                .with_filter(EnvFilter::new(env_filter)),
+        )
+        .with(otel_layer)
+        .init();
+    Ok(())
+}
+
+/// shutdown_tracing exports the spans that are still buffered
+pub fn shutdown_tracing() {
+    global::shutdown_tracer_provider();
+}
+
+/// continue_trace makes the span a child of the trace of the HTTP request, if it came with a
+/// `traceparent` header. Otherwise the span is a root span.
+pub fn continue_trace(span: &Span) {
+    if let Ok(parent) = TRACE_PARENT.try_with(|parent| parent.clone()) {
+        span.set_parent(parent);
+    }
+}
+
+/// in_request_trace is whether a sendTransaction span is current. Sends and confirmations outside
+/// of one, i.e. retries and scheduled releases, get no span.
+pub fn in_request_trace() -> bool {
+    !Span::current().is_none()
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+/// is_websocket_upgrade is whether the request opens a WebSocket connection
+fn is_websocket_upgrade(headers: &HeaderMap) -> bool {
+    let has_token = |name, token: &str| {
+        headers.get_all(name).iter().any(|value| {
+            value.to_str().is_ok_and(|value| {
+                value
+                    .split(',')
+                    .any(|part| part.trim().eq_ignore_ascii_case(token))
+            })
+        })
+    };
+    has_token(CONNECTION, "upgrade") && has_token(UPGRADE, "websocket")
+}
+
+/// TraceContextLayer reads the W3C trace context of each HTTP request, for the RPC methods to
+/// continue the trace with continue_trace. WebSocket upgrades are left out, the connection carries
+/// many calls and the headers of its upgrade are the context of none of them.
+#[derive(Clone)]
+pub struct TraceContextLayer;
+
+impl<S> Layer<S> for TraceContextLayer {
+    type Service = TraceContextService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        TraceContextService { inner }
+    }
+}
+
+#[derive(Clone)]
+pub struct TraceContextService<S> {
+    inner: S,
+}
+
+impl<S, B> Service<Request<B>> for TraceContextService<S>
+where
+    S: Service<Request<B>>,
+    S::Error: Into<Box<dyn Error + Send + Sync>> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = Box<dyn Error + Send + Sync + 'static>;
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        if is_websocket_upgrade(req.headers()) {
+            let response = self.inner.call(req);
+            return Box::pin(async move { response.await.map_err(Into::into) });
+        }
+        let parent = TraceContextPropagator::new().extract(&HeaderExtractor(req.headers()));
+        let response = self.inner.call(req);
+        // jsonrpsee runs the method of an HTTP request within this future
+        Box::pin(TRACE_PARENT.scope(parent, async move { response.await.map_err(Into::into) }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use hyper::Body;
+    use opentelemetry::trace::TraceContextExt;
+    use tower::{service_fn, ServiceExt};
+
+    use super::*;
+
+    const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    /// has_trace_parent is whether the inner service ran with the trace context of the request
+    async fn has_trace_parent(req: Request<Body>) -> bool {
+        let inner = service_fn(|_req: Request<Body>| async {
+            Ok::<_, Infallible>(
+                TRACE_PARENT
+                    .try_with(|parent| parent.span().span_context().is_valid())
+                    .unwrap_or(false),
+            )
+        });
+        TraceContextLayer.layer(inner).oneshot(req).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn scopes_http_requests_to_their_trace() {
+        let req = Request::post("/")
+            .header("traceparent", TRACEPARENT)
+            .body(Body::empty())
+            .unwrap();
+        assert!(has_trace_parent(req).await);
+        let req = Request::post("/").body(Body::empty()).unwrap();
+        assert!(!has_trace_parent(req).await);
+    }
+
+    #[tokio::test]
+    async fn leaves_websocket_upgrades_out() {
+        let req = Request::get("/")
+            .header("traceparent", TRACEPARENT)
+            .header(CONNECTION, "keep-alive, Upgrade")
+            .header(UPGRADE, "websocket")
+            .body(Body::empty())
+            .unwrap();
+        assert!(!has_trace_parent(req).await);
+    }
+}
@@ -0,0 +1,7 @@
+use cadence::{NopMetricSink, StatsdClient};
+use cadence_macros::set_global_default;
+
+/// init_metrics sets a client that drops everything, so code emitting metrics can run in tests
+pub fn init_metrics() {
+    set_global_default(StatsdClient::from_sink("test", NopMetricSink));
+}
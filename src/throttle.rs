@@ -0,0 +1,692 @@
+use std::{
+    cmp::Ordering,
+    collections::{BinaryHeap, HashMap},
+    mem::size_of,
+    net::SocketAddr,
+    str::FromStr,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use bytemuck::{Pod, Zeroable};
+use cadence_macros::{statsd_count, statsd_gauge};
+use dashmap::DashMap;
+use solana_client::rpc_client::RpcClient;
+use solana_sdk::{account::Account, pubkey::Pubkey};
+use tokio::time::sleep;
+use tracing::{error, info};
+
+use crate::{
+    errors::AtlasTxnSenderError,
+    identity::{get_node_stakes, IdentityPool},
+    txn_sender::LeaderSend,
+};
+
+// Approximates the stream throttle of the validator's QUIC server: every 100ms a sender gets a share
+// of the staked streams proportional to its stake, and never less than an unstaked connection.
+const STREAM_THROTTLING_WINDOW: Duration = Duration::from_millis(100);
+const STAKED_STREAMS_PER_WINDOW: u128 = 20_000;
+const UNSTAKED_STREAMS_PER_WINDOW: u64 = 10;
+
+const STAKE_REFRESH_INTERVAL: Duration = Duration::from_secs(300);
+const STAKE_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+const MAX_QUEUED_SENDS: usize = 10_000;
+// leaders move on after a few slots, there is no point sending later than this
+const MAX_QUEUED_SEND_AGE: Duration = Duration::from_secs(2);
+
+// The P3 stake program keeps the PAL stake of each validator in a `ValidatorStake` account derived
+// from its vote account and the stake config
+const VALIDATOR_STAKE_SEED: &[u8] = b"validator_stake";
+
+/// StakeConfigAccount is the start of the P3 stake program's `Config` account, up to the total
+/// stake. The program's crate isn't published, so the layout is mirrored field by field here and
+/// every account read is checked against it.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+struct StakeConfigAccount {
+    discriminator: [u8; 8],
+    authority: [u8; 32],
+    slash_authority: [u8; 32],
+    vault: [u8; 32],
+    /// total effective PAL stake
+    token_amount_effective: u64,
+}
+
+/// ValidatorStakeAccount is the start of the P3 stake program's `ValidatorStake` account, up to the
+/// active amount of its delegation
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+struct ValidatorStakeAccount {
+    discriminator: [u8; 8],
+    active_amount: u64,
+}
+
+/// read_account reads the start of a P3 stake program account, refusing accounts owned by another
+/// program, too short or not initialized
+fn read_account<T: Pod>(account: &Account, program_id: &Pubkey) -> Result<T, String> {
+    if &account.owner != program_id {
+        return Err(format!("owned by {}, not {}", account.owner, program_id));
+    }
+    let data = account.data.get(..size_of::<T>()).ok_or_else(|| {
+        format!(
+            "{} bytes, expected at least {}",
+            account.data.len(),
+            size_of::<T>()
+        )
+    })?;
+    if data[..8].iter().all(|byte| *byte == 0) {
+        return Err("not initialized".to_string());
+    }
+    Ok(bytemuck::pod_read_unaligned(data))
+}
+
+/// Stake of an identity as seen by the P3 QUIC server
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StakeShare {
+    pub stake: u64,
+    pub total_stake: u64,
+}
+
+impl StakeShare {
+    /// streams_per_window returns how many streams the validator accepts from the identity every 100ms
+    pub fn streams_per_window(&self) -> u64 {
+        if self.total_stake == 0 {
+            return UNSTAKED_STREAMS_PER_WINDOW;
+        }
+        let staked_streams =
+            STAKED_STREAMS_PER_WINDOW * self.stake as u128 / self.total_stake as u128;
+        (staked_streams as u64).max(UNSTAKED_STREAMS_PER_WINDOW)
+    }
+}
+
+/// PalStakeProgram is where the P3 program exposes PAL stake
+#[derive(Clone, Copy, Debug)]
+pub struct PalStakeProgram {
+    pub program_id: Pubkey,
+    pub config: Pubkey,
+}
+
+impl PalStakeProgram {
+    /// parse returns the program if both its id and stake config are given
+    pub fn parse(
+        program_id: Option<&str>,
+        config: Option<&str>,
+    ) -> Result<Option<Self>, AtlasTxnSenderError> {
+        let (program_id, config) = match (program_id, config) {
+            (Some(program_id), Some(config)) => (program_id, config),
+            (None, None) => return Ok(None),
+            _ => {
+                return Err(
+                    "PAL_STAKE_PROGRAM_ID and PAL_STAKE_CONFIG must be set together"
+                        .to_string()
+                        .into(),
+                )
+            }
+        };
+        Ok(Some(Self {
+            program_id: Pubkey::from_str(program_id)
+                .map_err(|e| format!("Invalid PAL stake program {program_id}: {e}"))?,
+            config: Pubkey::from_str(config)
+                .map_err(|e| format!("Invalid PAL stake config {config}: {e}"))?,
+        }))
+    }
+
+    fn validator_stake_address(&self, vote_pubkey: &Pubkey) -> Pubkey {
+        Pubkey::find_program_address(
+            &[
+                VALIDATOR_STAKE_SEED,
+                vote_pubkey.as_ref(),
+                self.config.as_ref(),
+            ],
+            &self.program_id,
+        )
+        .0
+    }
+
+    /// get_stakes returns the PAL stake of the identities with a stake account and the total PAL stake
+    fn get_stakes(
+        &self,
+        rpc_client: &RpcClient,
+        pubkeys: &[Pubkey],
+    ) -> Result<(HashMap<Pubkey, u64>, u64), AtlasTxnSenderError> {
+        let vote_accounts = rpc_client
+            .get_vote_accounts()
+            .map_err(|e| format!("Error getting vote accounts: {e}"))?;
+        let mut vote_pubkeys = vec![];
+        for vote_account in vote_accounts
+            .current
+            .iter()
+            .chain(vote_accounts.delinquent.iter())
+        {
+            let (Ok(node_pubkey), Ok(vote_pubkey)) = (
+                Pubkey::from_str(&vote_account.node_pubkey),
+                Pubkey::from_str(&vote_account.vote_pubkey),
+            ) else {
+                continue;
+            };
+            if pubkeys.contains(&node_pubkey) {
+                vote_pubkeys.push((node_pubkey, vote_pubkey));
+            }
+        }
+        let mut addresses = vec![self.config];
+        addresses.extend(
+            vote_pubkeys
+                .iter()
+                .map(|(_, vote_pubkey)| self.validator_stake_address(vote_pubkey)),
+        );
+        let mut accounts = rpc_client
+            .get_multiple_accounts(&addresses)
+            .map_err(|e| format!("Error getting PAL stake accounts: {e}"))?
+            .into_iter();
+        let config = accounts
+            .next()
+            .flatten()
+            .ok_or_else(|| format!("PAL stake config {} not found", self.config))?;
+        let validator_stakes: Vec<(Pubkey, Option<Account>)> = vote_pubkeys
+            .iter()
+            .map(|(node_pubkey, _)| *node_pubkey)
+            .zip(accounts)
+            .collect();
+        self.read_stakes(&config, validator_stakes)
+    }
+
+    /// read_stakes reads the total PAL stake from the config and the stake of each identity from
+    /// its validator stake account. Accounts that don't match the program's layout are an error, not
+    /// an unstaked identity.
+    fn read_stakes(
+        &self,
+        config: &Account,
+        validator_stakes: Vec<(Pubkey, Option<Account>)>,
+    ) -> Result<(HashMap<Pubkey, u64>, u64), AtlasTxnSenderError> {
+        let total_stake = read_account::<StakeConfigAccount>(config, &self.program_id)
+            .map_err(|e| format!("Invalid PAL stake config {}: {e}", self.config))?
+            .token_amount_effective;
+        let mut stakes = HashMap::new();
+        for (node_pubkey, account) in validator_stakes {
+            // validators without PAL stake have no account
+            let Some(account) = account else {
+                continue;
+            };
+            let stake = read_account::<ValidatorStakeAccount>(&account, &self.program_id)
+                .map_err(|e| format!("Invalid PAL validator stake of {node_pubkey}: {e}"))?
+                .active_amount;
+            *stakes.entry(node_pubkey).or_default() += stake;
+        }
+        if stakes.values().any(|stake| *stake > total_stake) {
+            return Err(format!(
+                "PAL validator stake exceeds the total stake {total_stake} of {}, the accounts don't match the program's layout",
+                self.config
+            )
+            .into());
+        }
+        Ok((stakes, total_stake))
+    }
+}
+
+/// PalStakes are where the PAL stake of the identities comes from. Configured stakes take
+/// precedence over the ones read from the program.
+#[derive(Clone, Debug, Default)]
+pub struct PalStakes {
+    pub program: Option<PalStakeProgram>,
+    pub stakes: HashMap<Pubkey, u64>,
+    pub total_stake: Option<u64>,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    capacity: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(capacity: u64) -> Self {
+        Self {
+            tokens: capacity as f64,
+            capacity: capacity as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn try_take(&mut self, capacity: u64) -> bool {
+        let now = Instant::now();
+        self.capacity = capacity as f64;
+        let refill = now.duration_since(self.last_refill).as_secs_f64()
+            / STREAM_THROTTLING_WINDOW.as_secs_f64()
+            * self.capacity;
+        self.tokens = (self.tokens + refill).min(self.capacity);
+        self.last_refill = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return true;
+        }
+        false
+    }
+}
+
+struct QueuedSend {
+    priority: u64,
+    queued_at: Instant,
+    send: LeaderSend,
+}
+
+impl PartialEq for QueuedSend {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for QueuedSend {}
+
+impl PartialOrd for QueuedSend {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for QueuedSend {
+    // highest priority first, then oldest first
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.queued_at.cmp(&self.queued_at))
+    }
+}
+
+/// SendThrottle keeps sends to each destination within the identity's stake based stream budget.
+/// Sends over budget wait in a priority queue until the destination has budget again.
+pub struct SendThrottle {
+    enabled: bool,
+    stake_shares: Arc<RwLock<HashMap<Pubkey, StakeShare>>>,
+    buckets: Arc<DashMap<(Pubkey, SocketAddr), TokenBucket>>,
+    queue: Mutex<BinaryHeap<QueuedSend>>,
+}
+
+impl SendThrottle {
+    pub fn new(
+        enabled: bool,
+        rpc_client: Arc<RpcClient>,
+        identities: Arc<IdentityPool>,
+        pal_stakes: PalStakes,
+    ) -> Self {
+        let send_throttle = Self {
+            enabled,
+            stake_shares: Arc::new(RwLock::new(HashMap::new())),
+            buckets: Arc::new(DashMap::new()),
+            queue: Mutex::new(BinaryHeap::new()),
+        };
+        if enabled {
+            send_throttle.refresh_stake_shares(rpc_client, identities, pal_stakes);
+        }
+        send_throttle
+    }
+
+    /// submit returns the send if its destination has budget left, otherwise it queues the send
+    pub fn submit(&self, send: LeaderSend, priority: u64) -> Option<LeaderSend> {
+        if !self.enabled || self.try_acquire(&send) {
+            return Some(send);
+        }
+        statsd_count!("transaction_send_throttled", 1);
+        let mut queue = self.queue.lock().unwrap();
+        if queue.len() >= MAX_QUEUED_SENDS {
+            statsd_count!("throttled_send_dropped", 1, "reason" => "queue_full");
+            return None;
+        }
+        queue.push(QueuedSend {
+            priority,
+            queued_at: Instant::now(),
+            send,
+        });
+        None
+    }
+
+    /// drain returns the queued sends whose destinations got budget back, highest priority first,
+    /// and drops the ones that waited too long
+    pub fn drain(&self) -> Vec<LeaderSend> {
+        let mut queue = self.queue.lock().unwrap();
+        statsd_gauge!("throttled_send_queue_length", queue.len() as u64);
+        let mut ready = vec![];
+        let mut waiting = Vec::with_capacity(queue.len());
+        while let Some(queued_send) = queue.pop() {
+            if queued_send.queued_at.elapsed() > MAX_QUEUED_SEND_AGE {
+                statsd_count!("throttled_send_dropped", 1, "reason" => "stale");
+                continue;
+            }
+            if self.try_acquire(&queued_send.send) {
+                ready.push(queued_send.send);
+            } else {
+                waiting.push(queued_send);
+            }
+        }
+        queue.extend(waiting);
+        ready
+    }
+
+    fn try_acquire(&self, send: &LeaderSend) -> bool {
+        let capacity = self
+            .stake_shares
+            .read()
+            .unwrap()
+            .get(&send.identity)
+            .copied()
+            .unwrap_or_default()
+            .streams_per_window();
+        self.buckets
+            .entry((send.identity, send.socket_addr))
+            .or_insert_with(|| TokenBucket::new(capacity))
+            .try_take(capacity)
+    }
+
+    /// refresh_stake_shares periodically looks up the stake share of every identity
+    fn refresh_stake_shares(
+        &self,
+        rpc_client: Arc<RpcClient>,
+        identities: Arc<IdentityPool>,
+        pal_stakes: PalStakes,
+    ) {
+        let stake_shares = self.stake_shares.clone();
+        let buckets = self.buckets.clone();
+        tokio::spawn(async move {
+            loop {
+                let rpc_client = rpc_client.clone();
+                let pubkeys = identities.pubkeys();
+                let pal_stakes = pal_stakes.clone();
+                let new_stake_shares = tokio::task::spawn_blocking(move || {
+                    get_stake_shares(&rpc_client, &pubkeys, &pal_stakes)
+                })
+                .await;
+                match new_stake_shares {
+                    Ok(Ok(new_stake_shares)) => {
+                        for (pubkey, stake_share) in new_stake_shares.iter() {
+                            info!(
+                                "identity {} can send {} streams per destination every {:?}",
+                                pubkey,
+                                stake_share.streams_per_window(),
+                                STREAM_THROTTLING_WINDOW
+                            );
+                        }
+                        *stake_shares.write().unwrap() = new_stake_shares;
+                        // forget destinations we stopped sending to
+                        buckets.retain(|_, bucket| {
+                            bucket.last_refill.elapsed() < STAKE_REFRESH_INTERVAL
+                        });
+                        sleep(STAKE_REFRESH_INTERVAL).await;
+                    }
+                    Ok(Err(e)) => {
+                        error!("Error refreshing send throttle stakes: {}", e);
+                        statsd_count!("send_throttle_stake_refresh_error", 1);
+                        sleep(STAKE_RETRY_INTERVAL).await;
+                    }
+                    Err(e) => {
+                        error!("Error refreshing send throttle stakes: {}", e);
+                        statsd_count!("send_throttle_stake_refresh_error", 1);
+                        sleep(STAKE_RETRY_INTERVAL).await;
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// get_stake_shares returns the PAL stake share of identities with a configured or on-chain PAL
+/// stake and the activated SOL stake share of the others
+fn get_stake_shares(
+    rpc_client: &RpcClient,
+    pubkeys: &[Pubkey],
+    pal_stakes: &PalStakes,
+) -> Result<HashMap<Pubkey, StakeShare>, AtlasTxnSenderError> {
+    let (sol_stakes, sol_total_stake) = get_node_stakes(rpc_client)?;
+    let (program_stakes, program_total_stake) = match &pal_stakes.program {
+        Some(program) => program.get_stakes(rpc_client, pubkeys)?,
+        None => (HashMap::new(), 0),
+    };
+    let mut stake_shares = HashMap::new();
+    for pubkey in pubkeys {
+        let configured = pal_stakes.stakes.get(pubkey).zip(pal_stakes.total_stake);
+        let on_chain = program_stakes
+            .get(pubkey)
+            .map(|stake| (stake, program_total_stake));
+        let stake_share = match configured.or(on_chain) {
+            Some((stake, total_stake)) => StakeShare {
+                stake: *stake,
+                total_stake,
+            },
+            None => StakeShare {
+                stake: sol_stakes.get(&pubkey.to_string()).copied().unwrap_or(0),
+                total_stake: sol_total_stake,
+            },
+        };
+        stake_shares.insert(*pubkey, stake_share);
+    }
+    Ok(stake_shares)
+}
+
+/// parse_pal_stakes parses a comma separated list of `identity_pubkey:stake` pairs
+pub fn parse_pal_stakes(value: &str) -> Result<HashMap<Pubkey, u64>, AtlasTxnSenderError> {
+    let mut pal_stakes = HashMap::new();
+    for entry in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let Some((pubkey, stake)) = entry.split_once(':') else {
+            return Err(format!("Invalid PAL stake entry: {entry}").into());
+        };
+        let pubkey = Pubkey::from_str(pubkey.trim())
+            .map_err(|e| format!("Invalid PAL stake identity {pubkey}: {e}"))?;
+        let stake = stake
+            .trim()
+            .parse::<u64>()
+            .map_err(|e| format!("Invalid PAL stake for {pubkey}: {e}"))?;
+        pal_stakes.insert(pubkey, stake);
+    }
+    Ok(pal_stakes)
+}
+
+#[cfg(test)]
+mod tests {
+    use solana_rpc_client_api::response::RpcContactInfo;
+    use solana_sdk::signature::Keypair;
+
+    use super::*;
+    use crate::{identity::Identity, test_utils::init_metrics};
+
+    fn throttle() -> SendThrottle {
+        init_metrics();
+        SendThrottle {
+            enabled: true,
+            stake_shares: Arc::new(RwLock::new(HashMap::new())),
+            buckets: Arc::new(DashMap::new()),
+            queue: Mutex::new(BinaryHeap::new()),
+        }
+    }
+
+    fn leader_send(identity: &Identity, signature: &str) -> LeaderSend {
+        let leader = RpcContactInfo {
+            pubkey: Pubkey::new_unique().to_string(),
+            gossip: Some("127.0.0.1:8001".parse().unwrap()),
+            tvu: None,
+            tpu: None,
+            tpu_quic: None,
+            tpu_forwards: None,
+            tpu_forwards_quic: None,
+            tpu_vote: None,
+            serve_repair: None,
+            rpc: None,
+            pubsub: None,
+            version: None,
+            feature_set: None,
+            shred_version: None,
+        };
+        LeaderSend::new(
+            signature.to_string(),
+            identity,
+            &leader,
+            0,
+            4819,
+            vec![],
+            None,
+        )
+        .unwrap()
+    }
+
+    fn signatures(sends: &[LeaderSend]) -> Vec<&str> {
+        sends.iter().map(|send| send.signature.as_str()).collect()
+    }
+
+    #[test]
+    fn budget_follows_stake_share() {
+        assert_eq!(StakeShare::default().streams_per_window(), 10);
+        let stake_share = StakeShare {
+            stake: 1,
+            total_stake: 2,
+        };
+        assert_eq!(stake_share.streams_per_window(), 10_000);
+        let stake_share = StakeShare {
+            stake: 1,
+            total_stake: 1_000_000,
+        };
+        assert_eq!(stake_share.streams_per_window(), 10);
+    }
+
+    #[test]
+    fn token_bucket_refills_over_the_window() {
+        let mut bucket = TokenBucket::new(2);
+        assert!(bucket.try_take(2));
+        assert!(bucket.try_take(2));
+        assert!(!bucket.try_take(2));
+        bucket.last_refill -= STREAM_THROTTLING_WINDOW / 2;
+        assert!(bucket.try_take(2));
+        assert!(!bucket.try_take(2));
+        bucket.last_refill -= STREAM_THROTTLING_WINDOW * 10;
+        assert!(bucket.try_take(2));
+        assert!(bucket.try_take(2));
+        assert!(!bucket.try_take(2));
+    }
+
+    #[tokio::test]
+    async fn queued_sends_drain_by_priority() {
+        let throttle = throttle();
+        let identity = Identity::new(&Keypair::new(), 1);
+        for i in 0..UNSTAKED_STREAMS_PER_WINDOW {
+            assert!(throttle
+                .submit(leader_send(&identity, &format!("sent{i}")), 0)
+                .is_some());
+        }
+        for (signature, priority) in [("low", 1), ("high", 3), ("medium", 2)] {
+            assert!(throttle
+                .submit(leader_send(&identity, signature), priority)
+                .is_none());
+        }
+        assert!(throttle.drain().is_empty());
+
+        // two of the ten streams come back
+        for mut bucket in throttle.buckets.iter_mut() {
+            bucket.last_refill -= STREAM_THROTTLING_WINDOW / 5;
+        }
+        assert_eq!(signatures(&throttle.drain()), vec!["high", "medium"]);
+        assert_eq!(throttle.queue.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn stale_sends_are_dropped() {
+        let throttle = throttle();
+        let identity = Identity::new(&Keypair::new(), 1);
+        throttle.queue.lock().unwrap().push(QueuedSend {
+            priority: 0,
+            queued_at: Instant::now() - MAX_QUEUED_SEND_AGE * 2,
+            send: leader_send(&identity, "stale"),
+        });
+        assert!(throttle.drain().is_empty());
+        assert!(throttle.queue.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn reads_pal_stake_accounts() {
+        let program = PalStakeProgram::parse(
+            Some(&Pubkey::new_unique().to_string()),
+            Some(&Pubkey::new_unique().to_string()),
+        )
+        .unwrap()
+        .unwrap();
+        let account = |data: Vec<u8>| Account {
+            lamports: 1,
+            data,
+            owner: program.program_id,
+            executable: false,
+            rent_epoch: 0,
+        };
+        // the program's `Config`: discriminator, authority, slash authority, vault, total effective
+        // stake, then fields not read
+        let mut config = vec![1; 8];
+        for _ in 0..3 {
+            config.extend_from_slice(Pubkey::new_unique().as_ref());
+        }
+        config.extend_from_slice(&500u64.to_le_bytes());
+        config.extend_from_slice(&[7; 48]);
+        let config = account(config);
+        // the program's `ValidatorStake`: discriminator, then the delegation starting with the active
+        // amount
+        let validator_stake = |amount: u64| {
+            let mut data = vec![2; 8];
+            data.extend_from_slice(&amount.to_le_bytes());
+            data.extend_from_slice(&[9; 64]);
+            account(data)
+        };
+        let (staked, unstaked) = (Pubkey::new_unique(), Pubkey::new_unique());
+
+        let (stakes, total_stake) = program
+            .read_stakes(
+                &config,
+                vec![(staked, Some(validator_stake(200))), (unstaked, None)],
+            )
+            .unwrap();
+        assert_eq!(total_stake, 500);
+        assert_eq!(stakes, HashMap::from([(staked, 200)]));
+
+        // accounts that don't match the layout are errors
+        assert!(program
+            .read_stakes(&config, vec![(staked, Some(validator_stake(600)))])
+            .is_err());
+        let mut foreign = validator_stake(200);
+        foreign.owner = Pubkey::new_unique();
+        assert!(program
+            .read_stakes(&config, vec![(staked, Some(foreign))])
+            .is_err());
+        assert!(program
+            .read_stakes(&config, vec![(staked, Some(account(vec![2; 12])))])
+            .is_err());
+        assert!(program
+            .read_stakes(&config, vec![(staked, Some(account(vec![0; 16])))])
+            .is_err());
+        assert!(program.read_stakes(&account(vec![1; 64]), vec![]).is_err());
+    }
+
+    #[test]
+    fn derives_validator_stake_address() {
+        assert!(PalStakeProgram::parse(None, None).unwrap().is_none());
+        assert!(PalStakeProgram::parse(Some(&Pubkey::new_unique().to_string()), None).is_err());
+        let program = PalStakeProgram::parse(
+            Some(&Pubkey::new_unique().to_string()),
+            Some(&Pubkey::new_unique().to_string()),
+        )
+        .unwrap()
+        .unwrap();
+        let vote_pubkey = Pubkey::new_unique();
+        assert_eq!(
+            program.validator_stake_address(&vote_pubkey),
+            Pubkey::find_program_address(
+                &[
+                    b"validator_stake",
+                    vote_pubkey.as_ref(),
+                    program.config.as_ref()
+                ],
+                &program.program_id
+            )
+            .0
+        );
+    }
+
+    #[test]
+    fn parses_pal_stakes() {
+        let pubkey = Pubkey::new_unique();
+        let pal_stakes = parse_pal_stakes(&format!("{pubkey}:100, ")).unwrap();
+        assert_eq!(pal_stakes.get(&pubkey), Some(&100));
+        assert!(parse_pal_stakes("missing_stake").is_err());
+    }
+}
@@ -1,11 +1,44 @@
-use std::{sync::Arc, time::Instant};
+use std::{
+    collections::{BTreeSet, HashSet},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use crate::rpc_server::RequestMetadata;
-use cadence_macros::statsd_time;
-use dashmap::DashMap;
-use solana_sdk::transaction::VersionedTransaction;
+use crate::{
+    errors::AtlasTxnSenderError, lifecycle::LifecycleEvent, priority::TransactionPriority,
+    rpc_server::RequestMetadata,
+};
+use cadence_macros::{statsd_count, statsd_gauge, statsd_time};
+use dashmap::{mapref::entry::Entry, DashMap};
+use serde::{Deserialize, Serialize};
+use solana_client::{nonce_utils, rpc_client::RpcClient};
+use solana_sdk::{hash::Hash, pubkey::Pubkey, transaction::VersionedTransaction};
+use solana_system_interface::{instruction::SystemInstruction, program as system_program};
+use tokio::time::sleep;
+use tonic::async_trait;
 use tracing::error;
 
+// keep tombstones well past a blockhash lifetime. Durable nonce transactions don't expire, their
+// tombstones are kept until the nonce advances.
+pub const TOMBSTONE_RETENTION: Duration = Duration::from_secs(600);
+// how often the nonces of tombstones past the retention are read
+pub const NONCE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+// nonce accounts read per getMultipleAccounts call
+const NONCE_BATCH_SIZE: usize = 100;
+
+/// TransactionStoreBackend chooses where queued transactions are kept
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TransactionStoreBackend {
+    /// in memory only, lost on restart
+    #[default]
+    Memory,
+    /// in memory and in an append-only log at TRANSACTION_LOG_PATH, recovered on startup
+    File,
+    /// shared by every instance through a Redis protocol service at SHARED_STORE_URL
+    Shared,
+}
+
 #[derive(Clone, Debug)]
 pub struct TransactionData {
     pub wire_transaction: Vec<u8>,
@@ -13,27 +46,275 @@ pub struct TransactionData {
     pub sent_at: Instant,
     pub retry_count: usize,
     pub max_retries: usize,
+    pub priority: TransactionPriority,
     // might not be the best spot but is easy to add for what we need out of metrics now
     pub request_metadata: RequestMetadata,
+    /// the state changes of the transaction so far, oldest first
+    pub history: Vec<LifecycleEvent>,
+}
+
+/// DurableNonce is the nonce account a durable nonce transaction advances and the nonce it was
+/// signed with. It is stored as `{account}:{nonce}`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(into = "String", try_from = "String")]
+pub struct DurableNonce {
+    pub account: Pubkey,
+    pub nonce: Hash,
+}
+
+impl DurableNonce {
+    /// from_transaction returns the nonce if the first instruction advances a nonce account
+    pub fn from_transaction(transaction: &VersionedTransaction) -> Option<Self> {
+        let account_keys = transaction.message.static_account_keys();
+        let instruction = transaction.message.instructions().first()?;
+        if !account_keys
+            .get(instruction.program_id_index as usize)
+            .is_some_and(system_program::check_id)
+        {
+            return None;
+        }
+        let Ok(SystemInstruction::AdvanceNonceAccount) =
+            bincode::deserialize::<SystemInstruction>(&instruction.data)
+        else {
+            return None;
+        };
+        let account = instruction
+            .accounts
+            .first()
+            .and_then(|i| account_keys.get(*i as usize))?;
+        Some(Self {
+            account: *account,
+            nonce: *transaction.message.recent_blockhash(),
+        })
+    }
+}
+
+impl From<DurableNonce> for String {
+    fn from(nonce: DurableNonce) -> Self {
+        format!("{}:{}", nonce.account, nonce.nonce)
+    }
+}
+
+impl TryFrom<String> for DurableNonce {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let (account, nonce) = value
+            .split_once(':')
+            .ok_or_else(|| format!("invalid durable nonce: {value}"))?;
+        Ok(Self {
+            account: account
+                .parse()
+                .map_err(|e| format!("invalid nonce account {account}: {e}"))?,
+            nonce: nonce
+                .parse()
+                .map_err(|e| format!("invalid nonce {nonce}: {e}"))?,
+        })
+    }
 }
 
+/// Tombstone keeps a cancelled signature from being resubmitted
+#[derive(Clone, Copy, Debug)]
+pub struct Tombstone {
+    pub cancelled_at: Instant,
+    /// set for durable nonce transactions, which can land until the nonce advances
+    pub nonce: Option<DurableNonce>,
+}
+
+impl Tombstone {
+    pub fn new(transaction: Option<&TransactionData>) -> Self {
+        Self {
+            cancelled_at: Instant::now(),
+            nonce: transaction.and_then(|transaction| {
+                DurableNonce::from_transaction(&transaction.versioned_transaction)
+            }),
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.nonce.is_none() && self.cancelled_at.elapsed() >= TOMBSTONE_RETENTION
+    }
+}
+
+#[async_trait]
 pub trait TransactionStore: Send + Sync {
     fn add_transaction(&self, transaction: TransactionData);
     fn remove_transaction(&self, signature: String) -> Option<TransactionData>;
     fn get_transactions(&self) -> Arc<DashMap<String, TransactionData>>;
     fn has_signature(&self, signature: &str) -> bool;
+    /// get_fee_payers returns the fee payers of the queued transactions
+    fn get_fee_payers(&self) -> BTreeSet<String>;
+    /// record_event adds a state change to the history of the queued transaction
+    fn record_event(&self, signature: &str, event: LifecycleEvent);
+    /// take_adopted returns the transactions queued since the last call that weren't sent through
+    /// this instance, e.g. recovered from a log, so they can be tracked like the ones it was sent
+    fn take_adopted(&self) -> Vec<TransactionData> {
+        vec![]
+    }
+    /// schedule_transaction keeps a transaction held by the scheduler, with its SendSchedule in the
+    /// request metadata, so a store that outlives the process can hand it back on restart
+    fn schedule_transaction(&self, _signature: &str, _transaction: &TransactionData) {}
+    /// unschedule_transaction forgets a scheduled transaction once it is released, expired or cancelled
+    fn unschedule_transaction(&self, _signature: &str) {}
+    /// take_scheduled returns the scheduled transactions recovered or taken over since the last call
+    fn take_scheduled(&self) -> Vec<TransactionData> {
+        vec![]
+    }
+    /// holds_scheduled is false once a scheduled transaction is sent by another instance instead
+    fn holds_scheduled(&self, _signature: &str) -> bool {
+        true
+    }
+    /// fetch_history returns the history of a transaction tracked by another instance
+    async fn fetch_history(&self, _signature: &str) -> Option<Vec<LifecycleEvent>> {
+        None
+    }
+    /// get_api_key returns the api key the queued transaction was sent with
+    async fn get_api_key(&self, signature: &str) -> Option<String> {
+        self.get_transactions()
+            .get(signature)
+            .map(|transaction| transaction.request_metadata.api_key.clone())
+    }
+    /// cancel_transaction removes the signature from the store and keeps a tombstone so it can't be resubmitted.
+    /// `held` is the transaction if it is held elsewhere, e.g. by the scheduler, so the tombstone of
+    /// a durable nonce transaction is kept until its nonce advances.
+    fn cancel_transaction(
+        &self,
+        signature: &str,
+        held: Option<&TransactionData>,
+    ) -> Option<TransactionData>;
+    fn is_cancelled(&self, signature: &str) -> bool;
+    /// replace_transaction swaps the queued transaction for `transaction`, keeping the original request metadata.
+    /// The replacement is refused unless it passes check_replacement. Returns the transaction as it was queued.
+    fn replace_transaction(
+        &self,
+        signature: &str,
+        transaction: TransactionData,
+    ) -> Result<TransactionData, AtlasTxnSenderError>;
 }
 
 pub struct TransactionStoreImpl {
     transactions: Arc<DashMap<String, TransactionData>>,
+    tombstones: Arc<DashMap<String, Tombstone>>,
+    /// number of queued transactions of each fee payer, kept in step with `transactions`
+    fee_payers: DashMap<Pubkey, usize>,
 }
 
 impl TransactionStoreImpl {
-    pub fn new() -> Self {
-        Self {
+    /// new returns a store whose nonce tombstones are released once `rpc_client` sees the nonce advance
+    pub fn new(rpc_client: Arc<RpcClient>) -> Self {
+        let transaction_store = Self {
             transactions: Arc::new(DashMap::new()),
+            tombstones: Arc::new(DashMap::new()),
+            fee_payers: DashMap::new(),
+        };
+        transaction_store.clean_tombstones(rpc_client);
+        transaction_store
+    }
+
+    /// get_tombstones returns the cancelled signatures and their tombstones
+    pub fn get_tombstones(&self) -> Vec<(String, Tombstone)> {
+        self.tombstones
+            .iter()
+            .map(|tombstone| (tombstone.key().to_owned(), *tombstone.value()))
+            .collect()
+    }
+
+    pub fn get_tombstone(&self, signature: &str) -> Option<Tombstone> {
+        self.tombstones.get(signature).map(|tombstone| *tombstone)
+    }
+
+    /// restore_tombstone brings back a cancellation recovered from disk or another instance
+    pub fn restore_tombstone(&self, signature: String, tombstone: Tombstone) {
+        self.tombstones.insert(signature, tombstone);
+    }
+
+    /// tombstone_transaction removes the queued transaction to replace it. It is tombstoned while
+    /// its entry is locked, so a concurrent retry, cancel or resubmit sees either the queued
+    /// original or the tombstone.
+    pub fn tombstone_transaction(&self, signature: &str) -> Option<TransactionData> {
+        match self.transactions.entry(signature.to_string()) {
+            Entry::Occupied(entry) => {
+                self.tombstones
+                    .insert(signature.to_string(), Tombstone::new(Some(entry.get())));
+                self.untrack_fee_payer(entry.get());
+                Some(entry.remove())
+            }
+            Entry::Vacant(_) => None,
+        }
+    }
+
+    fn track_fee_payer(&self, transaction: &TransactionData) {
+        if let Some(fee_payer) = get_fee_payer(transaction) {
+            *self.fee_payers.entry(fee_payer).or_default() += 1;
+        }
+    }
+
+    fn untrack_fee_payer(&self, transaction: &TransactionData) {
+        let Some(fee_payer) = get_fee_payer(transaction) else {
+            return;
+        };
+        if let Entry::Occupied(mut entry) = self.fee_payers.entry(fee_payer) {
+            *entry.get_mut() -= 1;
+            if *entry.get() == 0 {
+                entry.remove();
+            }
         }
     }
+
+    fn clean_tombstones(&self, rpc_client: Arc<RpcClient>) {
+        let tombstones = self.tombstones.clone();
+        tokio::spawn(async move {
+            loop {
+                tombstones.retain(|_, tombstone| !tombstone.is_expired());
+                let nonces: Vec<DurableNonce> = tombstones
+                    .iter()
+                    .filter(|tombstone| tombstone.cancelled_at.elapsed() >= TOMBSTONE_RETENTION)
+                    .filter_map(|tombstone| tombstone.nonce)
+                    .collect();
+                statsd_gauge!("nonce_tombstones", nonces.len() as u64);
+                if !nonces.is_empty() {
+                    let rpc_client = rpc_client.clone();
+                    match tokio::task::spawn_blocking(move || advanced_nonces(&rpc_client, &nonces))
+                        .await
+                    {
+                        Ok(advanced) => tombstones.retain(|_, tombstone| {
+                            !tombstone
+                                .nonce
+                                .is_some_and(|nonce| advanced.contains(&nonce))
+                        }),
+                        Err(e) => error!("Error checking nonces of tombstones: {}", e),
+                    }
+                }
+                sleep(NONCE_CHECK_INTERVAL).await;
+            }
+        });
+    }
+}
+
+/// advanced_nonces returns the nonces that moved on, or whose account was closed, so the
+/// transactions signed with them can never land. Nonces that can't be read are assumed current.
+pub fn advanced_nonces(rpc_client: &RpcClient, nonces: &[DurableNonce]) -> HashSet<DurableNonce> {
+    let mut advanced = HashSet::new();
+    for batch in nonces.chunks(NONCE_BATCH_SIZE) {
+        let accounts: Vec<Pubkey> = batch.iter().map(|nonce| nonce.account).collect();
+        let accounts = match rpc_client.get_multiple_accounts(&accounts) {
+            Ok(accounts) => accounts,
+            Err(e) => {
+                error!("Error reading nonce accounts: {}", e);
+                continue;
+            }
+        };
+        for (nonce, account) in batch.iter().zip(accounts) {
+            let current = account
+                .as_ref()
+                .and_then(|account| nonce_utils::data_from_account(account).ok())
+                .map(|data| data.blockhash());
+            if current != Some(nonce.nonce) {
+                advanced.insert(*nonce);
+            }
+        }
+    }
+    advanced
 }
 
 impl TransactionStore for TransactionStoreImpl {
@@ -43,10 +324,17 @@ impl TransactionStore for TransactionStoreImpl {
     fn add_transaction(&self, transaction: TransactionData) {
         let start = Instant::now();
         if let Some(signature) = get_signature(&transaction) {
-            if self.transactions.contains_key(&signature) {
+            // the entry is locked while the tombstone is checked, cancel and replace tombstone a
+            // signature while its entry is locked
+            let Entry::Vacant(entry) = self.transactions.entry(signature) else {
+                return;
+            };
+            if self.tombstones.contains_key(entry.key()) {
+                statsd_count!("cancelled_transaction_resubmitted", 1);
                 return;
             }
-            self.transactions.insert(signature.to_string(), transaction);
+            self.track_fee_payer(&transaction);
+            entry.insert(transaction);
         } else {
             error!("Transaction has no signatures");
         }
@@ -54,13 +342,140 @@ impl TransactionStore for TransactionStoreImpl {
     }
     fn remove_transaction(&self, signature: String) -> Option<TransactionData> {
         let start = Instant::now();
-        let transaction = self.transactions.remove(&signature);
+        let transaction = self.transactions.remove(&signature).map(|t| t.1);
+        if let Some(transaction) = &transaction {
+            self.untrack_fee_payer(transaction);
+        }
         statsd_time!("remove_signature_time", start.elapsed());
-        transaction.map(|t| t.1)
+        transaction
     }
     fn get_transactions(&self) -> Arc<DashMap<String, TransactionData>> {
         self.transactions.clone()
     }
+    fn get_fee_payers(&self) -> BTreeSet<String> {
+        self.fee_payers
+            .iter()
+            .map(|fee_payer| fee_payer.key().to_string())
+            .collect()
+    }
+    fn record_event(&self, signature: &str, event: LifecycleEvent) {
+        if let Some(mut transaction) = self.transactions.get_mut(signature) {
+            transaction.history.push(event);
+        }
+    }
+    fn cancel_transaction(
+        &self,
+        signature: &str,
+        held: Option<&TransactionData>,
+    ) -> Option<TransactionData> {
+        let transaction = match self.transactions.entry(signature.to_string()) {
+            Entry::Occupied(entry) => {
+                self.tombstones
+                    .insert(signature.to_string(), Tombstone::new(Some(entry.get())));
+                self.untrack_fee_payer(entry.get());
+                Some(entry.remove())
+            }
+            Entry::Vacant(_) => {
+                self.tombstones
+                    .insert(signature.to_string(), Tombstone::new(held));
+                None
+            }
+        };
+        statsd_count!("transaction_cancelled", 1, "queued" => if transaction.is_some() { "true" } else { "false" });
+        transaction
+    }
+    fn is_cancelled(&self, signature: &str) -> bool {
+        self.tombstones.contains_key(signature)
+    }
+    fn replace_transaction(
+        &self,
+        signature: &str,
+        mut transaction: TransactionData,
+    ) -> Result<TransactionData, AtlasTxnSenderError> {
+        let new_signature = get_signature(&transaction).ok_or_else(|| {
+            AtlasTxnSenderError::from("Transaction has no signatures".to_string())
+        })?;
+        if self.transactions.contains_key(&new_signature) || self.is_cancelled(&new_signature) {
+            return Err(format!("Transaction {new_signature} was already submitted").into());
+        }
+        match self.transactions.get(signature) {
+            Some(original) => check_replacement(&original, &transaction)?,
+            None => return Err(format!("Transaction {signature} is not queued").into()),
+        }
+        let original = self
+            .tombstone_transaction(signature)
+            .ok_or_else(|| format!("Transaction {signature} is not queued"))?;
+        transaction.request_metadata = original.request_metadata.clone();
+        transaction.max_retries = original.max_retries;
+        match self.transactions.entry(new_signature.clone()) {
+            Entry::Vacant(entry) => {
+                self.track_fee_payer(&transaction);
+                entry.insert(transaction.clone());
+            }
+            Entry::Occupied(_) => {
+                // the replacement was submitted in the meantime, keep the original queued
+                self.tombstones.remove(signature);
+                self.track_fee_payer(&original);
+                self.transactions.insert(signature.to_string(), original);
+                return Err(format!("Transaction {new_signature} was already submitted").into());
+            }
+        }
+        statsd_count!("transaction_replaced", 1);
+        Ok(transaction)
+    }
+}
+
+/// check_replacement refuses a replacement that doesn't take the place of the original, or that was
+/// sent with another api key. It has to be paid by the same fee payer and spend the same durable
+/// nonce, so only one of them lands, or use the same blockhash if the original doesn't spend one.
+pub fn check_replacement(
+    original: &TransactionData,
+    replacement: &TransactionData,
+) -> Result<(), AtlasTxnSenderError> {
+    if original.request_metadata.api_key != replacement.request_metadata.api_key {
+        return Err("Transaction was sent with another api key"
+            .to_string()
+            .into());
+    }
+    let fee_payer = get_fee_payer(original);
+    if get_fee_payer(replacement) != fee_payer {
+        return Err(format!(
+            "Replacement must be paid by the fee payer of the original, {}",
+            fee_payer
+                .map(|fee_payer| fee_payer.to_string())
+                .unwrap_or_default()
+        )
+        .into());
+    }
+    let nonce = DurableNonce::from_transaction(&original.versioned_transaction);
+    let replacement_nonce = DurableNonce::from_transaction(&replacement.versioned_transaction);
+    match nonce {
+        Some(nonce) if replacement_nonce != Some(nonce) => Err(format!(
+            "Replacement must spend the durable nonce of the original, {}",
+            String::from(nonce)
+        )
+        .into()),
+        None if replacement_nonce.is_some()
+            || replacement.versioned_transaction.message.recent_blockhash()
+                != original.versioned_transaction.message.recent_blockhash() =>
+        {
+            Err(format!(
+                "Replacement must use the blockhash of the original, {}",
+                original.versioned_transaction.message.recent_blockhash()
+            )
+            .into())
+        }
+        _ => Ok(()),
+    }
+}
+
+pub fn get_fee_payer(transaction: &TransactionData) -> Option<Pubkey> {
+    transaction
+        .versioned_transaction
+        .message
+        .static_account_keys()
+        .first()
+        .copied()
 }
 
 pub fn get_signature(transaction: &TransactionData) -> Option<String> {
@@ -70,3 +485,237 @@ pub fn get_signature(transaction: &TransactionData) -> Option<String> {
         .first()
         .map(|s| s.to_string())
 }
+
+#[cfg(test)]
+mod tests {
+    use solana_sdk::{
+        signature::Keypair, signer::Signer, system_transaction, transaction::Transaction,
+    };
+    use solana_system_interface::instruction as system_instruction;
+
+    use super::*;
+    use crate::test_utils::init_metrics;
+
+    fn store() -> TransactionStoreImpl {
+        init_metrics();
+        TransactionStoreImpl::new(Arc::new(RpcClient::new("http://127.0.0.1:1".to_string())))
+    }
+
+    fn transaction_data(transaction: Transaction) -> TransactionData {
+        TransactionData {
+            wire_transaction: vec![],
+            versioned_transaction: VersionedTransaction::from(transaction),
+            sent_at: Instant::now(),
+            retry_count: 0,
+            max_retries: 5,
+            priority: TransactionPriority::default(),
+            request_metadata: RequestMetadata::default(),
+            history: vec![],
+        }
+    }
+
+    fn transfer(lamports: u64) -> TransactionData {
+        transfer_from(&Keypair::new(), lamports, Hash::new_unique())
+    }
+
+    fn transfer_from(payer: &Keypair, lamports: u64, blockhash: Hash) -> TransactionData {
+        transaction_data(system_transaction::transfer(
+            payer,
+            &Pubkey::new_unique(),
+            lamports,
+            blockhash,
+        ))
+    }
+
+    fn nonce_transfer(nonce: DurableNonce) -> TransactionData {
+        nonce_transfer_from(&Keypair::new(), nonce, 1)
+    }
+
+    fn nonce_transfer_from(payer: &Keypair, nonce: DurableNonce, lamports: u64) -> TransactionData {
+        transaction_data(Transaction::new_signed_with_payer(
+            &[
+                system_instruction::advance_nonce_account(&nonce.account, &payer.pubkey()),
+                system_instruction::transfer(&payer.pubkey(), &Pubkey::new_unique(), lamports),
+            ],
+            Some(&payer.pubkey()),
+            &[payer],
+            nonce.nonce,
+        ))
+    }
+
+    fn signature(transaction: &TransactionData) -> String {
+        get_signature(transaction).unwrap()
+    }
+
+    #[test]
+    fn detects_durable_nonces() {
+        let nonce = DurableNonce {
+            account: Pubkey::new_unique(),
+            nonce: Hash::new_unique(),
+        };
+        let transaction = nonce_transfer(nonce);
+        assert_eq!(
+            DurableNonce::from_transaction(&transaction.versioned_transaction),
+            Some(nonce)
+        );
+        assert_eq!(
+            DurableNonce::from_transaction(&transfer(1).versioned_transaction),
+            None
+        );
+        assert_eq!(DurableNonce::try_from(String::from(nonce)), Ok(nonce));
+        assert!(DurableNonce::try_from("not a nonce".to_string()).is_err());
+    }
+
+    #[test]
+    fn nonce_tombstones_do_not_expire() {
+        let cancelled_at = Instant::now() - TOMBSTONE_RETENTION;
+        let tombstone = Tombstone {
+            cancelled_at,
+            nonce: None,
+        };
+        assert!(tombstone.is_expired());
+        let tombstone = Tombstone {
+            cancelled_at,
+            nonce: Some(DurableNonce {
+                account: Pubkey::new_unique(),
+                nonce: Hash::new_unique(),
+            }),
+        };
+        assert!(!tombstone.is_expired());
+    }
+
+    #[tokio::test]
+    async fn cancelled_transaction_is_not_added() {
+        let store = store();
+        let transaction = transfer(1);
+        let signature = signature(&transaction);
+        store.add_transaction(transaction.clone());
+        assert!(store.cancel_transaction(&signature, None).is_some());
+        assert!(store.is_cancelled(&signature));
+        store.add_transaction(transaction);
+        assert!(!store.has_signature(&signature));
+    }
+
+    #[tokio::test]
+    async fn cancel_keeps_nonce_of_held_transaction() {
+        let store = store();
+        let nonce = DurableNonce {
+            account: Pubkey::new_unique(),
+            nonce: Hash::new_unique(),
+        };
+        let transaction = nonce_transfer(nonce);
+        let signature = signature(&transaction);
+        assert!(store
+            .cancel_transaction(&signature, Some(&transaction))
+            .is_none());
+        assert_eq!(store.get_tombstone(&signature).unwrap().nonce, Some(nonce));
+    }
+
+    #[tokio::test]
+    async fn replace_swaps_transaction() {
+        let store = store();
+        let (payer, blockhash) = (Keypair::new(), Hash::new_unique());
+        let mut original = transfer_from(&payer, 1, blockhash);
+        original.max_retries = 2;
+        original.request_metadata.api_key = "key".to_string();
+        let original_signature = signature(&original);
+        let mut replacement = transfer_from(&payer, 2, blockhash);
+        replacement.request_metadata.api_key = "key".to_string();
+        let replacement_signature = signature(&replacement);
+        store.add_transaction(original);
+
+        let replaced = store
+            .replace_transaction(&original_signature, replacement)
+            .unwrap();
+        assert_eq!(replaced.max_retries, 2);
+        assert_eq!(replaced.request_metadata.api_key, "key");
+        assert!(!store.has_signature(&original_signature));
+        assert!(store.is_cancelled(&original_signature));
+        assert!(store.has_signature(&replacement_signature));
+    }
+
+    #[tokio::test]
+    async fn tracks_fee_payers() {
+        let store = store();
+        let first = transfer(1);
+        let fee_payer = get_fee_payer(&first).unwrap().to_string();
+        let mut second = first.clone();
+        second.versioned_transaction.signatures[0] = solana_sdk::signature::Signature::new_unique();
+        let (other_payer, blockhash) = (Keypair::new(), Hash::new_unique());
+        let other = transfer_from(&other_payer, 2, blockhash);
+        store.add_transaction(first.clone());
+        store.add_transaction(second.clone());
+        store.add_transaction(other.clone());
+        assert_eq!(store.get_fee_payers().len(), 2);
+
+        store.remove_transaction(signature(&first));
+        assert!(store.get_fee_payers().contains(&fee_payer));
+        store.cancel_transaction(&signature(&second), None);
+        assert!(!store.get_fee_payers().contains(&fee_payer));
+
+        let replacement = transfer_from(&other_payer, 3, blockhash);
+        store
+            .replace_transaction(&signature(&other), replacement)
+            .unwrap();
+        assert_eq!(
+            store.get_fee_payers(),
+            BTreeSet::from([other_payer.pubkey().to_string()])
+        );
+    }
+
+    #[tokio::test]
+    async fn replace_fails_without_changes() {
+        let store = store();
+        let original = transfer(1);
+        let original_signature = signature(&original);
+        let queued = transfer(2);
+        store.add_transaction(original);
+        store.add_transaction(queued.clone());
+
+        // the replacement is already queued
+        assert!(store
+            .replace_transaction(&original_signature, queued)
+            .is_err());
+        assert!(store.has_signature(&original_signature));
+        assert!(!store.is_cancelled(&original_signature));
+
+        // the original isn't queued
+        let replacement = transfer(3);
+        assert!(store
+            .replace_transaction("unknown", replacement.clone())
+            .is_err());
+        assert!(!store.has_signature(&signature(&replacement)));
+    }
+
+    #[test]
+    fn replacement_spends_the_same_nonce_or_blockhash() {
+        let (payer, blockhash) = (Keypair::new(), Hash::new_unique());
+        let original = transfer_from(&payer, 1, blockhash);
+        assert!(check_replacement(&original, &transfer_from(&payer, 2, blockhash)).is_ok());
+        // could land alongside the original
+        assert!(
+            check_replacement(&original, &transfer_from(&payer, 2, Hash::new_unique())).is_err()
+        );
+        assert!(
+            check_replacement(&original, &transfer_from(&Keypair::new(), 2, blockhash)).is_err()
+        );
+        let mut other_key = transfer_from(&payer, 2, blockhash);
+        other_key.request_metadata.api_key = "other".to_string();
+        assert!(check_replacement(&original, &other_key).is_err());
+
+        let nonce = DurableNonce {
+            account: Pubkey::new_unique(),
+            nonce: Hash::new_unique(),
+        };
+        let original = nonce_transfer_from(&payer, nonce, 1);
+        assert!(check_replacement(&original, &nonce_transfer_from(&payer, nonce, 2)).is_ok());
+        let other_nonce = DurableNonce {
+            account: Pubkey::new_unique(),
+            nonce: nonce.nonce,
+        };
+        assert!(
+            check_replacement(&original, &nonce_transfer_from(&payer, other_nonce, 2)).is_err()
+        );
+        assert!(check_replacement(&original, &transfer_from(&payer, 2, nonce.nonce)).is_err());
+    }
+}
@@ -1,7 +1,11 @@
 use cadence_macros::{statsd_count, statsd_gauge, statsd_time};
 use solana_client::connection_cache::ConnectionCache;
 use solana_connection_cache::nonblocking::client_connection::ClientConnection;
+use solana_rpc_client_api::response::RpcContactInfo;
+use solana_sdk::pubkey::Pubkey;
 use std::{
+    collections::HashMap,
+    net::SocketAddr,
     sync::Arc,
     time::{Duration, Instant},
 };
@@ -10,18 +14,183 @@ use tokio::{
     time::{sleep, timeout},
 };
 use tonic::async_trait;
-use tracing::{error, info, warn};
+use tracing::{error, field, info_span, warn, Instrument, Span};
 
 use crate::{
+    attribution::LandingAttribution,
+    events::{EventBus, TransactionEvent},
+    identity::{Identity, IdentityPool},
+    latency::PipelineLatency,
     leader_tracker::{LeaderTracker, LeaderTrackerTrait},
-    rpc_server::RequestMetadata,
+    lifecycle::{TransactionLifecycles, TransactionState},
+    priority::RetryQueuePolicy,
+    rpc_server::{RequestMetadata, SendPorts},
+    signature_status::SignatureStatus,
     solana_rpc::SolanaRpc,
+    telemetry::in_request_trace,
+    throttle::SendThrottle,
     transaction_store::{get_signature, TransactionData, TransactionStore},
+    webhook::{CallbackPayload, CallbackStatus, WebhookNotifier},
 };
 
 const MAX_TIMEOUT_SEND_DATA: Duration = Duration::from_millis(500);
 const MAX_TIMEOUT_SEND_DATA_BATCH: Duration = Duration::from_millis(500);
 const SEND_TXN_RETRIES: usize = 10;
+const THROTTLE_DRAIN_INTERVAL: Duration = Duration::from_millis(10);
+// how often transactions adopted by the store, e.g. recovered ones, are picked up
+const ADOPTED_TRACK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// LeaderSend is a transaction on its way to one leader
+pub struct LeaderSend {
+    pub signature: String,
+    pub identity: Pubkey,
+    pub socket_addr: SocketAddr,
+    connection_cache: Arc<ConnectionCache>,
+    leader: RpcContactInfo,
+    leader_num: usize,
+    wire_transaction: Vec<u8>,
+    /// api key and receive time of a first send, None for retries
+    request: Option<(String, Instant)>,
+    sent_at: Instant,
+    span: Span,
+}
+
+impl LeaderSend {
+    /// new returns None if the leader has no gossip address
+    pub fn new(
+        signature: String,
+        identity: &Identity,
+        leader: &RpcContactInfo,
+        leader_num: usize,
+        send_port: u16,
+        wire_transaction: Vec<u8>,
+        request: Option<(String, Instant)>,
+    ) -> Option<Self> {
+        let Some(mut socket_addr) = leader.gossip else {
+            error!("leader {:?} has no gossip", leader);
+            return None;
+        };
+        socket_addr.set_port(send_port);
+        let span = if in_request_trace() {
+            info_span!(
+                "sendToLeader",
+                signature,
+                route = SendPorts::from(send_port).as_str(),
+                leader = leader.pubkey,
+                leader_num,
+                destination = %socket_addr,
+                outcome = field::Empty,
+            )
+        } else {
+            Span::none()
+        };
+        Some(Self {
+            signature,
+            identity: identity.pubkey,
+            socket_addr,
+            connection_cache: identity.connection_cache.clone(),
+            leader: leader.clone(),
+            leader_num,
+            wire_transaction,
+            request,
+            sent_at: Instant::now(),
+            span,
+        })
+    }
+
+    /// send sends the transaction, retrying unless it times out, records the delivery for landing
+    /// attribution and publishes the outcome
+    pub async fn send(
+        self,
+        landing_attribution: Arc<LandingAttribution>,
+        event_bus: Arc<EventBus>,
+        pipeline_latency: Arc<PipelineLatency>,
+    ) {
+        let span = self.span.clone();
+        self.send_to_leader(landing_attribution, event_bus, pipeline_latency)
+            .instrument(span)
+            .await
+    }
+
+    async fn send_to_leader(
+        self,
+        landing_attribution: Arc<LandingAttribution>,
+        event_bus: Arc<EventBus>,
+        pipeline_latency: Arc<PipelineLatency>,
+    ) {
+        let (retry, send_timeout) = match self.request {
+            Some(_) => ("false", MAX_TIMEOUT_SEND_DATA),
+            None => ("true", MAX_TIMEOUT_SEND_DATA_BATCH),
+        };
+        let start = Instant::now();
+        let mut outcome = "timeout";
+        for i in 0..SEND_TXN_RETRIES {
+            let conn = self
+                .connection_cache
+                .get_nonblocking_connection(&self.socket_addr);
+            match timeout(send_timeout, conn.send_data(&self.wire_transaction)).await {
+                Ok(Ok(())) => {
+                    let leader_num_str = self.leader_num.to_string();
+                    let identity = self.identity.to_string();
+                    let (api_key, received_at) = match &self.request {
+                        Some((api_key, received_at)) => (api_key.as_str(), *received_at),
+                        None => ("not_applicable", self.sent_at),
+                    };
+                    statsd_time!(
+                        "transaction_received_by_leader",
+                        received_at.elapsed(), "leader_num" => &leader_num_str, "api_key" => api_key, "retry" => retry, "identity" => &identity);
+                    landing_attribution.record_delivery(
+                        &self.signature,
+                        &self.leader.pubkey,
+                        self.leader_num,
+                    );
+                    pipeline_latency.record_leader_ack(&self.signature, self.leader_num);
+                    self.publish(&event_bus, "delivered", i + 1, start);
+                    return;
+                }
+                Ok(Err(e)) => {
+                    outcome = "error";
+                    if i == SEND_TXN_RETRIES - 1 {
+                        error!(
+                            retry,
+                            "Failed to send transaction to {:?}: {}", self.leader, e
+                        );
+                        statsd_count!("transaction_send_error", 1, "retry" => retry, "last_attempt" => "true");
+                    } else {
+                        statsd_count!("transaction_send_error", 1, "retry" => retry, "last_attempt" => "false");
+                    }
+                }
+                Err(_) => {
+                    outcome = "timeout";
+                    // Note: This is far too frequent to log. It will fill the disks on the host and cost too much on DD.
+                    statsd_count!("transaction_send_timeout", 1);
+                }
+            }
+        }
+        self.publish(&event_bus, outcome, SEND_TXN_RETRIES, start);
+    }
+
+    fn publish(
+        &self,
+        event_bus: &EventBus,
+        outcome: &'static str,
+        attempts: usize,
+        start: Instant,
+    ) {
+        self.span.record("outcome", outcome);
+        event_bus.publish(TransactionEvent::SendAttempt {
+            signature: self.signature.clone(),
+            leader: self.leader.pubkey.clone(),
+            leader_num: self.leader_num,
+            destination: self.socket_addr.to_string(),
+            identity: self.identity.to_string(),
+            retry: self.request.is_none(),
+            outcome,
+            attempts,
+            elapsed_ms: start.elapsed().as_millis() as u64,
+        });
+    }
+}
 
 #[async_trait]
 pub trait TxnSender: Send + Sync {
@@ -31,22 +200,35 @@ pub trait TxnSender: Send + Sync {
 pub struct TxnSenderImpl {
     leader_tracker: Arc<LeaderTracker>,
     transaction_store: Arc<dyn TransactionStore>,
-    connection_cache: Arc<ConnectionCache>,
+    identities: Arc<IdentityPool>,
     solana_rpc: Arc<dyn SolanaRpc>,
     txn_sender_runtime: Arc<Runtime>,
     txn_send_retry_interval_seconds: usize,
-    max_retry_queue_size: Option<usize>,
+    retry_queue_policy: RetryQueuePolicy,
+    send_throttle: Arc<SendThrottle>,
+    landing_attribution: Arc<LandingAttribution>,
+    transaction_lifecycles: Arc<TransactionLifecycles>,
+    event_bus: Arc<EventBus>,
+    webhooks: Arc<WebhookNotifier>,
+    pipeline_latency: Arc<PipelineLatency>,
 }
 
 impl TxnSenderImpl {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         leader_tracker: Arc<LeaderTracker>,
         transaction_store: Arc<dyn TransactionStore>,
-        connection_cache: Arc<ConnectionCache>,
+        identities: Arc<IdentityPool>,
         solana_rpc: Arc<dyn SolanaRpc>,
         txn_sender_threads: usize,
         txn_send_retry_interval_seconds: usize,
-        max_retry_queue_size: Option<usize>,
+        retry_queue_policy: RetryQueuePolicy,
+        send_throttle: Arc<SendThrottle>,
+        landing_attribution: Arc<LandingAttribution>,
+        transaction_lifecycles: Arc<TransactionLifecycles>,
+        event_bus: Arc<EventBus>,
+        webhooks: Arc<WebhookNotifier>,
+        pipeline_latency: Arc<PipelineLatency>,
     ) -> Self {
         let txn_sender_runtime = Builder::new_multi_thread()
             .worker_threads(txn_sender_threads)
@@ -56,23 +238,36 @@ impl TxnSenderImpl {
         let txn_sender = Self {
             leader_tracker,
             transaction_store,
-            connection_cache,
+            identities,
             solana_rpc,
             txn_sender_runtime: Arc::new(txn_sender_runtime),
             txn_send_retry_interval_seconds,
-            max_retry_queue_size,
+            retry_queue_policy,
+            send_throttle,
+            landing_attribution,
+            transaction_lifecycles,
+            event_bus,
+            webhooks,
+            pipeline_latency,
         };
         txn_sender.retry_transactions();
+        txn_sender.send_throttled_transactions();
         txn_sender
     }
 
     fn retry_transactions(&self) {
         let leader_tracker = self.leader_tracker.clone();
         let transaction_store = self.transaction_store.clone();
-        let connection_cache = self.connection_cache.clone();
+        let identities = self.identities.clone();
         let txn_sender_runtime = self.txn_sender_runtime.clone();
         let txn_send_retry_interval_seconds = self.txn_send_retry_interval_seconds;
-        let max_retry_queue_size = self.max_retry_queue_size;
+        let retry_queue_policy = self.retry_queue_policy.clone();
+        let solana_rpc = self.solana_rpc.clone();
+        let send_throttle = self.send_throttle.clone();
+        let landing_attribution = self.landing_attribution.clone();
+        let transaction_lifecycles = self.transaction_lifecycles.clone();
+        let event_bus = self.event_bus.clone();
+        let pipeline_latency = self.pipeline_latency.clone();
         tokio::spawn(async move {
             loop {
                 let mut transactions_reached_max_retries = vec![];
@@ -80,11 +275,11 @@ impl TxnSenderImpl {
                 let queue_length = transaction_map.len();
                 statsd_gauge!("transaction_retry_queue_length", queue_length as u64);
 
-                // Shed transactions by retry_count, if necessary.
-                if let Some(max_size) = max_retry_queue_size {
+                // Shed the lowest priority transactions, if necessary.
+                if let Some(max_size) = retry_queue_policy.max_size {
                     if queue_length > max_size {
                         warn!(
-                            "Transaction retry queue length is over the limit of {}: {}. Load shedding transactions with highest retry count.",
+                            "Transaction retry queue length is over the limit of {}: {}. Load shedding transactions with lowest effective priority.",
                             max_size,
                             queue_length
                         );
@@ -92,22 +287,66 @@ impl TxnSenderImpl {
                             .iter()
                             .map(|x| (x.key().to_owned(), x.value().to_owned()))
                             .collect();
-                        transactions.sort_by(|(_, a), (_, b)| a.retry_count.cmp(&b.retry_count));
-                        let transactions_to_remove = transactions[(max_size + 1)..].to_vec();
-                        for (signature, _) in transactions_to_remove {
+                        let transactions_to_remove = retry_queue_policy.shed(&mut transactions);
+                        let records_dropped = transactions_to_remove.len();
+                        for (signature, reason) in transactions_to_remove {
                             transaction_store.remove_transaction(signature.clone());
                             transaction_map.remove(&signature);
+                            transaction_lifecycles.record(
+                                &signature,
+                                TransactionState::Shed,
+                                Some(reason.as_str().to_string()),
+                            );
+                            statsd_count!("transactions_retry_queue_shed", 1, "reason" => reason.as_str());
                         }
-                        let records_dropped = queue_length - max_size;
                         statsd_gauge!("transactions_retry_queue_dropped", records_dropped as u64);
                     }
                 }
 
+                let cur_slot = solana_rpc.get_next_slot();
+                let mut transactions_expired = vec![];
                 let mut wire_transactions = vec![];
+                let mut retried = vec![];
+                let mut cur_leaders = HashMap::new();
                 for mut transaction_data in transaction_map.iter_mut() {
+                    if let (Some(schedule), Some(cur_slot)) =
+                        (&transaction_data.request_metadata.schedule, cur_slot)
+                    {
+                        if schedule.is_expired(cur_slot) {
+                            transactions_expired.push(transaction_data.key().to_owned());
+                            continue;
+                        }
+                    }
+                    let RequestMetadata {
+                        leader_policy,
+                        schedule,
+                        ..
+                    } = &transaction_data.request_metadata;
+                    let policy_leaders = cur_leaders
+                        .entry(*leader_policy)
+                        .or_insert_with(|| leader_tracker.get_leaders(*leader_policy))
+                        .clone();
+                    let leaders = match schedule {
+                        Some(schedule) => schedule.filter_leaders(policy_leaders),
+                        None => policy_leaders,
+                    };
+                    // e.g. no allowed leader is upcoming, the retry is kept for when one is
+                    if leaders.is_empty() {
+                        statsd_count!("transaction_retry_skipped_no_leaders", 1);
+                        continue;
+                    }
                     wire_transactions.push((
-                        transaction_data.request_metadata.send_port,
+                        leaders,
+                        transaction_data
+                            .priority
+                            .effective_priority(transaction_data.retry_count),
+                        transaction_data.key().to_owned(),
                         transaction_data.wire_transaction.clone(),
+                        transaction_data.request_metadata.clone(),
+                    ));
+                    retried.push((
+                        transaction_data.key().to_owned(),
+                        transaction_data.retry_count + 1,
                     ));
                     if transaction_data.retry_count >= transaction_data.max_retries {
                         transactions_reached_max_retries
@@ -116,69 +355,241 @@ impl TxnSenderImpl {
                         transaction_data.retry_count += 1;
                     }
                 }
-                for (send_port, wire_transaction) in wire_transactions.iter() {
-                    for (leader_num, leader) in leader_tracker.get_leaders().iter().enumerate() {
-                        let connection_cache = connection_cache.clone();
-                        let sent_at = Instant::now();
-                        let leader = Arc::new(leader.clone());
-                        let mut socket_addr = leader.gossip.unwrap();
-                        info!("p3 port is: {}", send_port);
-                        socket_addr.set_port(*send_port);
-                        let wire_transaction = wire_transaction.clone();
-                        txn_sender_runtime.spawn(async move {
-                        // retry unless its a timeout
-                        for i in 0..SEND_TXN_RETRIES {
-                            let conn = connection_cache
-                                .get_nonblocking_connection(&socket_addr);
-                            if let Ok(result) = timeout(MAX_TIMEOUT_SEND_DATA_BATCH, conn.send_data(&wire_transaction)).await {
-                                if let Err(e) = result {
-                                    if i == SEND_TXN_RETRIES-1 {
-                                        error!(
-                                            retry = "true",
-                                            "Failed to send transaction batch to {:?}: {}",
-                                            leader, e
-                                        );
-                                        statsd_count!("transaction_send_error", 1, "retry" => "true", "last_attempt" => "true");
-                                    } else {
-                                        statsd_count!("transaction_send_error", 1, "retry" => "true", "last_attempt" => "false");
-                                    }
-                                } else {
-                                    let leader_num_str = leader_num.to_string();
-                                    statsd_time!(
-                                        "transaction_received_by_leader",
-                                        sent_at.elapsed(), "leader_num" => &leader_num_str, "api_key" => "not_applicable", "retry" => "true");
-                                    return;
-                                }
-                            } else {
-                                // Note: This is far too frequent to log. It will fill the disks on the host and cost too much on DD.
-                                statsd_count!("transaction_send_timeout", 1);
-                            }
+                // recorded once the store is unlocked, the history is kept with the transaction
+                for (signature, retry) in retried {
+                    transaction_lifecycles.record(
+                        &signature,
+                        TransactionState::Retried { retry },
+                        None,
+                    );
+                }
+                // send the most valuable transactions first
+                wire_transactions.sort_by(|(_, a, ..), (_, b, ..)| b.cmp(a));
+                for (leaders, priority, signature, wire_transaction, request_metadata) in
+                    wire_transactions.iter()
+                {
+                    let send_port = &request_metadata.send_port;
+                    let identity = identities.select(request_metadata);
+                    for (leader_num, leader) in leaders.iter().enumerate() {
+                        let Some(send) = LeaderSend::new(
+                            signature.clone(),
+                            &identity,
+                            leader,
+                            leader_num,
+                            *send_port,
+                            wire_transaction.clone(),
+                            None,
+                        ) else {
+                            continue;
+                        };
+                        if let Some(send) = send_throttle.submit(send, *priority) {
+                            txn_sender_runtime.spawn(send.send(
+                                landing_attribution.clone(),
+                                event_bus.clone(),
+                                pipeline_latency.clone(),
+                            ));
                         }
-                    });
                     }
                 }
                 // remove transactions that reached max retries
                 for signature in transactions_reached_max_retries {
+                    transaction_lifecycles.record(&signature, TransactionState::MaxRetries, None);
                     let _ = transaction_store.remove_transaction(signature);
                     statsd_count!("transactions_reached_max_retries", 1);
                 }
+                // remove scheduled transactions whose slot window passed
+                for signature in transactions_expired {
+                    transaction_lifecycles.record(
+                        &signature,
+                        TransactionState::Expired,
+                        Some("slot window passed".to_string()),
+                    );
+                    let _ = transaction_store.remove_transaction(signature);
+                    statsd_count!("scheduled_transaction_expired", 1);
+                }
                 sleep(Duration::from_secs(txn_send_retry_interval_seconds as u64)).await;
             }
         });
     }
 
+    /// send_throttled_transactions sends queued sends as their destinations get budget back
+    fn send_throttled_transactions(&self) {
+        let send_throttle = self.send_throttle.clone();
+        let txn_sender_runtime = self.txn_sender_runtime.clone();
+        let landing_attribution = self.landing_attribution.clone();
+        let event_bus = self.event_bus.clone();
+        let pipeline_latency = self.pipeline_latency.clone();
+        tokio::spawn(async move {
+            loop {
+                sleep(THROTTLE_DRAIN_INTERVAL).await;
+                for send in send_throttle.drain() {
+                    txn_sender_runtime.spawn(send.send(
+                        landing_attribution.clone(),
+                        event_bus.clone(),
+                        pipeline_latency.clone(),
+                    ));
+                }
+            }
+        });
+    }
+
+    /// track_adopted_transactions tracks the transactions the store queued without them being sent
+    /// through this instance, e.g. recovered from the transaction log, so they are confirmed,
+    /// attributed and timed, and their callbacks sent
+    pub fn track_adopted_transactions(self: &Arc<Self>) {
+        let txn_sender = self.clone();
+        tokio::spawn(async move {
+            loop {
+                for transaction_data in txn_sender.transaction_store.take_adopted() {
+                    statsd_count!("adopted_transaction_tracked", 1);
+                    txn_sender.track_transaction(&transaction_data);
+                }
+                sleep(ADOPTED_TRACK_INTERVAL).await;
+            }
+        });
+    }
+
     fn track_transaction(&self, transaction_data: &TransactionData) {
         let signature = get_signature(transaction_data);
         if signature.is_none() {
             return;
         }
         let signature = signature.unwrap();
-        self.transaction_store
-            .add_transaction(transaction_data.clone());
+        let mut queued = transaction_data.clone();
+        queued.history = self
+            .transaction_lifecycles
+            .get_history(&signature)
+            .unwrap_or_default();
+        self.transaction_store.add_transaction(queued);
         let solana_rpc = self.solana_rpc.clone();
-        let RequestMetadata { api_key, .. } = transaction_data.request_metadata.clone();
+        let leader_tracker = self.leader_tracker.clone();
+        let landing_attribution = self.landing_attribution.clone();
+        let transaction_lifecycles = self.transaction_lifecycles.clone();
+        let event_bus = self.event_bus.clone();
+        let webhooks = self.webhooks.clone();
+        let request_metadata = transaction_data.request_metadata.clone();
+        let RequestMetadata {
+            api_key,
+            send_port,
+            identity,
+            schedule,
+            ..
+        } = transaction_data.request_metadata.clone();
+        landing_attribution.track(
+            signature.clone(),
+            SendPorts::from(send_port).as_str(),
+            identity.unwrap_or_default(),
+            transaction_data.sent_at,
+        );
+        // scheduled transactions are held back on purpose, their stages would measure the schedule
+        if schedule.is_none() {
+            self.pipeline_latency.track(
+                signature.clone(),
+                SendPorts::from(send_port).as_str(),
+                &api_key,
+                transaction_data.sent_at,
+            );
+        }
+        let sent_at = transaction_data.sent_at;
+        let span = if in_request_trace() {
+            info_span!(
+                "confirmTransaction",
+                signature,
+                route = SendPorts::from(send_port).as_str(),
+                slot = field::Empty,
+                leader = field::Empty,
+                status = field::Empty,
+            )
+        } else {
+            Span::none()
+        };
         self.txn_sender_runtime.spawn(async move {
-            let confirmed_at = solana_rpc.confirm_transaction(signature.clone()).await;
+            let confirmed_at = solana_rpc
+                .confirm_transaction(signature.clone())
+                .instrument(span.clone())
+                .await;
+            let status = confirmed_at.and_then(|_| solana_rpc.get_signature_status(&signature));
+            // why we stopped sending it, if we did before the confirmation timed out
+            let stopped_state = transaction_lifecycles.get_state(&signature);
+            match &status {
+                Some(status) => {
+                    let state = match &status.err {
+                        Some(err) => TransactionState::FailedOnChain {
+                            slot: status.slot,
+                            err: err.clone(),
+                        },
+                        None => TransactionState::Landed { slot: status.slot },
+                    };
+                    transaction_lifecycles.record(&signature, state, None);
+                }
+                None if confirmed_at.is_none() => transaction_lifecycles.record(
+                    &signature,
+                    TransactionState::Expired,
+                    Some("not confirmed within the confirmation timeout".to_string()),
+                ),
+                None => {}
+            }
+            let (callback_status, reason) = match (&status, confirmed_at) {
+                (Some(SignatureStatus { err: Some(_), .. }), _) => {
+                    (CallbackStatus::FailedOnChain, None)
+                }
+                (_, Some(_)) => (CallbackStatus::Landed, None),
+                // the state the transaction was in before it timed out
+                (_, None) => match &stopped_state {
+                    Some(TransactionState::Shed) => (CallbackStatus::Dropped, Some("shed")),
+                    Some(TransactionState::Cancelled) => {
+                        (CallbackStatus::Dropped, Some("cancelled"))
+                    }
+                    Some(TransactionState::MaxRetries) => {
+                        (CallbackStatus::Expired, Some("max_retries"))
+                    }
+                    _ => (CallbackStatus::Expired, Some("not_confirmed")),
+                },
+            };
+            webhooks.notify(
+                &request_metadata,
+                CallbackPayload {
+                    signature: signature.clone(),
+                    status: callback_status,
+                    slot: status.as_ref().map(|status| status.slot),
+                    err: status.as_ref().and_then(|status| status.err.clone()),
+                    reason,
+                    history: transaction_lifecycles
+                        .get_history(&signature)
+                        .unwrap_or_default(),
+                },
+            );
+            let slot = status.map(|status| status.slot);
+            // the one terminal event of the transaction, published once it is known whether a send
+            // landed, even one made before we stopped sending
+            match confirmed_at {
+                Some(_) => event_bus.publish(TransactionEvent::Confirmed {
+                    signature: signature.clone(),
+                    slot,
+                    landing_ms: sent_at.elapsed().as_millis() as u64,
+                }),
+                None => event_bus.publish(TransactionEvent::Dropped {
+                    signature: signature.clone(),
+                    reason: match stopped_state {
+                        Some(TransactionState::Shed) => "shed",
+                        Some(TransactionState::Cancelled) => "cancelled",
+                        Some(TransactionState::MaxRetries) => "max_retries",
+                        Some(TransactionState::Expired) => "expired",
+                        _ => "not_confirmed",
+                    },
+                }),
+            };
+            let slot_leader = slot
+                .and_then(|slot| leader_tracker.get_slot_leader(slot))
+                .map(|leader| leader.pubkey);
+            if let Some(slot) = slot {
+                span.record("slot", slot);
+            }
+            if let Some(slot_leader) = &slot_leader {
+                span.record("leader", slot_leader.as_str());
+            }
+            span.record("status", callback_status.as_str());
+            landing_attribution.record_landing(&signature, slot, slot_leader);
 
             // Collect metrics
             // We separate the retry metrics to reduce the cardinality with API key and price.
@@ -195,55 +606,61 @@ impl TxnSenderImpl {
 
 #[async_trait]
 impl TxnSender for TxnSenderImpl {
-    fn send_transaction(&self, transaction_data: TransactionData) {
+    fn send_transaction(&self, mut transaction_data: TransactionData) {
+        // pin the identity so retries use the same one
+        let identity = self.identities.select(&transaction_data.request_metadata);
+        transaction_data.request_metadata.identity = Some(identity.pubkey.to_string());
         self.track_transaction(&transaction_data);
-        let RequestMetadata { api_key, send_port } = transaction_data.request_metadata.clone();
-        let mut leader_num = 0;
-        for leader in self.leader_tracker.get_leaders() {
-            if leader.gossip.is_none() {
-                error!("leader {:?} has no gossip", leader);
+        let RequestMetadata {
+            api_key,
+            send_port,
+            leader_policy,
+            schedule,
+            ..
+        } = transaction_data.request_metadata.clone();
+        let mut leaders = self.leader_tracker.get_leaders(leader_policy);
+        if let Some(schedule) = &schedule {
+            leaders = schedule.filter_leaders(leaders);
+        }
+        if leaders.is_empty() {
+            statsd_count!("transaction_no_permitted_leaders", 1, "leader_policy" => leader_policy.as_str());
+        }
+        let priority = transaction_data.priority.effective_priority(0);
+        let signature = get_signature(&transaction_data).unwrap_or_default();
+        self.transaction_lifecycles.record(
+            &signature,
+            TransactionState::Sent,
+            Some(format!("{} leaders", leaders.len())),
+        );
+        self.event_bus.publish(TransactionEvent::Submitted {
+            signature: signature.clone(),
+            api_key: api_key.clone(),
+            route: SendPorts::from(send_port).as_str(),
+            identity: identity.pubkey.to_string(),
+            leader_policy: leader_policy.as_str(),
+            compute_unit_price: transaction_data.priority.compute_unit_price,
+            tip_lamports: transaction_data.priority.tip_lamports,
+        });
+        for (leader_num, leader) in leaders.iter().enumerate() {
+            let Some(send) = LeaderSend::new(
+                signature.clone(),
+                &identity,
+                leader,
+                leader_num,
+                send_port,
+                transaction_data.wire_transaction.clone(),
+                Some((api_key.clone(), transaction_data.sent_at)),
+            ) else {
                 continue;
+            };
+            if let Some(send) = self.send_throttle.submit(send, priority) {
+                self.txn_sender_runtime.spawn(send.send(
+                    self.landing_attribution.clone(),
+                    self.event_bus.clone(),
+                    self.pipeline_latency.clone(),
+                ));
             }
-            let connection_cache = self.connection_cache.clone();
-            let wire_transaction = transaction_data.wire_transaction.clone();
-            let api_key = api_key.clone();
-            self.txn_sender_runtime.spawn(async move {
-                let mut socket_addr = leader.gossip.unwrap();
-                socket_addr.set_port(send_port);
-
-                for i in 0..SEND_TXN_RETRIES {
-                    let conn =
-                        connection_cache.get_nonblocking_connection(&socket_addr);
-                        let e = conn.server_addr();
-                        error!("___{:?}", e);
-
-                    if let Ok(result) = timeout(MAX_TIMEOUT_SEND_DATA, conn.send_data(&wire_transaction)).await {
-                            if let Err(e) = result {
-                                if i == SEND_TXN_RETRIES-1 {
-                                    error!(
-                                        retry = "false",
-                                        "Failed to send transaction to {:?}: {}",
-                                        leader, e
-                                    );
-                                    statsd_count!("transaction_send_error", 1, "retry" => "false", "last_attempt" => "true");
-                                } else {
-                                    statsd_count!("transaction_send_error", 1, "retry" => "false", "last_attempt" => "false");
-                                }
-                        } else {
-                            let leader_num_str = leader_num.to_string();
-                            info!("Data sent!");
-                            statsd_time!(
-                                "transaction_received_by_leader",
-                                transaction_data.sent_at.elapsed(), "leader_num" => &leader_num_str, "api_key" => &api_key, "retry" => "false");
-                            return;
-                        }
-                    } else {
-                        // Note: This is far too frequent to log. It will fill the disks on the host and cost too much on DD.
-                        statsd_count!("transaction_send_timeout", 1);
-                    }
-                }
-            });
-            leader_num += 1;
         }
+        self.pipeline_latency.record_enqueue(&signature);
     }
 }
@@ -0,0 +1,694 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, Ipv4Addr},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use cadence_macros::statsd_count;
+use hmac::{Hmac, Mac};
+use hyper::client::connect::dns::Name;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use serde::Serialize;
+use sha2::Sha256;
+use solana_sdk::{slot_history::Slot, transaction::TransactionError};
+use tokio::{
+    fs::OpenOptions,
+    io::AsyncWriteExt,
+    net::lookup_host,
+    sync::{mpsc, Semaphore},
+    time::sleep,
+};
+use tracing::{error, warn};
+
+use crate::{
+    errors::AtlasTxnSenderError, lifecycle::LifecycleEvent, persistent_store::unix_millis_now,
+    rpc_server::RequestMetadata,
+};
+
+const CALLBACK_TIMEOUT: Duration = Duration::from_secs(10);
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+// callbacks waiting to be delivered, being delivered including the ones waiting to be retried,
+// and being posted at once
+const CALLBACK_QUEUE_SIZE: usize = 10_000;
+const MAX_PENDING_CALLBACKS: usize = 10_000;
+const MAX_CONCURRENT_CALLBACKS: usize = 100;
+
+/// CallbackStatus is the outcome of a transaction reported to its callback
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum CallbackStatus {
+    Landed,
+    FailedOnChain,
+    Expired,
+    Dropped,
+}
+
+impl CallbackStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CallbackStatus::Landed => "landed",
+            CallbackStatus::FailedOnChain => "failed_on_chain",
+            CallbackStatus::Expired => "expired",
+            CallbackStatus::Dropped => "dropped",
+        }
+    }
+}
+
+/// CallbackPayload is the body POSTed to the callback url
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CallbackPayload {
+    pub signature: String,
+    pub status: CallbackStatus,
+    pub slot: Option<Slot>,
+    pub err: Option<TransactionError>,
+    /// why the transaction expired or was dropped
+    pub reason: Option<&'static str>,
+    pub history: Vec<LifecycleEvent>,
+}
+
+struct Callback {
+    url: String,
+    body: Vec<u8>,
+}
+
+/// DeadLetter is a callback that couldn't be delivered, as written to the dead-letter log
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DeadLetter<'a> {
+    url: &'a str,
+    payload: serde_json::Value,
+    attempts: usize,
+    error: String,
+    /// unix timestamp in milliseconds
+    at: u64,
+}
+
+/// WebhookNotifier POSTs the outcome of a transaction to the callback url of its request, or to the
+/// default one of its api key. Payloads are signed with HMAC-SHA256, failed deliveries are retried
+/// with exponential backoff and the ones that never succeed go to the dead-letter log.
+/// Callbacks aren't sent to loopback, private or link-local addresses, unless their host is allowed.
+pub struct WebhookNotifier {
+    secret: Option<Vec<u8>>,
+    default_urls: HashMap<String, String>,
+    allowed_hosts: Arc<HashSet<String>>,
+    sender: mpsc::Sender<Callback>,
+}
+
+impl WebhookNotifier {
+    /// new returns a notifier that rejects callbacks when no secret is set
+    pub fn new(
+        secret: Option<String>,
+        default_urls: HashMap<String, String>,
+        allowed_hosts: HashSet<String>,
+        max_attempts: usize,
+        dead_letter_path: PathBuf,
+    ) -> Result<Self, AtlasTxnSenderError> {
+        let allowed_hosts = Arc::new(allowed_hosts);
+        for url in default_urls.values() {
+            validate_callback_url(url, &allowed_hosts)?;
+        }
+        if secret.is_none() && !default_urls.is_empty() {
+            return Err("CALLBACK_URLS needs CALLBACK_SECRET to sign callbacks"
+                .to_string()
+                .into());
+        }
+        let client = reqwest::Client::builder()
+            .timeout(CALLBACK_TIMEOUT)
+            // a redirect could lead anywhere, the resolver only checks the hosts it resolves
+            .redirect(reqwest::redirect::Policy::none())
+            .dns_resolver(Arc::new(PublicResolver {
+                allowed_hosts: allowed_hosts.clone(),
+            }))
+            .build()
+            .map_err(|e| format!("Error building http client: {e}"))?;
+        let (sender, receiver) = mpsc::channel(CALLBACK_QUEUE_SIZE);
+        let notifier = Self {
+            secret: secret.map(String::into_bytes),
+            default_urls,
+            allowed_hosts,
+            sender,
+        };
+        notifier.deliver_callbacks(client, receiver, max_attempts.max(1), dead_letter_path);
+        Ok(notifier)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.secret.is_some()
+    }
+
+    /// validate_callback_url checks a callback url sent with a request
+    pub fn validate_callback_url(&self, url: &str) -> Result<(), AtlasTxnSenderError> {
+        validate_callback_url(url, &self.allowed_hosts)
+    }
+
+    /// notify queues the callback of the transaction, if it has one
+    pub fn notify(&self, request_metadata: &RequestMetadata, payload: CallbackPayload) {
+        if !self.is_enabled() {
+            return;
+        }
+        let Some(url) = request_metadata
+            .callback_url
+            .as_ref()
+            .or_else(|| self.default_urls.get(&request_metadata.api_key))
+        else {
+            return;
+        };
+        let body = match serde_json::to_vec(&payload) {
+            Ok(body) => body,
+            Err(e) => {
+                error!(
+                    "Error serializing callback for {}: {}",
+                    payload.signature, e
+                );
+                return;
+            }
+        };
+        statsd_count!("callback_queued", 1, "status" => payload.status.as_str());
+        if self
+            .sender
+            .try_send(Callback {
+                url: url.clone(),
+                body,
+            })
+            .is_err()
+        {
+            warn!(
+                "Callback queue is full, dropping the callback of {}",
+                payload.signature
+            );
+            statsd_count!("callback_dropped", 1);
+        }
+    }
+
+    fn deliver_callbacks(
+        &self,
+        client: reqwest::Client,
+        mut receiver: mpsc::Receiver<Callback>,
+        max_attempts: usize,
+        dead_letter_path: PathBuf,
+    ) {
+        let Some(secret) = self.secret.clone() else {
+            return;
+        };
+        let secret = Arc::new(secret);
+        let (dead_letter_sender, dead_letter_receiver) = mpsc::unbounded_channel();
+        write_dead_letters(dead_letter_path, dead_letter_receiver);
+        let pending = Arc::new(Semaphore::new(MAX_PENDING_CALLBACKS));
+        let posting = Arc::new(Semaphore::new(MAX_CONCURRENT_CALLBACKS));
+        tokio::spawn(async move {
+            while let Some(callback) = receiver.recv().await {
+                let Ok(pending) = pending.clone().acquire_owned().await else {
+                    return;
+                };
+                let client = client.clone();
+                let secret = secret.clone();
+                let posting = posting.clone();
+                let dead_letter_sender = dead_letter_sender.clone();
+                tokio::spawn(async move {
+                    match deliver(
+                        &client,
+                        &secret,
+                        &callback,
+                        max_attempts,
+                        FIRST_RETRY_DELAY,
+                        &posting,
+                    )
+                    .await
+                    {
+                        Ok(_) => {
+                            statsd_count!("callback_delivered", 1);
+                        }
+                        Err((attempts, e)) => {
+                            error!(
+                                "Giving up on callback to {} after {} attempts: {}",
+                                callback.url, attempts, e
+                            );
+                            statsd_count!("callback_dead_lettered", 1);
+                            let _ = dead_letter_sender.send((callback, attempts, e));
+                        }
+                    }
+                    drop(pending);
+                });
+            }
+        });
+    }
+}
+
+/// deliver posts the callback until it is delivered or `max_attempts` failed, returning the
+/// attempts made. A posting permit is only held during a post, so callbacks waiting to be retried
+/// don't hold up the others.
+async fn deliver(
+    client: &reqwest::Client,
+    secret: &[u8],
+    callback: &Callback,
+    max_attempts: usize,
+    first_retry_delay: Duration,
+    posting: &Semaphore,
+) -> Result<usize, (usize, AtlasTxnSenderError)> {
+    let mut delay = first_retry_delay;
+    let mut attempt = 1;
+    loop {
+        let posted = match posting.acquire().await {
+            Ok(_permit) => post_callback(client, secret, callback).await,
+            Err(e) => Err(format!("Callback delivery stopped: {e}").into()),
+        };
+        match posted {
+            Ok(()) => return Ok(attempt),
+            Err(e) if attempt >= max_attempts => return Err((attempt, e)),
+            Err(e) => {
+                warn!("Error delivering callback to {}: {}", callback.url, e);
+                statsd_count!("callback_retry", 1);
+                sleep(delay).await;
+                delay = next_retry_delay(delay);
+                attempt += 1;
+            }
+        }
+    }
+}
+
+fn next_retry_delay(delay: Duration) -> Duration {
+    (delay * 2).min(MAX_RETRY_DELAY)
+}
+
+/// sign returns the HMAC-SHA256 of `{timestamp}.{body}`, hex encoded
+fn sign(secret: &[u8], timestamp: &str, body: &[u8]) -> Result<String, AtlasTxnSenderError> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret)
+        .map_err(|e| format!("Invalid callback secret: {e}"))?;
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    Ok(to_hex(&mac.finalize().into_bytes()))
+}
+
+/// post_callback sends the payload with its timestamp and a signature over `{timestamp}.{body}`
+async fn post_callback(
+    client: &reqwest::Client,
+    secret: &[u8],
+    callback: &Callback,
+) -> Result<(), AtlasTxnSenderError> {
+    let timestamp = unix_millis_now().to_string();
+    let signature = sign(secret, &timestamp, &callback.body)?;
+    let response = client
+        .post(callback.url.as_str())
+        .header("content-type", "application/json")
+        .header("x-atlas-timestamp", &timestamp)
+        .header("x-atlas-signature", format!("sha256={signature}"))
+        .body(callback.body.clone())
+        .send()
+        .await
+        .map_err(|e| format!("Error posting callback: {e}"))?;
+    if !response.status().is_success() {
+        return Err(format!("Callback returned {}", response.status()).into());
+    }
+    Ok(())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// write_dead_letters appends the callbacks that couldn't be delivered to the dead-letter log
+fn write_dead_letters(
+    path: PathBuf,
+    mut receiver: mpsc::UnboundedReceiver<(Callback, usize, AtlasTxnSenderError)>,
+) {
+    tokio::spawn(async move {
+        while let Some((callback, attempts, e)) = receiver.recv().await {
+            let dead_letter = DeadLetter {
+                url: &callback.url,
+                payload: serde_json::from_slice(&callback.body).unwrap_or_default(),
+                attempts,
+                error: e.to_string(),
+                at: unix_millis_now(),
+            };
+            let Ok(mut line) = serde_json::to_vec(&dead_letter) else {
+                continue;
+            };
+            line.push(b'\n');
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .await;
+            let written = match file {
+                Ok(mut file) => file.write_all(&line).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = written {
+                error!("Error writing dead letter to {}: {}", path.display(), e);
+                statsd_count!("callback_dead_letter_write_error", 1);
+            }
+        }
+    });
+}
+
+/// validate_callback_url accepts http and https urls, except to loopback, private or link-local
+/// addresses unless their host is allowed. Host names are checked when they are resolved.
+fn validate_callback_url(
+    url: &str,
+    allowed_hosts: &HashSet<String>,
+) -> Result<(), AtlasTxnSenderError> {
+    let parsed =
+        reqwest::Url::parse(url).map_err(|e| format!("invalid callback url {url}: {e}"))?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err(format!("callback url must be http or https: {url}").into());
+    }
+    let host = parsed
+        .host_str()
+        .ok_or_else(|| format!("callback url has no host: {url}"))?;
+    let blocked = if allowed_hosts.contains(host) {
+        false
+    } else {
+        // ipv6 hosts are bracketed
+        match host.trim_matches(['[', ']']).parse::<IpAddr>() {
+            Ok(ip) => is_blocked(ip),
+            Err(_) => {
+                let host = host.trim_end_matches('.');
+                host == "localhost" || host.ends_with(".localhost")
+            }
+        }
+    };
+    if blocked {
+        return Err(format!("callback url must not point to a private address: {url}").into());
+    }
+    Ok(())
+}
+
+/// is_blocked returns whether callbacks to the address could reach this host or its network
+fn is_blocked(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_blocked_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_blocked_v4(ip),
+            None => {
+                let segments = ip.segments();
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // unique local fc00::/7 and link-local fe80::/10
+                    || segments[0] & 0xfe00 == 0xfc00
+                    || segments[0] & 0xffc0 == 0xfe80
+            }
+        },
+    }
+}
+
+fn is_blocked_v4(ip: Ipv4Addr) -> bool {
+    let octets = ip.octets();
+    ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        // shared address space 100.64.0.0/10, e.g. carrier-grade NAT
+        || (octets[0] == 100 && octets[1] & 0xc0 == 64)
+        || octets[0] == 0
+}
+
+/// PublicResolver resolves callback hosts to their public addresses only, so a host name can't
+/// lead a callback to a private address
+struct PublicResolver {
+    allowed_hosts: Arc<HashSet<String>>,
+}
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let allowed = self.allowed_hosts.contains(name.as_str());
+        Box::pin(async move {
+            let addrs: Vec<_> = lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| allowed || !is_blocked(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} has no public address", name.as_str()).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// parse_allowed_hosts parses a comma separated list of hosts
+pub fn parse_allowed_hosts(value: &str) -> HashSet<String> {
+    value
+        .split(',')
+        .map(|host| host.trim().to_ascii_lowercase())
+        .filter(|host| !host.is_empty())
+        .collect()
+}
+
+/// parse_callback_urls parses a comma separated list of `api_key:url` pairs
+pub fn parse_callback_urls(value: &str) -> Result<HashMap<String, String>, AtlasTxnSenderError> {
+    let mut callback_urls = HashMap::new();
+    for entry in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        // the url has colons of its own
+        let Some((api_key, url)) = entry.split_once(':') else {
+            return Err(format!("Invalid callback url entry: {entry}").into());
+        };
+        callback_urls.insert(api_key.trim().to_string(), url.trim().to_string());
+    }
+    Ok(callback_urls)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::Infallible,
+        str::FromStr,
+        sync::Mutex,
+        time::{Duration, Instant},
+    };
+
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Body, HeaderMap, Request, Response, Server,
+    };
+
+    use super::*;
+    use crate::test_utils::init_metrics;
+
+    type Received = Arc<Mutex<Vec<(HeaderMap, Vec<u8>)>>>;
+
+    /// callback_server answers 500 to the first `failures` callbacks and 200 to the rest
+    async fn callback_server(failures: usize) -> (String, Received) {
+        let received: Received = Arc::default();
+        let recorded = received.clone();
+        let make_service = make_service_fn(move |_| {
+            let received = recorded.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let received = received.clone();
+                    async move {
+                        let (parts, body) = request.into_parts();
+                        let body = hyper::body::to_bytes(body).await.unwrap().to_vec();
+                        let mut received = received.lock().unwrap();
+                        received.push((parts.headers, body));
+                        let status = if received.len() <= failures { 500 } else { 200 };
+                        Ok::<_, Infallible>(
+                            Response::builder()
+                                .status(status)
+                                .body(Body::empty())
+                                .unwrap(),
+                        )
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+        let url = format!("http://{}/callback", server.local_addr());
+        tokio::spawn(server);
+        (url, received)
+    }
+
+    fn callback(url: &str) -> Callback {
+        Callback {
+            url: url.to_string(),
+            body: br#"{"signature":"abc"}"#.to_vec(),
+        }
+    }
+
+    fn hosts(hosts: &[&str]) -> HashSet<String> {
+        hosts.iter().map(|host| host.to_string()).collect()
+    }
+
+    #[test]
+    fn signs_timestamp_and_body() {
+        assert_eq!(
+            sign(b"secret", "1700000000000", br#"{"signature":"abc"}"#).unwrap(),
+            "92aa2a34786272399e6b7517ea3ba036b0708d36bffceffd639480e32d094add"
+        );
+    }
+
+    #[test]
+    fn backs_off_exponentially() {
+        let mut delay = FIRST_RETRY_DELAY;
+        let mut delays = vec![];
+        for _ in 0..8 {
+            delays.push(delay.as_secs());
+            delay = next_retry_delay(delay);
+        }
+        assert_eq!(delays, vec![1, 2, 4, 8, 16, 32, 60, 60]);
+    }
+
+    #[test]
+    fn rejects_private_callback_urls() {
+        let none = hosts(&[]);
+        assert!(validate_callback_url("https://example.com/callback", &none).is_ok());
+        assert!(validate_callback_url("http://8.8.8.8/callback", &none).is_ok());
+        for url in [
+            "ftp://example.com/callback",
+            "http://127.0.0.1:8080/callback",
+            "http://10.1.2.3/callback",
+            "http://172.16.0.1/callback",
+            "http://192.168.1.1/callback",
+            "http://169.254.169.254/latest/meta-data",
+            "http://100.64.0.1/callback",
+            "http://0.0.0.0/callback",
+            "http://localhost/callback",
+            "http://api.localhost/callback",
+            "http://[::1]/callback",
+            "http://[fd00::1]/callback",
+            "http://[fe80::1]/callback",
+            "http://[::ffff:127.0.0.1]/callback",
+        ] {
+            assert!(validate_callback_url(url, &none).is_err(), "{url}");
+        }
+        let allowed = hosts(&["127.0.0.1", "localhost"]);
+        assert!(validate_callback_url("http://127.0.0.1:8080/callback", &allowed).is_ok());
+        assert!(validate_callback_url("http://localhost/callback", &allowed).is_ok());
+        assert!(validate_callback_url("http://10.1.2.3/callback", &allowed).is_err());
+        assert_eq!(
+            parse_allowed_hosts(" Internal.Example.com,,127.0.0.1 "),
+            hosts(&["internal.example.com", "127.0.0.1"])
+        );
+    }
+
+    #[tokio::test]
+    async fn resolves_only_public_addresses() {
+        let resolver = PublicResolver {
+            allowed_hosts: Arc::new(hosts(&[])),
+        };
+        assert!(resolver
+            .resolve(Name::from_str("localhost").unwrap())
+            .await
+            .is_err());
+        let resolver = PublicResolver {
+            allowed_hosts: Arc::new(hosts(&["localhost"])),
+        };
+        let addrs: Vec<_> = resolver
+            .resolve(Name::from_str("localhost").unwrap())
+            .await
+            .unwrap()
+            .collect();
+        assert!(addrs.iter().all(|addr| addr.ip().is_loopback()));
+    }
+
+    #[tokio::test]
+    async fn retries_until_delivered() {
+        init_metrics();
+        let (url, received) = callback_server(2).await;
+        let callback = callback(&url);
+        let attempts = deliver(
+            &reqwest::Client::new(),
+            b"secret",
+            &callback,
+            5,
+            Duration::from_millis(10),
+            &Semaphore::new(1),
+        )
+        .await
+        .unwrap();
+        assert_eq!(attempts, 3);
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 3);
+        let (headers, body) = &received[2];
+        assert_eq!(body, &callback.body);
+        let timestamp = headers["x-atlas-timestamp"].to_str().unwrap();
+        assert_eq!(
+            headers["x-atlas-signature"].to_str().unwrap(),
+            format!("sha256={}", sign(b"secret", timestamp, body).unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_attempts() {
+        init_metrics();
+        let (url, received) = callback_server(usize::MAX).await;
+        let (attempts, e) = deliver(
+            &reqwest::Client::new(),
+            b"secret",
+            &callback(&url),
+            3,
+            Duration::from_millis(10),
+            &Semaphore::new(1),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(attempts, 3);
+        assert!(e.to_string().contains("500"));
+        assert_eq!(received.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn releases_permit_while_backing_off() {
+        init_metrics();
+        let (url, received) = callback_server(usize::MAX).await;
+        let posting = Arc::new(Semaphore::new(1));
+        let delivery = {
+            let posting = posting.clone();
+            tokio::spawn(async move {
+                deliver(
+                    &reqwest::Client::new(),
+                    b"secret",
+                    &callback(&url),
+                    2,
+                    Duration::from_secs(1),
+                    &posting,
+                )
+                .await
+            })
+        };
+        let start = Instant::now();
+        while received.lock().unwrap().is_empty() && start.elapsed() < Duration::from_secs(5) {
+            sleep(Duration::from_millis(10)).await;
+        }
+        sleep(Duration::from_millis(100)).await;
+        // the first attempt failed and the delivery waits to retry without the permit
+        assert!(posting.try_acquire().is_ok());
+        assert!(!delivery.is_finished());
+        assert!(delivery.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn writes_dead_letters() {
+        let path =
+            std::env::temp_dir().join(format!("dead-letters-{}.jsonl", uuid::Uuid::new_v4()));
+        let (sender, receiver) = mpsc::unbounded_channel();
+        write_dead_letters(path.clone(), receiver);
+        sender
+            .send((
+                callback("https://example.com/callback"),
+                3,
+                AtlasTxnSenderError::from("Callback returned 500".to_string()),
+            ))
+            .unwrap();
+
+        let start = Instant::now();
+        let mut contents = String::new();
+        while contents.is_empty() && start.elapsed() < Duration::from_secs(5) {
+            sleep(Duration::from_millis(10)).await;
+            contents = tokio::fs::read_to_string(&path).await.unwrap_or_default();
+        }
+        let dead_letter: serde_json::Value = serde_json::from_str(contents.trim()).unwrap();
+        assert_eq!(dead_letter["url"], "https://example.com/callback");
+        assert_eq!(dead_letter["payload"]["signature"], "abc");
+        assert_eq!(dead_letter["attempts"], 3);
+        assert!(dead_letter["error"].as_str().unwrap().contains("500"));
+        let _ = tokio::fs::remove_file(&path).await;
+    }
+}
@@ -0,0 +1,208 @@
+use solana_sdk::system_transaction;
+
+use crate::suite::{SuitePorts, TestSuite, TESTER1_PUBKEY};
+
+mod suite;
+
+// Tests for cancelTransaction and replaceTransaction
+// Can be run using `cargo test --test cancel_replace -- --nocapture`
+
+/// A cancelled signature is refused when it is submitted afterwards
+#[tokio::test]
+async fn cancelled_transaction_is_refused() {
+    let suite = TestSuite::new_local(SuitePorts::default()).await;
+
+    let tx = system_transaction::transfer(
+        &suite.testers[0],
+        &TESTER1_PUBKEY,
+        1001,
+        suite.get_latest_blockhash().await,
+    );
+    let sig = tx.signatures[0].to_string();
+
+    // The transaction was never queued, but the tombstone is kept
+    let result = suite.p3_client.cancel_transaction(&sig).await;
+    assert_eq!(result["result"], serde_json::json!(false));
+
+    let result = suite.p3_client.try_send_transaction(&tx).await;
+    assert!(result.get("error").is_some(), "{result}");
+}
+
+/// Replacing a signature that is not queued fails and does not send the new transaction
+#[tokio::test]
+async fn replace_unknown_transaction_fails() {
+    let suite = TestSuite::new_local(SuitePorts::default()).await;
+
+    let blockhash = suite.get_latest_blockhash().await;
+    let original =
+        system_transaction::transfer(&suite.testers[0], &TESTER1_PUBKEY, 1002, blockhash);
+    let replacement =
+        system_transaction::transfer(&suite.testers[0], &TESTER1_PUBKEY, 1003, blockhash);
+
+    let result = suite
+        .p3_client
+        .replace_transaction(&original.signatures[0].to_string(), &replacement)
+        .await;
+    assert!(result.get("error").is_some(), "{result}");
+
+    // The replacement can still be sent on its own
+    let result = suite.p3_client.try_send_transaction(&replacement).await;
+    assert_eq!(
+        result["result"],
+        serde_json::json!(replacement.signatures[0].to_string())
+    );
+}
+
+/// A queued transaction can be replaced, after which the original is refused
+#[tokio::test]
+async fn replace_queued_transaction() {
+    let suite = TestSuite::new_local(SuitePorts::default()).await;
+
+    let blockhash = suite.get_latest_blockhash().await;
+    let original =
+        system_transaction::transfer(&suite.testers[0], &TESTER1_PUBKEY, 1004, blockhash);
+    let replacement =
+        system_transaction::transfer(&suite.testers[0], &TESTER1_PUBKEY, 1005, blockhash);
+    let original_sig = original.signatures[0].to_string();
+    let replacement_sig = replacement.signatures[0].to_string();
+
+    let result = suite.p3_client.try_send_transaction(&original).await;
+    assert_eq!(result["result"], serde_json::json!(original_sig));
+
+    let result = suite
+        .p3_client
+        .replace_transaction(&original_sig, &replacement)
+        .await;
+    assert_eq!(
+        result["result"],
+        serde_json::json!(replacement_sig),
+        "{result}"
+    );
+
+    let result = suite.p3_client.try_send_transaction(&original).await;
+    assert!(result.get("error").is_some(), "{result}");
+
+    let result = suite.get_transaction(&replacement_sig).await;
+    assert!(result.slot > 0);
+}
+
+/// A transaction waiting in the scheduler can be replaced and keeps its schedule
+#[tokio::test]
+async fn replace_scheduled_transaction() {
+    let suite = TestSuite::new_local(SuitePorts::default()).await;
+
+    let blockhash = suite.get_latest_blockhash().await;
+    let original =
+        system_transaction::transfer(&suite.testers[0], &TESTER1_PUBKEY, 1006, blockhash);
+    let replacement =
+        system_transaction::transfer(&suite.testers[0], &TESTER1_PUBKEY, 1007, blockhash);
+    let not_before_slot = suite.rpc_client.get_slot().await.unwrap() + 20;
+
+    let result = suite
+        .p3_client
+        .try_send_transaction_with_metadata(
+            &original,
+            serde_json::json!({"notBeforeSlot": not_before_slot}),
+        )
+        .await;
+    let original_sig = result["result"].as_str().expect("scheduled send failed");
+
+    let result = suite
+        .p3_client
+        .replace_transaction(original_sig, &replacement)
+        .await;
+    let replacement_sig = result["result"].as_str().expect("replace failed");
+
+    let result = suite.p3_client.try_send_transaction(&original).await;
+    assert!(result.get("error").is_some(), "{result}");
+
+    let result = suite.get_transaction(replacement_sig).await;
+    assert!(result.slot >= not_before_slot);
+}
+
+/// Cancelling a queued transaction reports it and refuses it afterwards
+#[tokio::test]
+async fn cancel_queued_transaction() {
+    let suite = TestSuite::new_local(SuitePorts::default()).await;
+
+    let tx = system_transaction::transfer(
+        &suite.testers[0],
+        &TESTER1_PUBKEY,
+        1008,
+        suite.get_latest_blockhash().await,
+    );
+    let sig = tx.signatures[0].to_string();
+
+    let result = suite.p3_client.try_send_transaction(&tx).await;
+    assert_eq!(result["result"], serde_json::json!(sig));
+
+    let result = suite.p3_client.cancel_transaction(&sig).await;
+    assert_eq!(result["result"], serde_json::json!(true));
+
+    let result = suite.p3_client.try_send_transaction(&tx).await;
+    assert!(result.get("error").is_some(), "{result}");
+}
+
+/// Only the api key a transaction was sent with can cancel or replace it, and a replacement has
+/// to spend the same blockhash
+#[tokio::test]
+async fn cancel_and_replace_check_the_original() {
+    let suite = TestSuite::new_local(SuitePorts::default()).await;
+
+    let blockhash = suite.get_latest_blockhash().await;
+    let original =
+        system_transaction::transfer(&suite.testers[0], &TESTER1_PUBKEY, 1009, blockhash);
+    let replacement =
+        system_transaction::transfer(&suite.testers[0], &TESTER1_PUBKEY, 1010, blockhash);
+    let original_sig = original.signatures[0].to_string();
+
+    let result = suite
+        .p3_client
+        .try_send_transaction_with_metadata(&original, serde_json::json!({"apiKey": "owner"}))
+        .await;
+    assert_eq!(result["result"], serde_json::json!(original_sig));
+
+    let result = suite
+        .p3_client
+        .cancel_transaction_with_metadata(&original_sig, serde_json::json!({"apiKey": "other"}))
+        .await;
+    assert!(result.get("error").is_some(), "{result}");
+    let result = suite
+        .p3_client
+        .replace_transaction(&original_sig, &replacement)
+        .await;
+    assert!(result.get("error").is_some(), "{result}");
+
+    // signed with a newer blockhash, the original could land alongside it
+    let newer = system_transaction::transfer(
+        &suite.testers[0],
+        &TESTER1_PUBKEY,
+        1011,
+        suite.get_latest_blockhash().await,
+    );
+    if newer.message.recent_blockhash != blockhash {
+        let result = suite
+            .p3_client
+            .replace_transaction_with_metadata(
+                &original_sig,
+                &newer,
+                serde_json::json!({"apiKey": "owner"}),
+            )
+            .await;
+        assert!(result.get("error").is_some(), "{result}");
+    }
+
+    let result = suite
+        .p3_client
+        .replace_transaction_with_metadata(
+            &original_sig,
+            &replacement,
+            serde_json::json!({"apiKey": "owner"}),
+        )
+        .await;
+    assert_eq!(
+        result["result"],
+        serde_json::json!(replacement.signatures[0].to_string()),
+        "{result}"
+    );
+}
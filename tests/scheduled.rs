@@ -0,0 +1,63 @@
+use solana_sdk::system_transaction;
+
+use crate::suite::{SuitePorts, TestSuite, TESTER2_PUBKEY};
+
+mod suite;
+
+// Tests for sends scheduled with notBeforeSlot / notAfterSlot / targetSlot / targetLeader
+// Can be run using `cargo test --test scheduled -- --nocapture`
+
+/// Transaction is held until notBeforeSlot
+#[tokio::test]
+async fn not_before_slot() {
+    let suite = TestSuite::new_local(SuitePorts::default()).await;
+
+    let tx = system_transaction::transfer(
+        &suite.testers[1],
+        &TESTER2_PUBKEY,
+        1000,
+        suite.get_latest_blockhash().await,
+    );
+    let not_before_slot = suite.rpc_client.get_slot().await.unwrap() + 20;
+
+    let result = suite
+        .p3_client
+        .try_send_transaction_with_metadata(
+            &tx,
+            serde_json::json!({"notBeforeSlot": not_before_slot}),
+        )
+        .await;
+    let sig = result["result"].as_str().expect("scheduled send failed");
+
+    let result = suite.get_transaction(sig).await;
+    assert!(result.slot >= not_before_slot);
+}
+
+/// Windows that already passed or that are empty are rejected up front
+#[tokio::test]
+async fn unserviceable_schedule_is_rejected() {
+    let suite = TestSuite::new_local(SuitePorts::default()).await;
+
+    let tx = system_transaction::transfer(
+        &suite.testers[1],
+        &TESTER2_PUBKEY,
+        1001,
+        suite.get_latest_blockhash().await,
+    );
+    let cur_slot = suite.rpc_client.get_slot().await.unwrap();
+
+    let result = suite
+        .p3_client
+        .try_send_transaction_with_metadata(&tx, serde_json::json!({"notAfterSlot": 1}))
+        .await;
+    assert!(result.get("error").is_some(), "{result}");
+
+    let result = suite
+        .p3_client
+        .try_send_transaction_with_metadata(
+            &tx,
+            serde_json::json!({"notBeforeSlot": cur_slot + 20, "notAfterSlot": cur_slot + 10}),
+        )
+        .await;
+    assert!(result.get("error").is_some(), "{result}");
+}
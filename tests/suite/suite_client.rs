@@ -1,5 +1,6 @@
 use std::{future::IntoFuture, time::Duration};
 
+use base64::{prelude::BASE64_STANDARD, Engine};
 use reqwest::Client;
 use solana_sdk::transaction::Transaction;
 
@@ -51,6 +52,113 @@ impl SuiteClient {
 
         results
     }
+
+    /// Sends single transaction to this port and returns the raw JSON-RPC response
+    pub async fn try_send_transaction(&self, tx: &Transaction) -> serde_json::Value {
+        self.try_send_transaction_with_metadata(tx, serde_json::json!({}))
+            .await
+    }
+
+    /// Sends single transaction to this port with extra request metadata, e.g. a slot window,
+    /// and returns the raw JSON-RPC response
+    pub async fn try_send_transaction_with_metadata(
+        &self,
+        tx: &Transaction,
+        mut metadata: serde_json::Value,
+    ) -> serde_json::Value {
+        metadata["sendPort"] = self.send_port.into();
+        rpc_request(
+            &self._client,
+            &self.client_url,
+            "sendTransaction",
+            serde_json::json!([
+                encode_transaction(tx),
+                {"skipPreflight": true, "encoding": "base64"},
+                metadata,
+            ]),
+        )
+        .await
+    }
+
+    /// Cancels a transaction by signature and returns the raw JSON-RPC response
+    pub async fn cancel_transaction(&self, signature: &str) -> serde_json::Value {
+        self.cancel_transaction_with_metadata(signature, serde_json::json!({}))
+            .await
+    }
+
+    /// Cancels a transaction by signature with request metadata, e.g. an api key, and returns the
+    /// raw JSON-RPC response
+    pub async fn cancel_transaction_with_metadata(
+        &self,
+        signature: &str,
+        metadata: serde_json::Value,
+    ) -> serde_json::Value {
+        rpc_request(
+            &self._client,
+            &self.client_url,
+            "cancelTransaction",
+            serde_json::json!([signature, metadata]),
+        )
+        .await
+    }
+
+    /// Replaces a queued transaction and returns the raw JSON-RPC response
+    pub async fn replace_transaction(
+        &self,
+        signature: &str,
+        tx: &Transaction,
+    ) -> serde_json::Value {
+        self.replace_transaction_with_metadata(signature, tx, serde_json::json!({}))
+            .await
+    }
+
+    /// Replaces a queued transaction with request metadata, e.g. an api key, and returns the raw
+    /// JSON-RPC response
+    pub async fn replace_transaction_with_metadata(
+        &self,
+        signature: &str,
+        tx: &Transaction,
+        metadata: serde_json::Value,
+    ) -> serde_json::Value {
+        rpc_request(
+            &self._client,
+            &self.client_url,
+            "replaceTransaction",
+            serde_json::json!([
+                signature,
+                encode_transaction(tx),
+                {"skipPreflight": true, "encoding": "base64"},
+                metadata,
+            ]),
+        )
+        .await
+    }
+}
+
+fn encode_transaction(tx: &Transaction) -> String {
+    BASE64_STANDARD.encode(bincode::serialize(tx).unwrap())
+}
+
+async fn rpc_request(
+    client: &Client,
+    client_url: &str,
+    method: &str,
+    params: serde_json::Value,
+) -> serde_json::Value {
+    client
+        .post(client_url)
+        .json(&serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": method,
+            "params": params,
+            "id": 1
+        }))
+        .send()
+        .await
+        .unwrap()
+        .json::<serde_json::Value>()
+        .await
+        .unwrap()
 }
 
 /// Helper function
@@ -61,7 +169,7 @@ async fn send_transaction(
     tx: Transaction,
     id: u8,
 ) -> String {
-    let serialized = base64::encode(bincode::serialize(&tx).unwrap());
+    let serialized = encode_transaction(&tx);
 
     let res = client
         .post(client_url)